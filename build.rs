@@ -0,0 +1,19 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/ywkv.proto");
+
+        // Use the vendored protoc unless one is set explicitly
+        if std::env::var_os("PROTOC").is_none() {
+            std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        }
+
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/ywkv.proto"], &["proto"])?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,470 @@
+//! Server administration endpoints under `/_admin`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path as UrlPath, Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use ywkv::{AuditPage, YwkvError};
+
+use crate::{blocking, compact::Compaction, openapi::RawValue, Caller, DbState};
+
+/// Write a consistent snapshot of the whole database. With a backup directory the snapshot is
+/// kept there and its path is returned, otherwise it is sent back as the response body.
+#[utoipa::path(
+    post,
+    path = "/_admin/backup",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The snapshot", body = RawValue, content_type = "application/octet-stream"),
+        (status = 201, description = "The path the snapshot was written to in the backup directory", body = ywkv::Response<String>),
+    )
+)]
+pub async fn backup(State(state): State<DbState>) -> Response {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    let path = match &state.backup_dir {
+        Some(dir) => dir.join(format!("ywkv-{millis}.redb")),
+        None => temp_path("backup"),
+    };
+
+    let res = blocking({
+        let state = state.clone();
+        let path = path.clone();
+        move || state.backup(path)
+    })
+    .await;
+    if let Err(e) = res {
+        return ywkv::Response::from_read_error(e).into_response();
+    }
+
+    if state.backup_dir.is_some() {
+        return (
+            StatusCode::CREATED,
+            Json::from(ywkv::Response::new(
+                path.display().to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+            )),
+        )
+            .into_response();
+    }
+
+    stream_file(path).await
+}
+
+/// A path in the temp directory that no other request will use.
+pub fn temp_path(purpose: &str) -> PathBuf {
+    // Keeps requests started in the same millisecond from claiming the same file
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    std::env::temp_dir().join(format!(
+        "ywkv-{purpose}-{}-{millis}-{n}.redb",
+        std::process::id()
+    ))
+}
+
+/// Respond with the contents of a temporary snapshot, removing it afterwards.
+pub async fn stream_file(path: PathBuf) -> Response {
+    let res = tokio::fs::read(&path).await;
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("Failed to remove backup `{}`: {e}", path.display());
+    }
+
+    let data = match res {
+        Ok(v) => v,
+        Err(e) => return ywkv::Response::from_read_error(e).into_response(),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(ywkv::DEFAULT_CONTENT_TYPE),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"ywkv-backup.redb\""),
+    );
+
+    (StatusCode::OK, headers, data).into_response()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RestoreQuery {
+    /// The file name of a snapshot in the backup directory
+    backup: Option<String>,
+}
+
+/// Replace the whole database with a snapshot, either one in the backup directory or one sent as
+/// the request body. Nothing changes if the snapshot can't be restored.
+#[utoipa::path(
+    post,
+    path = "/_admin/restore",
+    tag = "admin",
+    params(RestoreQuery),
+    request_body(
+        content = RawValue,
+        content_type = "application/octet-stream",
+        description = "A snapshot from `/_admin/backup`, unless `backup` is given",
+    ),
+    responses(
+        (status = 200, body = ywkv::Response<String>),
+        (status = 400, description = "The snapshot or backup name is invalid", body = ywkv::Response<String>),
+    )
+)]
+pub async fn restore(
+    State(state): State<DbState>,
+    Caller(actor): Caller,
+    Query(query): Query<RestoreQuery>,
+    payload: Bytes,
+) -> (StatusCode, Json<ywkv::Response>) {
+    let error = |code, message| {
+        (
+            code,
+            Json::from(ywkv::Response::new(
+                message,
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            )),
+        )
+    };
+
+    let (path, temporary) = match (query.backup, &state.backup_dir) {
+        // Only plain file names, so nothing outside the backup directory can be read
+        (Some(name), Some(dir)) if Path::new(&name).file_name() == Some(name.as_ref()) => {
+            (dir.join(name), false)
+        }
+        (Some(name), Some(_)) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid backup name `{name}`"),
+            )
+        }
+        (Some(_), None) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "no backup directory is configured".to_string(),
+            )
+        }
+        (None, _) => {
+            let path = temp_path("restore");
+            if let Err(e) = tokio::fs::write(&path, &payload).await {
+                return ywkv::Response::from_write_error(e);
+            }
+
+            (path, true)
+        }
+    };
+
+    let db = state.db.clone().with_actor(actor);
+    let res = blocking({
+        let path = path.clone();
+        move || db.restore(path)
+    })
+    .await;
+
+    if temporary {
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            tracing::warn!("Failed to remove snapshot `{}`: {e}", path.display());
+        }
+    }
+
+    match res {
+        Ok(()) => (
+            StatusCode::OK,
+            Json::from(ywkv::Response::new(
+                String::new(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+            )),
+        ),
+        Err(e) => ywkv::Response::from_write_error(e),
+    }
+}
+
+/// Shrink the database file, reporting its size before and after. Other requests wait until
+/// compaction is done.
+#[utoipa::path(
+    post,
+    path = "/_admin/compact",
+    tag = "admin",
+    responses((status = 200, body = ywkv::Response<Compaction>))
+)]
+pub async fn compact(
+    State(state): State<DbState>,
+) -> Result<(StatusCode, Json<ywkv::Response<Compaction>>), (StatusCode, Json<ywkv::Response>)> {
+    match blocking(move || crate::compact::compact(&state, &state.path)).await {
+        Ok(compaction) => Ok((
+            StatusCode::OK,
+            Json::from(ywkv::Response::new(
+                compaction,
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        )),
+        Err(e) => Err(ywkv::Response::from_write_error(e)),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    table: Option<String>,
+    key: Option<String>,
+    /// Only keys starting with this
+    prefix: Option<String>,
+    /// A token fingerprint
+    token: Option<String>,
+    /// Only records from at or after this many milliseconds since the unix epoch
+    since: Option<u64>,
+    /// Only records from before this many milliseconds since the unix epoch
+    until: Option<u64>,
+    limit: Option<usize>,
+    cursor: Option<u64>,
+}
+
+/// List audit records matching the query, newest first.
+#[utoipa::path(
+    get,
+    path = "/_admin/audit",
+    tag = "admin",
+    params(AuditQuery),
+    responses((status = 200, body = ywkv::Response<AuditPage>))
+)]
+pub async fn audit(
+    State(state): State<DbState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<(StatusCode, Json<ywkv::Response<AuditPage>>), (StatusCode, Json<ywkv::Response>)> {
+    const DEFAULT_LIMIT: usize = 100;
+    const MAX_LIMIT: usize = 1000;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let res = blocking(move || {
+        state.audit_log(query.cursor, limit, |record| {
+            let key = record.key.as_deref();
+
+            (query.table.is_none() || record.table == query.table)
+                && (query.key.is_none() || record.key == query.key)
+                && query
+                    .prefix
+                    .as_deref()
+                    .is_none_or(|prefix| key.is_some_and(|v| v.starts_with(prefix)))
+                && (query.token.is_none() || record.token == query.token)
+                && query.since.is_none_or(|v| record.at >= v)
+                && query.until.is_none_or(|v| record.at < v)
+        })
+    })
+    .await;
+
+    match res {
+        Ok(page) => Ok((
+            StatusCode::OK,
+            Json::from(ywkv::Response::new(
+                page,
+                ywkv::Status::Read(ywkv::ReadStatus::Found),
+            )),
+        )),
+        Err(e) => Err(ywkv::Response::from_read_error(e)),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TableInfo {
+    name: String,
+    /// Including expired keys that haven't been purged yet
+    keys: u64,
+    /// Whether requests outside `/_table/{table}` use this table
+    default: bool,
+}
+
+/// List the tables in the database that requests may use, sorted by name.
+#[utoipa::path(
+    get,
+    path = "/_admin/tables",
+    tag = "admin",
+    responses((status = 200, body = ywkv::Response<Vec<TableInfo>>))
+)]
+pub async fn tables(
+    State(state): State<DbState>,
+) -> Result<Json<ywkv::Response<Vec<TableInfo>>>, (StatusCode, Json<ywkv::Response>)> {
+    let res = blocking(move || {
+        let mut tables = vec![];
+        for name in state.tables()? {
+            if !state.allows_table(&name) {
+                continue;
+            }
+            tables.push(TableInfo {
+                keys: state.with_table(name.as_str())?.key_count()?,
+                default: name == state.table(),
+                name,
+            });
+        }
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok::<_, YwkvError>(tables)
+    })
+    .await;
+
+    match res {
+        Ok(tables) => Ok(Json::from(ywkv::Response::new(
+            tables,
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        ))),
+        Err(e) => Err(ywkv::Response::from_read_error(e)),
+    }
+}
+
+/// Create an empty table, which otherwise happens on its first write.
+#[utoipa::path(
+    post,
+    path = "/_admin/tables/{table}",
+    tag = "admin",
+    params(("table" = String, Path)),
+    responses(
+        (status = 201, body = ywkv::Response<String>),
+        (status = 400, description = "The table name is invalid", body = ywkv::Response<String>),
+        (status = 404, description = "Requests may not use the table", body = ywkv::Response<String>),
+        (status = 409, description = "The table already exists", body = ywkv::Response<String>),
+    )
+)]
+pub async fn create_table(
+    State(state): State<DbState>,
+    UrlPath(table): UrlPath<String>,
+) -> Result<(StatusCode, Json<ywkv::Response>), (StatusCode, Json<ywkv::Response>)> {
+    let db = state.open_table(Some(&table))?;
+
+    match blocking(move || db.create_table()).await {
+        Ok(()) => Ok((
+            StatusCode::CREATED,
+            Json::from(ywkv::Response::new(
+                String::new(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+            )),
+        )),
+        Err(e) => Err(ywkv::Response::from_write_error(e)),
+    }
+}
+
+/// Delete every key in a table and then the table, responding with how many keys were deleted.
+/// The default table can't be dropped.
+#[utoipa::path(
+    delete,
+    path = "/_admin/tables/{table}",
+    tag = "admin",
+    params(("table" = String, Path)),
+    responses(
+        (status = 200, body = ywkv::Response<u64>),
+        (status = 400, description = "The table name is invalid", body = ywkv::Response<String>),
+        (status = 404, description = "The table doesn't exist or requests may not use it", body = ywkv::Response<String>),
+        (status = 409, description = "The table is the default table", body = ywkv::Response<String>),
+    )
+)]
+pub async fn drop_table(
+    State(state): State<DbState>,
+    Caller(actor): Caller,
+    UrlPath(table): UrlPath<String>,
+) -> Result<Json<ywkv::Response<u64>>, (StatusCode, Json<ywkv::Response>)> {
+    if table == state.table() {
+        return Err((
+            StatusCode::CONFLICT,
+            Json::from(ywkv::Response::new(
+                format!("`{table}` is the default table and can't be dropped"),
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            )),
+        ));
+    }
+    let db = state.open_table(Some(&table))?.with_actor(actor);
+
+    match blocking(move || db.drop_table()).await {
+        Ok(deleted) => Ok(Json::from(ywkv::Response::new(
+            deleted,
+            ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+        ))),
+        Err(e) => Err(ywkv::Response::from_write_error(e)),
+    }
+}
+
+/// Delete every key in a table, keeping the table, and respond with how many were deleted.
+#[utoipa::path(
+    post,
+    path = "/_admin/tables/{table}/truncate",
+    tag = "admin",
+    params(("table" = String, Path)),
+    responses(
+        (status = 200, body = ywkv::Response<u64>),
+        (status = 400, description = "The table name is invalid", body = ywkv::Response<String>),
+        (status = 404, description = "The table doesn't exist or requests may not use it", body = ywkv::Response<String>),
+    )
+)]
+pub async fn truncate_table(
+    State(state): State<DbState>,
+    Caller(actor): Caller,
+    UrlPath(table): UrlPath<String>,
+) -> Result<Json<ywkv::Response<u64>>, (StatusCode, Json<ywkv::Response>)> {
+    let db = state.open_table(Some(&table))?.with_actor(actor);
+
+    match blocking(move || db.truncate()).await {
+        Ok(deleted) => Ok(Json::from(ywkv::Response::new(
+            deleted,
+            ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+        ))),
+        Err(e) => Err(ywkv::Response::from_write_error(e)),
+    }
+}
+
+/// Reload tokens, rate limits, the log level and the TLS certificate, like SIGHUP does.
+#[utoipa::path(
+    post,
+    path = "/_admin/reload",
+    tag = "admin",
+    responses(
+        (status = 200, body = ywkv::Response<String>),
+        (status = 500, description = "The configuration couldn't be loaded, so nothing changed", body = ywkv::Response<String>),
+    )
+)]
+pub async fn reload(State(state): State<DbState>) -> (StatusCode, Json<ywkv::Response>) {
+    let res = match &state.reloader {
+        Some(reloader) => reloader.reload().await,
+        None => Err(anyhow::anyhow!("reloading isn't set up")),
+    };
+
+    match res {
+        Ok(()) => (
+            StatusCode::OK,
+            Json::from(ywkv::Response::new(
+                String::new(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        ),
+        Err(e) => {
+            tracing::error!("Failed to reload configuration: {e:#}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json::from(ywkv::Response::new(
+                    format!("{e:#}"),
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                )),
+            )
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Bearer token authentication with a role per token.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// What a token is allowed to do. Each role can do everything the roles before it can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Read values and watch for changes
+    ReadOnly,
+    /// Also write and delete values
+    ReadWrite,
+    /// Also use server administration endpoints like `/_metrics` and `/_admin`
+    Admin,
+}
+
+impl Role {
+    /// The role needed to make a request. Writes over `/_ws` are checked per command.
+    pub fn required(method: &Method, route: Option<&str>) -> Self {
+        let route = route.unwrap_or_default();
+
+        if route == "/_metrics" || route.starts_with("/_admin/") {
+            return Role::Admin;
+        }
+        // Fetching many keys at once needs a body, so it is a POST even though it only reads
+        if route.ends_with("/_mget") || route.ends_with("/_snapshot-read") {
+            return Role::ReadOnly;
+        }
+
+        match *method {
+            Method::GET | Method::HEAD => Role::ReadOnly,
+            _ => Role::ReadWrite,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(Role::ReadOnly),
+            "read-write" => Ok(Role::ReadWrite),
+            "admin" => Ok(Role::Admin),
+            v => anyhow::bail!("unknown role `{v}`, expected `read-only`, `read-write` or `admin`"),
+        }
+    }
+}
+
+/// Identifies the token a request was made with, without revealing the token. It is the first
+/// 16 hex characters of the token's SHA-256.
+#[derive(Clone)]
+pub struct Fingerprint(pub String);
+
+impl Fingerprint {
+    fn new(token: &str) -> Self {
+        let mut hash = format!("{:x}", Sha256::digest(token));
+        hash.truncate(16);
+
+        Self(hash)
+    }
+}
+
+/// Valid tokens and their roles. Clones share the same tokens, so replacing them affects every
+/// clone.
+#[derive(Clone)]
+pub struct Tokens(Arc<RwLock<HashMap<String, Role>>>);
+
+impl Tokens {
+    pub fn new(tokens: impl IntoIterator<Item = (String, Role)>) -> Self {
+        Self(Arc::new(RwLock::new(Self::build(tokens))))
+    }
+
+    pub fn replace(&self, tokens: impl IntoIterator<Item = (String, Role)>) {
+        *self.0.write().unwrap() = Self::build(tokens);
+    }
+
+    /// Keep the most permissive role for tokens that are listed more than once.
+    fn build(tokens: impl IntoIterator<Item = (String, Role)>) -> HashMap<String, Role> {
+        let mut roles = HashMap::new();
+        for (token, role) in tokens {
+            let current = roles.entry(token).or_insert(role);
+            *current = role.max(*current);
+        }
+
+        roles
+    }
+
+    fn get(&self, token: &str) -> Option<Role> {
+        self.0.read().unwrap().get(token).copied()
+    }
+
+    /// The role and fingerprint of the bearer token in an `Authorization` header, if it's valid.
+    pub fn check(&self, authorization: &str) -> Option<(Role, Fingerprint)> {
+        let token = authorization.strip_prefix("Bearer ")?;
+
+        Some((self.get(token)?, Fingerprint::new(token)))
+    }
+}
+
+/// Any other fields, like a `label` describing who the token is for, are ignored.
+#[derive(Deserialize)]
+struct TokenEntry {
+    token: String,
+    role: Option<String>,
+}
+
+/// Read tokens from a file. The file is either a JSON array of `{"token", "role", "label"}`
+/// objects, or one token per line optionally followed by a role. Lines starting with `#` are
+/// ignored. Tokens without a role are read-write.
+pub fn load_file(path: &Path) -> anyhow::Result<Vec<(String, Role)>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read token file `{}`", path.display()))?;
+
+    if contents.trim_start().starts_with('[') {
+        let entries = serde_json::from_str::<Vec<TokenEntry>>(&contents)
+            .with_context(|| format!("failed to parse token file `{}`", path.display()))?;
+
+        return entries
+            .into_iter()
+            .map(|v| {
+                let role = match v.role {
+                    Some(role) => role.parse()?,
+                    None => Role::ReadWrite,
+                };
+
+                Ok((v.token, role))
+            })
+            .collect();
+    }
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && !v.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            let token = parts.next().unwrap_or_default().to_string();
+            let role = match parts.next() {
+                Some(role) => role.parse()?,
+                None => Role::ReadWrite,
+            };
+
+            Ok((token, role))
+        })
+        .collect()
+}
+
+/// Reject requests without a valid token or whose token's role doesn't allow the request. The
+/// token's [Role] and [Fingerprint] are added to the request extensions for handlers that need
+/// them.
+pub async fn authorize<B>(
+    State(tokens): State<Tokens>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let checked = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| tokens.check(v));
+
+    let (role, fingerprint) = match checked {
+        Some(v) => v,
+        None => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json::from(ywkv::Response::new(
+                    "missing or invalid bearer token".to_string(),
+                    ywkv::Status::Read(ywkv::ReadStatus::Failure),
+                )),
+            )
+                .into_response()
+        }
+    };
+
+    let required = Role::required(
+        request.method(),
+        request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|v| v.as_str()),
+    );
+    if role < required {
+        return (
+            StatusCode::FORBIDDEN,
+            Json::from(ywkv::Response::new(
+                format!("{role:?} tokens cannot make this request"),
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            )),
+        )
+            .into_response();
+    }
+
+    request.extensions_mut().insert(role);
+    request.extensions_mut().insert(fingerprint);
+
+    next.run(request).await
+}
@@ -0,0 +1,63 @@
+//! Serving every route under a path prefix like `/kv`, for reverse proxies that mount ywkv next to
+//! other services on the same domain.
+
+use axum::{
+    body::Body,
+    http::{uri::PathAndQuery, Request, StatusCode, Uri},
+    response::IntoResponse,
+    Router,
+};
+use hyper::service::Service;
+
+/// `/kv/` and `/kv` are the same prefix, and `/` is no prefix at all.
+pub fn parse(value: &str) -> Result<String, String> {
+    if !value.starts_with('/') {
+        return Err("expected a path starting with `/` like `/kv`".to_string());
+    }
+
+    Ok(value.trim_end_matches('/').to_string())
+}
+
+/// Serve `app` under `base_path` and nothing outside of it.
+///
+/// The prefix is stripped before `app` routes the request rather than nesting `app`, so matched
+/// paths stay the same and the checks that look at them, like which role a route needs, work
+/// the same with or without a prefix.
+pub fn mount(base_path: String, app: Router) -> Router {
+    Router::new().fallback(move |request: Request<Body>| {
+        let mut app = app.clone();
+        let base_path = base_path.clone();
+        async move {
+            let request = match strip(&base_path, request) {
+                Some(v) => v,
+                None => return StatusCode::NOT_FOUND.into_response(),
+            };
+
+            // Routers are always ready, so there's no need to wait with `poll_ready`
+            match app.call(request).await {
+                Ok(v) => v,
+                Err(e) => match e {},
+            }
+        }
+    })
+}
+
+fn strip(base_path: &str, request: Request<Body>) -> Option<Request<Body>> {
+    let (mut parts, body) = request.into_parts();
+
+    let path = match parts.uri.path().strip_prefix(base_path)? {
+        "" => "/",
+        v if v.starts_with('/') => v,
+        _ => return None,
+    };
+    let path_and_query = match parts.uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+
+    let mut uri = parts.uri.clone().into_parts();
+    uri.path_and_query = Some(path_and_query.parse::<PathAndQuery>().ok()?);
+    parts.uri = Uri::from_parts(uri).ok()?;
+
+    Some(Request::from_parts(parts, body))
+}
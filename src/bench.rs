@@ -0,0 +1,330 @@
+//! The `bench` subcommand, which measures how fast a server reads and writes.
+//!
+//! Without `--url` it starts a server of its own from the same binary on an empty database, so
+//! runs with different settings can be compared without anything else getting in the way.
+
+use std::{
+    net::TcpListener,
+    path::PathBuf,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use ywkv::{Client, Value};
+
+pub const NAME: &str = "bench";
+
+const URL: &str = "url";
+const TOKEN: &str = "token";
+const WRITES: &str = "writes";
+const READERS: &str = "readers";
+const WRITERS: &str = "writers";
+const VALUE_SIZE: &str = "value-size";
+const SERVER_ARGS: &str = "server-args";
+
+/// Every key the benchmark writes starts with this, so they can be cleaned up afterwards.
+const PREFIX: &str = "_bench:";
+/// How long a server that was started for the benchmark has to start accepting requests.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn command() -> Command {
+    Command::new(NAME)
+        .about("Measure read and write throughput and latency against a server")
+        .arg(
+            Arg::new(URL)
+                .long(URL)
+                .required(false)
+                .requires(TOKEN)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(TOKEN)
+                .long(TOKEN)
+                .env("YWKV_TOKEN")
+                .hide_env_values(true)
+                .required(false)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(WRITES)
+                .long(WRITES)
+                .required(false)
+                .default_value("10000")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(READERS)
+                .long(READERS)
+                .required(false)
+                .default_value("4")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(WRITERS)
+                .long(WRITERS)
+                .required(false)
+                .default_value("4")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(VALUE_SIZE)
+                .long(VALUE_SIZE)
+                .required(false)
+                .default_value("128")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(SERVER_ARGS)
+                .required(false)
+                .last(true)
+                .num_args(0..)
+                .action(ArgAction::Append),
+        )
+}
+
+/// What a benchmark does, shared by every task.
+struct Workload {
+    client: Client,
+    /// How many keys there are to read and overwrite
+    keys: u64,
+    value: Value,
+}
+
+impl Workload {
+    fn key(i: u64) -> String {
+        format!("{PREFIX}{i}")
+    }
+
+    fn random_key(&self) -> String {
+        Self::key(OsRng.next_u64() % self.keys)
+    }
+
+    async fn write(&self, key: &str) -> anyhow::Result<()> {
+        self.client.set(key, self.value.clone(), None).await?;
+        Ok(())
+    }
+
+    async fn read(&self, key: &str) -> anyhow::Result<()> {
+        match self.client.get(key).await? {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("key `{key}` was written but not found"),
+        }
+    }
+}
+
+/// Latencies of every request in a phase, which only ever runs once per benchmark.
+struct Phase {
+    name: &'static str,
+    took: Duration,
+    latencies: Vec<Duration>,
+}
+
+impl Phase {
+    fn report(mut self) {
+        self.latencies.sort();
+        let percentile = |p: usize| {
+            let i = (self.latencies.len() * p / 100).min(self.latencies.len() - 1);
+            millis(self.latencies[i])
+        };
+
+        println!(
+            "{:<8} {:>8} requests in {:>7.2}s {:>9.0}/s   p50 {:>7.2}ms   p90 {:>7.2}ms   p99 {:>7.2}ms   max {:>7.2}ms",
+            self.name,
+            self.latencies.len(),
+            self.took.as_secs_f64(),
+            self.latencies.len() as f64 / self.took.as_secs_f64(),
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            millis(*self.latencies.last().unwrap()),
+        );
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+pub async fn run(args: &ArgMatches, durability: &str) -> anyhow::Result<()> {
+    let writes = *args.get_one::<u64>(WRITES).unwrap();
+    let readers = *args.get_one::<u64>(READERS).unwrap();
+    let writers = *args.get_one::<u64>(WRITERS).unwrap();
+    let value_size = *args.get_one::<usize>(VALUE_SIZE).unwrap();
+
+    // Stopped when dropped, so the server goes away however the benchmark ends
+    let mut server = None;
+    let client = match args.get_one::<String>(URL) {
+        Some(url) => Client::new(url, args.get_one::<String>(TOKEN).unwrap())?,
+        None => {
+            let server_args = args
+                .get_many::<String>(SERVER_ARGS)
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let started = Server::start(durability, &server_args).await?;
+            let client = started.client.clone();
+            server = Some(started);
+            client
+        }
+    };
+
+    let workload = Arc::new(Workload {
+        client,
+        keys: writes,
+        value: Value::new(vec![b'x'; value_size], ywkv::DEFAULT_CONTENT_TYPE),
+    });
+    println!(
+        "{writes} writes of {value_size} bytes from {writers} writers, and as many reads from {readers} readers"
+    );
+
+    // Every key is written once first, so the reads after always find something
+    let phase = run_phase("write", writes, writers, &workload, |workload, i| {
+        Box::pin(async move { workload.write(&Workload::key(i)).await })
+    })
+    .await?;
+    phase.report();
+
+    let phase = run_phase("read", writes, readers, &workload, |workload, _| {
+        Box::pin(async move { workload.read(&workload.random_key()).await })
+    })
+    .await?;
+    phase.report();
+
+    let (overwrites, reads) = tokio::join!(
+        run_phase("mixed w", writes, writers, &workload, |workload, _| {
+            Box::pin(async move { workload.write(&workload.random_key()).await })
+        }),
+        run_phase("mixed r", writes, readers, &workload, |workload, _| {
+            Box::pin(async move { workload.read(&workload.random_key()).await })
+        }),
+    );
+    overwrites?.report();
+    reads?.report();
+
+    // A server started for the benchmark takes its database with it
+    if server.is_none() {
+        workload
+            .client
+            .delete_prefix(PREFIX)
+            .await
+            .context("failed to delete the keys the benchmark wrote")?;
+    }
+
+    Ok(())
+}
+
+type Request =
+    fn(
+        Arc<Workload>,
+        u64,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Send `total` requests spread evenly over `tasks` concurrent tasks, timing each one.
+async fn run_phase(
+    name: &'static str,
+    total: u64,
+    tasks: u64,
+    workload: &Arc<Workload>,
+    request: Request,
+) -> anyhow::Result<Phase> {
+    let started = Instant::now();
+    let mut set = tokio::task::JoinSet::new();
+    for task in 0..tasks {
+        let workload = workload.clone();
+        set.spawn(async move {
+            let mut latencies = Vec::new();
+            // Task `task` sends request `task`, then `task + tasks`, and so on
+            for i in (task..total).step_by(tasks as usize) {
+                let started = Instant::now();
+                request(workload.clone(), i).await?;
+                latencies.push(started.elapsed());
+            }
+            anyhow::Ok(latencies)
+        });
+    }
+
+    let mut latencies = Vec::new();
+    while let Some(res) = set.join_next().await {
+        latencies.extend(res.context("benchmark task panicked")??);
+    }
+
+    Ok(Phase {
+        name,
+        took: started.elapsed(),
+        latencies,
+    })
+}
+
+/// A server of this binary on a new database in the temporary directory, stopped and deleted
+/// when dropped.
+struct Server {
+    client: Client,
+    _process: tokio::process::Child,
+    _db_file: TempFile,
+}
+
+impl Server {
+    /// Start the server and wait for it to accept requests.
+    async fn start(durability: &str, server_args: &[String]) -> anyhow::Result<Self> {
+        // The port is free for the server to take once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let db_file = TempFile(
+            std::env::temp_dir().join(format!("ywkv-bench-{}-{port}.redb", std::process::id())),
+        );
+        let mut token = [0_u8; 16];
+        OsRng.fill_bytes(&mut token);
+        let token = token.iter().map(|v| format!("{v:02x}")).collect::<String>();
+
+        let mut process = tokio::process::Command::new(std::env::current_exe()?)
+            .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .arg("--db-file-name")
+            .arg(&db_file.0)
+            .args(["--create-if-missing", "true", "--durability", durability])
+            .args(["--log-level", "warn"])
+            .args(server_args)
+            .arg(&token)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to start a server")?;
+        let client = Client::new(&format!("http://127.0.0.1:{port}"), token)?;
+
+        let started = Instant::now();
+        while client.list_keys(None, 1).await.is_err() {
+            if let Some(status) = process.try_wait()? {
+                anyhow::bail!("the server exited with {status} before accepting requests");
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                anyhow::bail!(
+                    "the server didn't accept requests within {}s",
+                    STARTUP_TIMEOUT.as_secs()
+                );
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        Ok(Self {
+            client,
+            _process: process,
+            _db_file: db_file,
+        })
+    }
+}
+
+/// Removed when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,129 @@
+//! Opening a database file with [crate::Db::builder].
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use redb::Database;
+
+use crate::{Db, Durability, YwkvError};
+
+/// Options for opening a database file. Created with [Db::builder].
+#[derive(Clone, Debug)]
+pub struct DbBuilder {
+    cache_size: Option<usize>,
+    create_if_missing: bool,
+    read_only: bool,
+    durability: Durability,
+}
+
+impl Default for DbBuilder {
+    fn default() -> Self {
+        Self {
+            cache_size: None,
+            create_if_missing: true,
+            read_only: false,
+            durability: Durability::Immediate,
+        }
+    }
+}
+
+impl DbBuilder {
+    /// How many bytes redb may use to cache pages of the file. redb's default is 1 GiB.
+    pub fn cache_size(mut self, bytes: usize) -> Self {
+        self.cache_size = Some(bytes);
+        self
+    }
+
+    /// Create a new database if the file doesn't exist, which is the default. Otherwise opening a
+    /// missing file fails with [YwkvError::DatabaseMissing].
+    ///
+    /// A file that exists but can't be opened is never replaced.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Reject every write through the handle, and any handles created from it, with
+    /// [YwkvError::ReadOnly]. The file must already exist.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// See [Db::set_durability].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Open the database at `path` with a handle to `table`.
+    pub fn open<P: AsRef<Path>, T: Into<String>>(self, path: P, table: T) -> Result<Db, YwkvError> {
+        let path = path.as_ref();
+        let open_error = |e| YwkvError::Open(path.display().to_string(), e);
+
+        let mut builder = Database::builder();
+        if let Some(bytes) = self.cache_size {
+            builder.set_cache_size(bytes);
+        }
+
+        // Only create a new database when the file is genuinely absent, so a corrupt file or bad
+        // permissions are never papered over with a fresh database
+        let database = match std::fs::metadata(path) {
+            Ok(_) => match crate::is_database_file(path) {
+                Ok(true) => builder.open(path).map_err(open_error)?,
+                Ok(false) => return Err(YwkvError::NotADatabase(path.display().to_string())),
+                Err(e) => return Err(open_error(e.into())),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if !self.create_if_missing || self.read_only {
+                    return Err(YwkvError::DatabaseMissing(path.display().to_string()));
+                }
+
+                builder.create(path).map_err(open_error)?
+            }
+            Err(e) => return Err(open_error(e.into())),
+        };
+
+        let mut db = Db::new(database, table)?;
+        db.read_only = self.read_only;
+        db.durability = self.durability;
+        db.path = Some(path.into());
+
+        Ok(db)
+    }
+
+    /// Open a new, empty database in a file in the temporary directory with a handle to `table`.
+    /// The file is deleted once every handle to it is dropped, but is left behind if the process
+    /// is killed. [DbBuilder::create_if_missing] and [DbBuilder::read_only] don't apply.
+    pub fn open_temporary<T: Into<String>>(self, table: T) -> Result<Db, YwkvError> {
+        let mut name = [0_u8; 8];
+        OsRng.fill_bytes(&mut name);
+        let name = name.iter().map(|v| format!("{v:02x}")).collect::<String>();
+        let path = std::env::temp_dir().join(format!("ywkv-{}-{name}.redb", std::process::id()));
+
+        let builder = Self {
+            create_if_missing: true,
+            read_only: false,
+            ..self
+        };
+        // Made before opening, so a failed open doesn't leave a partly created file behind
+        let temporary = Arc::new(TempFile(path));
+        let mut db = builder.open(&temporary.0, table)?;
+        db.temporary = Some(temporary);
+
+        Ok(db)
+    }
+}
+
+/// Deleted when dropped.
+pub(crate) struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
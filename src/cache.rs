@@ -0,0 +1,160 @@
+//! An LRU cache of recently read values, shared by every handle to a database.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use lru::LruCache;
+
+use crate::{KeyMeta, Value};
+
+/// Counters for [crate::Db::cache_stats].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone)]
+pub(crate) struct Cached {
+    pub value: Value,
+    /// Unix millis
+    pub expires_at: Option<u64>,
+    pub meta: KeyMeta,
+}
+
+pub(crate) struct Cache {
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner {
+    entries: LruCache<String, Cached>,
+    bytes: usize,
+    max_bytes: Option<usize>,
+    /// Bumped on every invalidation, so a read that raced with a write can tell its value may
+    /// already be stale
+    generation: u64,
+}
+
+impl Cache {
+    pub fn new(max_entries: Option<NonZeroUsize>, max_bytes: Option<usize>) -> Self {
+        let entries = match max_entries {
+            Some(v) => LruCache::new(v),
+            None => LruCache::unbounded(),
+        };
+
+        Self {
+            inner: Mutex::new(Inner {
+                entries,
+                bytes: 0,
+                max_bytes,
+                generation: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, table: &str, key: &str, now: u64) -> Option<Cached> {
+        let id = id(table, key);
+        let mut inner = self.inner.lock().unwrap();
+
+        let cached = match inner.entries.get(&id) {
+            Some(v) if v.expires_at.is_none_or(|v| v > now) => Some(v.clone()),
+            Some(_) => {
+                inner.remove(&id);
+                None
+            }
+            None => None,
+        };
+
+        match cached {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        cached
+    }
+
+    /// Call before reading from the database, and pass the result to [Cache::insert].
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// Cache a value read from the database, unless a key was invalidated since `generation`.
+    pub fn insert(&self, generation: u64, table: &str, key: &str, cached: Cached) {
+        let id = id(table, key);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+
+        let added = size(&id, &cached);
+        if inner.max_bytes.is_some_and(|max| added > max) {
+            return;
+        }
+
+        inner.remove(&id);
+        inner.bytes += added;
+        if let Some((id, evicted)) = inner.entries.push(id, cached) {
+            inner.bytes -= size(&id, &evicted);
+        }
+
+        while inner.max_bytes.is_some_and(|max| inner.bytes > max) {
+            match inner.entries.pop_lru() {
+                Some((id, evicted)) => inner.bytes -= size(&id, &evicted),
+                None => break,
+            }
+        }
+    }
+
+    pub fn invalidate(&self, table: &str, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.remove(&id(table, key));
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.entries.clear();
+        inner.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, id: &str) {
+        if let Some(removed) = self.entries.pop(id) {
+            self.bytes -= size(id, &removed);
+        }
+    }
+}
+
+fn id(table: &str, key: &str) -> String {
+    // Table names can't contain NUL, so this can't collide
+    format!("{table}\0{key}")
+}
+
+/// An estimate of the memory used by an entry.
+fn size(id: &str, cached: &Cached) -> usize {
+    id.len() + cached.value.data.len() + cached.value.content_type.len()
+}
@@ -0,0 +1,152 @@
+//! The `get`, `set`, `del` and `ls` subcommands, which talk to a running server over HTTP.
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command};
+use ywkv::{Client, Value};
+
+pub const GET: &str = "get";
+pub const SET: &str = "set";
+pub const DEL: &str = "del";
+pub const LS: &str = "ls";
+pub const NAMES: [&str; 4] = [GET, SET, DEL, LS];
+
+const URL: &str = "url";
+const TOKEN: &str = "token";
+const KEY: &str = "key";
+const VALUE: &str = "value";
+const CONTENT_TYPE_ARG: &str = "content-type";
+const TTL: &str = "ttl";
+
+pub fn commands() -> [Command; 4] {
+    let key = || Arg::new(KEY).required(true).action(ArgAction::Set);
+
+    [
+        Command::new(GET)
+            .about("Print the value of a key on a running server")
+            .arg(key()),
+        Command::new(SET)
+            .about("Write a key on a running server, reading the value from stdin if not given")
+            .arg(key())
+            .arg(Arg::new(VALUE).required(false).action(ArgAction::Set))
+            .arg(
+                Arg::new(CONTENT_TYPE_ARG)
+                    .long(CONTENT_TYPE_ARG)
+                    .required(false)
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new(TTL)
+                    .long(TTL)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64))
+                    .action(ArgAction::Set),
+            ),
+        Command::new(DEL)
+            .about("Delete a key on a running server")
+            .arg(key()),
+        Command::new(LS).about("List every key on a running server"),
+    ]
+    .map(|command| {
+        command
+            .arg(
+                Arg::new(URL)
+                    .long(URL)
+                    .env("YWKV_URL")
+                    .required(false)
+                    .default_value("http://localhost:9958")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new(TOKEN)
+                    .long(TOKEN)
+                    .env("YWKV_TOKEN")
+                    .hide_env_values(true)
+                    .required(true)
+                    .action(ArgAction::Set),
+            )
+    })
+}
+
+fn client(args: &ArgMatches, table_name: &str) -> anyhow::Result<Client> {
+    let url = args.get_one::<String>(URL).unwrap();
+    let client = Client::new(url, args.get_one::<String>(TOKEN).unwrap())?;
+
+    // Only leave the server's default table when one was actually asked for
+    let explicit = args
+        .value_source(crate::TABLE_NAME_ARG)
+        .is_some_and(|v| v != ValueSource::DefaultValue);
+    match explicit {
+        true => Ok(client.with_table(table_name)),
+        false => Ok(client),
+    }
+}
+
+pub async fn run(name: &str, args: &ArgMatches, table_name: &str) -> anyhow::Result<()> {
+    let client = client(args, table_name)?;
+
+    match name {
+        GET => {
+            let key = args.get_one::<String>(KEY).unwrap();
+            let value = match client.get(key).await? {
+                Some(v) => v,
+                None => anyhow::bail!("key `{key}` not found"),
+            };
+
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&value.data)?;
+            // Keeps the prompt on its own line for text, without changing binary values
+            if std::io::IsTerminal::is_terminal(&stdout) && !value.data.ends_with(b"\n") {
+                stdout.write_all(b"\n")?;
+            }
+        }
+        SET => {
+            let key = args.get_one::<String>(KEY).unwrap();
+            let data = match args.get_one::<String>(VALUE) {
+                Some(v) => v.clone().into_bytes(),
+                None => {
+                    let mut data = Vec::new();
+                    std::io::stdin()
+                        .read_to_end(&mut data)
+                        .context("failed to read the value from stdin")?;
+                    data
+                }
+            };
+            let content_type = args
+                .get_one::<String>(CONTENT_TYPE_ARG)
+                .map_or(ywkv::DEFAULT_CONTENT_TYPE, String::as_str);
+            let ttl = args.get_one::<u64>(TTL).copied().map(Duration::from_secs);
+
+            client.set(key, Value::new(data, content_type), ttl).await?;
+        }
+        DEL => {
+            let key = args.get_one::<String>(KEY).unwrap();
+            if client.delete(key).await?.is_none() {
+                anyhow::bail!("key `{key}` not found");
+            }
+        }
+        LS => {
+            let mut cursor = None;
+            loop {
+                let page = client.list_keys(cursor.as_deref(), 1000).await?;
+
+                let mut stdout = std::io::stdout().lock();
+                for key in page.keys {
+                    writeln!(stdout, "{key}")?;
+                }
+
+                match page.cursor {
+                    Some(v) => cursor = Some(v),
+                    None => break,
+                }
+            }
+        }
+        _ => unreachable!("not a client subcommand"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! An async HTTP client for a running server, enabled with the `client` feature.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use reqwest::{header::CONTENT_TYPE, Method, RequestBuilder, StatusCode, Url};
+use serde::de::DeserializeOwned;
+
+use crate::{KeyPage, Response, Status, Value, WriteStatus, DEFAULT_CONTENT_TYPE};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("invalid server URL `{0}`")]
+    InvalidUrl(String),
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("server responded with {status}: {}", response.value())]
+    Server {
+        status: StatusCode,
+        response: Response,
+    },
+    #[error("unexpected response from the server: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+/// Talks to a server with a bearer token. Clones share the same connection pool.
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    server: Url,
+    /// The server's default table is used if not set
+    table: Option<String>,
+    token: String,
+}
+
+impl Client {
+    /// `url` is where the server is, like `http://localhost:9958`. Requests go to the server's
+    /// default table.
+    pub fn new(url: &str, token: impl Into<String>) -> Result<Self, ClientError> {
+        let server = match Url::parse(url) {
+            Ok(v) if !v.cannot_be_a_base() => v,
+            _ => return Err(ClientError::InvalidUrl(url.to_string())),
+        };
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            server,
+            table: None,
+            token: token.into(),
+        })
+    }
+
+    /// A client for another table on the same server.
+    pub fn with_table(&self, table: impl Into<String>) -> Self {
+        Self {
+            table: Some(table.into()),
+            ..self.clone()
+        }
+    }
+
+    /// Read a key, or `None` if it doesn't exist. Tables that aren't available are also `None`.
+    pub async fn get(&self, key: &str) -> Result<Option<Value>, ClientError> {
+        let response = self.request(Method::GET, self.url(key)).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check(response).await?;
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(DEFAULT_CONTENT_TYPE)
+            .to_string();
+        let data = response.bytes().await?;
+
+        Ok(Some(Value::new(data, content_type)))
+    }
+
+    /// Write a key, which expires after `ttl` if given.
+    pub async fn set(
+        &self,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<WriteStatus, ClientError> {
+        let mut url = self.url(key);
+        if let Some(ttl) = ttl {
+            url.query_pairs_mut()
+                .append_pair("ttl", &ttl.as_secs().to_string());
+        }
+
+        let response = self
+            .request(Method::POST, url)
+            .header(CONTENT_TYPE, value.content_type)
+            .body(value.data)
+            .send()
+            .await?;
+        let status = response.status();
+        let response = parse::<String>(response).await?;
+
+        match response.status {
+            Status::Write(v) => Ok(v),
+            Status::Read(_) => Err(ClientError::Server { status, response }),
+        }
+    }
+
+    /// Delete a key, returning its value as text, or `None` if it didn't exist.
+    pub async fn delete(&self, key: &str) -> Result<Option<String>, ClientError> {
+        let response = self.request(Method::DELETE, self.url(key)).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = parse::<String>(response).await?;
+
+        Ok(Some(response.value))
+    }
+
+    /// Write many text values at once, returning each key's previous value and status.
+    pub async fn batch(
+        &self,
+        values: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, Response>, ClientError> {
+        let request = self.request(Method::POST, self.url("_batch")).json(values);
+        let response = check(request.send().await?).await?;
+
+        Ok(serde_json::from_slice(&response.bytes().await?)?)
+    }
+
+    /// Delete every key starting with `prefix`, returning how many there were.
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<u64, ClientError> {
+        let mut url = self.url("_prefix");
+        url.path_segments_mut()
+            .expect("URL was checked to be a base")
+            .push(prefix);
+
+        let response = self.request(Method::DELETE, url).send().await?;
+        let response = parse::<String>(response).await?;
+
+        Ok(serde_json::from_str(&response.value)?)
+    }
+
+    /// Up to `limit` keys after `cursor`, in order.
+    pub async fn list_keys(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<KeyPage, ClientError> {
+        let mut url = self.url("_keys");
+        url.query_pairs_mut()
+            .append_pair("limit", &limit.to_string());
+        if let Some(cursor) = cursor {
+            url.query_pairs_mut().append_pair("cursor", cursor);
+        }
+
+        let response = self.request(Method::GET, url).send().await?;
+
+        Ok(parse(response).await?.value)
+    }
+
+    fn url(&self, segment: &str) -> Url {
+        let mut url = self.server.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .expect("URL was checked to be a base");
+            segments.pop_if_empty();
+            if let Some(table) = &self.table {
+                segments.extend(["_table", table]);
+            }
+            segments.push(segment);
+        }
+
+        url
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.http.request(method, url).bearer_auth(&self.token)
+    }
+}
+
+/// Turn error responses into [ClientError::Server].
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.bytes().await?;
+    let response = serde_json::from_slice(&body).unwrap_or_else(|_| {
+        Response::new(
+            String::from_utf8_lossy(&body).into_owned(),
+            Status::Read(crate::ReadStatus::Failure),
+        )
+    });
+
+    Err(ClientError::Server { status, response })
+}
+
+async fn parse<T: DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<Response<T>, ClientError> {
+    let response = check(response).await?;
+
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
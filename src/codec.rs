@@ -0,0 +1,36 @@
+//! Converting typed values to and from stored data for [crate::Db::read_as] and
+//! [crate::Db::write_as].
+
+use std::error::Error;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub type CodecError = Box<dyn Error + Send + Sync>;
+
+/// A way of storing typed values. Implement it to store values in a format other than JSON.
+pub trait Codec {
+    /// The content type values are written with.
+    fn content_type(&self) -> &str;
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Stores values as JSON, so they can also be read over HTTP.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
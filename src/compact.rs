@@ -0,0 +1,45 @@
+//! The `compact` subcommand, and compaction shared with `/_admin/compact`.
+
+use clap::Command;
+use serde::Serialize;
+use utoipa::ToSchema;
+use ywkv::{Db, YwkvError};
+
+pub const NAME: &str = "compact";
+
+pub fn command() -> Command {
+    Command::new(NAME).about("Shrink the database file by reclaiming unused space")
+}
+
+/// The size of the database file around a compaction.
+#[derive(Serialize, ToSchema)]
+pub struct Compaction {
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+}
+
+pub fn compact(db: &Db, path: &str) -> Result<Compaction, YwkvError> {
+    let before_bytes = std::fs::metadata(path)?.len();
+    db.compact()?;
+    let after_bytes = std::fs::metadata(path)?.len();
+
+    Ok(Compaction {
+        before_bytes,
+        after_bytes,
+    })
+}
+
+pub fn run(db_file_name: &str, table_name: &str) -> anyhow::Result<()> {
+    // Compacting a file that doesn't exist yet would only create an empty one
+    let db = Db::builder()
+        .create_if_missing(false)
+        .open(db_file_name, table_name)?;
+
+    let compaction = compact(&db, db_file_name)?;
+    println!(
+        "Compacted `{db_file_name}` from {} to {} bytes",
+        compaction.before_bytes, compaction.after_bytes
+    );
+
+    Ok(())
+}
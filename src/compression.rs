@@ -0,0 +1,64 @@
+//! Compression of values at rest with zstd.
+
+use std::borrow::Cow;
+
+use crate::YwkvError;
+
+/// Marks data with a compression header. Like encrypted data, it starts with `0xff`, which never
+/// appears in UTF-8, so text written before compression was enabled can't be mistaken for it.
+const MAGIC: &[u8] = b"\xffYWC";
+/// The codec byte after [MAGIC] for data stored as is, which only happens when the data itself
+/// starts with [MAGIC].
+const STORED: u8 = 0;
+const ZSTD: u8 = 1;
+/// zstd's default, which is fast while still shrinking JSON several times over.
+const LEVEL: i32 = 3;
+
+/// Compress `data` if it's at least `min_size` bytes and compressing it saves space. Nothing is
+/// compressed if `min_size` isn't given.
+pub(crate) fn compress(data: &[u8], min_size: Option<usize>) -> Cow<'_, [u8]> {
+    if min_size.is_some_and(|v| data.len() >= v) {
+        match zstd::bulk::compress(data, LEVEL) {
+            Ok(compressed) if MAGIC.len() + 1 + compressed.len() < data.len() => {
+                return Cow::Owned(with_header(ZSTD, &compressed))
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Failed to compress a value of {} bytes: {e}", data.len()),
+        }
+    }
+
+    match data.starts_with(MAGIC) {
+        true => Cow::Owned(with_header(STORED, data)),
+        false => Cow::Borrowed(data),
+    }
+}
+
+fn with_header(codec: u8, data: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(MAGIC.len() + 1 + data.len());
+    stored.extend_from_slice(MAGIC);
+    stored.push(codec);
+    stored.extend_from_slice(data);
+
+    stored
+}
+
+/// Undo [compress]. Anything without a header is returned as is, since it was written before
+/// compression was enabled or wasn't worth compressing.
+pub(crate) fn decompress(data: Cow<'_, [u8]>) -> Result<Cow<'_, [u8]>, YwkvError> {
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+
+    match rest.split_first() {
+        Some((&STORED, rest)) => Ok(Cow::Owned(rest.to_vec())),
+        Some((&ZSTD, rest)) => zstd::stream::decode_all(rest)
+            .map(Cow::Owned)
+            .map_err(|e| YwkvError::Compression(format!("failed to decompress value: {e}"))),
+        Some((codec, _)) => Err(YwkvError::Compression(format!(
+            "value is compressed with unknown codec {codec}"
+        ))),
+        None => Err(YwkvError::Compression(
+            "compressed value is truncated".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,89 @@
+//! Layered configuration. Every flag can also be set with a `YWKV_` environment variable or a key
+//! of the same name in a TOML config file, in that order of precedence after the command line.
+
+use anyhow::Context;
+use clap::{builder::Resettable, Arg, ArgAction, Command};
+use toml::{Table, Value};
+
+/// The environment variable that sets the flag with the given id, e.g. `YWKV_TABLE_NAME`.
+fn env_name(id: &str) -> String {
+    format!("YWKV_{}", id.to_uppercase().replace('-', "_"))
+}
+
+/// Let `arg` be set from its environment variable, falling back to the config file before its
+/// built-in default.
+pub fn layer(arg: Arg, config: &Table) -> Arg {
+    let id = arg.get_id().to_string();
+    let arg = arg.env(env_name(&id));
+
+    match config.get(&id) {
+        Some(Value::Array(values)) => {
+            let values = values.iter().map(to_string).collect::<Vec<_>>();
+            arg.default_values(values).required(false)
+        }
+        Some(value) => arg.default_value(to_string(value)).required(false),
+        None => arg,
+    }
+}
+
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::String(v) => v.clone(),
+        v => v.to_string(),
+    }
+}
+
+pub fn load(path: &str) -> anyhow::Result<Table> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file `{path}`"))?;
+
+    contents
+        .parse::<Table>()
+        .with_context(|| format!("failed to parse config file `{path}`"))
+}
+
+/// Reject keys that don't match any flag, as well as values the flag wouldn't accept, so mistakes
+/// are caught at startup instead of being ignored.
+pub fn validate(config: &Table, command: &Command) -> anyhow::Result<()> {
+    for (key, value) in config {
+        let arg = match command.get_arguments().find(|v| v.get_id() == key) {
+            Some(v) => v,
+            None => anyhow::bail!("unknown config key `{key}`"),
+        };
+
+        let values = match value {
+            Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => {
+                values.iter().collect()
+            }
+            Value::Array(_) => anyhow::bail!("config key `{key}` only accepts a single value"),
+            Value::Table(_) | Value::Datetime(_) => {
+                anyhow::bail!("config key `{key}` must be a string, number, boolean or array")
+            }
+            v => vec![v],
+        };
+
+        for value in values.into_iter().map(to_string) {
+            // Run the value through the flag's own parser by passing it on a command of its own
+            let probe = Command::new(command.get_name().to_string()).arg(
+                arg.clone()
+                    .env(None)
+                    .default_value(None)
+                    .requires(Resettable::Reset)
+                    .required(true),
+            );
+            let input = match arg.get_long() {
+                Some(long) => format!("--{long}={value}"),
+                None => value.clone(),
+            };
+
+            if let Err(e) = probe.try_get_matches_from([command.get_name(), &input]) {
+                anyhow::bail!(
+                    "invalid value `{value}` for config key `{key}`: {}",
+                    e.kind()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
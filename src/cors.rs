@@ -0,0 +1,83 @@
+//! Cross-origin requests, so browsers can call the API directly.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::http::{
+    header::{
+        AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH,
+        LAST_MODIFIED, RETRY_AFTER,
+    },
+    HeaderValue, Method,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{idempotency, request_id, TTL_HEADER};
+
+/// Methods allowed when none are configured, which covers every route.
+const DEFAULT_METHODS: [Method; 5] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PATCH,
+    Method::DELETE,
+];
+
+/// How long browsers may cache a preflight response.
+const MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Allow requests from `origins`, or from anywhere if one of them is `*`. Browsers may only send
+/// the `Authorization` header when `allow_authorization` is set.
+pub fn layer(
+    origins: &[String],
+    methods: &[String],
+    allow_authorization: bool,
+) -> anyhow::Result<CorsLayer> {
+    let allow_origin = if origins.iter().any(|v| v == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|v| HeaderValue::from_str(v).with_context(|| format!("invalid origin `{v}`")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        AllowOrigin::list(origins)
+    };
+
+    let methods = match methods {
+        [] => DEFAULT_METHODS.to_vec(),
+        methods => methods
+            .iter()
+            .map(|v| {
+                Method::from_bytes(v.to_uppercase().as_bytes())
+                    .with_context(|| format!("invalid method `{v}`"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+    };
+
+    let mut headers = vec![
+        CONTENT_TYPE,
+        IF_MATCH,
+        IF_NONE_MATCH,
+        request_id::HEADER,
+        idempotency::HEADER,
+    ];
+    if allow_authorization {
+        headers.push(AUTHORIZATION);
+    }
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .expose_headers([
+            ETAG,
+            LAST_MODIFIED,
+            RETRY_AFTER,
+            CONTENT_DISPOSITION,
+            TTL_HEADER,
+            request_id::HEADER,
+            idempotency::REPLAYED_HEADER,
+        ])
+        .max_age(MAX_AGE))
+}
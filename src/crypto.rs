@@ -0,0 +1,132 @@
+//! Encryption of values at rest with XChaCha20-Poly1305.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::YwkvError;
+
+/// Marks encrypted data. `0xff` never appears in UTF-8, so text written before encryption was
+/// enabled can't be mistaken for it.
+const MAGIC: &[u8] = b"\xffYWE";
+const NONCE_LEN: usize = 24;
+
+/// The keys values are encrypted with. New values use the current key, and values written with
+/// any of the others can still be read, so keys can be rotated without rewriting everything.
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    current: String,
+    keys: HashMap<String, XChaCha20Poly1305>,
+}
+
+impl EncryptionKeys {
+    /// `id` is stored next to every value encrypted with `key` and must be at most 255 bytes.
+    pub fn new(id: impl Into<String>, key: [u8; 32]) -> Result<Self, YwkvError> {
+        let mut keys = Self {
+            current: id.into(),
+            keys: HashMap::new(),
+        };
+        keys.insert(keys.current.clone(), key)?;
+
+        Ok(keys)
+    }
+
+    /// Add an older key that values may still be encrypted with.
+    pub fn with_old_key(mut self, id: impl Into<String>, key: [u8; 32]) -> Result<Self, YwkvError> {
+        self.insert(id.into(), key)?;
+
+        Ok(self)
+    }
+
+    fn insert(&mut self, id: String, key: [u8; 32]) -> Result<(), YwkvError> {
+        if id.is_empty() || id.len() > usize::from(u8::MAX) {
+            return Err(YwkvError::Encryption(format!(
+                "key id `{id}` must be between 1 and 255 bytes"
+            )));
+        }
+        if self.keys.contains_key(&id) {
+            return Err(YwkvError::Encryption(format!("duplicate key id `{id}`")));
+        }
+
+        self.keys.insert(id, XChaCha20Poly1305::new(&key.into()));
+
+        Ok(())
+    }
+
+    /// Encrypt `data` with the current key as `MAGIC`, the key id length and key id, the nonce,
+    /// then the ciphertext. The content type is authenticated so it can't be swapped.
+    pub(crate) fn seal(&self, content_type: &str, data: &[u8]) -> Result<Vec<u8>, YwkvError> {
+        let cipher = &self.keys[&self.current];
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: content_type.as_bytes(),
+                },
+            )
+            .map_err(|_| YwkvError::Encryption("failed to encrypt value".to_string()))?;
+
+        let mut sealed =
+            Vec::with_capacity(MAGIC.len() + 1 + self.current.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(self.current.len() as u8);
+        sealed.extend_from_slice(self.current.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        Ok(sealed)
+    }
+
+    /// Decrypt data written by [Self::seal]. Anything else is returned as is, since it was written
+    /// before encryption was enabled.
+    pub(crate) fn open<'a>(
+        keys: Option<&Self>,
+        content_type: &str,
+        data: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, YwkvError> {
+        let rest = match data.strip_prefix(MAGIC) {
+            Some(v) => v,
+            None => return Ok(Cow::Borrowed(data)),
+        };
+        let keys = match keys {
+            Some(v) => v,
+            None => {
+                return Err(YwkvError::Encryption(
+                    "value is encrypted but no encryption keys are configured".to_string(),
+                ))
+            }
+        };
+        let invalid = || YwkvError::Encryption("encrypted value is truncated".to_string());
+
+        let (&id_len, rest) = rest.split_first().ok_or_else(invalid)?;
+        if rest.len() < usize::from(id_len) + NONCE_LEN {
+            return Err(invalid());
+        }
+        let (id, rest) = rest.split_at(usize::from(id_len));
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let id = String::from_utf8_lossy(id);
+        let cipher = match keys.keys.get(id.as_ref()) {
+            Some(v) => v,
+            None => return Err(YwkvError::Encryption(format!("unknown key id `{id}`"))),
+        };
+
+        match cipher.decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: content_type.as_bytes(),
+            },
+        ) {
+            Ok(v) => Ok(Cow::Owned(v)),
+            Err(_) => Err(YwkvError::Encryption(format!(
+                "failed to decrypt value with key id `{id}`"
+            ))),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Loading the keys values are encrypted with.
+
+use std::path::Path;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ywkv::EncryptionKeys;
+
+/// Read a key file with one `<key id> <base64 encoded 32 byte key>` per line. The first key
+/// encrypts new values and the rest are only used to read values written before a rotation.
+pub fn load_keys(path: &Path) -> anyhow::Result<EncryptionKeys> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read encryption key file `{}`", path.display()))?;
+
+    let mut keys: Option<EncryptionKeys> = None;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (id, key) = parse_line(line).with_context(|| {
+            format!(
+                "invalid key on line {} of encryption key file `{}`",
+                i + 1,
+                path.display()
+            )
+        })?;
+
+        keys = Some(match keys {
+            Some(keys) => keys.with_old_key(id, key)?,
+            None => EncryptionKeys::new(id, key)?,
+        });
+    }
+
+    match keys {
+        Some(v) => Ok(v),
+        None => anyhow::bail!("encryption key file `{}` has no keys", path.display()),
+    }
+}
+
+fn parse_line(line: &str) -> anyhow::Result<(&str, [u8; 32])> {
+    let mut parts = line.split_whitespace();
+    let (id, key) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(id), Some(key), None) => (id, key),
+        _ => anyhow::bail!("expected a key id and a key separated by whitespace"),
+    };
+
+    let key = STANDARD.decode(key).context("key is not valid base64")?;
+    match key.try_into() {
+        Ok(v) => Ok((id, v)),
+        Err(v) => anyhow::bail!("key must be 32 bytes, found {}", v.len()),
+    }
+}
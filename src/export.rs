@@ -0,0 +1,201 @@
+//! Streaming exports of a whole table as JSON or CSV.
+
+use std::{fmt::Write, time::Duration};
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::Query,
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderValue,
+    },
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use utoipa::{IntoParams, ToSchema};
+use ywkv::Entry;
+
+use crate::Table;
+
+/// Entries are sent in chunks of about this many bytes.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    #[serde(default)]
+    #[param(inline)]
+    format: Format,
+}
+
+/// A single exported entry. Values that aren't UTF-8 are base64 encoded so nothing is lost.
+#[derive(Serialize)]
+struct Exported<'a> {
+    key: &'a str,
+    value: String,
+    content_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    /// Seconds until the key expires, rounded up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+}
+
+impl<'a> Exported<'a> {
+    fn new(entry: &'a Entry, ttl: Option<Duration>) -> Self {
+        let (value, encoding) = match std::str::from_utf8(&entry.value.data) {
+            Ok(v) => (v.to_string(), None),
+            Err(_) => (STANDARD.encode(&entry.value.data), Some("base64")),
+        };
+
+        Self {
+            key: &entry.key,
+            value,
+            content_type: &entry.value.content_type,
+            encoding,
+            ttl: ttl.map(|v| v.as_millis().div_ceil(1000).try_into().unwrap_or(u64::MAX)),
+        }
+    }
+}
+
+impl Format {
+    fn start(self, out: &mut String) {
+        match self {
+            Format::Json => out.push('['),
+            Format::Csv => out.push_str("key,value,content_type,encoding,ttl\r\n"),
+        }
+    }
+
+    fn write(self, out: &mut String, entry: &Exported, first: bool) {
+        match self {
+            Format::Json => {
+                if !first {
+                    out.push(',');
+                }
+                out.push('\n');
+                // Serializing strings and integers can't fail
+                out.push_str(&serde_json::to_string(entry).unwrap_or_default());
+            }
+            Format::Csv => {
+                for field in [
+                    entry.key,
+                    &entry.value,
+                    entry.content_type,
+                    entry.encoding.unwrap_or_default(),
+                ] {
+                    write_csv_field(out, field);
+                    out.push(',');
+                }
+                if let Some(ttl) = entry.ttl {
+                    let _ = write!(out, "{ttl}");
+                }
+                out.push_str("\r\n");
+            }
+        }
+    }
+
+    fn finish(self, out: &mut String) {
+        match self {
+            Format::Json => out.push_str("\n]\n"),
+            Format::Csv => {}
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn content_disposition(self) -> &'static str {
+        match self {
+            Format::Json => "attachment; filename=\"ywkv-export.json\"",
+            Format::Csv => "attachment; filename=\"ywkv-export.csv\"",
+        }
+    }
+}
+
+/// Quote fields that contain separators, quotes or line breaks.
+fn write_csv_field(out: &mut String, field: &str) {
+    if field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+/// Stream every key in the table from a single read transaction, so the export is consistent
+/// even while writes continue. If reading fails part way the response is cut off.
+#[utoipa::path(
+    get,
+    path = "/_export",
+    tag = "values",
+    params(ExportQuery),
+    responses((
+        status = 200,
+        description = "Every entry with its content type and TTL, as a JSON array or CSV",
+        content(("application/json"), ("text/csv")),
+    ))
+)]
+pub async fn export(Table(db): Table, Query(query): Query<ExportQuery>) -> Response {
+    let format = query.format;
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        let mut out = String::new();
+        format.start(&mut out);
+
+        let mut first = true;
+        let res = db.for_each_entry(|entry, ttl| {
+            format.write(&mut out, &Exported::new(&entry, ttl), first);
+            first = false;
+
+            if out.len() < CHUNK_SIZE {
+                return true;
+            }
+            // Stop reading once the client has gone away
+            sender
+                .blocking_send(Ok(Bytes::from(std::mem::take(&mut out))))
+                .is_ok()
+        });
+
+        match res {
+            Ok(()) => {
+                format.finish(&mut out);
+                let _ = sender.blocking_send(Ok(Bytes::from(out)));
+            }
+            Err(e) => {
+                tracing::error!("Failed to export table `{}`: {e}", db.table());
+                let _ = sender.blocking_send(Err(std::io::Error::other(e.to_string())));
+            }
+        }
+    });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static(format.content_disposition()),
+    );
+
+    (headers, StreamBody::new(ReceiverStream::new(receiver))).into_response()
+}
@@ -0,0 +1,148 @@
+//! Group commit, where writes arriving close together share a single transaction and fsync.
+
+use std::time::Duration;
+
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use ywkv::{Db, TableWrite, Value, YwkvError};
+
+use crate::blocking;
+
+/// The most writes committed together, so one slow batch can't hold up too many requests.
+const MAX_BATCH: usize = 1024;
+
+struct Pending {
+    db: Db,
+    write: TableWrite,
+    reply: oneshot::Sender<Result<Option<Value>, YwkvError>>,
+}
+
+// Flushes are rare, so there's no point boxing writes to make them smaller
+#[allow(clippy::large_enum_variant)]
+enum Message {
+    Write(Pending),
+    /// Answered once every write sent before it has been committed
+    Flush(oneshot::Sender<()>),
+}
+
+/// A handle to the background writer. Clones share the same writer.
+#[derive(Clone)]
+pub struct GroupCommit(mpsc::Sender<Message>);
+
+impl GroupCommit {
+    /// Start a writer that waits up to `window` after the first pending write for others to join
+    /// it before committing.
+    pub fn spawn(window: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel(MAX_BATCH);
+        tokio::spawn(run(receiver, window));
+
+        Self(sender)
+    }
+
+    /// Write a value the same as [Db::write_with_ttl], returning once the shared commit is done.
+    pub async fn write(
+        &self,
+        db: Db,
+        key: String,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, YwkvError> {
+        let (reply, receiver) = oneshot::channel();
+        let pending = Pending {
+            write: TableWrite {
+                table: db.table().to_string(),
+                key,
+                value,
+                ttl,
+                actor: db.actor().cloned(),
+            },
+            db,
+            reply,
+        };
+
+        // The writer only goes away if it panicked, so fall back to writing directly
+        match self.0.send(Message::Write(pending)).await {
+            Ok(()) => receiver.await.expect("group commit writer panicked"),
+            Err(e) => {
+                let Message::Write(Pending { db, write, .. }) = e.0 else {
+                    unreachable!("only a write was sent");
+                };
+                blocking(move || write_one(&db, write)).await
+            }
+        }
+    }
+
+    /// Wait for every write sent so far to be committed, including ones whose requests have
+    /// since been cancelled.
+    pub async fn flush(&self) {
+        let (done, receiver) = oneshot::channel();
+        // Nothing is left to commit if the writer is gone
+        if self.0.send(Message::Flush(done)).await.is_ok() {
+            let _ = receiver.await;
+        }
+    }
+}
+
+async fn run(mut receiver: mpsc::Receiver<Message>, window: Duration) {
+    while let Some(first) = receiver.recv().await {
+        let first = match first {
+            Message::Write(v) => v,
+            Message::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let deadline = Instant::now() + window;
+
+        let mut batch = vec![first];
+        let mut flushed = None;
+        while batch.len() < MAX_BATCH {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(Message::Write(v))) => batch.push(v),
+                // Anything sent after the flush can wait for the next batch
+                Ok(Some(Message::Flush(done))) => {
+                    flushed = Some(done);
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        blocking(move || commit(batch)).await;
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn commit(batch: Vec<Pending>) {
+    let (writes, waiters): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|v| (v.write, (v.db, v.reply)))
+        .unzip();
+
+    match waiters[0].0.write_tables(&writes) {
+        Ok(old_values) => {
+            for ((_, reply), old_value) in waiters.into_iter().zip(old_values) {
+                // The request may have been cancelled, which is fine
+                let _ = reply.send(Ok(old_value));
+            }
+        }
+        // Nothing was written, so retry each write by itself to give it its own result
+        Err(e) => {
+            tracing::warn!(
+                "Failed to commit {} writes together, retrying separately: {e}",
+                writes.len()
+            );
+            for ((db, reply), write) in waiters.into_iter().zip(writes) {
+                let _ = reply.send(write_one(&db, write));
+            }
+        }
+    }
+}
+
+fn write_one(db: &Db, write: TableWrite) -> Result<Option<Value>, YwkvError> {
+    db.write_with_ttl(write.key, write.value, write.ttl)
+}
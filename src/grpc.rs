@@ -0,0 +1,359 @@
+//! A gRPC server on its own port, described by `proto/ywkv.proto`, with the same tokens, roles,
+//! limits and tables as the HTTP API.
+
+use std::{future::Future, ops::Bound, pin::Pin, time::Duration};
+
+use axum::{http::StatusCode, Json};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    Stream, StreamExt,
+};
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Code, Request, Status,
+};
+use ywkv::{self, Actor, ChangeKind, Db, Response, Value, YwkvError};
+
+use crate::{
+    auth::{Role, Tokens},
+    blocking,
+    ratelimit::RateLimiter,
+    ttl_secs, DbState,
+};
+
+mod proto {
+    tonic::include_proto!("ywkv.v1");
+}
+
+use proto::{
+    change::Kind,
+    ywkv_server::{Ywkv, YwkvServer},
+    Change, DeleteRequest, DeleteResponse, Entry, GetRequest, GetResponse, ScanRequest, SetRequest,
+    SetResponse, WatchRequest,
+};
+
+/// How many entries a scan reads ahead of a slow client.
+const SCAN_BUFFER: usize = 64;
+
+#[derive(Clone)]
+pub struct Service {
+    state: DbState,
+    tokens: Tokens,
+    limiter: RateLimiter,
+}
+
+impl Service {
+    pub fn new(state: DbState, tokens: Tokens, limiter: RateLimiter) -> Self {
+        Self {
+            state,
+            tokens,
+            limiter,
+        }
+    }
+
+    /// Check the call's token and rate limit like the HTTP middleware does, then open the table it
+    /// asked for.
+    // Status is what every RPC returns anyway
+    #[allow(clippy::result_large_err)]
+    fn table<T>(&self, request: &Request<T>, table: &str, required: Role) -> Result<Db, Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        let ip = request.remote_addr().map(|v| v.ip());
+
+        let (role, fingerprint) = authorization
+            .and_then(|v| self.tokens.check(v))
+            .ok_or_else(|| Status::unauthenticated("missing or invalid bearer token"))?;
+        if role < required {
+            return Err(Status::permission_denied(format!(
+                "{role:?} tokens cannot make this request"
+            )));
+        }
+
+        if let Err(retry_after) = self.limiter.check(authorization, ip) {
+            return Err(Status::resource_exhausted(format!(
+                "rate limit exceeded, retry after {retry_after}s"
+            )));
+        }
+
+        let table = Some(table).filter(|v| !v.is_empty());
+        let db = self.state.open_table(table).map_err(status)?;
+
+        Ok(db.with_actor(Actor {
+            token: Some(fingerprint.0),
+            ip,
+            request_id: None,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Ywkv for Service {
+    type ScanStream = ReceiverStream<Result<Entry, Status>>;
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<Change, Status>> + Send>>;
+
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> Result<tonic::Response<GetResponse>, Status> {
+        let db = self.table(&request, &request.get_ref().table, Role::ReadOnly)?;
+        let key = request.into_inner().key;
+
+        let (value, ttl) = blocking(move || db.read_with_ttl(key))
+            .await
+            .map_err(db_error)?;
+
+        Ok(tonic::Response::new(GetResponse {
+            value: value.data,
+            content_type: value.content_type,
+            ttl: ttl.map(ttl_secs),
+        }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<SetRequest>,
+    ) -> Result<tonic::Response<SetResponse>, Status> {
+        let db = self.table(&request, &request.get_ref().table, Role::ReadWrite)?;
+        let request = request.into_inner();
+
+        self.state.limits.check_key(&request.key).map_err(status)?;
+        self.state
+            .limits
+            .check_value(&request.value)
+            .map_err(status)?;
+
+        let content_type = match request.content_type.as_str() {
+            "" => ywkv::DEFAULT_CONTENT_TYPE,
+            v => v,
+        };
+        let value = Value::new(request.value, content_type);
+        let ttl = request.ttl.map(Duration::from_secs);
+        let key = request.key;
+
+        let old_value = match &self.state.group {
+            Some(group) => group.write(db, key, value, ttl).await,
+            None => blocking(move || db.write_with_ttl(key, value, ttl)).await,
+        }
+        .map_err(db_error)?;
+
+        Ok(tonic::Response::new(SetResponse {
+            old_value: old_value.map(|v| v.data),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<tonic::Response<DeleteResponse>, Status> {
+        let db = self.table(&request, &request.get_ref().table, Role::ReadWrite)?;
+        let key = request.into_inner().key;
+
+        match blocking(move || db.delete(&key).map(|v| (key, v))).await {
+            Ok((_, Some(old_value))) => Ok(tonic::Response::new(DeleteResponse {
+                old_value: old_value.data,
+            })),
+            Ok((key, None)) => Err(db_error(YwkvError::KeyMissing(key))),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<tonic::Response<Self::ScanStream>, Status> {
+        let db = self.table(&request, &request.get_ref().table, Role::ReadOnly)?;
+        let request = request.into_inner();
+
+        if request.prefix.is_some() && (request.start.is_some() || request.end.is_some()) {
+            return Err(Status::invalid_argument(
+                "`prefix` cannot be combined with `start` or `end`",
+            ));
+        }
+
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let prefix = request.prefix.unwrap_or_default();
+            let start = match (&request.start, prefix.is_empty()) {
+                (Some(v), _) => Bound::Included(v.as_str()),
+                (None, false) => Bound::Included(prefix.as_str()),
+                (None, true) => Bound::Unbounded,
+            };
+            let end = match &request.end {
+                Some(v) => Bound::Excluded(v.as_str()),
+                None => Bound::Unbounded,
+            };
+            let limit = request.limit.map_or(usize::MAX, |v| v as usize);
+
+            let entries = db
+                .range((start, end))
+                .take_while(|v| v.as_ref().map_or(true, |v| v.key.starts_with(&prefix)))
+                .take(limit);
+            for entry in entries {
+                let entry = entry
+                    .map(|v| Entry {
+                        key: v.key,
+                        value: v.value.data,
+                        content_type: v.value.content_type,
+                    })
+                    .map_err(db_error);
+
+                // The client went away
+                if sender.blocking_send(entry).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, Status> {
+        let db = self.table(&request, &request.get_ref().table, Role::ReadOnly)?;
+        let request = request.into_inner();
+
+        if request.key.is_some() && request.prefix.is_some() {
+            return Err(Status::invalid_argument(
+                "`key` cannot be combined with `prefix`",
+            ));
+        }
+
+        let table = db.table().to_string();
+        let stream = BroadcastStream::new(db.subscribe()).filter_map(move |change| {
+            let change = match change {
+                Ok(v) => v,
+                // Let the client know it missed some changes so it can resync if needed
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    return Some(Ok(Change {
+                        lagged: skipped,
+                        ..Default::default()
+                    }));
+                }
+            };
+            let matches = match (&request.key, &request.prefix) {
+                (Some(key), _) => change.key == *key,
+                (None, Some(prefix)) => change.key.starts_with(prefix),
+                (None, None) => true,
+            };
+            if change.table != table || !matches {
+                return None;
+            }
+
+            let kind = match change.kind {
+                ChangeKind::Set => Kind::Set,
+                ChangeKind::Delete => Kind::Delete,
+            };
+
+            Some(Ok(Change {
+                kind: kind.into(),
+                key: change.key.clone(),
+                value: change.value.clone().map(|v| Entry {
+                    key: change.key.clone(),
+                    value: v.data,
+                    content_type: v.content_type,
+                }),
+                lagged: 0,
+            }))
+        });
+
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serve gRPC on `listener` until `shutdown` resolves, over TLS if a certificate is configured.
+pub async fn serve(
+    listener: std::net::TcpListener,
+    service: Service,
+    tls: Option<RustlsConfig>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::from_std(listener)?;
+    let router = Server::builder().add_service(YwkvServer::new(service));
+
+    match tls {
+        Some(config) => {
+            router
+                .serve_with_incoming_shutdown(tls_incoming(listener, config), shutdown)
+                .await?
+        }
+        None => {
+            let incoming =
+                TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+            router
+                .serve_with_incoming_shutdown(incoming, shutdown)
+                .await?
+        }
+    }
+
+    Ok(())
+}
+
+/// Accept TLS connections using the same certificate as the HTTP server, so it's reloaded along
+/// with it. Handshakes run concurrently so a slow client can't hold up the others.
+fn tls_incoming(
+    listener: TcpListener,
+    config: RustlsConfig,
+) -> ReceiverStream<std::io::Result<TlsStream<tokio::net::TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept gRPC connection: {e}");
+                        continue;
+                    }
+                },
+                // The server stopped
+                _ = sender.closed() => break,
+            };
+
+            let acceptor = TlsAcceptor::from(config.get_inner());
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Err(e) => tracing::debug!("gRPC TLS handshake failed: {e}"),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+/// The gRPC equivalent of an HTTP error response.
+fn status((code, Json(response)): (StatusCode, Json<Response>)) -> Status {
+    let code = match code {
+        StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => Code::InvalidArgument,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::INSUFFICIENT_STORAGE => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+
+    Status::new(code, response.into_value())
+}
+
+fn db_error(e: YwkvError) -> Status {
+    let code = match e {
+        YwkvError::KeyMissing(_) | YwkvError::EmptyTable(_) => Code::NotFound,
+        YwkvError::ReadOnly => Code::FailedPrecondition,
+        YwkvError::KeyQuotaExceeded(..) | YwkvError::SizeQuotaExceeded(_) => {
+            Code::ResourceExhausted
+        }
+        _ => Code::Internal,
+    };
+
+    Status::new(code, e.to_string())
+}
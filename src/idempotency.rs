@@ -0,0 +1,388 @@
+//! Replaying the response to a write retried with the same `Idempotency-Key` header, instead of
+//! making the write again.
+//!
+//! Responses are kept in memory for a window after the write, keyed by the client's token and
+//! the header, so two clients can't see each other's responses. Server errors aren't kept, so
+//! retrying after one makes the write again. Reusing a key for a different method, URI or body
+//! is rejected rather than replayed.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, Body, Bytes, Full},
+    extract::State,
+    http::{
+        header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use hyper::body::HttpBody;
+use sha2::{Digest, Sha256};
+
+use crate::negotiate::Verbatim;
+
+pub const HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on responses that were replayed rather than made by the request.
+pub const REPLAYED_HEADER: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Longer keys are rejected, so they can't take up much memory.
+const MAX_LEN: usize = 255;
+/// Once this many responses are kept, the ones past the window are forgotten.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Saved {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    verbatim: bool,
+}
+
+enum Entry {
+    /// The first request with the key hasn't finished yet
+    Running { fingerprint: String },
+    Done {
+        fingerprint: String,
+        response: Arc<Saved>,
+        at: Instant,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> &str {
+        match self {
+            Entry::Running { fingerprint } | Entry::Done { fingerprint, .. } => fingerprint,
+        }
+    }
+}
+
+/// Clones share the same responses.
+#[derive(Clone)]
+pub struct Idempotency {
+    window: Duration,
+    /// The longest body read to tell requests apart, the same limit handlers have
+    max_body: usize,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Idempotency {
+    pub fn new(window: Duration, max_body: usize) -> Self {
+        Self {
+            window,
+            max_body,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Claim `id` for a request, unless there's a response to replay. The claim is given up if
+    /// the request never finishes, like when the client goes away.
+    fn start(&self, id: String, fingerprint: String) -> Result<Running, Rejection> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= PRUNE_THRESHOLD && !entries.contains_key(&id) {
+            entries.retain(|_, v| match v {
+                Entry::Running { .. } => true,
+                Entry::Done { at, .. } => now.duration_since(*at) < self.window,
+            });
+        }
+
+        match entries.get(&id) {
+            Some(v) if v.fingerprint() != fingerprint => return Err(Rejection::Mismatch),
+            Some(Entry::Running { .. }) => return Err(Rejection::Running),
+            Some(Entry::Done { response, at, .. }) if now.duration_since(*at) < self.window => {
+                return Err(Rejection::Replay(response.clone()))
+            }
+            _ => {}
+        }
+        entries.insert(id.clone(), Entry::Running { fingerprint });
+
+        Ok(Running {
+            idempotency: self.clone(),
+            id,
+            finished: false,
+        })
+    }
+}
+
+/// Why a request wasn't run.
+enum Rejection {
+    Replay(Arc<Saved>),
+    Running,
+    /// The key was already used for a different request
+    Mismatch,
+}
+
+/// A request that has claimed its key.
+struct Running {
+    idempotency: Idempotency,
+    id: String,
+    finished: bool,
+}
+
+impl Running {
+    fn finish(mut self, response: Option<Saved>) {
+        self.finished = true;
+
+        let mut entries = self.idempotency.entries.lock().unwrap();
+        let Some(response) = response else {
+            entries.remove(&self.id);
+            return;
+        };
+        if let Some(entry) = entries.get_mut(&self.id) {
+            *entry = Entry::Done {
+                fingerprint: entry.fingerprint().to_string(),
+                response: Arc::new(response),
+                at: Instant::now(),
+            };
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.idempotency.entries.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+/// Replay the response to an earlier `POST`, `PATCH` or `DELETE` with the same
+/// `Idempotency-Key`, or make the request and keep its response for next time.
+///
+/// Runs after authentication so only valid tokens keep responses.
+pub async fn replay(
+    State(idempotency): State<Idempotency>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let key = match request.headers().get(&HEADER) {
+        Some(v)
+            if matches!(
+                *request.method(),
+                Method::POST | Method::PATCH | Method::DELETE
+            ) =>
+        {
+            v
+        }
+        _ => return next.run(request).await,
+    };
+    let key = match key.to_str() {
+        Ok(v) if !v.is_empty() && v.len() <= MAX_LEN => v,
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!("`{HEADER}` must be 1 to {MAX_LEN} printable characters"),
+            )
+        }
+    };
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let id = format!("{token}\n{key}");
+
+    let (parts, body) = request.into_parts();
+    let body = match read_body(body, idempotency.max_body).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    let fingerprint = format!("{} {} {:x}", parts.method, parts.uri, Sha256::digest(&body));
+    let request = Request::from_parts(parts, Body::from(body));
+
+    let running = match idempotency.start(id, fingerprint) {
+        Ok(v) => v,
+        Err(Rejection::Replay(saved)) => {
+            let mut response =
+                (saved.status, saved.headers.clone(), saved.body.clone()).into_response();
+            response
+                .headers_mut()
+                .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+            if saved.verbatim {
+                response.extensions_mut().insert(Verbatim);
+            }
+            return response;
+        }
+        Err(Rejection::Running) => {
+            return error(
+                StatusCode::CONFLICT,
+                format!("a request with this `{HEADER}` is still running"),
+            )
+        }
+        Err(Rejection::Mismatch) => {
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("`{HEADER}` was already used for a different request"),
+            )
+        }
+    };
+
+    let response = next.run(request).await;
+    if response.status().is_server_error() {
+        running.finish(None);
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Failed to read response to keep for `{HEADER}`: {e}");
+            running.finish(None);
+            return Response::from_parts(parts, body::boxed(Full::default()));
+        }
+    };
+    running.finish(Some(Saved {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+        verbatim: parts.extensions.get::<Verbatim>().is_some(),
+    }));
+
+    Response::from_parts(parts, body::boxed(Full::from(body)))
+}
+
+/// Read the whole body, so a retry can be told apart from a different write to the same URI.
+async fn read_body(mut body: Body, max: usize) -> Result<Bytes, Response> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            error(
+                StatusCode::BAD_REQUEST,
+                format!("failed to read the body: {e}"),
+            )
+        })?;
+        if data.len() + chunk.len() > max {
+            return Err(error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("the body is longer than {max} bytes"),
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(Bytes::from(data))
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (
+        status,
+        Json::from(ywkv::Response::new(
+            message,
+            ywkv::Status::Write(ywkv::WriteStatus::Failure),
+        )),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use axum::{middleware, routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Responds with how many requests it has handled and the body, or 500 for `fail`.
+    fn app(handled: Arc<AtomicU64>) -> Router {
+        let handler = move |body: String| async move {
+            let count = handled.fetch_add(1, Ordering::SeqCst) + 1;
+            match body.as_str() {
+                "fail" => (StatusCode::INTERNAL_SERVER_ERROR, format!("{count}")),
+                _ => (StatusCode::OK, format!("{count} {body}")),
+            }
+        };
+
+        Router::new()
+            .route("/:key", post(handler))
+            .layer(middleware::from_fn_with_state(
+                Idempotency::new(Duration::from_secs(60), 16),
+                replay,
+            ))
+    }
+
+    async fn send(app: &Router, uri: &str, token: &str, body: &str) -> Response {
+        let request = Request::post(uri)
+            .header(HEADER, "retry-1")
+            .header(AUTHORIZATION, token)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn text(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_are_replayed() {
+        let handled = Arc::new(AtomicU64::new(0));
+        let app = app(handled.clone());
+
+        let first = send(&app, "/k", "a", "v").await;
+        assert!(first.headers().get(REPLAYED_HEADER).is_none());
+        assert_eq!(text(first).await, "1 v");
+
+        let retry = send(&app, "/k", "a", "v").await;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.headers()[REPLAYED_HEADER], "true");
+        assert_eq!(text(retry).await, "1 v");
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reusing_a_key_for_another_request_is_rejected() {
+        let handled = Arc::new(AtomicU64::new(0));
+        let app = app(handled.clone());
+        send(&app, "/k", "a", "v").await;
+
+        let other_body = send(&app, "/k", "a", "w").await;
+        assert_eq!(other_body.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let other_uri = send(&app, "/other", "a", "v").await;
+        assert_eq!(other_uri.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_are_kept_per_token() {
+        let handled = Arc::new(AtomicU64::new(0));
+        let app = app(handled.clone());
+        send(&app, "/k", "a", "v").await;
+
+        let other_token = send(&app, "/k", "b", "w").await;
+        assert_eq!(text(other_token).await, "2 w");
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let handled = Arc::new(AtomicU64::new(0));
+        let app = app(handled.clone());
+
+        send(&app, "/k", "a", "fail").await;
+        let retry = send(&app, "/k", "a", "fail").await;
+        assert_eq!(retry.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(text(retry).await, "2");
+    }
+
+    #[tokio::test]
+    async fn long_bodies_are_rejected() {
+        let handled = Arc::new(AtomicU64::new(0));
+        let app = app(handled.clone());
+
+        let response = send(&app, "/k", "a", &"x".repeat(17)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(handled.load(Ordering::SeqCst), 0);
+    }
+}
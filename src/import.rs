@@ -0,0 +1,181 @@
+//! The `import` subcommand, which loads a file of values straight into the database.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+use ywkv::{Db, Durability, EncryptionKeys, TableWrite, Value};
+
+pub const NAME: &str = "import";
+
+const FILE: &str = "file";
+const FORMAT: &str = "format";
+const BATCH_SIZE: &str = "batch-size";
+const DRY_RUN: &str = "dry-run";
+
+pub fn command() -> Command {
+    Command::new(NAME)
+        .about("Load a JSON or newline delimited JSON file into the database")
+        .arg(Arg::new(FILE).required(true).action(ArgAction::Set))
+        .arg(
+            Arg::new(FORMAT)
+                .long(FORMAT)
+                .required(false)
+                .value_parser(["json", "ndjson"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(BATCH_SIZE)
+                .long(BATCH_SIZE)
+                .required(false)
+                .default_value("1000")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new(DRY_RUN)
+                .long(DRY_RUN)
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+}
+
+/// An entry in the same shape `/_export` writes them.
+#[derive(Deserialize)]
+struct Entry {
+    key: String,
+    value: String,
+    content_type: Option<String>,
+    encoding: Option<String>,
+    /// Seconds until the key expires
+    ttl: Option<u64>,
+}
+
+impl Entry {
+    fn into_write(self, table: &str) -> anyhow::Result<TableWrite> {
+        let data = match self.encoding.as_deref() {
+            Some("base64") => STANDARD
+                .decode(&self.value)
+                .with_context(|| format!("invalid base64 value for key `{}`", self.key))?,
+            Some(v) => anyhow::bail!("unknown encoding `{v}` for key `{}`", self.key),
+            None => self.value.into_bytes(),
+        };
+        let content_type = self
+            .content_type
+            .unwrap_or_else(|| ywkv::TEXT_CONTENT_TYPE.to_string());
+
+        Ok(TableWrite {
+            table: table.to_string(),
+            key: self.key,
+            value: Value::new(data, content_type),
+            ttl: self.ttl.map(Duration::from_secs),
+            actor: None,
+        })
+    }
+}
+
+/// Parse the whole file before writing anything, so a mistake near the end doesn't leave the
+/// import half done.
+fn parse(contents: &str, ndjson: bool, table: &str) -> anyhow::Result<Vec<TableWrite>> {
+    if ndjson {
+        return contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str::<Entry>(line)
+                    .with_context(|| format!("invalid entry on line {}", i + 1))?
+                    .into_write(table)
+            })
+            .collect();
+    }
+
+    match serde_json::from_str::<serde_json::Value>(contents).context("invalid JSON")? {
+        // A map of keys to values. Strings are stored as text and anything else as JSON.
+        serde_json::Value::Object(map) => Ok(map
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(v) => Value::from(v),
+                    v => Value::new(v.to_string(), "application/json"),
+                };
+
+                TableWrite {
+                    table: table.to_string(),
+                    key,
+                    value,
+                    ttl: None,
+                    actor: None,
+                }
+            })
+            .collect()),
+        // The output of `/_export`
+        serde_json::Value::Array(entries) => entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                serde_json::from_value::<Entry>(v)
+                    .with_context(|| format!("invalid entry at index {i}"))?
+                    .into_write(table)
+            })
+            .collect(),
+        _ => anyhow::bail!("expected a JSON object or array"),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    args: &ArgMatches,
+    db_file_name: &str,
+    table_name: &str,
+    create_if_missing: bool,
+    encryption_keys: Option<EncryptionKeys>,
+    compress_above: Option<usize>,
+    audit: bool,
+    durability: Durability,
+) -> anyhow::Result<()> {
+    let file = args.get_one::<String>(FILE).unwrap();
+    let ndjson = match args.get_one::<String>(FORMAT).map(String::as_str) {
+        Some(v) => v == "ndjson",
+        None => file.ends_with(".ndjson") || file.ends_with(".jsonl"),
+    };
+    let batch_size = *args.get_one::<u64>(BATCH_SIZE).unwrap() as usize;
+    let dry_run = args.get_flag(DRY_RUN);
+
+    let contents =
+        std::fs::read_to_string(file).with_context(|| format!("failed to read `{file}`"))?;
+    let writes = parse(&contents, ndjson, table_name)
+        .with_context(|| format!("failed to parse `{file}`"))?;
+
+    if dry_run {
+        println!(
+            "Would import {} keys into table `{table_name}`",
+            writes.len()
+        );
+        return Ok(());
+    }
+
+    let mut db = Db::builder()
+        .create_if_missing(create_if_missing)
+        .durability(durability)
+        .open(db_file_name, table_name)?;
+    if let Some(keys) = encryption_keys {
+        db.enable_encryption(keys);
+    }
+    db.enable_compression(compress_above);
+    if audit {
+        db.enable_audit();
+    }
+
+    let mut imported = 0;
+    for batch in writes.chunks(batch_size) {
+        db.write_tables(batch)
+            .with_context(|| format!("failed after importing {imported} keys"))?;
+        imported += batch.len();
+        println!("Imported {imported}/{} keys", writes.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! Iterators over the entries of a table, returned by [crate::Db::iter], [crate::Db::range] and
+//! [crate::Db::keys].
+
+use std::{collections::VecDeque, ops::Bound};
+
+use crate::{Db, Entry, YwkvError};
+
+/// How many entries are read per transaction.
+const PAGE_SIZE: usize = 256;
+
+/// Entries in key order. Expired keys are skipped.
+///
+/// Entries are read a page at a time, each in its own read transaction, so the iterator owns
+/// everything it yields and never holds the database open between calls. Keys are visited at most
+/// once and always in order, but writes made while iterating may or may not be seen.
+pub struct Iter {
+    db: Db,
+    /// Where the next page starts, moved past the last key read
+    start: Bound<String>,
+    end: Bound<String>,
+    page: VecDeque<Entry>,
+    done: bool,
+}
+
+impl Iter {
+    pub(crate) fn new(db: Db, start: Bound<String>, end: Bound<String>) -> Self {
+        Self {
+            db,
+            start,
+            end,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn next_page(&mut self) -> Result<(), YwkvError> {
+        let entries = self.db.scan(
+            self.start.as_ref().map(String::as_str),
+            self.end.as_ref().map(String::as_str),
+            Some(PAGE_SIZE),
+        )?;
+
+        if entries.len() < PAGE_SIZE {
+            self.done = true;
+        }
+        if let Some(last) = entries.last() {
+            self.start = Bound::Excluded(last.key.clone());
+        }
+        self.page.extend(entries);
+
+        Ok(())
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<Entry, YwkvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if let Err(e) = self.next_page() {
+                // Stop after an error rather than retrying the same page forever
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.page.pop_front().map(Ok)
+    }
+}
+
+/// Keys in order, like [Iter] without the values.
+pub struct Keys(pub(crate) Iter);
+
+impl Iterator for Keys {
+    type Item = Result<String, YwkvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|entry| entry.map(|v| v.key))
+    }
+}
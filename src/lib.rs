@@ -1,8 +1,46 @@
-use std::error::Error;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    error::Error,
+    io::{ErrorKind, Read},
+    net::IpAddr,
+    num::NonZeroUsize,
+    ops::{Bound, RangeBounds, RangeInclusive},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock, RwLockReadGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use axum::{http::StatusCode, Json};
-use redb::{Database, ReadableTable, TableDefinition};
-use serde::Serialize;
+use redb::{
+    Database, ReadTransaction, ReadableTable, TableDefinition, TableHandle, WriteTransaction,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+mod builder;
+mod cache;
+#[cfg(feature = "client")]
+mod client;
+mod codec;
+mod compression;
+mod crypto;
+mod iter;
+
+pub use builder::DbBuilder;
+pub use cache::CacheStats;
+use cache::{Cache, Cached};
+#[cfg(feature = "client")]
+pub use client::{Client, ClientError};
+pub use codec::{Codec, CodecError, JsonCodec};
+pub use crypto::EncryptionKeys;
+pub use iter::{Iter, Keys};
+pub use redb::Durability;
 
 #[derive(thiserror::Error, Debug)]
 pub enum YwkvError {
@@ -12,113 +50,4041 @@ pub enum YwkvError {
     KeyMissing(String),
     #[error("table was empty while getting key `{0}`")]
     EmptyTable(String),
+    #[error("current value did not match the expected value for key `{0}`")]
+    PreconditionFailed(String),
+    #[error("key already exists `{0}`")]
+    KeyExists(String),
+    #[error("value is not an integer for key `{0}`")]
+    NotAnInteger(String),
+    #[error("integer overflow for key `{0}`")]
+    IntegerOverflow(String),
+    #[error("value is not a list for key `{0}`")]
+    NotAList(String),
+    #[error("list is empty for key `{0}`")]
+    EmptyList(String),
+    #[error("value is not a set for key `{0}`")]
+    NotASet(String),
+    #[error("value is not valid JSON for key `{0}`")]
+    NotJson(String),
+    #[error("value would be larger than {1} bytes for key `{0}`")]
+    ValueTooLarge(String, usize),
+    #[error("invalid table name `{0}`")]
+    InvalidTable(String),
+    #[error("table `{0}` does not exist")]
+    TableMissing(String),
+    #[error("table `{0}` already exists")]
+    TableExists(String),
+    #[error("table `{0}` in the migrated database doesn't match the original")]
+    MigrationMismatch(String),
+    #[error("encountered io error `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("invalid snapshot `{0}`")]
+    InvalidSnapshot(String),
+    #[error("revision {1} not found for key `{0}`")]
+    RevisionMissing(String, u64),
+    #[error("encryption error: {0}")]
+    Encryption(String),
+    #[error("compression error: {0}")]
+    Compression(String),
+    #[error("failed to convert value for key `{0}`: {1}")]
+    Codec(String, CodecError),
+    #[error("database file `{0}` does not exist")]
+    DatabaseMissing(String),
+    #[error("`{0}` is not a database file")]
+    NotADatabase(String),
+    #[error("failed to open database file `{0}`: {1}")]
+    Open(String, redb::Error),
+    #[error("database was opened read-only")]
+    ReadOnly,
+    #[error("change {1} does not follow change {0} in the changelog")]
+    ChangeOutOfOrder(u64, u64),
+    #[error("change {0} is a restore, which can only be followed by resyncing")]
+    RestoreChange(u64),
+    #[error("table `{0}` already holds the maximum of {1} keys")]
+    KeyQuotaExceeded(String, u64),
+    #[error("database file has reached the maximum size of {0} bytes")]
+    SizeQuotaExceeded(u64),
+    /// Returned from inside [Db::transaction] to roll it back when nothing else went wrong
+    #[error("transaction aborted: {0}")]
+    Aborted(String),
+}
+
+impl YwkvError {
+    /// The code error responses carry for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            YwkvError::KeyMissing(_) => ErrorCode::KeyMissing,
+            YwkvError::EmptyTable(_) | YwkvError::TableMissing(_) => ErrorCode::TableMissing,
+            YwkvError::RevisionMissing(..) => ErrorCode::RevisionMissing,
+            YwkvError::EmptyList(_) => ErrorCode::ListEmpty,
+            YwkvError::KeyExists(_)
+            | YwkvError::TableExists(_)
+            | YwkvError::IntegerOverflow(_)
+            | YwkvError::ChangeOutOfOrder(..)
+            | YwkvError::RestoreChange(_) => ErrorCode::Conflict,
+            YwkvError::PreconditionFailed(_) => ErrorCode::PreconditionFailed,
+            YwkvError::NotAnInteger(_) | YwkvError::NotAList(_) | YwkvError::NotASet(_) => {
+                ErrorCode::WrongType
+            }
+            YwkvError::NotJson(_) => ErrorCode::NotJson,
+            YwkvError::ValueTooLarge(..) => ErrorCode::TooLarge,
+            YwkvError::InvalidTable(_) | YwkvError::InvalidSnapshot(_) => ErrorCode::InvalidRequest,
+            YwkvError::KeyQuotaExceeded(..) | YwkvError::SizeQuotaExceeded(_) => {
+                ErrorCode::QuotaExceeded
+            }
+            YwkvError::ReadOnly => ErrorCode::ReadOnly,
+            YwkvError::Aborted(_) => ErrorCode::Aborted,
+            YwkvError::Redb(_)
+            | YwkvError::Io(_)
+            | YwkvError::Encryption(_)
+            | YwkvError::Compression(_)
+            | YwkvError::Codec(..)
+            | YwkvError::DatabaseMissing(_)
+            | YwkvError::NotADatabase(_)
+            | YwkvError::Open(..)
+            | YwkvError::MigrationMismatch(_) => ErrorCode::StorageError,
+        }
+    }
+
+    /// The HTTP status of error responses for this error. 507 when a quota is full, since that's
+    /// the client's to deal with, and 500 for anything the client can't fix.
+    pub fn status(&self) -> StatusCode {
+        match self.code() {
+            ErrorCode::KeyMissing
+            | ErrorCode::TableMissing
+            | ErrorCode::RevisionMissing
+            | ErrorCode::ListEmpty => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict | ErrorCode::WrongType => StatusCode::CONFLICT,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::NotJson | ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::ReadOnly => StatusCode::METHOD_NOT_ALLOWED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What the error is about, like the key or the limit that was reached.
+    pub fn details(&self) -> Option<serde_json::Value> {
+        let details = match self {
+            YwkvError::KeyMissing(key)
+            | YwkvError::PreconditionFailed(key)
+            | YwkvError::KeyExists(key)
+            | YwkvError::NotAnInteger(key)
+            | YwkvError::IntegerOverflow(key)
+            | YwkvError::NotAList(key)
+            | YwkvError::EmptyList(key)
+            | YwkvError::NotASet(key)
+            | YwkvError::NotJson(key)
+            | YwkvError::EmptyTable(key) => serde_json::json!({ "key": key }),
+            YwkvError::ValueTooLarge(key, max) => {
+                serde_json::json!({ "key": key, "max_bytes": max })
+            }
+            YwkvError::RevisionMissing(key, rev) => serde_json::json!({ "key": key, "rev": rev }),
+            YwkvError::InvalidTable(table)
+            | YwkvError::TableMissing(table)
+            | YwkvError::TableExists(table)
+            | YwkvError::MigrationMismatch(table) => serde_json::json!({ "table": table }),
+            YwkvError::KeyQuotaExceeded(table, max) => {
+                serde_json::json!({ "table": table, "max_keys": max })
+            }
+            YwkvError::SizeQuotaExceeded(max) => serde_json::json!({ "max_bytes": max }),
+            YwkvError::ChangeOutOfOrder(last, change) => {
+                serde_json::json!({ "last": last, "change": change })
+            }
+            YwkvError::RestoreChange(change) => serde_json::json!({ "change": change }),
+            _ => return None,
+        };
+
+        Some(details)
+    }
+}
+
+/// A stable reason for an error response, for clients to tell failures apart without parsing
+/// messages. New codes may be added, so clients should handle ones they don't know.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    KeyMissing,
+    TableMissing,
+    RevisionMissing,
+    ListEmpty,
+    /// The request doesn't fit what's stored, like creating a key that already exists
+    Conflict,
+    /// An `If-Match` header or expected value didn't match
+    PreconditionFailed,
+    /// The stored value isn't the kind the request works on, like a list or an integer
+    WrongType,
+    NotJson,
+    TooLarge,
+    QuotaExceeded,
+    ReadOnly,
+    /// A transaction or script rolled back
+    Aborted,
+    /// The database couldn't be read or written
+    StorageError,
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RateLimited,
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code for an error response that didn't come from a [YwkvError], going by its status.
+    pub fn for_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::PRECONDITION_FAILED => ErrorCode::PreconditionFailed,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::TooLarge,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::INSUFFICIENT_STORAGE => ErrorCode::QuotaExceeded,
+            v if v.is_server_error() => ErrorCode::Internal,
+            _ => ErrorCode::InvalidRequest,
+        }
+    }
 }
 
-#[derive(Serialize)]
+/// Read statuses are tried first when deserializing, so `Missing` and `Failure` are always
+/// [Status::Read].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 #[serde(untagged)]
 pub enum Status {
     Read(ReadStatus),
     Write(WriteStatus),
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum ReadStatus {
     Found,
     Missing,
     Failure,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum WriteStatus {
     SuccessNew,
     SuccessOverwrite,
+    SuccessUpdate,
+    Deleted,
+    Missing,
+    PreconditionFailed,
     Failure,
 }
 
-#[derive(Serialize)]
-pub struct Response {
-    value: String,
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Response<T = String> {
+    value: T,
     status: Status,
+    /// Why the request failed. Every error response from the server has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    /// More about some errors, like the key or limit involved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    details: Option<serde_json::Value>,
+    /// Added to error responses by the server, to match them up with its logs and audit log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
-impl Response {
-    pub fn new(value: String, status: Status) -> Self {
-        Self { value, status }
+impl<T> Response<T> {
+    pub fn new(value: T, status: Status) -> Self {
+        Self {
+            value,
+            status,
+            code: None,
+            details: None,
+            request_id: None,
+        }
     }
 
-    pub fn from_read_error(e: impl Error) -> (StatusCode, Json<Response>) {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json::from(Response::new(
-                e.to_string(),
-                Status::Read(ReadStatus::Failure),
-            )),
-        )
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_value(self) -> T {
+        self.value
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn code(&self) -> Option<ErrorCode> {
+        self.code
+    }
+
+    pub fn details(&self) -> Option<&serde_json::Value> {
+        self.details.as_ref()
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Give an error response without a code the one for its HTTP `status`.
+    pub fn with_default_code(mut self, status: StatusCode) -> Self {
+        self.code.get_or_insert(ErrorCode::for_status(status));
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = Some(request_id);
+        self
     }
+}
 
-    pub fn from_write_error(e: impl Error) -> (StatusCode, Json<Response>) {
+impl Response {
+    /// The response to a [YwkvError], with the HTTP status and code for it and `status` as the
+    /// status in the body.
+    pub fn from_error(e: &YwkvError, status: Status) -> (StatusCode, Json<Response>) {
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json::from(Response::new(
-                e.to_string(),
-                Status::Write(WriteStatus::Failure),
-            )),
+            e.status(),
+            Json::from(Response {
+                details: e.details(),
+                ..Response::new(e.to_string(), status).with_code(e.code())
+            }),
         )
     }
+
+    pub fn from_read_error(e: impl Error + 'static) -> (StatusCode, Json<Response>) {
+        let status = match Self::missing(&e) {
+            true => ReadStatus::Missing,
+            false => ReadStatus::Failure,
+        };
+
+        Self::from_any_error(&e, Status::Read(status))
+    }
+
+    pub fn from_write_error(e: impl Error + 'static) -> (StatusCode, Json<Response>) {
+        let status = match (&e as &dyn Error).downcast_ref::<YwkvError>() {
+            _ if Self::missing(&e) => WriteStatus::Missing,
+            Some(YwkvError::PreconditionFailed(_)) => WriteStatus::PreconditionFailed,
+            _ => WriteStatus::Failure,
+        };
+
+        Self::from_any_error(&e, Status::Write(status))
+    }
+
+    fn missing(e: &(dyn Error + 'static)) -> bool {
+        e.downcast_ref::<YwkvError>()
+            .is_some_and(|v| v.status() == StatusCode::NOT_FOUND)
+    }
+
+    /// Errors other than [YwkvError] only come from reading or writing files.
+    fn from_any_error(e: &(dyn Error + 'static), status: Status) -> (StatusCode, Json<Response>) {
+        match e.downcast_ref::<YwkvError>() {
+            Some(e) => Self::from_error(e, status),
+            None => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json::from(Response::new(e.to_string(), status).with_code(ErrorCode::StorageError)),
+            ),
+        }
+    }
 }
 
-pub struct Db<'a> {
-    pub database: Database,
-    pub table: TableDefinition<'a, &'static str, &'static str>,
+/// A single page of keys returned by [Db::list_keys].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct KeyPage {
+    pub keys: Vec<String>,
+    /// The cursor to pass in to fetch the next page. `None` once all keys have been listed.
+    pub cursor: Option<String>,
 }
 
-impl<'a> Db<'a> {
-    pub fn read<T: AsRef<str>>(&self, key: T) -> Result<String, YwkvError> {
-        let tx = match self.database.begin_read() {
-            Ok(v) => v,
-            Err(e) => return Err(e.into()),
-        };
+/// The end of a list that [Db::push] and [Db::pop] work on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ListEnd {
+    Front,
+    Back,
+}
 
-        let table = match tx.open_table(self.table) {
-            Ok(v) => v,
-            Err(redb::Error::TableDoesNotExist(_)) => {
-                return Err(YwkvError::EmptyTable(key.as_ref().to_string()))
-            }
-            Err(e) => return Err(e.into()),
-        };
+/// Who made a change, as recorded in the audit log.
+#[derive(Clone, Debug, Default)]
+pub struct Actor {
+    /// Identifies the token the change was made with, without revealing it
+    pub token: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// The `X-Request-Id` of the HTTP request that made the change
+    pub request_id: Option<String>,
+}
 
-        let val = table.get(key.as_ref());
-        match val {
-            Ok(Some(value)) => Ok(value.value().into()),
-            Ok(None) => Err(YwkvError::KeyMissing(key.as_ref().to_string())),
-            Err(e) => Err(e.into()),
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOp {
+    Write,
+    Delete,
+    Restore,
+}
+
+/// An entry in the audit log. Hashes are the hex encoded SHA-256 of the data as stored, so they
+/// are hashes of the encrypted data when encryption is enabled.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditRecord {
+    /// Counts up from 1 in the order changes were committed
+    pub seq: u64,
+    /// Milliseconds since the unix epoch
+    pub at: u64,
+    pub op: AuditOp,
+    /// Not set for restores, which change every table
+    pub table: Option<String>,
+    pub key: Option<String>,
+    pub token: Option<String>,
+    #[schema(value_type = Option<String>)]
+    pub ip: Option<IpAddr>,
+    /// Only set for changes made over HTTP
+    pub request_id: Option<String>,
+    /// Not set when the key didn't exist
+    pub old_hash: Option<String>,
+    /// Not set when the key was deleted
+    pub new_hash: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Set,
+    Delete,
+    /// The whole database was replaced by [Db::restore]
+    Restore,
+}
+
+impl ChangeOp {
+    fn from_u8(op: u8) -> Self {
+        match op {
+            0 => ChangeOp::Set,
+            1 => ChangeOp::Delete,
+            _ => ChangeOp::Restore,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            ChangeOp::Set => 0,
+            ChangeOp::Delete => 1,
+            ChangeOp::Restore => 2,
+        }
+    }
+}
+
+/// An entry in the changelog kept by [Db::keep_changelog].
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangeRecord {
+    /// Counts up from 1 in the order changes were committed
+    pub seq: u64,
+    /// Milliseconds since the unix epoch
+    pub at: u64,
+    pub op: ChangeOp,
+    /// Empty for restores
+    pub table: String,
+    /// Empty for restores
+    pub key: String,
+    /// The content type of the value written, for sets
+    pub content_type: String,
+    /// The value written as stored, so it is encrypted when encryption is enabled. Base64 encoded.
+    #[serde(with = "base64_data")]
+    #[schema(value_type = String)]
+    pub data: Vec<u8>,
+    /// When the value written expires, in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+}
+
+mod base64_data {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let data = String::deserialize(deserializer)?;
+
+        STANDARD.decode(data).map_err(D::Error::custom)
+    }
+}
+
+/// A single page of records returned by [Db::changes_since].
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ChangePage {
+    pub records: Vec<ChangeRecord>,
+    /// The oldest change still in the changelog. Changes before it have been forgotten.
+    pub first: Option<u64>,
+    /// The newest change in the changelog, or 0 if nothing was recorded yet
+    pub last: u64,
+}
+
+/// A change to a table listed by [Db::changes].
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ChangeEvent {
+    /// The change's sequence number in the changelog
+    pub seq: u64,
+    /// Milliseconds since the unix epoch
+    pub at: u64,
+    pub op: ChangeOp,
+    /// Empty for restores
+    pub key: String,
+    /// Not set for deletes and restores. Sent as text.
+    #[schema(value_type = Option<String>)]
+    pub value: Option<Value>,
+    pub content_type: Option<String>,
+    /// When the value written expires, in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+}
+
+/// A single page of events returned by [Db::changes].
+#[derive(Serialize, ToSchema)]
+pub struct ChangeFeed {
+    pub events: Vec<ChangeEvent>,
+    /// The sequence number to pass in to list the changes after this page
+    pub next: u64,
+    /// The oldest change still in the changelog. Changes before it have been forgotten.
+    pub first: Option<u64>,
+}
+
+/// How much space keys sharing a prefix use, returned by [Db::prefix_stats].
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PrefixStats {
+    /// Ends with the delimiter, unless it's empty
+    pub prefix: String,
+    pub keys: u64,
+    /// Total size of the stored values, after any encryption
+    pub value_bytes: u64,
+}
+
+/// What [Db::migrate] copied into the new database file.
+#[derive(Debug, Default)]
+pub struct Migration {
+    /// How many keys each value table holds, by name
+    pub tables: BTreeMap<String, u64>,
+    /// The value tables that held text and were converted
+    pub converted: Vec<String>,
+    /// How many entries each of ywkv's own tables holds, by name
+    pub internal: BTreeMap<String, u64>,
+}
+
+/// A lease on a name that one client holds at a time, taken with [Db::acquire_lease].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    /// Larger than the token of every lease taken in the table before this one, so whatever the
+    /// lease protects can turn away holders whose lease has since expired
+    pub token: u64,
+    pub owner: Option<String>,
+    /// Milliseconds since the unix epoch
+    pub expires_at: u64,
+}
+
+/// What [Db::acquire_lease] or [Db::release_lease] did, with the lease it did it to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LeaseOutcome {
+    Acquired(Lease),
+    Renewed(Lease),
+    Released(Lease),
+    /// Someone else holds the lease
+    Held(Lease),
+    /// There's no lease with the given token to renew or release
+    Missing,
+}
+
+/// What's in a table and the database file it's in, returned by [Db::stats].
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DbStats {
+    /// Keys in the table that haven't expired
+    pub keys: u64,
+    /// Total size of the table's stored values, after any encryption
+    pub value_bytes: u64,
+    /// Every table in the database, other than ywkv's own
+    pub tables: Vec<String>,
+    /// Size of the database file. Only known for databases opened with [Db::builder].
+    pub file_bytes: Option<u64>,
+    /// Size of every key and value stored in the database, including in ywkv's own tables
+    pub stored_bytes: u64,
+    /// Size of redb's own bookkeeping
+    pub metadata_bytes: u64,
+    /// Space in allocated pages that holds nothing
+    pub fragmented_bytes: u64,
+    pub page_size: usize,
+    pub allocated_pages: u64,
+    /// Pages in the file that aren't allocated, roughly. Only known along with `file_bytes`.
+    pub free_pages: Option<u64>,
+    /// When the last write was committed, in milliseconds since the unix epoch. Unknown until
+    /// something is written after the database is opened.
+    pub last_commit_at: Option<u64>,
+}
+
+/// A single page of records returned by [Db::audit_log].
+#[derive(Serialize, ToSchema)]
+pub struct AuditPage {
+    pub records: Vec<AuditRecord>,
+    /// The cursor to pass in to fetch the next, older page. `None` once every record has been
+    /// listed.
+    pub cursor: Option<u64>,
+}
+
+/// The content type used for values written without one.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+/// The content type used for values created from strings.
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+/// The content type of values stored as JSON, like the lists written by [Db::push] and the sets
+/// written by [Db::set_add].
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// A stored value along with the content type it was written with.
+///
+/// Serializes as (lossy) UTF-8 text so it can be embedded in JSON responses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Value {
+    pub data: Vec<u8>,
+    pub content_type: String,
+}
+
+impl Value {
+    pub fn new(data: impl Into<Vec<u8>>, content_type: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            content_type: content_type.into(),
         }
     }
 
-    pub fn write<T: AsRef<str>>(&self, key: T, val: T) -> Result<Option<String>, YwkvError> {
-        let tx = match self.database.begin_write() {
+    /// Convert the data to a string, replacing any invalid UTF-8.
+    pub fn into_string_lossy(self) -> String {
+        match String::from_utf8(self.data) {
             Ok(v) => v,
-            Err(e) => return Err(e.into()),
-        };
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        }
+    }
 
-        let old_value = {
-            let mut table = match tx.open_table(self.table) {
-                Ok(v) => v,
-                Err(e) => return Err(e.into()),
-            };
+    /// A quoted entity tag that changes whenever the data or content type changes.
+    ///
+    /// The first 128 bits of a SHA-256, so tags stay the same across restarts and builds, and a
+    /// stale tag can't be made to match a different value for `If-Match`.
+    pub fn etag(&self) -> String {
+        let hash = Sha256::new()
+            .chain_update(self.content_type.as_bytes())
+            // Separate the content type from the data so moving bytes between them changes the tag
+            .chain_update([0])
+            .chain_update(&self.data)
+            .finalize();
+        let hash = u128::from_be_bytes(hash[..16].try_into().expect("SHA-256 is 32 bytes"));
 
-            let res = table.insert(key.as_ref(), val.as_ref());
-            match res {
-                Ok(Some(v)) => Some(v.value().to_string()),
-                Ok(None) => None,
-                Err(e) => return Err(e.into()),
-            }
+        format!("\"{hash:032x}\"")
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::new(value, TEXT_CONTENT_TYPE)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::new(value, TEXT_CONTENT_TYPE)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Self::new(value, DEFAULT_CONTENT_TYPE)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&String::from_utf8_lossy(&self.data))
+    }
+}
+
+/// A key/value pair returned by range queries like [Db::scan].
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Entry {
+    pub key: String,
+    /// Sent as text
+    #[schema(value_type = String)]
+    pub value: Value,
+}
+
+/// Values read from a single snapshot of the database by [Db::read_snapshot].
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// Each key asked for, with `None` if it's missing
+    pub values: Vec<(String, Option<Value>)>,
+    /// Entries with keys starting with the prefix asked for, in key order
+    pub entries: Vec<Entry>,
+    /// The sequence number of the newest change in the changelog as of the snapshot, or 0 if
+    /// nothing was recorded
+    pub position: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ChangeKind {
+    Set,
+    Delete,
+}
+
+/// A committed change to a single key, published to every [Db::subscribe] receiver.
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub table: String,
+    pub key: String,
+    pub kind: ChangeKind,
+    /// The new value for [ChangeKind::Set] changes
+    pub value: Option<Value>,
+    /// Whether a [ChangeKind::Set] change wrote a key that didn't exist or had expired
+    pub created: bool,
+}
+
+/// When a key was first written and last changed, from [Db::read_with_meta].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct KeyMeta {
+    /// Milliseconds since the unix epoch. `None` if the key was written before ywkv tracked it.
+    pub created_at: Option<u64>,
+    /// Milliseconds since the unix epoch. `None` if the key was written before ywkv tracked it.
+    pub modified_at: Option<u64>,
+}
+
+/// A past value of a key, from [Db::history].
+#[derive(Clone, Debug)]
+pub struct Revision {
+    pub rev: u64,
+    /// Milliseconds since the unix epoch
+    pub modified_at: u64,
+    pub value: Value,
+}
+
+/// Limits on how much a database can hold, set with [Db::set_quota].
+#[derive(Clone, Debug, Default)]
+pub struct Quota {
+    /// How many keys each table can hold. Expired keys don't count, even before they're purged.
+    pub max_keys: Option<u64>,
+    /// How large the database file can grow, in bytes
+    pub max_size: Option<u64>,
+}
+
+/// A write for [Db::write_tables].
+#[derive(Clone, Debug)]
+pub struct TableWrite {
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+    /// Writing without a TTL clears any existing one
+    pub ttl: Option<Duration>,
+    /// Who the write is recorded as being made by in the audit log. The handle's actor is used
+    /// if not set.
+    pub actor: Option<Arc<Actor>>,
+}
+
+/// How many changes a subscriber can fall behind by before it starts missing them.
+const CHANGE_CAPACITY: usize = 1024;
+
+/// The least time between two commits that take page usage for [Db::stats]. Taking it walks
+/// every page in the file.
+const PAGE_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Page usage as redb reports it, which it only does from write transactions.
+#[derive(Clone, Copy)]
+struct PageStats {
+    stored_bytes: u64,
+    metadata_bytes: u64,
+    fragmented_bytes: u64,
+    page_size: usize,
+    allocated_pages: u64,
+    taken_at: Instant,
+}
+
+impl PageStats {
+    fn take(tx: &WriteTransaction) -> Result<Self, redb::Error> {
+        let stats = tx.stats()?;
+        Ok(Self {
+            stored_bytes: stats.stored_bytes(),
+            metadata_bytes: stats.metadata_bytes(),
+            fragmented_bytes: stats.fragmented_bytes(),
+            page_size: stats.page_size(),
+            allocated_pages: stats.allocated_pages(),
+            taken_at: Instant::now(),
+        })
+    }
+}
+
+/// The last [PageStats] taken, shared by every handle so [Db::stats] doesn't have to wait for
+/// the writer to get them.
+#[derive(Default)]
+struct PageStatsCache {
+    latest: Option<PageStats>,
+    /// Set by [Db::stats] so the next commit takes them, and cleared once one has
+    wanted: bool,
+}
+
+/// Values are stored as `(content type, data)`.
+pub type ValueTable<'a> = TableDefinition<'a, &'static str, (&'static str, &'static [u8])>;
+/// How value tables were stored before values were bytes with a content type.
+type TextTable<'a> = TableDefinition<'a, &'static str, &'static str>;
+
+/// When keys with a TTL expire, as milliseconds since the unix epoch. Keyed by `(table, key)` so
+/// every table in the database can share it.
+const EXPIRY_TABLE: TableDefinition<(&str, &str), u64> = TableDefinition::new("ywkv.expiry");
+
+/// When keys were created and last modified, as milliseconds since the unix epoch. A creation
+/// time of 0 means the key was written before ywkv tracked it.
+const META_TABLE: TableDefinition<(&str, &str), (u64, u64)> = TableDefinition::new("ywkv.meta");
+
+type HistoryTable<'a> =
+    TableDefinition<'a, (&'static str, &'static str, u64), (u64, &'static str, &'static [u8])>;
+
+/// Past values of keys as `(modified at, content type, data)`, keyed by `(table, key, revision)`.
+/// Revisions count up from 1 for each key.
+const HISTORY_TABLE: HistoryTable = TableDefinition::new("ywkv.history");
+
+type HistoryRecords<'db, 'txn> =
+    redb::Table<'db, 'txn, (&'static str, &'static str, u64), (u64, &'static str, &'static [u8])>;
+
+/// Records of every change, serialized [AuditRecord]s keyed by their sequence number.
+const AUDIT_TABLE: TableDefinition<u64, &str> = TableDefinition::new("ywkv.audit");
+
+type AuditLog<'db, 'txn> = redb::Table<'db, 'txn, u64, &'static str>;
+
+type ChangelogEntry = (
+    u8,
+    u64,
+    &'static str,
+    &'static str,
+    &'static str,
+    &'static [u8],
+    u64,
+);
+
+/// Every change in the order it was committed, keyed by sequence number as `(op, at, table, key,
+/// content type, data, expires at)`. Data is stored like in value tables, and an expiry of 0
+/// means the key doesn't expire.
+const CHANGELOG_TABLE: TableDefinition<u64, ChangelogEntry> =
+    TableDefinition::new("ywkv.changelog");
+
+type Changelog<'db, 'txn> = redb::Table<'db, 'txn, u64, ChangelogEntry>;
+
+/// Leases as `(fencing token, expires at, owner)`, keyed by `(table, name)`. An empty owner means
+/// none was given. Expired leases are left until they're taken again or purged.
+const LEASE_TABLE: TableDefinition<(&str, &str), (u64, u64, &str)> =
+    TableDefinition::new("ywkv.leases");
+
+/// The last fencing token given out for a lease in each table.
+const FENCING_TABLE: TableDefinition<&str, u64> = TableDefinition::new("ywkv.fencing");
+
+/// Append a change to `id` to the changelog, numbering it after the last change. `stored` is
+/// `None` for deletes.
+fn log_change(
+    log: &mut Changelog,
+    keep: usize,
+    op: ChangeOp,
+    id: (&str, &str),
+    stored: Option<(&str, &[u8])>,
+    expires_at: Option<u64>,
+) -> Result<(), redb::Error> {
+    let last = log.iter()?.next_back().transpose()?.map(|(k, _)| k.value());
+    let (content_type, data) = stored.unwrap_or_default();
+
+    insert_change(
+        log,
+        keep,
+        last.unwrap_or_default() + 1,
+        (
+            op.as_u8(),
+            now_millis(),
+            id.0,
+            id.1,
+            content_type,
+            data,
+            expires_at.unwrap_or(0),
+        ),
+    )
+}
+
+/// Insert a change as `seq`, forgetting all but the last `keep` changes.
+fn insert_change(
+    log: &mut Changelog,
+    keep: usize,
+    seq: u64,
+    entry: (u8, u64, &str, &str, &str, &[u8], u64),
+) -> Result<(), redb::Error> {
+    log.insert(seq, entry)?;
+
+    let cutoff = seq.saturating_sub(keep as u64);
+    if cutoff > 0 {
+        log.drain(..=cutoff)?;
+    }
+
+    Ok(())
+}
+
+/// When `id` expires after the changes made to it so far in the transaction.
+fn expiry_of(
+    expiry: &redb::Table<(&'static str, &'static str), u64>,
+    id: (&str, &str),
+) -> Result<Option<u64>, redb::Error> {
+    Ok(expiry.get(id)?.map(|v| v.value()))
+}
+
+/// Append `record` to the audit log, numbering it after the last record.
+fn append_audit(log: &mut AuditLog, mut record: AuditRecord) -> Result<(), redb::Error> {
+    let last = log.iter()?.next_back().transpose()?.map(|(k, _)| k.value());
+    record.seq = last.unwrap_or_default() + 1;
+
+    // Serializing strings and integers can't fail
+    let record_json = serde_json::to_string(&record).unwrap_or_default();
+    log.insert(record.seq, record_json.as_str())?;
+
+    Ok(())
+}
+
+/// Append a write or delete of `id` to the audit log.
+fn audit_change(
+    log: &mut AuditLog,
+    actor: Option<&Actor>,
+    op: AuditOp,
+    id: (&str, &str),
+    old_hash: Option<String>,
+    new_data: Option<&[u8]>,
+) -> Result<(), redb::Error> {
+    append_audit(
+        log,
+        AuditRecord {
+            seq: 0,
+            at: now_millis(),
+            op,
+            table: Some(id.0.to_string()),
+            key: Some(id.1.to_string()),
+            token: actor.and_then(|v| v.token.clone()),
+            ip: actor.and_then(|v| v.ip),
+            request_id: actor.and_then(|v| v.request_id.clone()),
+            old_hash,
+            new_hash: new_data.map(sha256),
+        },
+    )
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Add the `stored` content type and data as the newest revision of `id`, forgetting revisions older than the last `keep`.
+fn record_revision(
+    history: &mut HistoryRecords,
+    id: (&str, &str),
+    stored: (&str, &[u8]),
+    keep: usize,
+) -> Result<(), redb::Error> {
+    let (table, key) = id;
+
+    let latest = history
+        .range((table, key, 0)..=(table, key, u64::MAX))?
+        .next_back()
+        .transpose()?
+        .map(|(k, _)| k.value().2);
+    let rev = latest.unwrap_or_default() + 1;
+    history.insert((table, key, rev), (now_millis(), stored.0, stored.1))?;
+
+    let cutoff = rev.saturating_sub(keep as u64);
+    if cutoff > 0 {
+        history.drain((table, key, 0)..=(table, key, cutoff))?;
+    }
+
+    Ok(())
+}
+
+/// Record a write to `id` in the metadata table. `existed` is whether the key held a value that
+/// hadn't expired, in which case its creation time is kept.
+fn touch(
+    meta: &mut redb::Table<(&'static str, &'static str), (u64, u64)>,
+    id: (&str, &str),
+    existed: bool,
+) -> Result<(), redb::Error> {
+    let now = now_millis();
+    let created_at = match meta.get(id)?.map(|v| v.value().0) {
+        Some(v) if existed => v,
+        // Written before metadata was tracked
+        None if existed => 0,
+        _ => now,
+    };
+    meta.insert(id, (created_at, now))?;
+
+    Ok(())
+}
+
+/// The lease on `id`, unless it has expired.
+fn live_lease(
+    leases: &impl ReadableTable<(&'static str, &'static str), (u64, u64, &'static str)>,
+    id: (&str, &str),
+) -> Result<Option<Lease>, redb::Error> {
+    let Some(lease) = leases.get(id)? else {
+        return Ok(None);
+    };
+    let (token, expires_at, owner) = lease.value();
+    if expires_at <= now_millis() {
+        return Ok(None);
+    }
+
+    Ok(Some(Lease {
+        token,
+        owner: (!owner.is_empty()).then(|| owner.to_string()),
+        expires_at,
+    }))
+}
+
+/// When something given `ttl` from now expires, in milliseconds since the unix epoch.
+fn expiry_after(ttl: Duration) -> u64 {
+    let ttl: u64 = ttl.as_millis().try_into().unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl)
+}
+
+/// Everywhere a write is recorded besides the value table itself, opened once per transaction.
+struct WriteRecords<'db, 'txn> {
+    expiry: redb::Table<'db, 'txn, (&'static str, &'static str), u64>,
+    meta: redb::Table<'db, 'txn, (&'static str, &'static str), (u64, u64)>,
+    history: Option<HistoryRecords<'db, 'txn>>,
+    keep_history: usize,
+    changelog: Option<Changelog<'db, 'txn>>,
+    keep_changes: usize,
+    audit: Option<AuditLog<'db, 'txn>>,
+}
+
+impl WriteRecords<'_, '_> {
+    /// The hash of an old value's data for the audit log, if there is one.
+    fn old_hash(&self, data: &[u8]) -> Option<String> {
+        self.audit.as_ref().map(|_| sha256(data))
+    }
+
+    /// Record that `stored` was written to `id`: set or clear its expiry, touch its metadata,
+    /// and add it to the history, changelog and audit log when they're open. `existed` is
+    /// whether the key held a value that hadn't expired, and `old_hash` is from
+    /// [WriteRecords::old_hash].
+    fn record_write(
+        &mut self,
+        id: (&str, &str),
+        existed: bool,
+        old_hash: Option<String>,
+        stored: (&str, &[u8]),
+        expires_at: Option<u64>,
+        actor: Option<&Actor>,
+    ) -> Result<(), redb::Error> {
+        match expires_at {
+            Some(v) => self.expiry.insert(id, v)?,
+            None => self.expiry.remove(id)?,
         };
+        touch(&mut self.meta, id, existed)?;
+        if let Some(history) = &mut self.history {
+            record_revision(history, id, stored, self.keep_history)?;
+        }
+        if let Some(log) = &mut self.changelog {
+            log_change(
+                log,
+                self.keep_changes,
+                ChangeOp::Set,
+                id,
+                Some(stored),
+                expires_at,
+            )?;
+        }
+        if let Some(log) = &mut self.audit {
+            audit_change(log, actor, AuditOp::Write, id, old_hash, Some(stored.1))?;
+        }
 
-        if let Err(e) = tx.commit() {
-            return Err(e.into());
+        Ok(())
+    }
+}
+
+/// Milliseconds since the Unix epoch, the unit every timestamp in the database is in.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+/// How a write changes the expiry of a key.
+enum TtlUpdate {
+    /// Keep the current TTL, if any
+    Keep,
+    /// Replace the current TTL. `None` removes it.
+    Set(Option<Duration>),
+}
+
+fn parse_list(key: &str, value: &Value) -> Result<VecDeque<String>, YwkvError> {
+    serde_json::from_slice(&value.data).map_err(|_| YwkvError::NotAList(key.to_string()))
+}
+
+fn parse_set(key: &str, value: &Value) -> Result<BTreeSet<String>, YwkvError> {
+    serde_json::from_slice(&value.data).map_err(|_| YwkvError::NotASet(key.to_string()))
+}
+
+/// Merge `patch` into `target` as RFC 7386 describes. Members of an object patch set to `null`
+/// are removed, and anything else replaces the target outright.
+fn merge(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    let serde_json::Value::Object(target) = target else {
+        unreachable!("target was just made an object");
+    };
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(name);
+        } else {
+            merge(
+                target
+                    .entry(name.clone())
+                    .or_insert(serde_json::Value::Null),
+                value,
+            );
         }
+    }
+}
 
-        Ok(old_value)
+/// Whether `data` is a single valid JSON document.
+pub fn is_json(data: &[u8]) -> bool {
+    serde_json::from_slice::<serde::de::IgnoredAny>(data).is_ok()
+}
+
+/// Check that `to` holds the same entries as `from` in the same order, as decided by `same`,
+/// returning how many there are.
+fn verify_entries<T, U>(
+    table: &str,
+    from: impl Iterator<Item = Result<T, redb::Error>>,
+    mut to: impl Iterator<Item = Result<U, redb::Error>>,
+    same: impl Fn(&T, &U) -> bool,
+) -> Result<u64, YwkvError> {
+    let mismatch = || YwkvError::MigrationMismatch(table.to_string());
+
+    let mut count = 0;
+    for entry in from {
+        let Some(copied) = to.next() else {
+            return Err(mismatch());
+        };
+        if !same(&entry?, &copied?) {
+            return Err(mismatch());
+        }
+        count += 1;
+    }
+    if to.next().is_some() {
+        return Err(mismatch());
+    }
+
+    Ok(count)
+}
+
+/// Treat a table that was never written to the same as an empty one.
+fn open_optional<T>(res: Result<T, redb::Error>) -> Result<Option<T>, redb::Error> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(redb::Error::TableDoesNotExist(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The first bytes of every redb database file.
+const REDB_MAGIC: [u8; 9] = *b"redb\x1a\x0a\xa9\x0d\x0a";
+
+/// Whether `path` starts with [REDB_MAGIC]. redb turns any other file it opens into a new, empty
+/// database, so files are checked first.
+fn is_database_file(path: &Path) -> Result<bool, std::io::Error> {
+    let mut magic = [0; REDB_MAGIC.len()];
+    match std::fs::File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == REDB_MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Tables used internally by ywkv share this prefix and cannot be opened as value tables.
+pub const RESERVED_TABLE_PREFIX: &str = "ywkv.";
+
+/// Called with the duration of every successful commit.
+pub type CommitObserver = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// A handle to a single table in a shared [Database].
+///
+/// Cloning is cheap. redb supports any number of concurrent read transactions alongside a single
+/// write transaction, so the lock around the database is only taken exclusively by [Db::compact].
+#[derive(Clone)]
+pub struct Db {
+    database: Arc<RwLock<Database>>,
+    table: String,
+    changes: broadcast::Sender<Arc<Change>>,
+    on_commit: Option<CommitObserver>,
+    cache: Option<Arc<Cache>>,
+    /// How many revisions of each key to keep. 0 turns history off.
+    history: usize,
+    /// How many changes to keep in the changelog. 0 turns the changelog off.
+    changelog: usize,
+    encryption: Option<Arc<EncryptionKeys>>,
+    /// Values at least this many bytes are compressed
+    compress_above: Option<usize>,
+    audit: bool,
+    /// Who changes made through this handle are recorded as being made by
+    actor: Option<Arc<Actor>>,
+    read_only: bool,
+    durability: Durability,
+    quota: Quota,
+    /// Tables whose values must be valid JSON
+    json_tables: Arc<BTreeSet<String>>,
+    /// Where the database file is, if opened with [Db::builder]
+    path: Option<Arc<Path>>,
+    /// When the last commit through any handle finished, in milliseconds since the unix epoch. 0
+    /// until the first one.
+    last_commit: Arc<AtomicU64>,
+    page_stats: Arc<Mutex<PageStatsCache>>,
+    /// The file opened with [DbBuilder::open_temporary]. Declared after `database` so the
+    /// database is closed before the file is deleted.
+    temporary: Option<Arc<builder::TempFile>>,
+}
+
+impl Db {
+    pub fn new<T: Into<String>>(database: Database, table: T) -> Result<Self, YwkvError> {
+        let (changes, _) = broadcast::channel(CHANGE_CAPACITY);
+
+        Ok(Self {
+            database: Arc::new(RwLock::new(database)),
+            table: Self::validate_table(table.into())?,
+            changes,
+            on_commit: None,
+            cache: None,
+            history: 0,
+            changelog: 0,
+            encryption: None,
+            compress_above: None,
+            audit: false,
+            actor: None,
+            read_only: false,
+            durability: Durability::Immediate,
+            quota: Quota::default(),
+            json_tables: Arc::default(),
+            path: None,
+            last_commit: Arc::new(AtomicU64::new(0)),
+            page_stats: Arc::default(),
+            temporary: None,
+        })
+    }
+
+    /// Open a database file with more control than [Database::create].
+    pub fn builder() -> DbBuilder {
+        DbBuilder::default()
+    }
+
+    /// Get a handle to another table in the same database. The table is created on first write.
+    ///
+    /// Changes to either table are published to subscribers of both.
+    pub fn with_table<T: Into<String>>(&self, table: T) -> Result<Self, YwkvError> {
+        Ok(Self {
+            table: Self::validate_table(table.into())?,
+            ..self.clone()
+        })
+    }
+
+    fn validate_table(table: String) -> Result<String, YwkvError> {
+        if !Self::is_valid_table(&table) {
+            return Err(YwkvError::InvalidTable(table));
+        }
+
+        Ok(table)
+    }
+
+    fn is_valid_table(table: &str) -> bool {
+        !table.is_empty() && !table.starts_with(RESERVED_TABLE_PREFIX)
+    }
+
+    /// Observe commits made through this handle and any handles created from it afterwards.
+    pub fn set_commit_observer(&mut self, observer: impl Fn(Duration) + Send + Sync + 'static) {
+        self.on_commit = Some(Arc::new(observer));
+    }
+
+    /// Keep recently read values in memory for this handle and any handles created from it
+    /// afterwards. Least recently used values are evicted once either limit is reached.
+    ///
+    /// Only writes made through these handles invalidate cached values.
+    pub fn enable_cache(&mut self, max_entries: Option<NonZeroUsize>, max_bytes: Option<usize>) {
+        self.cache = Some(Arc::new(Cache::new(max_entries, max_bytes)));
+    }
+
+    /// How commits through this handle and any handles created from it afterwards reach the disk.
+    /// Defaults to [Durability::Immediate], which waits for `fsync` on every commit.
+    ///
+    /// Commits with [Durability::None] are only persisted by a later durable commit or when the
+    /// database is closed, and the space they free isn't reused until then.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Keep the last `versions` values of every key written through this handle and any handles
+    /// created from it afterwards, including the current value. 0 turns history off, but keeps
+    /// the revisions already recorded.
+    pub fn keep_history(&mut self, versions: usize) {
+        self.history = versions;
+    }
+
+    /// Record every change made through this handle and any handles created from it afterwards
+    /// in a changelog, keeping the last `entries` changes. 0 turns the changelog off, but keeps
+    /// the changes already recorded.
+    pub fn keep_changelog(&mut self, entries: usize) {
+        self.changelog = entries;
+    }
+
+    /// Reject every write through this handle and any handles created from it afterwards with
+    /// [YwkvError::ReadOnly].
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Reject writes through this handle and any handles created from it afterwards once the
+    /// database is full. A write that creates a key in a table already holding
+    /// [Quota::max_keys] keys fails with [YwkvError::KeyQuotaExceeded], and any write once the
+    /// file reaches [Quota::max_size] fails with [YwkvError::SizeQuotaExceeded]. Deletes always
+    /// work, and the file size is only checked for handles opened with [Db::builder].
+    ///
+    /// Changes applied with [Db::apply_changes] and snapshots restored are never rejected.
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    /// Where the database file is, if opened with [Db::builder].
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Reject writes of anything but valid JSON to these tables through this handle and any
+    /// handles created from it afterwards with [YwkvError::NotJson]. Values already stored aren't
+    /// checked, and neither are changes applied with [Db::apply_changes] or snapshots restored.
+    pub fn require_json<T: Into<String>>(&mut self, tables: impl IntoIterator<Item = T>) {
+        self.json_tables = Arc::new(tables.into_iter().map(Into::into).collect());
+    }
+
+    /// Compress values of at least `min_size` bytes with zstd when they're written through this
+    /// handle and any handles created from it afterwards, if that makes them smaller. Compressed
+    /// values are always decompressed when read, so this can be turned off with `None` at any
+    /// time, and values written before this are compressed the next time they are written.
+    pub fn enable_compression(&mut self, min_size: Option<usize>) {
+        self.compress_above = min_size;
+    }
+
+    /// Encrypt values written through this handle and any handles created from it afterwards,
+    /// and decrypt them when read. Values written before this stay readable, and are encrypted
+    /// the next time they are written.
+    pub fn enable_encryption(&mut self, keys: EncryptionKeys) {
+        self.encryption = Some(Arc::new(keys));
+    }
+
+    /// Record every write and delete made through this handle, and any handles created from it
+    /// afterwards, in the audit log. Expired keys being purged are not recorded.
+    pub fn enable_audit(&mut self) {
+        self.audit = true;
+    }
+
+    /// Record changes made through the returned handle as being made by `actor`.
+    pub fn with_actor(mut self, actor: Actor) -> Self {
+        self.actor = Some(Arc::new(actor));
+        self
+    }
+
+    pub fn actor(&self) -> Option<&Arc<Actor>> {
+        self.actor.as_ref()
+    }
+
+    fn open_audit<'db, 'txn>(
+        &self,
+        tx: &'txn WriteTransaction<'db>,
+    ) -> Result<Option<AuditLog<'db, 'txn>>, redb::Error> {
+        match self.audit {
+            true => tx.open_table(AUDIT_TABLE).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// Up to `limit` audit records older than the `before` cursor that match `filter`, newest
+    /// first.
+    #[tracing::instrument(skip_all)]
+    pub fn audit_log(
+        &self,
+        before: Option<u64>,
+        limit: usize,
+        filter: impl Fn(&AuditRecord) -> bool,
+    ) -> Result<AuditPage, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let log = match open_optional(tx.open_table(AUDIT_TABLE))? {
+            Some(v) => v,
+            None => {
+                return Ok(AuditPage {
+                    records: vec![],
+                    cursor: None,
+                })
+            }
+        };
+
+        let mut records = vec![];
+        for entry in log.range(..before.unwrap_or(u64::MAX))?.rev() {
+            let (_, record) = entry?;
+            let record = match serde_json::from_str::<AuditRecord>(record.value()) {
+                Ok(v) => v,
+                Err(e) => return Err(YwkvError::Io(e.into())),
+            };
+            if !filter(&record) {
+                continue;
+            }
+
+            // Find one extra record to know if there is another page
+            if records.len() == limit {
+                let cursor = records.last().map(|v: &AuditRecord| v.seq);
+                return Ok(AuditPage { records, cursor });
+            }
+            records.push(record);
+        }
+
+        Ok(AuditPage {
+            records,
+            cursor: None,
+        })
+    }
+
+    /// Up to `limit` changes recorded in the changelog after the `after` sequence number, oldest
+    /// first.
+    #[tracing::instrument(skip_all)]
+    pub fn changes_since(&self, after: u64, limit: usize) -> Result<ChangePage, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let log = match open_optional(tx.open_table(CHANGELOG_TABLE))? {
+            Some(v) => v,
+            None => {
+                return Ok(ChangePage {
+                    records: vec![],
+                    first: None,
+                    last: 0,
+                })
+            }
+        };
+        let first = log.iter()?.next().transpose()?.map(|(k, _)| k.value());
+        let last = log.iter()?.next_back().transpose()?.map(|(k, _)| k.value());
+
+        let mut records = vec![];
+        for entry in log.range(after.saturating_add(1)..)?.take(limit) {
+            let (seq, entry) = entry?;
+            let (op, at, table, key, content_type, data, expires_at) = entry.value();
+            records.push(ChangeRecord {
+                seq: seq.value(),
+                at,
+                op: ChangeOp::from_u8(op),
+                table: table.to_string(),
+                key: key.to_string(),
+                content_type: content_type.to_string(),
+                data: data.to_vec(),
+                expires_at: Some(expires_at).filter(|v| *v != 0),
+            });
+        }
+
+        Ok(ChangePage {
+            records,
+            first,
+            last: last.unwrap_or_default(),
+        })
+    }
+
+    /// Up to `limit` changes to this table recorded in the changelog after the `since` sequence
+    /// number, oldest first. Restores are included too, since they replace every table.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn changes(&self, since: u64, limit: usize) -> Result<ChangeFeed, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let log = match open_optional(tx.open_table(CHANGELOG_TABLE))? {
+            Some(v) => v,
+            None => {
+                return Ok(ChangeFeed {
+                    events: vec![],
+                    next: since,
+                    first: None,
+                })
+            }
+        };
+        let first = log.iter()?.next().transpose()?.map(|(k, _)| k.value());
+
+        let mut events = vec![];
+        let mut next = since;
+        for entry in log.range(since.saturating_add(1)..)? {
+            if events.len() == limit {
+                break;
+            }
+
+            let (seq, entry) = entry?;
+            let (op, at, table, key, content_type, data, expires_at) = entry.value();
+            // Changes to other tables are skipped over, not listed again on the next page
+            next = seq.value();
+            let op = ChangeOp::from_u8(op);
+            if op != ChangeOp::Restore && table != self.table {
+                continue;
+            }
+
+            let value = match op {
+                ChangeOp::Set => Some(self.unseal((content_type, data))?),
+                ChangeOp::Delete | ChangeOp::Restore => None,
+            };
+            events.push(ChangeEvent {
+                seq: next,
+                at,
+                op,
+                key: key.to_string(),
+                content_type: value.as_ref().map(|v| v.content_type.clone()),
+                value,
+                expires_at: Some(expires_at).filter(|v| *v != 0),
+            });
+        }
+
+        Ok(ChangeFeed {
+            events,
+            next,
+            first,
+        })
+    }
+
+    /// The sequence number of the newest change in the changelog, or 0 if nothing was recorded.
+    pub fn changelog_position(&self) -> Result<u64, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        Self::changelog_position_in(&tx)
+    }
+
+    fn changelog_position_in(tx: &ReadTransaction) -> Result<u64, YwkvError> {
+        let position = match open_optional(tx.open_table(CHANGELOG_TABLE))? {
+            Some(log) => log.iter()?.next_back().transpose()?.map(|(k, _)| k.value()),
+            None => None,
+        };
+
+        Ok(position.unwrap_or_default())
+    }
+
+    /// Apply changes read from another database with [Db::changes_since] in a single write
+    /// transaction, and record them in the changelog with the same sequence numbers. The first
+    /// change must directly follow the newest one already in the changelog.
+    ///
+    /// Values are stored exactly as they were, so encrypted values need the same keys to be
+    /// read. Restores can't be applied since they don't include the restored data, so use
+    /// [Db::resync] with a new snapshot instead.
+    #[tracing::instrument(skip_all)]
+    pub fn apply_changes(&self, records: &[ChangeRecord]) -> Result<(), YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let mut changes = vec![];
+        {
+            let mut log = tx.open_table(CHANGELOG_TABLE)?;
+            // Changes are logged below with their own sequence numbers, and replicated writes
+            // aren't audited
+            let mut written = WriteRecords {
+                expiry: tx.open_table(EXPIRY_TABLE)?,
+                meta: tx.open_table(META_TABLE)?,
+                history: self.open_history(&tx)?,
+                keep_history: self.history,
+                changelog: None,
+                keep_changes: 0,
+                audit: None,
+            };
+
+            let mut position = log.iter()?.next_back().transpose()?.map(|(k, _)| k.value());
+            for record in records {
+                let position = position.get_or_insert(0);
+                if record.seq != *position + 1 {
+                    return Err(YwkvError::ChangeOutOfOrder(*position, record.seq));
+                }
+                if !Self::is_valid_table(&record.table) && record.op != ChangeOp::Restore {
+                    return Err(YwkvError::InvalidTable(record.table.clone()));
+                }
+
+                let id = (record.table.as_str(), record.key.as_str());
+                match record.op {
+                    ChangeOp::Set => {
+                        let mut table = tx.open_table(ValueTable::new(&record.table))?;
+                        let expired =
+                            matches!(expiry_of(&written.expiry, id)?, Some(v) if v <= now_millis());
+                        let stored = (record.content_type.as_str(), record.data.as_slice());
+                        let existed = table.insert(id.1, stored)?.is_some() && !expired;
+                        written.record_write(id, existed, None, stored, record.expires_at, None)?;
+
+                        changes.push((record, Some(self.unseal(stored)?), !existed));
+                    }
+                    ChangeOp::Delete => {
+                        tx.open_table(ValueTable::new(&record.table))?
+                            .remove(id.1)?;
+                        written.expiry.remove(id)?;
+                        written.meta.remove(id)?;
+
+                        changes.push((record, None, false));
+                    }
+                    ChangeOp::Restore => return Err(YwkvError::RestoreChange(record.seq)),
+                }
+
+                // Recording the position is what lets the next changes be applied
+                insert_change(
+                    &mut log,
+                    self.changelog.max(1),
+                    record.seq,
+                    (
+                        record.op.as_u8(),
+                        record.at,
+                        id.0,
+                        id.1,
+                        &record.content_type,
+                        &record.data,
+                        record.expires_at.unwrap_or(0),
+                    ),
+                )?;
+                *position = record.seq;
+            }
+        }
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for (record, value, created) in changes {
+            self.publish(&record.table, &record.key, value.map(Cow::Owned), created);
+        }
+
+        Ok(())
+    }
+
+    fn check_json(&self, table: &str, key: &str, value: &Value) -> Result<(), YwkvError> {
+        if self.json_tables.contains(table) && !is_json(&value.data) {
+            return Err(YwkvError::NotJson(key.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// The data to store for `value`, compressed before it's encrypted since encrypted data
+    /// doesn't compress.
+    fn seal<'a>(&self, value: &'a Value) -> Result<Cow<'a, [u8]>, YwkvError> {
+        let data = compression::compress(&value.data, self.compress_above);
+        match &self.encryption {
+            Some(keys) => keys.seal(&value.content_type, &data).map(Cow::Owned),
+            None => Ok(data),
+        }
+    }
+
+    fn unseal(&self, (content_type, data): (&str, &[u8])) -> Result<Value, YwkvError> {
+        let data = EncryptionKeys::open(self.encryption.as_deref(), content_type, data)?;
+        let data = compression::decompress(data)?;
+
+        Ok(Value::new(data, content_type))
+    }
+
+    fn open_changelog<'db, 'txn>(
+        &self,
+        tx: &'txn WriteTransaction<'db>,
+    ) -> Result<Option<Changelog<'db, 'txn>>, redb::Error> {
+        match self.changelog {
+            0 => Ok(None),
+            _ => tx.open_table(CHANGELOG_TABLE).map(Some),
+        }
+    }
+
+    fn open_records<'db, 'txn>(
+        &self,
+        tx: &'txn WriteTransaction<'db>,
+    ) -> Result<WriteRecords<'db, 'txn>, redb::Error> {
+        Ok(WriteRecords {
+            expiry: tx.open_table(EXPIRY_TABLE)?,
+            meta: tx.open_table(META_TABLE)?,
+            history: self.open_history(tx)?,
+            keep_history: self.history,
+            changelog: self.open_changelog(tx)?,
+            keep_changes: self.changelog,
+            audit: self.open_audit(tx)?,
+        })
+    }
+
+    fn open_history<'db, 'txn>(
+        &self,
+        tx: &'txn WriteTransaction<'db>,
+    ) -> Result<Option<HistoryRecords<'db, 'txn>>, redb::Error> {
+        match self.history {
+            0 => Ok(None),
+            _ => tx.open_table(HISTORY_TABLE).map(Some),
+        }
+    }
+
+    /// Hit and miss counts along with the current size of the cache, if enabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|v| v.stats())
+    }
+
+    /// Share the database with every other operation but compaction. The lock's poisoning is
+    /// ignored, since redb keeps the database consistent on its own, so a panic while it's held
+    /// doesn't make every request after it panic too.
+    fn shared_database(&self) -> RwLockReadGuard<'_, Database> {
+        self.database.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn begin_write<'db>(
+        &self,
+        database: &'db Database,
+    ) -> Result<WriteTransaction<'db>, YwkvError> {
+        if self.read_only {
+            return Err(YwkvError::ReadOnly);
+        }
+
+        let mut tx = database.begin_write()?;
+        tx.set_durability(self.durability);
+
+        Ok(tx)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn commit(&self, tx: WriteTransaction) -> Result<(), redb::Error> {
+        let start = Instant::now();
+        self.take_page_stats(&tx);
+        tx.commit()?;
+        self.last_commit.store(now_millis(), Ordering::Relaxed);
+
+        if let Some(on_commit) = &self.on_commit {
+            on_commit(start.elapsed());
+        }
+
+        Ok(())
+    }
+
+    /// Take page usage from a transaction that's about to commit if [Db::stats] has asked for it
+    /// and the last was taken more than [PAGE_STATS_INTERVAL] ago. It's taken without holding the
+    /// lock so [Db::stats] never waits on the walk.
+    fn take_page_stats(&self, tx: &WriteTransaction) {
+        {
+            let cache = self.page_stats.lock().unwrap();
+            let fresh = cache
+                .latest
+                .is_some_and(|v| v.taken_at.elapsed() < PAGE_STATS_INTERVAL);
+            if !cache.wanted || fresh {
+                return;
+            }
+        }
+
+        match PageStats::take(tx) {
+            Ok(stats) => {
+                let mut cache = self.page_stats.lock().unwrap();
+                cache.latest = Some(stats);
+                cache.wanted = false;
+            }
+            Err(err) => tracing::warn!("Failed to read page usage: {err}"),
+        }
+    }
+
+    /// Fail if the database file has reached [Quota::max_size].
+    fn check_size_quota(&self) -> Result<(), YwkvError> {
+        if let (Some(max), Some(path)) = (self.quota.max_size, &self.path) {
+            if std::fs::metadata(path)?.len() >= max {
+                return Err(YwkvError::SizeQuotaExceeded(max));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail if a write that `created` a key took `table` past [Quota::max_keys]. Keys that have
+    /// expired but haven't been swept yet don't count. Counting them means reading every TTL in
+    /// the table, so it's only done when a key is created.
+    fn check_key_quota(
+        &self,
+        table: &impl ReadableTable<&'static str, (&'static str, &'static [u8])>,
+        expiry: &impl ReadableTable<(&'static str, &'static str), u64>,
+        name: &str,
+        created: bool,
+    ) -> Result<(), YwkvError> {
+        if let Some(max) = self.quota.max_keys.filter(|_| created) {
+            let now = now_millis();
+            let mut expired = 0;
+            for entry in expiry.range::<(&str, &str)>((name, "")..)? {
+                let (id, expires_at) = entry?;
+                if id.value().0 != name {
+                    break;
+                }
+                if expires_at.value() <= now {
+                    expired += 1;
+                }
+            }
+
+            if table.len()?.saturating_sub(expired) > max {
+                return Err(YwkvError::KeyQuotaExceeded(name.to_string(), max));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receive every change committed to any table in the database from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Change>> {
+        self.changes.subscribe()
+    }
+
+    fn publish(&self, table: &str, key: &str, value: Option<Cow<Value>>, created: bool) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(table, key);
+        }
+
+        // Avoid copying values around when nobody is listening
+        if self.changes.receiver_count() == 0 {
+            return;
+        }
+
+        let kind = match value {
+            Some(_) => ChangeKind::Set,
+            None => ChangeKind::Delete,
+        };
+
+        // Sending only fails when there are no receivers left, which is fine
+        let _ = self.changes.send(Arc::new(Change {
+            table: table.to_string(),
+            key: key.to_string(),
+            kind,
+            value: value.map(Cow::into_owned),
+            created,
+        }));
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    /// The names of every value table in the database.
+    pub fn tables(&self) -> Result<Vec<String>, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        Ok(tx
+            .list_tables()?
+            .map(|v| v.name().to_string())
+            .filter(|v| !v.starts_with(RESERVED_TABLE_PREFIX))
+            .collect())
+    }
+
+    /// Copy every table into a new database file at `path`. The copy is made from a single read
+    /// transaction, so it is consistent even while writes continue. Fails if `path` exists.
+    #[tracing::instrument(skip_all)]
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(), YwkvError> {
+        let path = path.as_ref();
+
+        // Claim the path first so an existing file is never opened and written into
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        let res = self.copy_into(path);
+        if res.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+
+        res
+    }
+
+    fn copy_into(&self, path: &Path) -> Result<(), YwkvError> {
+        let backup = Database::create(path)?;
+
+        let database = self.shared_database();
+        let source = database.begin_read()?;
+        let tx = backup.begin_write()?;
+        Self::copy_tables(&source, &tx, &[])?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Replace the contents of every table with a snapshot made by [Db::backup]. The snapshot is
+    /// copied in a single write transaction, so either all of it is restored or nothing changes.
+    ///
+    /// Subscribers are not told about the restored values. The changelog is kept, and records the
+    /// restore as a single change.
+    #[tracing::instrument(skip_all)]
+    pub fn restore<P: AsRef<Path>>(&self, path: P) -> Result<(), YwkvError> {
+        self.replace(path.as_ref(), false)
+    }
+
+    /// Like [Db::restore], but also replaces the changelog with the one in the snapshot. Changes
+    /// made after the snapshot can then be applied with [Db::apply_changes].
+    #[tracing::instrument(skip_all)]
+    pub fn resync<P: AsRef<Path>>(&self, path: P) -> Result<(), YwkvError> {
+        self.replace(path.as_ref(), true)
+    }
+
+    fn replace(&self, path: &Path, resync: bool) -> Result<(), YwkvError> {
+        let invalid = |e: redb::Error| YwkvError::InvalidSnapshot(e.to_string());
+
+        if !is_database_file(path)? {
+            return Err(YwkvError::InvalidSnapshot(
+                "not a database file".to_string(),
+            ));
+        }
+
+        let snapshot = Database::open(path).map_err(invalid)?;
+        let source = snapshot.begin_read()?;
+        if let Some(name) = source
+            .list_tables()?
+            .map(|v| v.name().to_string())
+            .find(|v| {
+                v.starts_with(RESERVED_TABLE_PREFIX)
+                    && v != EXPIRY_TABLE.name()
+                    && v != META_TABLE.name()
+                    && v != HISTORY_TABLE.name()
+                    && v != AUDIT_TABLE.name()
+                    && v != CHANGELOG_TABLE.name()
+                    && v != LEASE_TABLE.name()
+                    && v != FENCING_TABLE.name()
+            })
+        {
+            return Err(YwkvError::InvalidSnapshot(format!(
+                "unknown table `{name}`"
+            )));
+        }
+
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+        // The audit log is kept as it is, so restores can't hide earlier changes, and so are
+        // leases, so fencing tokens never go backwards
+        let mut kept = vec![AUDIT_TABLE.name(), LEASE_TABLE.name(), FENCING_TABLE.name()];
+        if !resync {
+            kept.push(CHANGELOG_TABLE.name());
+        }
+        for table in tx.list_tables()?.collect::<Vec<_>>() {
+            if !kept.contains(&table.name()) {
+                tx.delete_table(table)?;
+            }
+        }
+        match Self::copy_tables(&source, &tx, &kept) {
+            Ok(()) => {}
+            Err(e @ redb::Error::TableTypeMismatch(_)) => return Err(invalid(e)),
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(log) = &mut self.open_audit(&tx)? {
+            append_audit(
+                log,
+                AuditRecord {
+                    seq: 0,
+                    at: now_millis(),
+                    op: AuditOp::Restore,
+                    table: None,
+                    key: None,
+                    token: self.actor.as_ref().and_then(|v| v.token.clone()),
+                    ip: self.actor.as_ref().and_then(|v| v.ip),
+                    request_id: self.actor.as_ref().and_then(|v| v.request_id.clone()),
+                    old_hash: None,
+                    new_hash: None,
+                },
+            )?;
+        }
+        if let (Some(log), false) = (&mut self.open_changelog(&tx)?, resync) {
+            log_change(log, self.changelog, ChangeOp::Restore, ("", ""), None, None)?;
+        }
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Copy every table but the `skipped` ones.
+    fn copy_tables(
+        source: &ReadTransaction,
+        tx: &WriteTransaction,
+        skipped: &[&str],
+    ) -> Result<(), redb::Error> {
+        for name in source.list_tables()?.map(|v| v.name().to_string()) {
+            if !skipped.contains(&name.as_str()) {
+                Self::copy_table(source, tx, &name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the table called `name` as it is, whichever of ywkv's tables it is.
+    fn copy_table(
+        source: &ReadTransaction,
+        tx: &WriteTransaction,
+        name: &str,
+    ) -> Result<(), redb::Error> {
+        if name == CHANGELOG_TABLE.name() {
+            let from = source.open_table(CHANGELOG_TABLE)?;
+            let mut to = tx.open_table(CHANGELOG_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else if name == AUDIT_TABLE.name() {
+            let from = source.open_table(AUDIT_TABLE)?;
+            let mut to = tx.open_table(AUDIT_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else if name == EXPIRY_TABLE.name() {
+            let from = source.open_table(EXPIRY_TABLE)?;
+            let mut to = tx.open_table(EXPIRY_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else if name == META_TABLE.name() {
+            let from = source.open_table(META_TABLE)?;
+            let mut to = tx.open_table(META_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else if name == HISTORY_TABLE.name() {
+            let from = source.open_table(HISTORY_TABLE)?;
+            let mut to = tx.open_table(HISTORY_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else if name == LEASE_TABLE.name() {
+            let from = source.open_table(LEASE_TABLE)?;
+            let mut to = tx.open_table(LEASE_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else if name == FENCING_TABLE.name() {
+            let from = source.open_table(FENCING_TABLE)?;
+            let mut to = tx.open_table(FENCING_TABLE)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        } else {
+            let definition = ValueTable::new(name);
+            let from = source.open_table(definition)?;
+            let mut to = tx.open_table(definition)?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), value.value())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy every table in the database file at `from` into a new file at `to`, converting
+    /// tables of text values from before values were stored as bytes along the way. Converted
+    /// values get `content_type`. Both files are read back afterwards to check that every entry
+    /// was copied. Fails if `to` exists, and removes it if anything goes wrong.
+    ///
+    /// Files written by older versions of redb can't be opened, and fail with
+    /// [YwkvError::Open].
+    #[tracing::instrument(skip_all)]
+    pub fn migrate<P: AsRef<Path>, Q: AsRef<Path>>(
+        from: P,
+        to: Q,
+        content_type: &str,
+    ) -> Result<Migration, YwkvError> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+
+        if !is_database_file(from)? {
+            return Err(YwkvError::NotADatabase(from.display().to_string()));
+        }
+        let source =
+            Database::open(from).map_err(|e| YwkvError::Open(from.display().to_string(), e))?;
+
+        // Claim the path first so an existing file is never opened and written into
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)?;
+
+        let res = Self::migrate_into(&source, to, content_type);
+        if res.is_err() {
+            let _ = std::fs::remove_file(to);
+        }
+
+        res
+    }
+
+    fn migrate_into(
+        source: &Database,
+        to: &Path,
+        content_type: &str,
+    ) -> Result<Migration, YwkvError> {
+        let target = Database::create(to)?;
+        let source = source.begin_read()?;
+        let names = source
+            .list_tables()?
+            .map(|v| v.name().to_string())
+            .collect::<Vec<_>>();
+
+        let mut converted = BTreeSet::new();
+        let tx = target.begin_write()?;
+        for name in &names {
+            let text = match name.starts_with(RESERVED_TABLE_PREFIX) {
+                true => None,
+                false => match source.open_table(ValueTable::new(name)) {
+                    Ok(_) => None,
+                    Err(redb::Error::TableTypeMismatch(_)) => {
+                        Some(source.open_table(TextTable::new(name))?)
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+            };
+            let Some(from) = text else {
+                Self::copy_table(&source, &tx, name)?;
+                continue;
+            };
+
+            let mut to = tx.open_table(ValueTable::new(name))?;
+            for entry in from.iter()? {
+                let (key, value) = entry?;
+                to.insert(key.value(), (content_type, value.value().as_bytes()))?;
+            }
+            converted.insert(name.clone());
+        }
+        tx.commit()?;
+
+        // Read back from the new file rather than trusting the commit
+        let target = target.begin_read()?;
+        let mut migration = Migration::default();
+        for name in names {
+            let copied = if converted.contains(&name) {
+                let from = source.open_table(TextTable::new(&name))?;
+                let to = target.open_table(ValueTable::new(&name))?;
+                let (entries, copied) = (from.iter()?, to.iter()?);
+                verify_entries(&name, entries, copied, |(k, v), (k2, v2)| {
+                    k.value() == k2.value()
+                        && v.value().as_bytes() == v2.value().1
+                        && v2.value().0 == content_type
+                })?
+            } else if name.starts_with(RESERVED_TABLE_PREFIX) {
+                let count = |tx: &ReadTransaction| -> Result<u64, redb::Error> {
+                    match name.as_str() {
+                        v if v == CHANGELOG_TABLE.name() => tx.open_table(CHANGELOG_TABLE)?.len(),
+                        v if v == AUDIT_TABLE.name() => tx.open_table(AUDIT_TABLE)?.len(),
+                        v if v == EXPIRY_TABLE.name() => tx.open_table(EXPIRY_TABLE)?.len(),
+                        v if v == META_TABLE.name() => tx.open_table(META_TABLE)?.len(),
+                        v if v == HISTORY_TABLE.name() => tx.open_table(HISTORY_TABLE)?.len(),
+                        v if v == LEASE_TABLE.name() => tx.open_table(LEASE_TABLE)?.len(),
+                        v if v == FENCING_TABLE.name() => tx.open_table(FENCING_TABLE)?.len(),
+                        v => tx.open_table(ValueTable::new(v))?.len(),
+                    }
+                };
+                let (expected, copied) = (count(&source)?, count(&target)?);
+                if expected != copied {
+                    return Err(YwkvError::MigrationMismatch(name));
+                }
+                copied
+            } else {
+                let from = source.open_table(ValueTable::new(&name))?;
+                let to = target.open_table(ValueTable::new(&name))?;
+                let (entries, copied) = (from.iter()?, to.iter()?);
+                verify_entries(&name, entries, copied, |(k, v), (k2, v2)| {
+                    k.value() == k2.value() && v.value() == v2.value()
+                })?
+            };
+
+            match name.starts_with(RESERVED_TABLE_PREFIX) {
+                true => migration.internal.insert(name, copied),
+                false => migration.tables.insert(name, copied),
+            };
+        }
+        migration.converted = converted.into_iter().collect();
+
+        Ok(migration)
+    }
+
+    /// Make every commit so far durable, including ones made with [Durability::Eventual] or
+    /// [Durability::None], by committing an empty transaction that waits for `fsync`.
+    pub fn checkpoint(&self) -> Result<(), YwkvError> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let database = self.shared_database();
+        let mut tx = database.begin_write()?;
+        tx.set_durability(Durability::Immediate);
+        self.commit(tx)?;
+
+        Ok(())
+    }
+
+    /// Reclaim space left behind by overwritten and deleted values, returning whether anything
+    /// changed. Waits for every other operation on the database to finish, and holds new ones
+    /// back until it is done.
+    #[tracing::instrument(skip_all)]
+    pub fn compact(&self) -> Result<bool, YwkvError> {
+        if self.read_only {
+            return Err(YwkvError::ReadOnly);
+        }
+
+        let mut database = self
+            .database
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let compacted = database.compact()?;
+        if compacted {
+            self.page_stats.lock().unwrap().latest = None;
+        }
+        Ok(compacted)
+    }
+
+    /// Count the keys in the table and the size of their values, grouped by everything up to
+    /// and including the `depth`th `delimiter` in each key. Keys with fewer delimiters are
+    /// grouped by their longest prefix ending in one, or `""` if they have none. Expired keys are
+    /// skipped.
+    ///
+    /// Values are never decoded, and only the totals for each group are held in memory.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn prefix_stats(
+        &self,
+        depth: usize,
+        delimiter: &str,
+    ) -> Result<Vec<PrefixStats>, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let table = match open_optional(tx.open_table(self.definition()))? {
+            Some(v) => v,
+            None => return Ok(vec![]),
+        };
+        let expiry = open_optional(tx.open_table(EXPIRY_TABLE))?;
+
+        let mut groups = BTreeMap::<String, (u64, u64)>::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            let key = key.value();
+            if self.is_expired(expiry.as_ref(), key)? {
+                continue;
+            }
+
+            let end = match delimiter {
+                "" => 0,
+                _ => key
+                    .match_indices(delimiter)
+                    .take(depth)
+                    .last()
+                    .map_or(0, |(i, _)| i + delimiter.len()),
+            };
+            let group = match groups.get_mut(&key[..end]) {
+                Some(v) => v,
+                None => groups.entry(key[..end].to_string()).or_default(),
+            };
+            group.0 += 1;
+            group.1 += value.value().1.len() as u64;
+        }
+
+        Ok(groups
+            .into_iter()
+            .map(|(prefix, (keys, value_bytes))| PrefixStats {
+                prefix,
+                keys,
+                value_bytes,
+            })
+            .collect())
+    }
+
+    /// Sizes and counts describing the table and the whole database. The table is read from
+    /// start to finish to add up its values.
+    ///
+    /// redb only reports page usage from write transactions, so it's taken along with the next
+    /// commit after this is called and can be up to [PAGE_STATS_INTERVAL] old, or older if
+    /// nothing has been written since. Only the first call after opening or compacting waits for
+    /// other writes to finish to read it, even on read-only handles. Nothing is written.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn stats(&self) -> Result<DbStats, YwkvError> {
+        let (keys, value_bytes) = self
+            .prefix_stats(1, "")?
+            .first()
+            .map_or((0, 0), |v| (v.keys, v.value_bytes));
+        let tables = self.tables()?;
+        let file_bytes = match &self.path {
+            Some(path) => Some(std::fs::metadata(path)?.len()),
+            None => None,
+        };
+
+        let cached = {
+            let mut cache = self.page_stats.lock().unwrap();
+            cache.wanted = true;
+            cache.latest
+        };
+        let stats = match cached {
+            Some(v) => v,
+            None => {
+                let database = self.shared_database();
+                let tx = database.begin_write()?;
+                let stats = PageStats::take(&tx)?;
+                tx.abort()?;
+                self.page_stats.lock().unwrap().latest = Some(stats);
+                stats
+            }
+        };
+
+        Ok(DbStats {
+            keys,
+            value_bytes,
+            tables,
+            file_bytes,
+            stored_bytes: stats.stored_bytes,
+            metadata_bytes: stats.metadata_bytes,
+            fragmented_bytes: stats.fragmented_bytes,
+            page_size: stats.page_size,
+            allocated_pages: stats.allocated_pages,
+            free_pages: file_bytes
+                .map(|v| (v / stats.page_size as u64).saturating_sub(stats.allocated_pages)),
+            last_commit_at: match self.last_commit.load(Ordering::Relaxed) {
+                0 => None,
+                v => Some(v),
+            },
+        })
+    }
+
+    /// How many keys start with `prefix`, skipping expired keys. Only the keys in the range are
+    /// visited, and values are never decoded.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn count<T: AsRef<str>>(&self, prefix: T) -> Result<u64, YwkvError> {
+        let prefix = prefix.as_ref();
+
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let table = match open_optional(tx.open_table(self.definition()))? {
+            Some(v) => v,
+            None => return Ok(0),
+        };
+        let expiry = open_optional(tx.open_table(EXPIRY_TABLE))?;
+
+        let mut count = 0;
+        for entry in table.range::<&str>(prefix..)? {
+            let (key, _) = entry?;
+            let key = key.value();
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if !self.is_expired(expiry.as_ref(), key)? {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// The number of keys in the table, including expired keys that have not been purged yet.
+    pub fn key_count(&self) -> Result<u64, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let count = match open_optional(tx.open_table(self.definition()))? {
+            Some(table) => table.len()?,
+            None => 0,
+        };
+
+        Ok(count)
+    }
+
+    fn definition(&self) -> ValueTable<'_> {
+        ValueTable::new(&self.table)
+    }
+
+    pub fn read<T: AsRef<str>>(&self, key: T) -> Result<Value, YwkvError> {
+        self.read_with_ttl(key).map(|(value, _)| value)
+    }
+
+    /// Read a value along with the time left until it expires, if it has a TTL.
+    pub fn read_with_ttl<T: AsRef<str>>(
+        &self,
+        key: T,
+    ) -> Result<(Value, Option<Duration>), YwkvError> {
+        self.read_with_meta(key).map(|(value, ttl, _)| (value, ttl))
+    }
+
+    /// Read a value along with its TTL and when it was created and last modified.
+    pub fn read_with_meta<T: AsRef<str>>(
+        &self,
+        key: T,
+    ) -> Result<(Value, Option<Duration>, KeyMeta), YwkvError> {
+        let cached = self.read_cached(key.as_ref())?;
+
+        Ok((cached.value, Self::ttl(cached.expires_at), cached.meta))
+    }
+
+    #[tracing::instrument(name = "read", skip_all, fields(table = %self.table))]
+    fn read_cached(&self, key: &str) -> Result<Cached, YwkvError> {
+        let generation = match &self.cache {
+            Some(cache) => match cache.get(&self.table, key, now_millis()) {
+                Some(cached) => return Ok(cached),
+                None => Some(cache.generation()),
+            },
+            None => None,
+        };
+
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let table = match tx.open_table(self.definition()) {
+            Ok(v) => v,
+            Err(redb::Error::TableDoesNotExist(_)) => {
+                return Err(YwkvError::EmptyTable(key.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let expiry = open_optional(tx.open_table(EXPIRY_TABLE))?;
+
+        let val = table.get(key);
+        let value = match val {
+            Ok(Some(value)) => self.unseal(value.value())?,
+            Ok(None) => return Err(YwkvError::KeyMissing(key.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+
+        let expires_at = self.expires_at(expiry.as_ref(), key)?;
+        if matches!(expires_at, Some(v) if v <= now_millis()) {
+            return Err(YwkvError::KeyMissing(key.to_string()));
+        }
+
+        let meta = match open_optional(tx.open_table(META_TABLE))? {
+            Some(meta) => match meta.get((self.table.as_str(), key))? {
+                Some(v) => {
+                    let (created_at, modified_at) = v.value();
+                    KeyMeta {
+                        created_at: Some(created_at).filter(|v| *v != 0),
+                        modified_at: Some(modified_at),
+                    }
+                }
+                None => KeyMeta::default(),
+            },
+            None => KeyMeta::default(),
+        };
+
+        let cached = Cached {
+            value,
+            expires_at,
+            meta,
+        };
+        if let (Some(cache), Some(generation)) = (&self.cache, generation) {
+            cache.insert(generation, &self.table, key, cached.clone());
+        }
+
+        Ok(cached)
+    }
+
+    /// The time left until `expires_at`, which must be in the future.
+    fn ttl(expires_at: Option<u64>) -> Option<Duration> {
+        expires_at.map(|v| Duration::from_millis(v.saturating_sub(now_millis()).max(1)))
+    }
+
+    /// Read every key from the same read transaction. Missing keys map to `None`.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn read_many<T: AsRef<str>>(
+        &self,
+        keys: impl IntoIterator<Item = T>,
+    ) -> Result<Vec<(T, Option<Value>)>, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        self.read_many_in(&tx, keys)
+    }
+
+    fn read_many_in<T: AsRef<str>>(
+        &self,
+        tx: &ReadTransaction,
+        keys: impl IntoIterator<Item = T>,
+    ) -> Result<Vec<(T, Option<Value>)>, YwkvError> {
+        let table = open_optional(tx.open_table(self.definition()))?;
+        let expiry = open_optional(tx.open_table(EXPIRY_TABLE))?;
+
+        let mut values = vec![];
+        for key in keys {
+            let value = match &table {
+                Some(table) if !self.is_expired(expiry.as_ref(), key.as_ref())? => table
+                    .get(key.as_ref())?
+                    .map(|v| self.unseal(v.value()))
+                    .transpose()?,
+                _ => None,
+            };
+            values.push((key, value));
+        }
+
+        Ok(values)
+    }
+
+    /// Read `keys` and up to `limit` entries with keys starting with `prefix`, all from the same
+    /// read transaction, so no write can land between any two of them.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn read_snapshot(
+        &self,
+        keys: Vec<String>,
+        prefix: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Snapshot, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let values = self.read_many_in(&tx, keys)?;
+        let entries = match prefix {
+            Some(prefix) => self.scan_while_in(
+                &tx,
+                Bound::Included(prefix),
+                Bound::Unbounded,
+                limit,
+                |key| key.starts_with(prefix),
+            )?,
+            None => vec![],
+        };
+
+        Ok(Snapshot {
+            values,
+            entries,
+            position: Self::changelog_position_in(&tx)?,
+        })
+    }
+
+    /// Read a value written by [Self::write_as], or anything else that is valid JSON.
+    pub fn read_as<T: DeserializeOwned>(&self, key: impl AsRef<str>) -> Result<T, YwkvError> {
+        self.read_as_with(key, &JsonCodec)
+    }
+
+    /// Read a value stored in the format of `codec`.
+    pub fn read_as_with<T: DeserializeOwned, C: Codec>(
+        &self,
+        key: impl AsRef<str>,
+        codec: &C,
+    ) -> Result<T, YwkvError> {
+        let key = key.as_ref();
+        let value = self.read(key)?;
+
+        codec
+            .decode(&value.data)
+            .map_err(|e| YwkvError::Codec(key.to_string(), e))
+    }
+
+    /// Write a value as JSON, returning the old value.
+    pub fn write_as<T: Serialize + ?Sized>(
+        &self,
+        key: impl AsRef<str>,
+        val: &T,
+    ) -> Result<Option<Value>, YwkvError> {
+        self.write_as_with(key, val, &JsonCodec)
+    }
+
+    /// Write a value in the format of `codec`, returning the old value.
+    pub fn write_as_with<T: Serialize + ?Sized, C: Codec>(
+        &self,
+        key: impl AsRef<str>,
+        val: &T,
+        codec: &C,
+    ) -> Result<Option<Value>, YwkvError> {
+        let key = key.as_ref();
+        let data = codec
+            .encode(val)
+            .map_err(|e| YwkvError::Codec(key.to_string(), e))?;
+
+        self.write(key, Value::new(data, codec.content_type()))
+    }
+
+    pub fn write<K: AsRef<str>, V: Into<Value>>(
+        &self,
+        key: K,
+        val: V,
+    ) -> Result<Option<Value>, YwkvError> {
+        self.write_with_ttl(key, val, None)
+    }
+
+    /// Write a value that expires after `ttl`. Writing without a TTL clears any existing one.
+    pub fn write_with_ttl<K: AsRef<str>, V: Into<Value>>(
+        &self,
+        key: K,
+        val: V,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, YwkvError> {
+        let val = val.into();
+
+        self.update(key.as_ref(), TtlUpdate::Set(ttl), |_| {
+            Ok(Cow::Borrowed(&val))
+        })
+    }
+
+    /// Write a value only if the current value matches `expected`, returning the old value.
+    ///
+    /// Fails with [YwkvError::PreconditionFailed] without writing anything if the key is missing
+    /// or holds a different value.
+    pub fn compare_and_swap<K: AsRef<str>, E: AsRef<[u8]>, V: Into<Value>>(
+        &self,
+        key: K,
+        expected: E,
+        val: V,
+        ttl: Option<Duration>,
+    ) -> Result<Value, YwkvError> {
+        let key = key.as_ref();
+        let val = val.into();
+
+        let old_value = self.update(key, TtlUpdate::Set(ttl), |current| match current {
+            Some(v) if v.data == expected.as_ref() => Ok(Cow::Borrowed(&val)),
+            _ => Err(YwkvError::PreconditionFailed(key.to_string())),
+        })?;
+
+        // The check above guarantees there was an old value
+        Ok(old_value.unwrap_or_else(|| Value::new(vec![], DEFAULT_CONTENT_TYPE)))
+    }
+
+    /// Write a value only if the current value's [Value::etag] is one of `etags`, returning the old
+    /// value. `*` matches any current value, like in an `If-Match` header.
+    ///
+    /// Fails with [YwkvError::PreconditionFailed] without writing anything if the key is missing
+    /// or none of the tags match.
+    pub fn write_if_match<K: AsRef<str>, E: AsRef<str>, V: Into<Value>>(
+        &self,
+        key: K,
+        etags: &[E],
+        val: V,
+        ttl: Option<Duration>,
+    ) -> Result<Value, YwkvError> {
+        let key = key.as_ref();
+        let val = val.into();
+
+        let old_value = self.update(key, TtlUpdate::Set(ttl), |current| match current {
+            Some(v) => {
+                let etag = v.etag();
+                match etags
+                    .iter()
+                    .any(|e| e.as_ref() == "*" || e.as_ref() == etag)
+                {
+                    true => Ok(Cow::Borrowed(&val)),
+                    false => Err(YwkvError::PreconditionFailed(key.to_string())),
+                }
+            }
+            None => Err(YwkvError::PreconditionFailed(key.to_string())),
+        })?;
+
+        // The check above guarantees there was an old value
+        Ok(old_value.unwrap_or_else(|| Value::new(vec![], DEFAULT_CONTENT_TYPE)))
+    }
+
+    /// Write a value only if the key doesn't exist yet. Expired keys count as missing.
+    ///
+    /// Fails with [YwkvError::KeyExists] without writing anything if the key holds a value.
+    pub fn create<K: AsRef<str>, V: Into<Value>>(
+        &self,
+        key: K,
+        val: V,
+        ttl: Option<Duration>,
+    ) -> Result<(), YwkvError> {
+        let key = key.as_ref();
+        let val = val.into();
+
+        self.update(key, TtlUpdate::Set(ttl), |current| match current {
+            Some(_) => Err(YwkvError::KeyExists(key.to_string())),
+            None => Ok(Cow::Borrowed(&val)),
+        })?;
+
+        Ok(())
+    }
+
+    /// Add the data in `val` to the end of the value at `key`, returning the new length in bytes.
+    /// Missing keys are created with `val` as is, while existing keys keep their content type and
+    /// any TTL.
+    ///
+    /// Fails with [YwkvError::ValueTooLarge] without writing anything if the new value would be
+    /// longer than `max_len`.
+    pub fn append<K: AsRef<str>, V: Into<Value>>(
+        &self,
+        key: K,
+        val: V,
+        max_len: Option<usize>,
+    ) -> Result<usize, YwkvError> {
+        let key = key.as_ref();
+        let val = val.into();
+
+        let mut len = 0;
+        self.update(key, TtlUpdate::Keep, |current| {
+            let new_value = match current {
+                Some(v) => {
+                    let mut data = Vec::with_capacity(v.data.len() + val.data.len());
+                    data.extend_from_slice(&v.data);
+                    data.extend_from_slice(&val.data);
+
+                    Cow::Owned(Value::new(data, v.content_type.as_str()))
+                }
+                None => Cow::Borrowed(&val),
+            };
+
+            len = new_value.data.len();
+            match max_len {
+                Some(max) if len > max => Err(YwkvError::ValueTooLarge(key.to_string(), max)),
+                _ => Ok(new_value),
+            }
+        })?;
+
+        Ok(len)
+    }
+
+    /// Add `amount` to the integer stored at `key`, returning the new value. Missing keys start
+    /// at 0. Any existing TTL is kept.
+    pub fn increment<T: AsRef<str>>(&self, key: T, amount: i64) -> Result<i64, YwkvError> {
+        let key = key.as_ref();
+
+        let mut new_value = 0;
+        self.update(key, TtlUpdate::Keep, |current| {
+            let current = match current {
+                Some(v) => std::str::from_utf8(&v.data)
+                    .ok()
+                    .and_then(|v| v.trim().parse::<i64>().ok())
+                    .ok_or_else(|| YwkvError::NotAnInteger(key.to_string()))?,
+                None => 0,
+            };
+
+            new_value = current
+                .checked_add(amount)
+                .ok_or_else(|| YwkvError::IntegerOverflow(key.to_string()))?;
+
+            Ok(Cow::Owned(Value::from(new_value.to_string())))
+        })?;
+
+        Ok(new_value)
+    }
+
+    /// Add `item` to one end of the list stored at `key`, returning how many items it now holds.
+    /// Lists are stored as JSON arrays of strings. Missing keys start out as an empty list, and
+    /// any existing TTL is kept.
+    ///
+    /// Fails with [YwkvError::ValueTooLarge] without writing anything if the new value would be
+    /// longer than `max_len`.
+    pub fn push<K: AsRef<str>>(
+        &self,
+        key: K,
+        item: String,
+        end: ListEnd,
+        max_len: Option<usize>,
+    ) -> Result<usize, YwkvError> {
+        let key = key.as_ref();
+
+        let mut len = 0;
+        self.update(key, TtlUpdate::Keep, |current| {
+            let mut list = match current {
+                Some(v) => parse_list(key, v)?,
+                None => VecDeque::new(),
+            };
+            match end {
+                ListEnd::Front => list.push_front(item),
+                ListEnd::Back => list.push_back(item),
+            }
+            len = list.len();
+
+            let data = serde_json::to_vec(&list).expect("lists are always valid JSON");
+            match max_len {
+                Some(max) if data.len() > max => {
+                    Err(YwkvError::ValueTooLarge(key.to_string(), max))
+                }
+                _ => Ok(Cow::Owned(Value::new(data, JSON_CONTENT_TYPE))),
+            }
+        })?;
+
+        Ok(len)
+    }
+
+    /// Remove the item at one end of the list stored at `key` and return it. Any TTL is kept, and
+    /// the key is left holding an empty list once the last item is gone.
+    ///
+    /// Fails with [YwkvError::KeyMissing] or [YwkvError::EmptyList] without writing anything if
+    /// there's nothing to remove.
+    pub fn pop<K: AsRef<str>>(&self, key: K, end: ListEnd) -> Result<String, YwkvError> {
+        let key = key.as_ref();
+
+        let mut item = None;
+        self.update(key, TtlUpdate::Keep, |current| {
+            let mut list = match current {
+                Some(v) => parse_list(key, v)?,
+                None => return Err(YwkvError::KeyMissing(key.to_string())),
+            };
+            item = match end {
+                ListEnd::Front => list.pop_front(),
+                ListEnd::Back => list.pop_back(),
+            };
+            if item.is_none() {
+                return Err(YwkvError::EmptyList(key.to_string()));
+            }
+
+            let data = serde_json::to_vec(&list).expect("lists are always valid JSON");
+            Ok(Cow::Owned(Value::new(data, JSON_CONTENT_TYPE)))
+        })?;
+
+        Ok(item.unwrap_or_default())
+    }
+
+    /// How many items the list stored at `key` holds, or 0 if the key is missing.
+    pub fn list_len<K: AsRef<str>>(&self, key: K) -> Result<usize, YwkvError> {
+        let key = key.as_ref();
+
+        match self.read(key) {
+            Ok(v) => parse_list(key, &v).map(|v| v.len()),
+            Err(YwkvError::KeyMissing(_) | YwkvError::EmptyTable(_)) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Add `member` to the set stored at `key`, returning whether it wasn't already there. Sets
+    /// are stored as sorted JSON arrays of strings. Missing keys start out as an empty set, and
+    /// any existing TTL is kept. Nothing is written if `member` was already in the set.
+    ///
+    /// Fails with [YwkvError::ValueTooLarge] without writing anything if the new value would be
+    /// longer than `max_len`.
+    pub fn set_add<K: AsRef<str>>(
+        &self,
+        key: K,
+        member: String,
+        max_len: Option<usize>,
+    ) -> Result<bool, YwkvError> {
+        let key = key.as_ref();
+
+        let res = self.update_or_keep(key, TtlUpdate::Keep, |current| {
+            let mut set = match current {
+                Some(v) => parse_set(key, v)?,
+                None => BTreeSet::new(),
+            };
+            if !set.insert(member) {
+                return Ok(None);
+            }
+
+            let data = serde_json::to_vec(&set).expect("sets are always valid JSON");
+            match max_len {
+                Some(max) if data.len() > max => {
+                    Err(YwkvError::ValueTooLarge(key.to_string(), max))
+                }
+                _ => Ok(Some(Cow::Owned(Value::new(data, JSON_CONTENT_TYPE)))),
+            }
+        })?;
+
+        Ok(res.is_some())
+    }
+
+    /// Remove `member` from the set stored at `key`, returning whether it was there. Any TTL is
+    /// kept, and the key is left holding an empty set once the last member is gone. Nothing is
+    /// written if `member` wasn't in the set.
+    pub fn set_remove<K: AsRef<str>>(&self, key: K, member: &str) -> Result<bool, YwkvError> {
+        let key = key.as_ref();
+
+        let res = self.update_or_keep(key, TtlUpdate::Keep, |current| {
+            let mut set = match current {
+                Some(v) => parse_set(key, v)?,
+                None => BTreeSet::new(),
+            };
+            if !set.remove(member) {
+                return Ok(None);
+            }
+
+            let data = serde_json::to_vec(&set).expect("sets are always valid JSON");
+            Ok(Some(Cow::Owned(Value::new(data, JSON_CONTENT_TYPE))))
+        })?;
+
+        Ok(res.is_some())
+    }
+
+    /// Every member of the set stored at `key` in order, or none if the key is missing.
+    pub fn set_members<K: AsRef<str>>(&self, key: K) -> Result<BTreeSet<String>, YwkvError> {
+        let key = key.as_ref();
+
+        match self.read(key) {
+            Ok(v) => parse_set(key, &v),
+            Err(YwkvError::KeyMissing(_) | YwkvError::EmptyTable(_)) => Ok(BTreeSet::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `member` is in the set stored at `key`. Missing keys are empty sets.
+    pub fn set_contains<K: AsRef<str>>(&self, key: K, member: &str) -> Result<bool, YwkvError> {
+        self.set_members(key).map(|v| v.contains(member))
+    }
+
+    /// Apply a JSON Merge Patch ([RFC 7386](https://www.rfc-editor.org/rfc/rfc7386)) to the JSON
+    /// document stored at `key`, returning the patched document. Missing keys start out as
+    /// `null`, so the patch is stored as it is, and any existing TTL is kept.
+    ///
+    /// Fails with [YwkvError::NotJson] if the current value isn't JSON, or
+    /// [YwkvError::ValueTooLarge] if the patched document would be longer than `max_len`, without
+    /// writing anything.
+    pub fn merge_patch<K: AsRef<str>>(
+        &self,
+        key: K,
+        patch: &serde_json::Value,
+        max_len: Option<usize>,
+    ) -> Result<serde_json::Value, YwkvError> {
+        let key = key.as_ref();
+
+        let mut document = serde_json::Value::Null;
+        self.update(key, TtlUpdate::Keep, |current| {
+            if let Some(v) = current {
+                document = serde_json::from_slice(&v.data)
+                    .map_err(|_| YwkvError::NotJson(key.to_string()))?;
+            }
+            merge(&mut document, patch);
+
+            let data = serde_json::to_vec(&document).expect("JSON values always serialize");
+            match max_len {
+                Some(max) if data.len() > max => {
+                    Err(YwkvError::ValueTooLarge(key.to_string(), max))
+                }
+                _ => Ok(Cow::Owned(Value::new(data, JSON_CONTENT_TYPE))),
+            }
+        })?;
+
+        Ok(document)
+    }
+
+    /// Replace the value at `key` with the output of `update`, returning the old value. `update`
+    /// runs inside the write transaction, so nothing can change the value in between, and can
+    /// abort the write by returning an error. Expired values are passed in as `None`.
+    fn update<'v>(
+        &self,
+        key: &str,
+        ttl: TtlUpdate,
+        update: impl FnOnce(Option<&Value>) -> Result<Cow<'v, Value>, YwkvError>,
+    ) -> Result<Option<Value>, YwkvError> {
+        self.update_or_keep(key, ttl, |current| update(current).map(Some))
+            .map(Option::flatten)
+    }
+
+    /// Like [Db::update], but `update` can return `None` to leave the value as it is. Nothing is
+    /// committed then and the result is `None`, while a write returns `Some` with the old value.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    fn update_or_keep<'v>(
+        &self,
+        key: &str,
+        ttl: TtlUpdate,
+        update: impl FnOnce(Option<&Value>) -> Result<Option<Cow<'v, Value>>, YwkvError>,
+    ) -> Result<Option<Option<Value>>, YwkvError> {
+        self.check_size_quota()?;
+
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let (old_value, val) = {
+            let mut table = tx.open_table(self.definition())?;
+            let mut records = self.open_records(&tx)?;
+            let id = (self.table.as_str(), key);
+
+            let expired = self.is_expired(Some(&records.expiry), key)?;
+            let (current, old_hash) = match table.get(key)? {
+                Some(v) if !expired => {
+                    (Some(self.unseal(v.value())?), records.old_hash(v.value().1))
+                }
+                _ => (None, None),
+            };
+            let Some(val) = update(current.as_ref())? else {
+                return Ok(None);
+            };
+            self.check_json(&self.table, key, &val)?;
+
+            let expires_at = match ttl {
+                TtlUpdate::Set(ttl) => ttl.map(expiry_after),
+                TtlUpdate::Keep if expired => None,
+                TtlUpdate::Keep => expiry_of(&records.expiry, id)?,
+            };
+            let stored = (val.content_type.as_str(), &*self.seal(&val)?);
+            let created = table.insert(key, stored)?.is_none();
+            records.record_write(
+                id,
+                current.is_some(),
+                old_hash,
+                stored,
+                expires_at,
+                self.actor.as_deref(),
+            )?;
+            self.check_key_quota(&table, &records.expiry, &self.table, created)?;
+
+            (current, val)
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        self.publish(&self.table, key, Some(val), old_value.is_none());
+
+        Ok(Some(old_value))
+    }
+
+    /// Write to any tables in the database inside a single transaction, returning the old value
+    /// for each write in order. Later writes to the same key see the earlier ones.
+    ///
+    /// Either every write is committed or none of them are.
+    #[tracing::instrument(skip_all)]
+    pub fn write_tables(&self, writes: &[TableWrite]) -> Result<Vec<Option<Value>>, YwkvError> {
+        if let Some(write) = writes.iter().find(|v| !Self::is_valid_table(&v.table)) {
+            return Err(YwkvError::InvalidTable(write.table.clone()));
+        }
+        self.check_size_quota()?;
+
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let old_values = {
+            let mut records = self.open_records(&tx)?;
+
+            let mut old_values = vec![];
+            for write in writes {
+                let mut table = tx.open_table(ValueTable::new(&write.table))?;
+                let id = (write.table.as_str(), write.key.as_str());
+                self.check_json(&write.table, &write.key, &write.value)?;
+
+                let expired =
+                    matches!(expiry_of(&records.expiry, id)?, Some(v) if v <= now_millis());
+                let stored = (
+                    write.value.content_type.as_str(),
+                    &*self.seal(&write.value)?,
+                );
+                let (old_value, old_hash, created) =
+                    match table.insert(write.key.as_str(), stored)? {
+                        Some(v) if !expired => (
+                            Some(self.unseal(v.value())?),
+                            records.old_hash(v.value().1),
+                            false,
+                        ),
+                        Some(_) => (None, None, false),
+                        None => (None, None, true),
+                    };
+                let actor = write.actor.as_ref().or(self.actor.as_ref());
+                records.record_write(
+                    id,
+                    old_value.is_some(),
+                    old_hash,
+                    stored,
+                    write.ttl.map(expiry_after),
+                    actor.map(Arc::as_ref),
+                )?;
+                self.check_key_quota(&table, &records.expiry, &write.table, created)?;
+                old_values.push(old_value);
+            }
+
+            old_values
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for (write, old_value) in writes.iter().zip(&old_values) {
+            self.publish(
+                &write.table,
+                &write.key,
+                Some(Cow::Borrowed(&write.value)),
+                old_value.is_none(),
+            );
+        }
+
+        Ok(old_values)
+    }
+
+    /// Write every entry inside a single transaction, returning the old value for each key in order.
+    ///
+    /// Either every entry is committed or none of them are.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn write_many<K: AsRef<str>, V: Into<Value>>(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Vec<(K, Option<Value>)>, YwkvError> {
+        self.check_size_quota()?;
+
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let (old_values, changes) = {
+            let mut table = tx.open_table(self.definition())?;
+            let mut records = self.open_records(&tx)?;
+
+            let mut old_values = vec![];
+            let mut changes = vec![];
+            for (key, val) in entries {
+                let val = val.into();
+                let id = (self.table.as_str(), key.as_ref());
+                self.check_json(&self.table, key.as_ref(), &val)?;
+                let expired = self.is_expired(Some(&records.expiry), key.as_ref())?;
+
+                let stored = (val.content_type.as_str(), &*self.seal(&val)?);
+                let (old_value, old_hash, created) = match table.insert(key.as_ref(), stored)? {
+                    Some(v) if !expired => (
+                        Some(self.unseal(v.value())?),
+                        records.old_hash(v.value().1),
+                        false,
+                    ),
+                    Some(_) => (None, None, false),
+                    None => (None, None, true),
+                };
+                records.record_write(
+                    id,
+                    old_value.is_some(),
+                    old_hash,
+                    stored,
+                    None,
+                    self.actor.as_deref(),
+                )?;
+                self.check_key_quota(&table, &records.expiry, &self.table, created)?;
+                changes.push((key.as_ref().to_string(), val, old_value.is_none()));
+                old_values.push((key, old_value));
+            }
+
+            (old_values, changes)
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for (key, val, created) in changes {
+            self.publish(&self.table, &key, Some(Cow::Owned(val)), created);
+        }
+
+        Ok(old_values)
+    }
+
+    /// Run `f` inside a single write transaction on this table, committing everything it did if
+    /// it returns `Ok` and rolling all of it back if it returns an error.
+    ///
+    /// Other writes wait until `f` returns, so it should be quick.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&mut Transaction) -> Result<T, YwkvError>,
+    ) -> Result<T, YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let (res, changes) = {
+            let mut txn = Transaction {
+                db: self,
+                tx: &tx,
+                changes: vec![],
+            };
+            let res = f(&mut txn);
+
+            (res, txn.changes)
+        };
+
+        let value = match res {
+            Ok(v) => v,
+            Err(e) => {
+                tx.abort()?;
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for (key, val, created) in changes {
+            self.publish(&self.table, &key, val.map(Cow::Owned), created);
+        }
+
+        Ok(value)
+    }
+
+    /// Every recorded revision of `key`, oldest first. Revisions are kept after the key is deleted.
+    pub fn history<T: AsRef<str>>(&self, key: T) -> Result<Vec<Revision>, YwkvError> {
+        self.revisions(key.as_ref(), 0..=u64::MAX)
+    }
+
+    pub fn read_revision<T: AsRef<str>>(&self, key: T, rev: u64) -> Result<Revision, YwkvError> {
+        let key = key.as_ref();
+
+        match self.revisions(key, rev..=rev)?.pop() {
+            Some(v) => Ok(v),
+            None => Err(YwkvError::RevisionMissing(key.to_string(), rev)),
+        }
+    }
+
+    #[tracing::instrument(name = "history", skip_all, fields(table = %self.table))]
+    fn revisions(&self, key: &str, revs: RangeInclusive<u64>) -> Result<Vec<Revision>, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let history = match open_optional(tx.open_table(HISTORY_TABLE))? {
+            Some(v) => v,
+            None => return Ok(vec![]),
+        };
+
+        let table = self.table.as_str();
+        let mut revisions = vec![];
+        for entry in history.range((table, key, *revs.start())..=(table, key, *revs.end()))? {
+            let (id, stored) = entry?;
+            let (modified_at, content_type, data) = stored.value();
+            revisions.push(Revision {
+                rev: id.value().2,
+                modified_at,
+                value: self.unseal((content_type, data))?,
+            });
+        }
+
+        Ok(revisions)
+    }
+
+    /// Write the value from an earlier revision again, returning the value it replaced. The
+    /// rollback becomes the newest revision, and any TTL is cleared.
+    pub fn rollback<T: AsRef<str>>(&self, key: T, rev: u64) -> Result<Option<Value>, YwkvError> {
+        let revision = self.read_revision(key.as_ref(), rev)?;
+
+        self.write(key, revision.value)
+    }
+
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn delete<T: AsRef<str>>(&self, key: T) -> Result<Option<Value>, YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let old_value = {
+            let mut table = tx.open_table(self.definition())?;
+            let mut expiry = tx.open_table(EXPIRY_TABLE)?;
+            let mut audit = self.open_audit(&tx)?;
+            let mut changelog = self.open_changelog(&tx)?;
+
+            let expired = self.is_expired(Some(&expiry), key.as_ref())?;
+            expiry.remove((self.table.as_str(), key.as_ref()))?;
+            tx.open_table(META_TABLE)?
+                .remove((self.table.as_str(), key.as_ref()))?;
+
+            let res = table.remove(key.as_ref());
+            // Expired values are logged too, so they are removed everywhere the changelog is
+            // applied
+            if let (Some(log), Ok(Some(_))) = (&mut changelog, &res) {
+                let id = (self.table.as_str(), key.as_ref());
+                log_change(log, self.changelog, ChangeOp::Delete, id, None, None)?;
+            }
+            match res {
+                Ok(Some(_)) if expired => None,
+                Ok(Some(v)) => {
+                    if let Some(log) = &mut audit {
+                        audit_change(
+                            log,
+                            self.actor.as_deref(),
+                            AuditOp::Delete,
+                            (self.table.as_str(), key.as_ref()),
+                            Some(sha256(v.value().1)),
+                            None,
+                        )?;
+                    }
+
+                    Some(self.unseal(v.value())?)
+                }
+                Ok(None) => None,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        if old_value.is_some() {
+            self.publish(&self.table, key.as_ref(), None, false);
+        }
+
+        Ok(old_value)
+    }
+
+    /// Delete every key starting with `prefix` inside a single write transaction, returning how
+    /// many were deleted. Expired keys are removed too, but not counted. With `dry_run`, the
+    /// transaction is rolled back instead of committed, so the count is exact but nothing changes.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn delete_prefix<T: AsRef<str>>(&self, prefix: T, dry_run: bool) -> Result<u64, YwkvError> {
+        let prefix = prefix.as_ref();
+
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let deleted = self.remove_prefix(&tx, prefix)?;
+
+        if dry_run {
+            tx.abort()?;
+            return Ok(deleted.len() as u64);
+        }
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for key in &deleted {
+            self.publish(&self.table, key, None, false);
+        }
+
+        Ok(deleted.len() as u64)
+    }
+
+    /// Remove every key starting with `prefix` in `tx`, returning the ones that hadn't expired.
+    fn remove_prefix(&self, tx: &WriteTransaction, prefix: &str) -> Result<Vec<String>, YwkvError> {
+        let mut table = tx.open_table(self.definition())?;
+        let mut expiry = tx.open_table(EXPIRY_TABLE)?;
+        let mut meta = tx.open_table(META_TABLE)?;
+        let mut audit = self.open_audit(tx)?;
+        let mut changelog = self.open_changelog(tx)?;
+
+        let mut keys = vec![];
+        for entry in table.range::<&str>(prefix..)? {
+            let (key, _) = entry?;
+            if !key.value().starts_with(prefix) {
+                break;
+            }
+            keys.push(key.value().to_string());
+        }
+
+        let mut deleted = vec![];
+        for key in keys {
+            let id = (self.table.as_str(), key.as_str());
+            let expired = self.is_expired(Some(&expiry), &key)?;
+            expiry.remove(id)?;
+            meta.remove(id)?;
+
+            let Some(removed) = table.remove(key.as_str())? else {
+                continue;
+            };
+            // Expired values are logged too, so they are removed everywhere the changelog is
+            // applied
+            if let Some(log) = &mut changelog {
+                log_change(log, self.changelog, ChangeOp::Delete, id, None, None)?;
+            }
+            if expired {
+                continue;
+            }
+            if let Some(log) = &mut audit {
+                audit_change(
+                    log,
+                    self.actor.as_deref(),
+                    AuditOp::Delete,
+                    id,
+                    Some(sha256(removed.value().1)),
+                    None,
+                )?;
+            }
+            deleted.push(key);
+        }
+
+        Ok(deleted)
+    }
+
+    fn table_exists(&self, tx: &WriteTransaction) -> Result<bool, YwkvError> {
+        Ok(tx.list_tables()?.any(|v| v.name() == self.table))
+    }
+
+    /// Create this handle's table, which otherwise happens on its first write. Fails with
+    /// [YwkvError::TableExists] if it already exists.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn create_table(&self) -> Result<(), YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        if self.table_exists(&tx)? {
+            return Err(YwkvError::TableExists(self.table.clone()));
+        }
+        tx.open_table(self.definition())?;
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Delete every key in this handle's table, returning how many were deleted like
+    /// [Db::delete_prefix]. The table itself is kept. Fails with [YwkvError::TableMissing] if it
+    /// doesn't exist.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn truncate(&self) -> Result<u64, YwkvError> {
+        self.remove_table(false)
+    }
+
+    /// Delete every key in this handle's table and then the table, returning how many keys were
+    /// deleted like [Db::delete_prefix]. History of the keys is kept. Fails with
+    /// [YwkvError::TableMissing] if the table doesn't exist.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn drop_table(&self) -> Result<u64, YwkvError> {
+        self.remove_table(true)
+    }
+
+    fn remove_table(&self, drop: bool) -> Result<u64, YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        if !self.table_exists(&tx)? {
+            return Err(YwkvError::TableMissing(self.table.clone()));
+        }
+        // Keys are removed one at a time first, so expiry, metadata, the changelog and the audit
+        // log are updated just like when they are deleted
+        let deleted = self.remove_prefix(&tx, "")?;
+        if drop {
+            tx.delete_table(self.definition())?;
+        }
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for key in &deleted {
+            self.publish(&self.table, key, None, false);
+        }
+
+        Ok(deleted.len() as u64)
+    }
+
+    /// Remove every expired key from every table, returning how many keys were removed.
+    #[tracing::instrument(skip_all)]
+    pub fn purge_expired(&self) -> Result<u64, YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let purged = {
+            let mut expiry = tx.open_table(EXPIRY_TABLE)?;
+
+            let now = now_millis();
+            let mut expired: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for entry in
+                expiry.drain_filter::<(&str, &str), _>(.., |_, expires_at| expires_at <= now)?
+            {
+                let (key, _) = entry?;
+                let (table_name, key) = key.value();
+                expired
+                    .entry(table_name.to_string())
+                    .or_default()
+                    .push(key.to_string());
+            }
+
+            if tx.list_tables()?.any(|v| v.name() == LEASE_TABLE.name()) {
+                tx.open_table(LEASE_TABLE)?
+                    .drain_filter::<(&str, &str), _>(.., |_, (_, expires_at, _)| {
+                        expires_at <= now
+                    })?;
+            }
+
+            let mut meta = tx.open_table(META_TABLE)?;
+            let mut changelog = self.open_changelog(&tx)?;
+            let mut purged = vec![];
+            for (table_name, keys) in expired {
+                let mut table = tx.open_table(ValueTable::new(&table_name))?;
+                for key in keys {
+                    let id = (table_name.as_str(), key.as_str());
+                    meta.remove(id)?;
+                    if table.remove(key.as_str())?.is_some() {
+                        if let Some(log) = &mut changelog {
+                            log_change(log, self.changelog, ChangeOp::Delete, id, None, None)?;
+                        }
+                        purged.push((table_name.clone(), key));
+                    }
+                }
+            }
+
+            purged
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        for (table_name, key) in &purged {
+            self.publish(table_name, key, None, false);
+        }
+
+        Ok(purged.len() as u64)
+    }
+
+    /// Take the lease on `name` in this table for `ttl` if nobody holds it, or renew it if `token`
+    /// is its fencing token. Expired leases are free to take, but can't be renewed.
+    ///
+    /// Leases are kept apart from keys, so writes to the table can't change them, and they
+    /// aren't sent to subscribers or replicas.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn acquire_lease(
+        &self,
+        name: &str,
+        owner: Option<&str>,
+        ttl: Duration,
+        token: Option<u64>,
+    ) -> Result<LeaseOutcome, YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let outcome = {
+            let mut leases = tx.open_table(LEASE_TABLE)?;
+            let id = (self.table.as_str(), name);
+
+            let outcome = match (live_lease(&leases, id)?, token) {
+                (Some(current), Some(token)) if current.token == token => {
+                    LeaseOutcome::Renewed(Lease {
+                        expires_at: expiry_after(ttl),
+                        ..current
+                    })
+                }
+                (Some(current), _) => return Ok(LeaseOutcome::Held(current)),
+                (None, Some(_)) => return Ok(LeaseOutcome::Missing),
+                (None, None) => {
+                    let mut fencing = tx.open_table(FENCING_TABLE)?;
+                    let last = fencing.get(self.table.as_str())?.map(|v| v.value());
+                    let token = last.unwrap_or_default() + 1;
+                    fencing.insert(self.table.as_str(), token)?;
+
+                    LeaseOutcome::Acquired(Lease {
+                        token,
+                        owner: owner.filter(|v| !v.is_empty()).map(str::to_string),
+                        expires_at: expiry_after(ttl),
+                    })
+                }
+            };
+            if let LeaseOutcome::Acquired(lease) | LeaseOutcome::Renewed(lease) = &outcome {
+                let owner = lease.owner.as_deref().unwrap_or_default();
+                leases.insert(id, (lease.token, lease.expires_at, owner))?;
+            }
+
+            outcome
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        Ok(outcome)
+    }
+
+    /// Give up the lease on `name` in this table if `token` is its fencing token, so it can be
+    /// taken again before it expires.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn release_lease(&self, name: &str, token: u64) -> Result<LeaseOutcome, YwkvError> {
+        let database = self.shared_database();
+        let tx = self.begin_write(&database)?;
+
+        let lease = {
+            let mut leases = tx.open_table(LEASE_TABLE)?;
+            let id = (self.table.as_str(), name);
+
+            match live_lease(&leases, id)? {
+                Some(current) if current.token == token => {
+                    leases.remove(id)?;
+                    current
+                }
+                Some(current) => return Ok(LeaseOutcome::Held(current)),
+                None => return Ok(LeaseOutcome::Missing),
+            }
+        };
+
+        if let Err(e) = self.commit(tx) {
+            return Err(e.into());
+        }
+
+        Ok(LeaseOutcome::Released(lease))
+    }
+
+    /// List up to `limit` keys in order, starting after `cursor` if provided.
+    pub fn list_keys(&self, cursor: Option<&str>, limit: usize) -> Result<KeyPage, YwkvError> {
+        let start = match cursor {
+            Some(v) => Bound::Excluded(v),
+            None => Bound::Unbounded,
+        };
+
+        // Grab one extra key to find out if there is another page without a second lookup
+        let mut keys = self
+            .scan_while(start, Bound::Unbounded, Some(limit + 1), |_| true)?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+
+        let cursor = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().cloned()
+        } else {
+            None
+        };
+
+        Ok(KeyPage { keys, cursor })
+    }
+
+    /// Get up to `limit` entries in order with keys between `start` and `end`.
+    pub fn scan(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Entry>, YwkvError> {
+        self.scan_while(start, end, limit, |_| true)
+    }
+
+    /// Get up to `limit` entries in order with keys starting with `prefix`.
+    pub fn scan_prefix<T: AsRef<str>>(
+        &self,
+        prefix: T,
+        limit: Option<usize>,
+    ) -> Result<Vec<Entry>, YwkvError> {
+        let prefix = prefix.as_ref();
+
+        self.scan_while(Bound::Included(prefix), Bound::Unbounded, limit, |key| {
+            key.starts_with(prefix)
+        })
+    }
+
+    /// Every entry in key order. See [Iter] for what is seen when writing while iterating.
+    pub fn iter(&self) -> Iter {
+        Iter::new(self.clone(), Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Entries in key order with keys in `range`, like `db.range("a".."n")`.
+    pub fn range<'k>(&self, range: impl RangeBounds<&'k str>) -> Iter {
+        let owned = |bound: Bound<&&str>| bound.map(|v| v.to_string());
+
+        Iter::new(
+            self.clone(),
+            owned(range.start_bound()),
+            owned(range.end_bound()),
+        )
+    }
+
+    /// Every key in order.
+    pub fn keys(&self) -> Keys {
+        Keys(self.iter())
+    }
+
+    /// Call `visit` with every key in order along with the time left until it expires, all from a
+    /// single read transaction. Stops early once `visit` returns `false`.
+    #[tracing::instrument(skip_all, fields(table = %self.table))]
+    pub fn for_each_entry(
+        &self,
+        mut visit: impl FnMut(Entry, Option<Duration>) -> bool,
+    ) -> Result<(), YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        let table = match open_optional(tx.open_table(self.definition()))? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let expiry = open_optional(tx.open_table(EXPIRY_TABLE))?;
+
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+
+            let ttl = match self.expires_at(expiry.as_ref(), key.value())? {
+                Some(expires_at) => match expires_at.checked_sub(now_millis()) {
+                    Some(v) if v > 0 => Some(Duration::from_millis(v)),
+                    _ => continue,
+                },
+                None => None,
+            };
+
+            let entry = Entry {
+                key: key.value().to_string(),
+                value: self.unseal(value.value())?,
+            };
+            if !visit(entry, ttl) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keys are sorted, so the scan stops at the first key that fails `predicate`. Expired keys
+    /// are skipped and do not count towards `limit`.
+    #[tracing::instrument(name = "scan", skip_all, fields(table = %self.table))]
+    fn scan_while(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: Option<usize>,
+        predicate: impl Fn(&str) -> bool,
+    ) -> Result<Vec<Entry>, YwkvError> {
+        let database = self.shared_database();
+        let tx = database.begin_read()?;
+
+        self.scan_while_in(&tx, start, end, limit, predicate)
+    }
+
+    fn scan_while_in(
+        &self,
+        tx: &ReadTransaction,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: Option<usize>,
+        predicate: impl Fn(&str) -> bool,
+    ) -> Result<Vec<Entry>, YwkvError> {
+        let table = match tx.open_table(self.definition()) {
+            Ok(v) => v,
+            Err(redb::Error::TableDoesNotExist(_)) => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let expiry = open_optional(tx.open_table(EXPIRY_TABLE))?;
+
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut entries = vec![];
+        for entry in table.range::<&str>((start, end))? {
+            if entries.len() >= limit {
+                break;
+            }
+
+            let (key, value) = entry?;
+            if !predicate(key.value()) {
+                break;
+            }
+            if self.is_expired(expiry.as_ref(), key.value())? {
+                continue;
+            }
+
+            entries.push(Entry {
+                key: key.value().to_string(),
+                value: self.unseal(value.value())?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn expires_at(
+        &self,
+        expiry: Option<&impl ReadableTable<(&'static str, &'static str), u64>>,
+        key: &str,
+    ) -> Result<Option<u64>, YwkvError> {
+        let expiry = match expiry {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        Ok(expiry
+            .get((self.table.as_str(), key))?
+            .map(|expires_at| expires_at.value()))
+    }
+
+    fn is_expired(
+        &self,
+        expiry: Option<&impl ReadableTable<(&'static str, &'static str), u64>>,
+        key: &str,
+    ) -> Result<bool, YwkvError> {
+        Ok(matches!(self.expires_at(expiry, key)?, Some(v) if v <= now_millis()))
+    }
+}
+
+/// Reads and writes inside [Db::transaction]. Reads see the writes made earlier in the same
+/// transaction.
+pub struct Transaction<'db, 'txn> {
+    db: &'txn Db,
+    tx: &'txn WriteTransaction<'db>,
+    /// Published once the transaction commits. `None` is a delete, and the flag is whether the
+    /// key was created.
+    changes: Vec<(String, Option<Value>, bool)>,
+}
+
+impl Transaction<'_, '_> {
+    pub fn read<T: AsRef<str>>(&self, key: T) -> Result<Value, YwkvError> {
+        let key = key.as_ref();
+        let table = self.tx.open_table(self.db.definition())?;
+        let expiry = self.tx.open_table(EXPIRY_TABLE)?;
+
+        if self.db.is_expired(Some(&expiry), key)? {
+            return Err(YwkvError::KeyMissing(key.to_string()));
+        }
+
+        let val = table.get(key);
+        match val {
+            Ok(Some(value)) => self.db.unseal(value.value()),
+            Ok(None) => Err(YwkvError::KeyMissing(key.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn write<K: AsRef<str>, V: Into<Value>>(
+        &mut self,
+        key: K,
+        val: V,
+    ) -> Result<Option<Value>, YwkvError> {
+        self.write_with_ttl(key, val, None)
+    }
+
+    /// Write a value that expires after `ttl`, returning the old value. Writing without a TTL
+    /// clears any existing one.
+    pub fn write_with_ttl<K: AsRef<str>, V: Into<Value>>(
+        &mut self,
+        key: K,
+        val: V,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, YwkvError> {
+        let (db, tx) = (self.db, self.tx);
+        let key = key.as_ref();
+        let val = val.into();
+        let id = (db.table.as_str(), key);
+        db.check_size_quota()?;
+        db.check_json(&db.table, key, &val)?;
+
+        let mut table = tx.open_table(db.definition())?;
+        let mut records = db.open_records(tx)?;
+
+        let expired = db.is_expired(Some(&records.expiry), key)?;
+        let stored = (val.content_type.as_str(), &*db.seal(&val)?);
+        let (old_value, old_hash, created) = match table.insert(key, stored)? {
+            Some(v) if !expired => (
+                Some(db.unseal(v.value())?),
+                records.old_hash(v.value().1),
+                false,
+            ),
+            Some(_) => (None, None, false),
+            None => (None, None, true),
+        };
+        records.record_write(
+            id,
+            old_value.is_some(),
+            old_hash,
+            stored,
+            ttl.map(expiry_after),
+            db.actor.as_deref(),
+        )?;
+        db.check_key_quota(&table, &records.expiry, &db.table, created)?;
+
+        self.changes
+            .push((key.to_string(), Some(val), old_value.is_none()));
+
+        Ok(old_value)
+    }
+
+    /// Delete a key, returning its old value.
+    pub fn delete<T: AsRef<str>>(&mut self, key: T) -> Result<Option<Value>, YwkvError> {
+        let (db, tx) = (self.db, self.tx);
+        let key = key.as_ref();
+        let id = (db.table.as_str(), key);
+
+        let mut table = tx.open_table(db.definition())?;
+        let mut expiry = tx.open_table(EXPIRY_TABLE)?;
+        let mut audit = db.open_audit(tx)?;
+        let mut changelog = db.open_changelog(tx)?;
+
+        let expired = db.is_expired(Some(&expiry), key)?;
+        expiry.remove(id)?;
+        tx.open_table(META_TABLE)?.remove(id)?;
+
+        let removed = table.remove(key)?;
+        // Expired values are logged too, so they are removed everywhere the changelog is applied
+        if let (Some(log), Some(_)) = (&mut changelog, &removed) {
+            log_change(log, db.changelog, ChangeOp::Delete, id, None, None)?;
+        }
+        let old_value = match removed {
+            Some(_) if expired => None,
+            Some(v) => {
+                if let Some(log) = &mut audit {
+                    audit_change(
+                        log,
+                        db.actor.as_deref(),
+                        AuditOp::Delete,
+                        id,
+                        Some(sha256(v.value().1)),
+                        None,
+                    )?;
+                }
+
+                Some(db.unseal(v.value())?)
+            }
+            None => None,
+        };
+
+        if old_value.is_some() {
+            self.changes.push((key.to_string(), None, false));
+        }
+
+        Ok(old_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn db() -> Db {
+        Db::builder().open_temporary("main").unwrap()
+    }
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::new(serde_json::to_vec(&value).unwrap(), JSON_CONTENT_TYPE)
+    }
+
+    #[test]
+    fn merge_patch_removes_null_members() {
+        let db = db();
+        db.write("doc", json(json!({"a": 1, "b": {"c": 2, "d": 3}})))
+            .unwrap();
+
+        let patched = db
+            .merge_patch("doc", &json!({"b": {"c": null}, "e": [4]}), None)
+            .unwrap();
+
+        assert_eq!(patched, json!({"a": 1, "b": {"d": 3}, "e": [4]}));
+        assert_eq!(
+            db.read("doc").unwrap().data,
+            serde_json::to_vec(&patched).unwrap()
+        );
+    }
+
+    #[test]
+    fn merge_patch_replaces_with_non_object_patch() {
+        let db = db();
+        db.write("doc", json(json!({"a": 1}))).unwrap();
+
+        assert_eq!(
+            db.merge_patch("doc", &json!([1, 2]), None).unwrap(),
+            json!([1, 2])
+        );
+        assert_eq!(
+            db.merge_patch("doc", &json!({"a": null, "b": 1}), None)
+                .unwrap(),
+            json!({"b": 1})
+        );
+    }
+
+    #[test]
+    fn merge_patch_starts_missing_keys_from_null() {
+        let db = db();
+
+        let patched = db
+            .merge_patch("doc", &json!({"a": null, "b": 1}), None)
+            .unwrap();
+
+        assert_eq!(patched, json!({"b": 1}));
+    }
+
+    #[test]
+    fn merge_patch_rejects_non_json_and_too_large_documents() {
+        let db = db();
+        db.write("text", "not json").unwrap();
+        db.write("doc", json(json!({"a": 1}))).unwrap();
+
+        assert!(matches!(
+            db.merge_patch("text", &json!({"a": 1}), None),
+            Err(YwkvError::NotJson(_))
+        ));
+        assert!(matches!(
+            db.merge_patch("doc", &json!({"b": "long"}), Some(8)),
+            Err(YwkvError::ValueTooLarge(_, 8))
+        ));
+        assert_eq!(db.read("doc").unwrap().data, br#"{"a":1}"#);
+    }
+
+    #[test]
+    fn write_if_match_compares_etags() {
+        let db = db();
+        db.write("k", "a").unwrap();
+        let etag = db.read("k").unwrap().etag();
+
+        assert!(matches!(
+            db.write_if_match("k", &["\"nope\""], "b", None),
+            Err(YwkvError::PreconditionFailed(_))
+        ));
+        assert_eq!(
+            db.write_if_match("k", &[etag.as_str()], "b", None)
+                .unwrap()
+                .data,
+            b"a"
+        );
+        // The tag changed with the value
+        assert!(db.write_if_match("k", &[etag.as_str()], "c", None).is_err());
+        assert_eq!(
+            db.write_if_match("k", &["*"], "c", None).unwrap().data,
+            b"b"
+        );
+        assert!(matches!(
+            db.write_if_match("missing", &["*"], "a", None),
+            Err(YwkvError::PreconditionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn create_only_writes_missing_or_expired_keys() {
+        let db = db();
+
+        db.create("k", "a", None).unwrap();
+        assert!(matches!(
+            db.create("k", "b", None),
+            Err(YwkvError::KeyExists(_))
+        ));
+        assert_eq!(db.read("k").unwrap().data, b"a");
+
+        db.create("short", "a", Some(Duration::from_millis(1)))
+            .unwrap();
+        sleep(Duration::from_millis(5));
+        db.create("short", "b", None).unwrap();
+        assert_eq!(db.read("short").unwrap().data, b"b");
+    }
+
+    #[test]
+    fn set_membership_only_writes_changes() {
+        let mut db = db();
+        db.keep_history(10);
+
+        assert!(db.set_add("s", "b".to_string(), None).unwrap());
+        assert!(db.set_add("s", "a".to_string(), None).unwrap());
+        assert!(!db.set_add("s", "a".to_string(), None).unwrap());
+        assert_eq!(db.read("s").unwrap().data, br#"["a","b"]"#);
+
+        assert!(db.set_remove("s", "b").unwrap());
+        assert!(!db.set_remove("s", "b").unwrap());
+        assert!(db.set_contains("s", "a").unwrap());
+        assert!(!db.set_contains("s", "b").unwrap());
+        assert_eq!(db.history("s").unwrap().len(), 3);
+
+        assert!(!db.set_remove("missing", "a").unwrap());
+        assert!(db.set_members("missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn set_operations_reject_other_values() {
+        let db = db();
+        db.write("k", "not a set").unwrap();
+
+        assert!(matches!(
+            db.set_add("k", "a".to_string(), None),
+            Err(YwkvError::NotASet(_))
+        ));
+        assert!(matches!(
+            db.set_remove("k", "a"),
+            Err(YwkvError::NotASet(_))
+        ));
+        assert!(matches!(db.set_members("k"), Err(YwkvError::NotASet(_))));
+    }
+
+    #[test]
+    fn key_quota_skips_expired_keys() {
+        let mut db = db();
+        db.set_quota(Quota {
+            max_keys: Some(1),
+            ..Quota::default()
+        });
+
+        db.write_with_ttl("a", "1", Some(Duration::from_millis(1)))
+            .unwrap();
+        sleep(Duration::from_millis(5));
+        db.write("b", "2").unwrap();
+
+        assert!(matches!(
+            db.write("c", "3"),
+            Err(YwkvError::KeyQuotaExceeded(_, 1))
+        ));
+        // Overwriting doesn't create a key
+        db.write("b", "3").unwrap();
     }
 }
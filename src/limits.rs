@@ -0,0 +1,98 @@
+//! Limits on the keys and values clients may write.
+
+use std::ops::RangeInclusive;
+
+use axum::{http::StatusCode, Json};
+use ywkv::Response;
+
+pub struct Limits {
+    pub max_value_size: usize,
+    max_key_length: Option<usize>,
+    key_chars: Option<Vec<RangeInclusive<char>>>,
+}
+
+impl Limits {
+    /// `key_chars` lists the characters keys may use, with ranges written like `a-z`. A `-` at the
+    /// start or end is taken literally.
+    pub fn new(
+        max_value_size: usize,
+        max_key_length: Option<usize>,
+        key_chars: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            max_value_size,
+            max_key_length,
+            key_chars: key_chars.map(parse_chars).transpose()?,
+        })
+    }
+
+    pub fn check_key(&self, key: &str) -> Result<(), (StatusCode, Json<Response>)> {
+        let error = |message| {
+            Err((
+                StatusCode::BAD_REQUEST,
+                Json::from(Response::new(
+                    message,
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                )),
+            ))
+        };
+
+        if let Some(max) = self.max_key_length {
+            if key.len() > max {
+                return error(format!("key is longer than {max} bytes"));
+            }
+        }
+        if let Some(key_chars) = &self.key_chars {
+            if let Some(c) = key
+                .chars()
+                .find(|c| !key_chars.iter().any(|v| v.contains(c)))
+            {
+                return error(format!("key contains the disallowed character `{c}`"));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_value(&self, value: &[u8]) -> Result<(), (StatusCode, Json<Response>)> {
+        if value.len() > self.max_value_size {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json::from(Response::new(
+                    format!("value is larger than {} bytes", self.max_value_size),
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                )),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_chars(spec: &str) -> anyhow::Result<Vec<RangeInclusive<char>>> {
+    if spec.is_empty() {
+        anyhow::bail!("the allowed key characters cannot be empty");
+    }
+
+    let chars = spec.chars().collect::<Vec<_>>();
+    let mut ranges = Vec::new();
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars.get(i..i + 3) {
+            Some(&[start, '-', end]) => {
+                if start > end {
+                    anyhow::bail!("invalid key character range `{start}-{end}`");
+                }
+                ranges.push(start..=end);
+                i += 3;
+            }
+            _ => {
+                ranges.push(chars[i]..=chars[i]);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(ranges)
+}
@@ -0,0 +1,337 @@
+//! Leases on a name that one client holds at a time, kept by [ywkv::Db::acquire_lease] apart from
+//! the table's keys.
+//!
+//! A lease expires on its own if the holder stops renewing it. Every lease gets a fencing token
+//! that only ever counts up within the table, so a holder whose lease expired can be told apart
+//! from the next one.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use ywkv::{LeaseOutcome, Response};
+
+use crate::{blocking, limits::Limits, Table};
+
+const DEFAULT_TTL: u64 = 30;
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Lease {
+    /// Larger than the token of every lease on the name before this one. Pass it to whatever
+    /// the lock protects, so it can turn away holders whose lease has since expired.
+    token: u64,
+    owner: Option<String>,
+    /// Seconds until the lease expires unless it's renewed
+    ttl: u64,
+}
+
+impl From<ywkv::Lease> for Lease {
+    fn from(lease: ywkv::Lease) -> Self {
+        Self {
+            token: lease.token,
+            owner: lease.owner,
+            ttl: lease
+                .expires_at
+                .saturating_sub(ywkv::now_millis())
+                .div_ceil(1000),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LockPath {
+    name: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AcquireQuery {
+    /// Seconds until the lease expires unless it's renewed. Defaults to 30.
+    ttl: Option<u64>,
+    /// Who is taking the lease, reported to anyone else trying to take it
+    owner: Option<String>,
+    /// Renew the lease with this fencing token instead of taking a new one
+    token: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReleaseQuery {
+    /// The fencing token of the lease being released
+    token: u64,
+}
+
+/// Take the lease on a name if nobody holds it, or renew it with the lease's fencing token.
+/// Expired leases are free to take.
+#[utoipa::path(
+    post,
+    path = "/_lock/{name}",
+    tag = "values",
+    params(("name" = String, Path), AcquireQuery),
+    responses(
+        (status = 200, description = "The lease was renewed", body = Response<Lease>),
+        (status = 201, description = "The lease was taken", body = Response<Lease>),
+        (status = 400, description = "The name or TTL isn't allowed", body = Response<String>),
+        (status = 404, description = "There's no lease with the given token to renew", body = Response<String>),
+        (status = 409, description = "Someone else holds the lease", body = Response<String>),
+    )
+)]
+pub async fn acquire(
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    Path(LockPath { name }): Path<LockPath>,
+    Query(query): Query<AcquireQuery>,
+) -> Result<(StatusCode, Json<Response<Lease>>), (StatusCode, Json<Response>)> {
+    limits.check_key(&name)?;
+    let ttl = query.ttl.unwrap_or(DEFAULT_TTL);
+    if ttl == 0 {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            "ttl must be at least 1 second".to_string(),
+        ));
+    }
+
+    let res = blocking({
+        let name = name.clone();
+        move || {
+            db.acquire_lease(
+                &name,
+                query.owner.as_deref(),
+                Duration::from_secs(ttl),
+                query.token,
+            )
+        }
+    })
+    .await;
+
+    match res {
+        Ok(LeaseOutcome::Acquired(lease)) => Ok((
+            StatusCode::CREATED,
+            Json::from(Response::new(
+                lease.into(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+            )),
+        )),
+        Ok(LeaseOutcome::Renewed(lease)) => Ok((
+            StatusCode::OK,
+            Json::from(Response::new(
+                lease.into(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        )),
+        Ok(outcome) => Err(rejected(&name, outcome)),
+        Err(e) => Err(Response::from_write_error(e)),
+    }
+}
+
+/// Give up the lease on a name, so it can be taken again before it expires.
+#[utoipa::path(
+    delete,
+    path = "/_lock/{name}",
+    tag = "values",
+    params(("name" = String, Path), ReleaseQuery),
+    responses(
+        (status = 200, description = "The lease was released", body = Response<Lease>),
+        (status = 404, description = "Nobody holds the lease", body = Response<String>),
+        (status = 409, description = "Someone else holds the lease", body = Response<String>),
+    )
+)]
+pub async fn release(
+    Table(db): Table,
+    Path(LockPath { name }): Path<LockPath>,
+    Query(query): Query<ReleaseQuery>,
+) -> Result<Json<Response<Lease>>, (StatusCode, Json<Response>)> {
+    let res = blocking({
+        let name = name.clone();
+        move || db.release_lease(&name, query.token)
+    })
+    .await;
+
+    match res {
+        Ok(LeaseOutcome::Released(lease)) => Ok(Json::from(Response::new(
+            lease.into(),
+            ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+        ))),
+        Ok(outcome) => Err(rejected(&name, outcome)),
+        Err(e) => Err(Response::from_write_error(e)),
+    }
+}
+
+/// The error response for anything but the lease changing as asked.
+fn rejected(name: &str, outcome: LeaseOutcome) -> (StatusCode, Json<Response>) {
+    match outcome {
+        LeaseOutcome::Held(ywkv::Lease {
+            owner: Some(owner), ..
+        }) => error(
+            StatusCode::CONFLICT,
+            format!("lock `{name}` is held by `{owner}`"),
+        ),
+        LeaseOutcome::Held(_) => error(StatusCode::CONFLICT, format!("lock `{name}` is held")),
+        _ => (
+            StatusCode::NOT_FOUND,
+            Json::from(Response::new(
+                format!("lock `{name}` isn't held with that token"),
+                ywkv::Status::Write(ywkv::WriteStatus::Missing),
+            )),
+        ),
+    }
+}
+
+fn error(status: StatusCode, message: String) -> (StatusCode, Json<Response>) {
+    (
+        status,
+        Json::from(Response::new(
+            message,
+            ywkv::Status::Write(ywkv::WriteStatus::Failure),
+        )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ywkv::Db;
+
+    use super::*;
+
+    fn db() -> Db {
+        Db::builder().open_temporary("main").unwrap()
+    }
+
+    async fn take(
+        db: &Db,
+        ttl: Option<u64>,
+        owner: Option<&str>,
+        token: Option<u64>,
+    ) -> Result<(StatusCode, Json<Response<Lease>>), (StatusCode, Json<Response>)> {
+        acquire(
+            Table(db.clone()),
+            State(Arc::new(Limits::new(1024, None, None).unwrap())),
+            Path(LockPath {
+                name: "job".to_string(),
+            }),
+            Query(AcquireQuery {
+                ttl,
+                owner: owner.map(str::to_string),
+                token,
+            }),
+        )
+        .await
+    }
+
+    async fn give_up(
+        db: &Db,
+        token: u64,
+    ) -> Result<Json<Response<Lease>>, (StatusCode, Json<Response>)> {
+        release(
+            Table(db.clone()),
+            Path(LockPath {
+                name: "job".to_string(),
+            }),
+            Query(ReleaseQuery { token }),
+        )
+        .await
+    }
+
+    fn status_of<T>(res: Result<(StatusCode, T), (StatusCode, Json<Response>)>) -> StatusCode {
+        match res {
+            Ok((status, _)) | Err((status, _)) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn acquire_holds_the_lease_until_released() {
+        let db = db();
+
+        let (status, Json(lease)) = take(&db, Some(60), Some("w1"), None).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(lease.value().token, 1);
+        assert_eq!(lease.value().owner.as_deref(), Some("w1"));
+        assert_eq!(lease.value().ttl, 60);
+
+        let (status, Json(held)) = take(&db, None, None, None).await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(held.value(), "lock `job` is held by `w1`");
+
+        assert_eq!(give_up(&db, 2).await.unwrap_err().0, StatusCode::CONFLICT);
+        assert_eq!(give_up(&db, 1).await.unwrap().value().token, 1);
+        assert_eq!(give_up(&db, 1).await.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        // Tokens keep counting up after a release
+        let (_, Json(lease)) = take(&db, None, None, None).await.unwrap();
+        assert_eq!(lease.value().token, 2);
+        assert_eq!(lease.value().ttl, DEFAULT_TTL);
+    }
+
+    #[tokio::test]
+    async fn renewing_needs_the_current_token() {
+        let db = db();
+        assert_eq!(
+            status_of(take(&db, Some(60), None, None).await),
+            StatusCode::CREATED
+        );
+
+        let (status, Json(lease)) = take(&db, Some(120), None, Some(1)).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(lease.value().token, 1);
+        assert_eq!(lease.value().ttl, 120);
+
+        assert_eq!(
+            status_of(take(&db, Some(60), None, Some(2)).await),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_leases_can_be_taken_but_not_renewed() {
+        let db = db();
+        assert_eq!(
+            status_of(take(&db, Some(1), None, None).await),
+            StatusCode::CREATED
+        );
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        assert_eq!(
+            status_of(take(&db, None, None, Some(1)).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(give_up(&db, 1).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        let (_, Json(lease)) = take(&db, None, None, None).await.unwrap();
+        assert_eq!(lease.value().token, 2);
+    }
+
+    #[tokio::test]
+    async fn keys_in_the_table_dont_touch_leases() {
+        let db = db();
+        assert_eq!(
+            status_of(take(&db, Some(60), None, None).await),
+            StatusCode::CREATED
+        );
+
+        db.write("_lock/job", "taken").unwrap();
+        db.write("_lock", "notanumber").unwrap();
+        db.delete("_lock").unwrap();
+
+        assert_eq!(
+            status_of(take(&db, None, None, None).await),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(give_up(&db, 1).await.unwrap().value().token, 1);
+        let (_, Json(lease)) = take(&db, None, None, None).await.unwrap();
+        assert_eq!(lease.value().token, 2);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_is_rejected() {
+        let db = db();
+
+        assert_eq!(
+            status_of(take(&db, Some(0), None, None).await),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Logging through `tracing`, with a span for every request. Spans are also exported over OTLP
+//! when an OpenTelemetry endpoint is configured.
+
+use std::{io::IsTerminal, sync::OnceLock, time::Instant};
+
+use anyhow::Context;
+use axum::{
+    extract::{MatchedPath, OriginalUri},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use opentelemetry::{propagation::Extractor, Key, KeyValue};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::Tracer, Resource};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload, EnvFilter, Registry};
+
+use crate::request_id::RequestId;
+
+/// Swaps the log filter when the configuration is reloaded.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Log at `level`, written as `tracing` filter directives like `info` or `ywkv=debug,warn`. The
+/// `RUST_LOG` environment variable takes precedence when it is set.
+pub fn filter(level: &str) -> anyhow::Result<EnvFilter> {
+    match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(v) => EnvFilter::try_new(&v).with_context(|| format!("invalid `RUST_LOG` `{v}`")),
+        Err(_) => EnvFilter::try_new(level).with_context(|| format!("invalid log level `{level}`")),
+    }
+}
+
+pub fn init(level: &str, json: bool) -> anyhow::Result<()> {
+    let (filter, handle) = reload::Layer::new(filter(level)?);
+    let _ = FILTER.set(handle);
+
+    let fmt = tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal());
+    let fmt = match json {
+        true => fmt.json().with_filter(filter).boxed(),
+        false => fmt.with_filter(filter).boxed(),
+    };
+
+    // Traces don't depend on the log level, so quieter logs don't leave gaps in them
+    let telemetry = otlp_tracer()?.map(|tracer| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(LevelFilter::INFO)
+    });
+
+    match tracing_subscriber::registry()
+        .with(fmt)
+        .with(telemetry)
+        .try_init()
+    {
+        Ok(()) => Ok(()),
+        Err(e) => anyhow::bail!("failed to set up logging: {e}"),
+    }
+}
+
+/// Export spans over OTLP/gRPC if `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set, unless `OTEL_SDK_DISABLED` is `true`. The rest of
+/// the standard `OTEL_*` variables, like `OTEL_SERVICE_NAME` and `OTEL_TRACES_SAMPLER`, are read
+/// by the exporter.
+fn otlp_tracer() -> anyhow::Result<Option<Tracer>> {
+    let configured = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|v| std::env::var_os(v).is_some());
+    let disabled = std::env::var("OTEL_SDK_DISABLED").is_ok_and(|v| v.eq_ignore_ascii_case("true"));
+    if !configured || disabled {
+        return Ok(None);
+    }
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    // Name the service unless `OTEL_SERVICE_NAME` or `OTEL_RESOURCE_ATTRIBUTES` already did
+    let mut resource = Resource::default();
+    let named = resource
+        .get(Key::new("service.name"))
+        .is_some_and(|v| !v.as_str().starts_with("unknown_service"));
+    if !named {
+        resource = resource.merge(&Resource::new([KeyValue::new("service.name", "ywkv")]));
+    }
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .context("failed to set up the OTLP exporter")?;
+
+    Ok(Some(tracer))
+}
+
+/// Replace the filter set up by [init].
+pub fn set_filter(filter: EnvFilter) -> anyhow::Result<()> {
+    match FILTER.get() {
+        Some(handle) => handle
+            .reload(filter)
+            .context("failed to change the log level"),
+        None => anyhow::bail!("logging isn't set up"),
+    }
+}
+
+/// Send any spans that haven't been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|v| v.as_str()).collect()
+    }
+}
+
+/// Run the rest of the request inside a span, logging its status and how long it took. The span
+/// continues any trace started by the caller's `traceparent` header.
+pub async fn trace<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|v| v.as_str().to_string());
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        // The full path even when it's served under a base path
+        path = request
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(request.uri().path(), |v| v.path()),
+        route,
+        request_id = request.extensions().get::<RequestId>().map(|v| v.0.as_str()),
+        otel.name = format!("{} {}", request.method(), route.as_deref().unwrap_or_default()),
+        otel.kind = "server",
+        otel.status_code = Empty,
+    );
+
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    async move {
+        let start = Instant::now();
+        let response = next.run(request).await.into_response();
+
+        let status = response.status();
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        if status.is_server_error() {
+            tracing::Span::current().record("otel.status_code", "ERROR");
+            tracing::warn!(status = status.as_u16(), latency_ms, "finished request");
+        } else {
+            tracing::info!(status = status.as_u16(), latency_ms, "finished request");
+        }
+
+        response
+    }
+    .instrument(span)
+    .await
+}
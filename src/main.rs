@@ -1,164 +1,3237 @@
 use std::{
-    net::{SocketAddr, SocketAddrV4},
-    ops::{Deref, DerefMut},
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    ops::{Bound, Deref, DerefMut},
+    path::PathBuf,
     sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow;
+use anyhow::Context;
 use axum::{
-    extract::{Path, State},
+    async_trait,
+    body::Bytes,
+    extract::{
+        ConnectInfo, DefaultBodyLimit, FromRef, FromRequestParts, OriginalUri, Path, Query, State,
+    },
     handler::Handler,
-    http::StatusCode,
-    routing::get,
-    Json, Router,
+    headers::{HeaderMapExt, LastModified},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, LOCATION},
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, StatusCode, Uri,
+    },
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
 use clap::{Arg, ArgAction};
-use redb::{Database, TableDefinition};
-use tokio::sync::RwLock;
-use tower_http::{compression::CompressionLayer, validate_request::ValidateRequestHeaderLayer};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tower_http::compression::CompressionLayer;
+use utoipa::{IntoParams, ToSchema};
 
-use ywkv::{self, Db, Response, YwkvError};
+use serde::{Deserialize, Serialize};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use ywkv::{
+    self, Actor, ChangeFeed, ChangeKind, Db, DbStats, Durability, Entry, KeyMeta, KeyPage, ListEnd,
+    PrefixStats, Response, Value, YwkvError,
+};
+
+mod admin;
+mod auth;
+mod base_path;
+#[cfg(feature = "client")]
+mod bench;
+#[cfg(feature = "client")]
+mod cli;
+mod compact;
+mod config;
+mod cors;
+mod encryption;
+mod export;
+mod group;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod idempotency;
+mod import;
+mod limits;
+mod lock;
+mod logging;
+mod memcached;
+mod metrics;
+mod migrate;
+mod negotiate;
+mod openapi;
+mod ratelimit;
+mod reload;
+mod replication;
+mod request_id;
+mod resp;
+mod s3;
+#[cfg(feature = "scripts")]
+mod scripts;
+mod tcp;
+mod tls;
+#[cfg(unix)]
+mod unix_socket;
+mod webhook;
+mod ws;
+
+use auth::{Fingerprint, Role, Tokens};
+use group::GroupCommit;
+use limits::Limits;
+use metrics::Metrics;
+use ratelimit::{Limit, LimitBy, RateLimiter};
+use request_id::RequestId;
+
+/// Also read by the client subcommands to pick a table.
+const TABLE_NAME_ARG: &str = "table-name";
+
+/// Seconds until the key expires, sent along with values that have a TTL.
+const TTL_HEADER: HeaderName = HeaderName::from_static("x-ywkv-ttl");
+
+/// Everything but unreserved characters, so a key always stays a single path segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Run database work on the blocking thread pool so slow disk commits don't stall other requests
+/// on the same runtime worker. The work stays inside the current span.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+        .await
+        .expect("database task panicked")
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReadQuery {
+    /// Read an earlier revision from the key's history instead of the current value
+    rev: Option<u64>,
+    /// A JSON Pointer to respond with only that part of a JSON value, like `/a/b`
+    pointer: Option<String>,
+}
 
+/// Responds with the raw value and the content type it was written with.
+///
+/// Replies 304 with no body when `If-None-Match` contains the value's current ETag. With
+/// `pointer`, only that part of the value is sent, but the ETag is still the whole value's.
+#[utoipa::path(
+    get,
+    path = "/{key}",
+    tag = "values",
+    params(("key" = String, Path), ReadQuery),
+    responses(
+        (
+            status = 200,
+            description = "The value, with the content type it was written with",
+            body = openapi::RawValue,
+            content_type = "application/octet-stream",
+            headers(
+                ("ETag" = String),
+                ("Last-Modified" = String),
+                ("X-Ywkv-Ttl" = u64, description = "Seconds until the key expires, if it has a TTL"),
+            ),
+        ),
+        (status = 304, description = "The value matches `If-None-Match`"),
+        (status = 400, description = "The pointer isn't a JSON Pointer", body = Response<String>),
+        (status = 404, description = "The key, revision or pointer doesn't exist", body = Response<String>),
+        (status = 409, description = "The value isn't JSON, with `pointer`", body = Response<String>),
+    )
+)]
 async fn read_key(
-    Path(key): Path<String>,
-    State(state): State<DbState<'_>>,
+    Path(KeyPath { key }): Path<KeyPath>,
+    Query(query): Query<ReadQuery>,
+    Table(db): Table,
+    request_headers: HeaderMap,
+) -> Result<
+    (
+        StatusCode,
+        HeaderMap,
+        Extension<negotiate::Verbatim>,
+        Vec<u8>,
+    ),
+    (StatusCode, Json<Response>),
+> {
+    let (value, mut headers) = match query.rev {
+        Some(rev) => read_revision(db, key.clone(), rev).await?,
+        None => read_with_headers(db, key.clone()).await?,
+    };
+
+    let etag = headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if etag_matches(&request_headers, etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            headers,
+            Extension(negotiate::Verbatim),
+            Vec::new(),
+        ));
+    }
+
+    let data = match query.pointer {
+        Some(pointer) => {
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(ywkv::JSON_CONTENT_TYPE),
+            );
+            json_pointer(&key, &value.data, &pointer)?
+        }
+        None => value.data,
+    };
+
+    Ok((
+        StatusCode::OK,
+        headers,
+        Extension(negotiate::Verbatim),
+        data,
+    ))
+}
+
+/// The part of the JSON document `data` that `pointer` points to.
+fn json_pointer(
+    key: &str,
+    data: &[u8],
+    pointer: &str,
+) -> Result<Vec<u8>, (StatusCode, Json<Response>)> {
+    let error = |status, message: String| {
+        (
+            status,
+            Json::from(Response::new(
+                message,
+                ywkv::Status::Read(ywkv::ReadStatus::Failure),
+            )),
+        )
+    };
+
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("pointer `{pointer}` must be empty or start with `/`"),
+        ));
+    }
+    let document = serde_json::from_slice::<serde_json::Value>(data).map_err(|_| {
+        (
+            StatusCode::CONFLICT,
+            Json::from(
+                Response::new(
+                    YwkvError::NotJson(key.to_string()).to_string(),
+                    ywkv::Status::Read(ywkv::ReadStatus::Failure),
+                )
+                .with_code(ywkv::ErrorCode::WrongType),
+            ),
+        )
+    })?;
+
+    match document.pointer(pointer) {
+        Some(v) => Ok(serde_json::to_vec(v).expect("JSON values always serialize")),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json::from(Response::new(
+                format!("pointer `{pointer}` not found for key `{key}`"),
+                ywkv::Status::Read(ywkv::ReadStatus::Missing),
+            )),
+        )),
+    }
+}
+
+/// Responds with the same headers as a read plus the value's length, without the value itself.
+#[utoipa::path(
+    head,
+    path = "/{key}",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses(
+        (status = 200, description = "The key exists"),
+        (status = 404, description = "The key doesn't exist"),
+    )
+)]
+async fn head_key(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> Result<HeaderMap, (StatusCode, Json<Response>)> {
+    let (value, mut headers) = read_with_headers(db, key).await?;
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(value.data.len()));
+
+    Ok(headers)
+}
+
+/// Read a value along with the headers describing it.
+async fn read_with_headers(
+    db: Db,
+    key: String,
+) -> Result<(Value, HeaderMap), (StatusCode, Json<Response>)> {
+    let (value, ttl, meta) = read_with_meta(db, key).await?;
+    let headers = value_headers(&value, ttl, meta.modified_at);
+
+    Ok((value, headers))
+}
+
+/// Read a value from a key's history along with the headers describing it.
+async fn read_revision(
+    db: Db,
+    key: String,
+    rev: u64,
+) -> Result<(Value, HeaderMap), (StatusCode, Json<Response>)> {
+    let revision = blocking(move || db.read_revision(key, rev))
+        .await
+        .map_err(Response::from_read_error)?;
+    let headers = value_headers(&revision.value, None, Some(revision.modified_at));
+
+    Ok((revision.value, headers))
+}
+
+fn value_headers(value: &Value, ttl: Option<Duration>, modified_at: Option<u64>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(&value.content_type)
+            .unwrap_or(HeaderValue::from_static(ywkv::DEFAULT_CONTENT_TYPE)),
+    );
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(&value.etag()).expect("etags are always valid header values"),
+    );
+    if let Some(ttl) = ttl {
+        headers.insert(TTL_HEADER, HeaderValue::from(ttl_secs(ttl)));
+    }
+    if let Some(modified_at) = modified_at {
+        headers.typed_insert(LastModified::from(
+            UNIX_EPOCH + Duration::from_millis(modified_at),
+        ));
+    }
+
+    headers
+}
+
+/// Round up so a key is never reported as having 0 seconds left while still readable.
+fn ttl_secs(ttl: Duration) -> u64 {
+    ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0)
+}
+
+#[derive(Serialize, ToSchema)]
+struct KeyInfo {
+    /// Bytes
+    size: usize,
+    content_type: String,
+    /// Milliseconds since the unix epoch
+    created_at: Option<u64>,
+    /// Milliseconds since the unix epoch
+    modified_at: Option<u64>,
+    /// Seconds until the key expires
+    ttl: Option<u64>,
+}
+
+/// Describe a value without sending the value itself.
+#[utoipa::path(
+    get,
+    path = "/{key}/_meta",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses(
+        (status = 200, body = Response<KeyInfo>),
+        (status = 404, description = "The key doesn't exist", body = Response<String>),
+    )
+)]
+async fn read_key_meta(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> Result<(StatusCode, Json<Response<KeyInfo>>), (StatusCode, Json<Response>)> {
+    let (value, ttl, meta) = read_with_meta(db, key).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json::from(Response::new(
+            KeyInfo {
+                size: value.data.len(),
+                content_type: value.content_type,
+                created_at: meta.created_at,
+                modified_at: meta.modified_at,
+                ttl: ttl.map(ttl_secs),
+            },
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        )),
+    ))
+}
+
+async fn read_with_meta(
+    db: Db,
+    key: String,
+) -> Result<(Value, Option<Duration>, KeyMeta), (StatusCode, Json<Response>)> {
+    blocking(move || db.read_with_meta(key))
+        .await
+        .map_err(Response::from_read_error)
+}
+
+#[derive(Serialize, ToSchema)]
+struct RevisionInfo {
+    rev: u64,
+    size: usize,
+    content_type: String,
+    /// Milliseconds since the unix epoch
+    modified_at: u64,
+}
+
+/// Lists the recorded revisions of a key, oldest first, without their values.
+#[utoipa::path(
+    get,
+    path = "/{key}/_history",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses((status = 200, body = Response<Vec<RevisionInfo>>))
+)]
+async fn read_key_history(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> Result<(StatusCode, Json<Response<Vec<RevisionInfo>>>), (StatusCode, Json<Response>)> {
+    let history = blocking(move || db.history(key))
+        .await
+        .map_err(Response::from_read_error)?;
+
+    Ok((
+        StatusCode::OK,
+        Json::from(Response::new(
+            history
+                .into_iter()
+                .map(|v| RevisionInfo {
+                    rev: v.rev,
+                    size: v.value.data.len(),
+                    content_type: v.value.content_type,
+                    modified_at: v.modified_at,
+                })
+                .collect(),
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        )),
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RollbackQuery {
+    rev: u64,
+}
+
+/// Writes an earlier revision back as the key's current value.
+#[utoipa::path(
+    post,
+    path = "/{key}/_rollback",
+    tag = "values",
+    params(("key" = String, Path), RollbackQuery),
+    responses(
+        (status = 201, description = "The value that was replaced", body = Response<String>),
+        (status = 404, description = "The revision doesn't exist", body = Response<String>),
+    )
+)]
+async fn rollback_key(
+    ValidKey(key): ValidKey,
+    Query(query): Query<RollbackQuery>,
+    Table(db): Table,
 ) -> (StatusCode, Json<Response>) {
-    let state = state.read().await;
+    match blocking(move || db.rollback(key, query.rev)).await {
+        Ok(Some(old_value)) => (
+            StatusCode::CREATED,
+            Json::from(Response::new(
+                old_value.into_string_lossy(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+            )),
+        ),
+        Ok(None) => (
+            StatusCode::CREATED,
+            Json::from(Response::new(
+                String::new(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+            )),
+        ),
+        Err(e @ YwkvError::RevisionMissing(..)) => Response::from_read_error(e),
+        Err(e) => Response::from_write_error(e),
+    }
+}
 
-    match state.read(key) {
-        Ok(value) => (
+/// Whether any `If-None-Match` header matches `etag`. Weak tags are compared as if they were strong.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+}
+
+#[utoipa::path(
+    post,
+    path = "/_mget",
+    tag = "values",
+    request_body = Vec<String>,
+    responses((status = 200, description = "The value and status of each key", body = inline(BTreeMap<String, Response<String>>)))
+)]
+async fn read_batch(
+    Table(db): Table,
+    negotiate::Body(payload): negotiate::Body<Vec<String>>,
+) -> Result<Json<BTreeMap<String, Response>>, (StatusCode, Json<Response>)> {
+    let values = blocking(move || db.read_many(payload))
+        .await
+        .map_err(Response::from_read_error)?;
+
+    Ok(Json::from(
+        values
+            .into_iter()
+            .map(|(key, value)| (key, read_response(value)))
+            .collect::<BTreeMap<_, _>>(),
+    ))
+}
+
+/// How a key read as part of a batch is reported.
+fn read_response(value: Option<Value>) -> Response {
+    match value {
+        Some(v) => Response::new(
+            v.into_string_lossy(),
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        ),
+        None => Response::new(String::new(), ywkv::Status::Read(ywkv::ReadStatus::Missing)),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SnapshotRead {
+    #[serde(default)]
+    keys: Vec<String>,
+    /// Also read every key starting with this
+    prefix: Option<String>,
+    /// The most keys to read with `prefix`
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SnapshotValues {
+    /// The value and status of each key in `keys`
+    #[schema(value_type = BTreeMap<String, Response<String>>)]
+    values: BTreeMap<String, Response>,
+    /// Entries with keys starting with `prefix`, in key order
+    entries: Vec<Entry>,
+    /// The newest change in the changelog as of the snapshot, or 0 if nothing was recorded
+    position: u64,
+}
+
+/// Read keys and a prefix from a single snapshot, so a write can't land between any of them.
+#[utoipa::path(
+    post,
+    path = "/_snapshot-read",
+    tag = "values",
+    request_body = SnapshotRead,
+    responses((status = 200, body = Response<SnapshotValues>))
+)]
+async fn snapshot_read(
+    Table(db): Table,
+    negotiate::Body(payload): negotiate::Body<SnapshotRead>,
+) -> Result<Json<Response<SnapshotValues>>, (StatusCode, Json<Response>)> {
+    let snapshot =
+        blocking(move || db.read_snapshot(payload.keys, payload.prefix.as_deref(), payload.limit))
+            .await
+            .map_err(Response::from_read_error)?;
+
+    Ok(Json::from(Response::new(
+        SnapshotValues {
+            values: snapshot
+                .values
+                .into_iter()
+                .map(|(key, value)| (key, read_response(value)))
+                .collect(),
+            entries: snapshot.entries,
+            position: snapshot.position,
+        },
+        ywkv::Status::Read(ywkv::ReadStatus::Found),
+    )))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WriteQuery {
+    /// Seconds until the key expires
+    ttl: Option<u64>,
+    /// Only write if the current value is exactly this. `If-Match` does the same with its ETag.
+    expected: Option<String>,
+    /// Only write if the key doesn't exist. Same as the `If-None-Match: *` header.
+    #[serde(default)]
+    create: bool,
+    /// Reject the value unless it's valid JSON, like every write to a `--json-tables` table
+    #[serde(default)]
+    json: bool,
+    /// `old` to respond with the value that was replaced, exactly as it was stored
+    #[serde(rename = "return")]
+    #[param(inline)]
+    returning: Option<Returning>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Returning {
+    Old,
+}
+
+#[utoipa::path(
+    post,
+    path = "/{key}",
+    tag = "values",
+    params(
+        ("key" = String, Path),
+        WriteQuery,
+        ("If-Match" = Option<String>, Header, description = "Only write if the current value has one of these ETags, or exists at all with `*`"),
+        ("If-None-Match" = Option<String>, Header, description = "`*` to only write if the key doesn't exist"),
+    ),
+    request_body(
+        content = openapi::RawValue,
+        content_type = "application/octet-stream",
+        description = "Stored along with the request's content type",
+    ),
+    responses(
+        (status = 200, description = "The value that was replaced, with `return=old`", body = openapi::RawValue, content_type = "application/octet-stream"),
+        (status = 201, description = "The value that was replaced", body = Response<String>),
+        (status = 204, description = "Nothing was replaced, with `return=old`"),
+        (status = 400, description = "The key isn't allowed or the value isn't JSON", body = Response<String>),
+        (status = 409, description = "The key already exists", body = Response<String>),
+        (status = 412, description = "The current value didn't match", body = Response<String>),
+        (status = 413, description = "The value is too large", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+async fn write_key(
+    ValidKey(key): ValidKey,
+    Query(query): Query<WriteQuery>,
+    Table(db): Table,
+    State(group): State<Option<GroupCommit>>,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> axum::response::Response {
+    let ttl = query.ttl.map(Duration::from_secs);
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(ywkv::DEFAULT_CONTENT_TYPE);
+    if query.json && !ywkv::is_json(&payload) {
+        return Response::from_write_error(YwkvError::NotJson(key)).into_response();
+    }
+    let payload = Value::new(payload.to_vec(), content_type);
+
+    // Compared to the current value's ETag, unlike `expected` which is compared to the value
+    let if_match = match headers.get(IF_MATCH).map(|v| v.to_str()) {
+        Some(Ok(v)) => Some(
+            v.split(',')
+                .map(|v| v.trim().to_string())
+                .collect::<Vec<_>>(),
+        ),
+        Some(Err(e)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json::from(Response::new(
+                    e.to_string(),
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                )),
+            )
+                .into_response()
+        }
+        None => None,
+    };
+    let create = query.create
+        || headers
+            .get(IF_NONE_MATCH)
+            .is_some_and(|v| v.as_bytes() == b"*");
+    if create && (if_match.is_some() || query.expected.is_some()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json::from(Response::new(
+                "a write can't both expect a value and only create the key".to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            )),
+        )
+            .into_response();
+    }
+
+    let res = if create {
+        blocking(move || db.create(key, payload, ttl))
+            .await
+            .map(|()| None)
+    } else if let Some(etags) = if_match {
+        blocking(move || db.write_if_match(key, &etags, payload, ttl))
+            .await
+            .map(Some)
+    } else if let Some(expected) = query.expected {
+        blocking(move || db.compare_and_swap(key, expected, payload, ttl))
+            .await
+            .map(Some)
+    } else {
+        match group {
+            Some(group) => group.write(db, key, payload, ttl).await,
+            None => blocking(move || db.write_with_ttl(key, payload, ttl)).await,
+        }
+    };
+
+    match res {
+        Ok(old_value) if query.returning == Some(Returning::Old) => match old_value {
+            Some(old_value) => (
+                StatusCode::OK,
+                [(
+                    CONTENT_TYPE,
+                    HeaderValue::from_str(&old_value.content_type)
+                        .unwrap_or(HeaderValue::from_static(ywkv::DEFAULT_CONTENT_TYPE)),
+                )],
+                Extension(negotiate::Verbatim),
+                old_value.data,
+            )
+                .into_response(),
+            None => (StatusCode::NO_CONTENT, Extension(negotiate::Verbatim)).into_response(),
+        },
+        Ok(Some(old_value)) => (
+            StatusCode::CREATED,
+            Json::from(Response::new(
+                old_value.into_string_lossy(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+            )),
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::CREATED,
+            Json::from(Response::new(
+                String::new(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+            )),
+        )
+            .into_response(),
+        Err(e @ YwkvError::KeyExists(_)) => Response::from_error(
+            &e,
+            ywkv::Status::Write(ywkv::WriteStatus::PreconditionFailed),
+        )
+        .into_response(),
+        Err(e) => Response::from_write_error(e).into_response(),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct InsertQuery {
+    /// Seconds until the key expires
+    ttl: Option<u64>,
+    /// Put in front of the generated key, like `pastes:`
+    prefix: Option<String>,
+}
+
+/// Store the body under a new key and respond with the key, along with its path in `Location`.
+/// Keys are [ULIDs](https://github.com/ulid/spec), so they sort by when they were created.
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "values",
+    params(InsertQuery),
+    request_body(
+        content = openapi::RawValue,
+        content_type = "application/octet-stream",
+        description = "Stored along with the request's content type",
+    ),
+    responses(
+        (status = 201, description = "The new key", body = Response<String>, headers(("Location" = String))),
+        (status = 400, description = "The prefix makes the key too long or isn't allowed", body = Response<String>),
+        (status = 413, description = "The value is too large", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+async fn insert_key(
+    Query(query): Query<InsertQuery>,
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> Result<(StatusCode, HeaderMap, Json<Response>), (StatusCode, Json<Response>)> {
+    let key = format!("{}{}", query.prefix.unwrap_or_default(), new_key());
+    limits.check_key(&key)?;
+    let ttl = query.ttl.map(Duration::from_secs);
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(ywkv::DEFAULT_CONTENT_TYPE);
+    let payload = Value::new(payload.to_vec(), content_type);
+
+    let res = blocking({
+        let key = key.clone();
+        move || db.create(key, payload, ttl)
+    })
+    .await;
+    if let Err(e) = res {
+        return Err(Response::from_write_error(e));
+    }
+
+    // Relative to wherever the request was sent, so it works under `/_table` and `--base-path`
+    let location = format!(
+        "{}/{}",
+        uri.path().trim_end_matches('/'),
+        utf8_percent_encode(&key, PATH_SEGMENT)
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        LOCATION,
+        HeaderValue::from_str(&location).expect("encoded paths are always valid header values"),
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        headers,
+        Json::from(Response::new(
+            key,
+            ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+        )),
+    ))
+}
+
+/// A [ULID](https://github.com/ulid/spec): the milliseconds since the unix epoch followed by 80
+/// random bits, in Crockford's base 32.
+fn new_key() -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    let mut random = [0_u8; 16];
+    OsRng.fill_bytes(&mut random[6..]);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let id = (millis << 80) | u128::from_be_bytes(random);
+
+    (0..26)
+        .map(|i| char::from(ALPHABET[(id >> (125 - i * 5)) as usize & 31]))
+        .collect()
+}
+
+/// Apply a JSON Merge Patch (RFC 7386) to a JSON value and respond with the patched value. Members
+/// of the patch set to `null` are removed, and a missing key is created from the patch.
+#[utoipa::path(
+    patch,
+    path = "/{key}",
+    tag = "values",
+    params(("key" = String, Path)),
+    request_body(
+        content = openapi::RawValue,
+        content_type = "application/merge-patch+json",
+        description = "The JSON to merge into the value",
+    ),
+    responses(
+        (status = 200, description = "The patched value", body = openapi::RawValue, content_type = "application/json", headers(("ETag" = String))),
+        (status = 400, description = "The key isn't allowed or the patch isn't JSON", body = Response<String>),
+        (status = 409, description = "The current value isn't JSON", body = Response<String>),
+        (status = 413, description = "The value would be too large", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+async fn patch_key(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    payload: Bytes,
+) -> Result<(HeaderMap, Extension<negotiate::Verbatim>, Vec<u8>), (StatusCode, Json<Response>)> {
+    let patch = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json::from(Response::new(
+                    format!("patch is not valid JSON: {e}"),
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                )),
+            ))
+        }
+    };
+    let max_len = limits.max_value_size;
+
+    let document = match blocking(move || db.merge_patch(key, &patch, Some(max_len))).await {
+        Ok(v) => v,
+        // The stored value isn't JSON, which is a conflict rather than a bad request
+        Err(e @ YwkvError::NotJson(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json::from(
+                    Response::new(
+                        e.to_string(),
+                        ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                    )
+                    .with_code(ywkv::ErrorCode::WrongType),
+                ),
+            ))
+        }
+        Err(e) => return Err(Response::from_write_error(e)),
+    };
+
+    let value = Value::new(
+        serde_json::to_vec(&document).expect("JSON values always serialize"),
+        ywkv::JSON_CONTENT_TYPE,
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(ywkv::JSON_CONTENT_TYPE),
+    );
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(&value.etag()).expect("etags are always valid header values"),
+    );
+
+    Ok((headers, Extension(negotiate::Verbatim), value.data))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{key}/incr",
+    tag = "values",
+    params(("key" = String, Path)),
+    request_body(
+        content = String,
+        content_type = "text/plain",
+        description = "How much to add by. Defaults to 1.",
+    ),
+    responses(
+        (status = 200, description = "The new value", body = Response<String>),
+        (status = 400, description = "The amount isn't an integer", body = Response<String>),
+        (status = 409, description = "The current value isn't an integer or would overflow", body = Response<String>),
+    )
+)]
+async fn increment_key(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    payload: String,
+) -> (StatusCode, Json<Response>) {
+    increment(db, key, payload, 1).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/{key}/decr",
+    tag = "values",
+    params(("key" = String, Path)),
+    request_body(
+        content = String,
+        content_type = "text/plain",
+        description = "How much to subtract by. Defaults to 1.",
+    ),
+    responses(
+        (status = 200, description = "The new value", body = Response<String>),
+        (status = 400, description = "The amount isn't an integer", body = Response<String>),
+        (status = 409, description = "The current value isn't an integer or would overflow", body = Response<String>),
+    )
+)]
+async fn decrement_key(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    payload: String,
+) -> (StatusCode, Json<Response>) {
+    increment(db, key, payload, -1).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/{key}/append",
+    tag = "values",
+    params(("key" = String, Path)),
+    request_body(
+        content = openapi::RawValue,
+        content_type = "application/octet-stream",
+        description = "Added to the end of the value. A missing key is created with the request's content type.",
+    ),
+    responses(
+        (status = 200, description = "The new length of the value in bytes", body = Response<String>),
+        (status = 400, description = "The key isn't allowed", body = Response<String>),
+        (status = 413, description = "The value would be too large", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+async fn append_key(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> (StatusCode, Json<Response>) {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(ywkv::DEFAULT_CONTENT_TYPE);
+    let payload = Value::new(payload.to_vec(), content_type);
+    let max_len = limits.max_value_size;
+
+    match blocking(move || db.append(key, payload, Some(max_len))).await {
+        Ok(len) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                len.to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    /// `front` or `back`. Items are pushed to the back and popped from the front by default, so
+    /// the list works as a queue.
+    #[param(inline)]
+    end: Option<ListEnd>,
+}
+
+/// Add the request body to a list, creating the list if the key is missing.
+#[utoipa::path(
+    post,
+    path = "/{key}/push",
+    tag = "values",
+    params(("key" = String, Path), ListQuery),
+    request_body(content = String, content_type = "text/plain", description = "The item to add"),
+    responses(
+        (status = 200, description = "How many items the list now holds", body = Response<String>),
+        (status = 409, description = "The current value isn't a list", body = Response<String>),
+        (status = 413, description = "The list would be too large", body = Response<String>),
+    )
+)]
+async fn push_key(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    Query(query): Query<ListQuery>,
+    payload: String,
+) -> (StatusCode, Json<Response>) {
+    let end = query.end.unwrap_or(ListEnd::Back);
+    let max_len = limits.max_value_size;
+
+    match blocking(move || db.push(key, payload, end, Some(max_len))).await {
+        Ok(len) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                len.to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+/// Remove an item from a list and respond with it.
+#[utoipa::path(
+    post,
+    path = "/{key}/pop",
+    tag = "values",
+    params(("key" = String, Path), ListQuery),
+    responses(
+        (status = 200, description = "The item that was removed", body = Response<String>),
+        (status = 404, description = "The key doesn't exist or the list is empty", body = Response<String>),
+        (status = 409, description = "The current value isn't a list", body = Response<String>),
+    )
+)]
+async fn pop_key(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    Query(query): Query<ListQuery>,
+) -> (StatusCode, Json<Response>) {
+    let end = query.end.unwrap_or(ListEnd::Front);
+
+    match blocking(move || db.pop(key, end)).await {
+        Ok(item) => (
             StatusCode::OK,
             Json::from(Response::new(
-                value,
+                item,
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+/// How many items a list holds, 0 if the key doesn't exist.
+#[utoipa::path(
+    get,
+    path = "/{key}/len",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses(
+        (status = 200, description = "How many items the list holds", body = Response<String>),
+        (status = 409, description = "The current value isn't a list", body = Response<String>),
+    )
+)]
+async fn list_len(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> (StatusCode, Json<Response>) {
+    match blocking(move || db.list_len(key)).await {
+        Ok(len) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                len.to_string(),
                 ywkv::Status::Read(ywkv::ReadStatus::Found),
             )),
         ),
-        Err(e) => match e {
-            YwkvError::KeyMissing(_) => (
-                StatusCode::NOT_FOUND,
+        Err(e) => Response::from_read_error(e),
+    }
+}
+
+/// Add the request body to a set, creating the set if the key is missing. Responds with 1 if it
+/// was added and 0 if it was already a member.
+#[utoipa::path(
+    post,
+    path = "/{key}/sadd",
+    tag = "values",
+    params(("key" = String, Path)),
+    request_body(content = String, content_type = "text/plain", description = "The member to add"),
+    responses(
+        (status = 200, description = "How many members were added", body = Response<String>),
+        (status = 409, description = "The current value isn't a set", body = Response<String>),
+        (status = 413, description = "The set would be too large", body = Response<String>),
+    )
+)]
+async fn set_add(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    payload: String,
+) -> (StatusCode, Json<Response>) {
+    let max_len = limits.max_value_size;
+
+    match blocking(move || db.set_add(key, payload, Some(max_len))).await {
+        Ok(added) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                u8::from(added).to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+/// Remove the request body from a set. Responds with 1 if it was removed and 0 if it wasn't a
+/// member.
+#[utoipa::path(
+    post,
+    path = "/{key}/srem",
+    tag = "values",
+    params(("key" = String, Path)),
+    request_body(content = String, content_type = "text/plain", description = "The member to remove"),
+    responses(
+        (status = 200, description = "How many members were removed", body = Response<String>),
+        (status = 409, description = "The current value isn't a set", body = Response<String>),
+    )
+)]
+async fn set_remove(
+    ValidKey(key): ValidKey,
+    Table(db): Table,
+    payload: String,
+) -> (StatusCode, Json<Response>) {
+    match blocking(move || db.set_remove(key, &payload)).await {
+        Ok(true) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                "1".to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+            )),
+        ),
+        Ok(false) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                "0".to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::Missing),
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+/// Every member of a set in order, none if the key doesn't exist.
+#[utoipa::path(
+    get,
+    path = "/{key}/smembers",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses(
+        (status = 200, body = Response<Vec<String>>),
+        (status = 409, description = "The current value isn't a set", body = Response<String>),
+    )
+)]
+async fn set_members(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> Result<Json<Response<Vec<String>>>, (StatusCode, Json<Response>)> {
+    match blocking(move || db.set_members(key)).await {
+        Ok(members) => Ok(Json::from(Response::new(
+            members.into_iter().collect(),
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        ))),
+        Err(e) => Err(Response::from_read_error(e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct MemberPath {
+    key: String,
+    member: String,
+}
+
+/// Whether a set has a member. Keys that don't exist are empty sets.
+#[utoipa::path(
+    get,
+    path = "/{key}/sismember/{member}",
+    tag = "values",
+    params(("key" = String, Path), ("member" = String, Path)),
+    responses(
+        (status = 200, body = Response<bool>),
+        (status = 409, description = "The current value isn't a set", body = Response<String>),
+    )
+)]
+async fn set_contains(
+    Path(MemberPath { key, member }): Path<MemberPath>,
+    Table(db): Table,
+) -> Result<Json<Response<bool>>, (StatusCode, Json<Response>)> {
+    match blocking(move || db.set_contains(key, &member)).await {
+        Ok(contains) => Ok(Json::from(Response::new(
+            contains,
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        ))),
+        Err(e) => Err(Response::from_read_error(e)),
+    }
+}
+
+/// Apply an optional amount from the request body, defaulting to 1, in the direction of `sign`.
+async fn increment(
+    db: Db,
+    key: String,
+    payload: String,
+    sign: i64,
+) -> (StatusCode, Json<Response>) {
+    let amount = match payload.trim() {
+        "" => 1,
+        v => match v.parse::<i64>() {
+            Ok(v) => v,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json::from(Response::new(
+                        format!("invalid amount `{v}`: {e}"),
+                        ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                    )),
+                )
+            }
+        },
+    };
+
+    let amount = match amount.checked_mul(sign) {
+        Some(v) => v,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
                 Json::from(Response::new(
-                    e.to_string(),
-                    ywkv::Status::Read(ywkv::ReadStatus::Missing),
+                    format!("invalid amount `{amount}`"),
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
                 )),
+            )
+        }
+    };
+
+    match blocking(move || db.increment(key, amount)).await {
+        Ok(value) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                value.to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/_batch",
+    tag = "values",
+    request_body = BTreeMap<String, String>,
+    responses(
+        (status = 200, description = "The previous value and status of each key", body = inline(BTreeMap<String, Response<String>>)),
+        (status = 400, description = "A key isn't allowed", body = Response<String>),
+        (status = 413, description = "A value is too large", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+async fn write_batch(
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    negotiate::Body(payload): negotiate::Body<BTreeMap<String, String>>,
+) -> Result<Json<BTreeMap<String, Response>>, (StatusCode, Json<Response>)> {
+    for (key, value) in &payload {
+        limits.check_key(key)?;
+        limits.check_value(value.as_bytes())?;
+    }
+
+    let old_values = blocking(move || db.write_many(payload))
+        .await
+        .map_err(Response::from_write_error)?;
+
+    Ok(Json::from(
+        old_values
+            .into_iter()
+            .map(|(key, old_value)| {
+                let response = match old_value {
+                    Some(v) => Response::new(
+                        v.into_string_lossy(),
+                        ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+                    ),
+                    None => Response::new(
+                        String::new(),
+                        ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+                    ),
+                };
+
+                (key, response)
+            })
+            .collect::<BTreeMap<_, _>>(),
+    ))
+}
+
+/// One operation of a transaction.
+#[derive(Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TxnOp {
+    Set {
+        key: String,
+        value: String,
+        /// Seconds until the key expires
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Fails the transaction unless the key holds `value`, or doesn't exist if no value is given
+    Check {
+        key: String,
+        value: Option<String>,
+    },
+}
+
+/// Run every operation in a single transaction, responding with a status for each in order. If
+/// any operation fails, nothing is written.
+#[utoipa::path(
+    post,
+    path = "/_txn",
+    tag = "values",
+    request_body = Vec<TxnOp>,
+    responses(
+        (status = 200, description = "The status of each operation, in order", body = [Response<String>]),
+        (status = 400, description = "A key isn't allowed", body = Response<String>),
+        (status = 412, description = "A check failed, so nothing was written", body = Response<String>),
+        (status = 413, description = "A value is too large", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+async fn transaction(
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    negotiate::Body(payload): negotiate::Body<Vec<TxnOp>>,
+) -> Result<Json<Vec<Response>>, (StatusCode, Json<Response>)> {
+    for op in &payload {
+        match op {
+            TxnOp::Set { key, value, .. } => {
+                limits.check_key(key)?;
+                limits.check_value(value.as_bytes())?;
+            }
+            TxnOp::Delete { key } | TxnOp::Check { key, .. } => limits.check_key(key)?,
+        }
+    }
+
+    let res = blocking(move || {
+        db.transaction(|txn| {
+            let mut responses = vec![];
+            for op in payload {
+                let response = match op {
+                    TxnOp::Set { key, value, ttl } => {
+                        match txn.write_with_ttl(key, value, ttl.map(Duration::from_secs))? {
+                            Some(v) => Response::new(
+                                v.into_string_lossy(),
+                                ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+                            ),
+                            None => Response::new(
+                                String::new(),
+                                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+                            ),
+                        }
+                    }
+                    TxnOp::Delete { key } => match txn.delete(key)? {
+                        Some(v) => Response::new(
+                            v.into_string_lossy(),
+                            ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+                        ),
+                        None => Response::new(
+                            String::new(),
+                            ywkv::Status::Write(ywkv::WriteStatus::Missing),
+                        ),
+                    },
+                    TxnOp::Check { key, value } => {
+                        let current = match txn.read(&key) {
+                            Ok(v) => Some(v),
+                            Err(YwkvError::KeyMissing(_)) => None,
+                            Err(e) => return Err(e),
+                        };
+
+                        match (current, value) {
+                            (Some(current), Some(expected))
+                                if current.data == expected.as_bytes() =>
+                            {
+                                Response::new(
+                                    current.into_string_lossy(),
+                                    ywkv::Status::Read(ywkv::ReadStatus::Found),
+                                )
+                            }
+                            (None, None) => Response::new(
+                                String::new(),
+                                ywkv::Status::Read(ywkv::ReadStatus::Missing),
+                            ),
+                            _ => return Err(YwkvError::PreconditionFailed(key)),
+                        }
+                    }
+                };
+                responses.push(response);
+            }
+
+            Ok(responses)
+        })
+    })
+    .await;
+
+    match res {
+        Ok(responses) => Ok(Json::from(responses)),
+        Err(e) => Err(Response::from_write_error(e)),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{key}",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses(
+        (status = 200, description = "The value that was deleted", body = Response<String>),
+        (status = 404, description = "The key doesn't exist", body = Response<String>),
+    )
+)]
+async fn delete_key(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> (StatusCode, Json<Response>) {
+    match blocking(move || db.delete(key)).await {
+        Ok(Some(old_value)) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                old_value.into_string_lossy(),
+                ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+            )),
+        ),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json::from(
+                Response::new(
+                    String::new(),
+                    ywkv::Status::Write(ywkv::WriteStatus::Missing),
+                )
+                .with_code(ywkv::ErrorCode::KeyMissing),
             ),
-            YwkvError::EmptyTable(_) => (
-                StatusCode::NOT_FOUND,
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct PrefixPath {
+    prefix: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeletePrefixQuery {
+    /// Count the keys that would be deleted without deleting them
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Delete every key starting with the prefix in a single transaction, responding with how many
+/// were deleted.
+#[utoipa::path(
+    delete,
+    path = "/_prefix/{prefix}",
+    tag = "values",
+    params(("prefix" = String, Path), DeletePrefixQuery),
+    responses(
+        (status = 200, description = "How many keys were deleted, or would be with `dry_run`", body = Response<String>),
+    )
+)]
+async fn delete_prefix(
+    Path(PrefixPath { prefix }): Path<PrefixPath>,
+    Query(query): Query<DeletePrefixQuery>,
+    Table(db): Table,
+) -> (StatusCode, Json<Response>) {
+    let dry_run = query.dry_run;
+
+    match blocking(move || db.delete_prefix(prefix, dry_run)).await {
+        Ok(deleted) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                deleted.to_string(),
+                if dry_run {
+                    ywkv::Status::Read(ywkv::ReadStatus::Found)
+                } else {
+                    ywkv::Status::Write(ywkv::WriteStatus::Deleted)
+                },
+            )),
+        ),
+        Err(e) => Response::from_write_error(e),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CountQuery {
+    /// Only count keys starting with this. Every key is counted if not set.
+    #[serde(default)]
+    prefix: String,
+}
+
+/// How many keys there are, without reading their values. Expired keys aren't counted.
+#[utoipa::path(
+    get,
+    path = "/_count",
+    tag = "values",
+    params(CountQuery),
+    responses((status = 200, body = Response<u64>))
+)]
+async fn count_keys(
+    Query(query): Query<CountQuery>,
+    Table(db): Table,
+) -> Result<Json<Response<u64>>, (StatusCode, Json<Response>)> {
+    let count = blocking(move || db.count(query.prefix))
+        .await
+        .map_err(Response::from_read_error)?;
+
+    Ok(Json::from(Response::new(
+        count,
+        ywkv::Status::Read(ywkv::ReadStatus::Found),
+    )))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListKeysQuery {
+    /// 100 by default, and at most 1000
+    limit: Option<usize>,
+    /// The cursor from the previous page
+    cursor: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/_keys",
+    tag = "values",
+    params(ListKeysQuery),
+    responses((status = 200, body = Response<KeyPage>))
+)]
+async fn list_keys(
+    Query(query): Query<ListKeysQuery>,
+    Table(db): Table,
+) -> Result<Json<Response<KeyPage>>, (StatusCode, Json<Response>)> {
+    const DEFAULT_LIMIT: usize = 100;
+    const MAX_LIMIT: usize = 1000;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    blocking(move || db.list_keys(query.cursor.as_deref(), limit))
+        .await
+        .map(|page| {
+            Json::from(Response::new(
+                page,
+                ywkv::Status::Read(ywkv::ReadStatus::Found),
+            ))
+        })
+        .map_err(Response::from_read_error)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ScanQuery {
+    /// Only keys starting with this
+    prefix: Option<String>,
+    /// The first key, inclusive
+    start: Option<String>,
+    /// The last key, exclusive
+    end: Option<String>,
+    limit: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/_scan",
+    tag = "values",
+    params(ScanQuery),
+    responses(
+        (status = 200, body = Response<Vec<Entry>>),
+        (status = 400, description = "`prefix` was combined with `start` or `end`", body = Response<String>),
+    )
+)]
+async fn scan(
+    Query(query): Query<ScanQuery>,
+    Table(db): Table,
+) -> Result<Json<Response<Vec<Entry>>>, (StatusCode, Json<Response>)> {
+    let entries = match query.prefix {
+        Some(_) if query.start.is_some() || query.end.is_some() => {
+            return Err((
+                StatusCode::BAD_REQUEST,
                 Json::from(Response::new(
-                    e.to_string(),
-                    ywkv::Status::Read(ywkv::ReadStatus::Missing),
+                    "`prefix` cannot be combined with `start` or `end`".to_string(),
+                    ywkv::Status::Read(ywkv::ReadStatus::Failure),
                 )),
+            ))
+        }
+        Some(prefix) => blocking(move || db.scan_prefix(prefix, query.limit)).await,
+        None => {
+            blocking(move || {
+                let start = match query.start.as_deref() {
+                    Some(v) => Bound::Included(v),
+                    None => Bound::Unbounded,
+                };
+                let end = match query.end.as_deref() {
+                    Some(v) => Bound::Excluded(v),
+                    None => Bound::Unbounded,
+                };
+
+                db.scan(start, end, query.limit)
+            })
+            .await
+        }
+    };
+
+    entries
+        .map(|entries| {
+            Json::from(Response::new(
+                entries,
+                ywkv::Status::Read(ywkv::ReadStatus::Found),
+            ))
+        })
+        .map_err(Response::from_read_error)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ChangesQuery {
+    /// Only list changes after this sequence number, usually `next` from the previous page
+    #[serde(default)]
+    since: u64,
+    /// 100 by default, and at most 1000
+    limit: Option<usize>,
+}
+
+/// Changes to the table recorded in the changelog, oldest first. Responds with 410 when some of
+/// the changes after `since` were already forgotten.
+#[utoipa::path(
+    get,
+    path = "/_changes",
+    tag = "values",
+    params(ChangesQuery),
+    responses(
+        (status = 200, body = Response<ChangeFeed>),
+        (status = 404, description = "The changelog is not enabled", body = Response<String>),
+        (status = 410, description = "Some of the changes after `since` were forgotten", body = Response<String>),
+    )
+)]
+async fn list_changes(
+    State(state): State<DbState>,
+    Query(query): Query<ChangesQuery>,
+    Table(db): Table,
+) -> Result<Json<Response<ChangeFeed>>, (StatusCode, Json<Response>)> {
+    const DEFAULT_LIMIT: usize = 100;
+    const MAX_LIMIT: usize = 1000;
+
+    let error = |code, message: String| {
+        (
+            code,
+            Json::from(Response::new(
+                message,
+                ywkv::Status::Read(ywkv::ReadStatus::Failure),
+            )),
+        )
+    };
+
+    if state.changelog_entries == 0 {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            "the changelog is not enabled".to_string(),
+        ));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let feed = blocking(move || db.changes(query.since, limit))
+        .await
+        .map_err(Response::from_read_error)?;
+    if feed.first.is_some_and(|v| v - 1 > query.since) {
+        return Err(error(
+            StatusCode::GONE,
+            format!(
+                "changes after {} are not in the changelog anymore",
+                query.since
             ),
-            _ => Response::from_read_error(e),
-        },
+        ));
     }
+
+    Ok(Json::from(Response::new(
+        feed,
+        ywkv::Status::Read(ywkv::ReadStatus::Found),
+    )))
 }
 
-async fn write_key(
-    Path(key): Path<String>,
-    State(state): State<DbState<'_>>,
-    payload: String,
-) -> (StatusCode, Json<Response>) {
-    let state = state.write().await;
+#[derive(Serialize, ToSchema)]
+struct Stats {
+    #[serde(flatten)]
+    db: DbStats,
+    /// Seconds since the server started
+    uptime: u64,
+}
 
-    match state.write(key, payload) {
-        Ok(Some(old_value)) => (
-            StatusCode::CREATED,
-            Json::from(Response::new(
-                old_value,
-                ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
-            )),
-        ),
-        Ok(None) => (
-            StatusCode::CREATED,
+/// Counts and sizes for the table and the database file. Only tables requests may use are listed.
+#[utoipa::path(
+    get,
+    path = "/_stats",
+    tag = "values",
+    responses((status = 200, body = Response<Stats>))
+)]
+async fn stats(
+    State(state): State<DbState>,
+    Table(db): Table,
+) -> Result<Json<Response<Stats>>, (StatusCode, Json<Response>)> {
+    let mut stats = blocking(move || db.stats())
+        .await
+        .map_err(Response::from_read_error)?;
+    stats.tables.retain(|v| state.allows_table(v));
+
+    Ok(Json::from(Response::new(
+        Stats {
+            db: stats,
+            uptime: state.started.elapsed().as_secs(),
+        },
+        ywkv::Status::Read(ywkv::ReadStatus::Found),
+    )))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PrefixStatsQuery {
+    /// How many delimiters each prefix goes up to. 1 by default.
+    depth: Option<usize>,
+    /// What separates the parts of a key. `:` by default.
+    delimiter: Option<String>,
+}
+
+/// Key counts and value sizes grouped by key prefix, sorted by prefix. Keys with fewer
+/// delimiters than `depth` are grouped by their longest prefix ending in one, or `""`.
+#[utoipa::path(
+    get,
+    path = "/_stats/prefixes",
+    tag = "values",
+    params(PrefixStatsQuery),
+    responses(
+        (status = 200, body = Response<Vec<PrefixStats>>),
+        (status = 400, description = "`depth` or `delimiter` is empty", body = Response<String>),
+    )
+)]
+async fn prefix_stats(
+    Query(query): Query<PrefixStatsQuery>,
+    Table(db): Table,
+) -> Result<Json<Response<Vec<PrefixStats>>>, (StatusCode, Json<Response>)> {
+    let depth = query.depth.unwrap_or(1);
+    let delimiter = query.delimiter.unwrap_or_else(|| ":".to_string());
+    if depth == 0 || delimiter.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
             Json::from(Response::new(
-                String::new(),
-                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+                "`depth` must be at least 1 and `delimiter` can't be empty".to_string(),
+                ywkv::Status::Read(ywkv::ReadStatus::Failure),
             )),
-        ),
-        Err(e) => Response::from_read_error(e),
+        ));
     }
+
+    let stats = blocking(move || db.prefix_stats(depth, &delimiter))
+        .await
+        .map_err(Response::from_read_error)?;
+
+    Ok(Json::from(Response::new(
+        stats,
+        ywkv::Status::Read(ywkv::ReadStatus::Found),
+    )))
 }
 
-#[derive(Clone)]
-struct DbState<'a>(Arc<RwLock<Db<'a>>>);
-
-impl<'a> DbState<'a> {
-    fn new<T: AsRef<str>>(path: T, table_name: &'a str) -> anyhow::Result<Self> {
-        let database = {
-            if let Ok(v) = Database::open(path.as_ref()) {
-                v
-            } else {
-                Database::create(path.as_ref()).unwrap()
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WatchQuery {
+    /// Only keys starting with this
+    prefix: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/_watch/{key}",
+    tag = "values",
+    params(("key" = String, Path)),
+    responses((
+        status = 200,
+        description = "Server-sent `set` and `delete` events for the key",
+        content_type = "text/event-stream",
+    ))
+)]
+async fn watch_key(
+    Path(KeyPath { key }): Path<KeyPath>,
+    Table(db): Table,
+) -> Sse<impl Stream<Item = Result<Event, axum::BoxError>>> {
+    watch(db, move |k| k == key)
+}
+
+#[utoipa::path(
+    get,
+    path = "/_watch",
+    tag = "values",
+    params(WatchQuery),
+    responses((
+        status = 200,
+        description = "Server-sent `set` and `delete` events for every matching key",
+        content_type = "text/event-stream",
+    ))
+)]
+async fn watch_prefix(
+    Query(query): Query<WatchQuery>,
+    Table(db): Table,
+) -> Sse<impl Stream<Item = Result<Event, axum::BoxError>>> {
+    let prefix = query.prefix.unwrap_or_default();
+
+    watch(db, move |k| k.starts_with(&prefix))
+}
+
+/// Stream every change to a key in the table that matches `filter` as server-sent events.
+fn watch(
+    db: Db,
+    filter: impl Fn(&str) -> bool + Send + 'static,
+) -> Sse<impl Stream<Item = Result<Event, axum::BoxError>>> {
+    let table = db.table().to_string();
+
+    let stream = BroadcastStream::new(db.subscribe()).filter_map(move |change| {
+        let change = match change {
+            Ok(v) => v,
+            // Let the client know it missed some changes so it can resync if needed
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                return Some(Ok(Event::default()
+                    .event("lagged")
+                    .data(skipped.to_string())));
             }
         };
+        if change.table != table || !filter(&change.key) {
+            return None;
+        }
+
+        let event = match change.kind {
+            ChangeKind::Set => "set",
+            ChangeKind::Delete => "delete",
+        };
+
+        Some(
+            Event::default()
+                .event(event)
+                .json_data(&*change)
+                .map_err(Into::into),
+        )
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(Deserialize)]
+struct KeyPath {
+    key: String,
+}
+
+/// The tables requests may use besides the default one.
+#[derive(Clone)]
+enum TableAccess {
+    Only(Arc<HashSet<String>>),
+    Any,
+}
+
+#[derive(Clone)]
+struct DbState {
+    db: Db,
+    tables: TableAccess,
+    path: Arc<str>,
+    metrics: Arc<Metrics>,
+    limits: Arc<Limits>,
+    /// Plain writes go through the group commit writer when it is enabled
+    group: Option<GroupCommit>,
+    /// Where `/_admin/backup` keeps snapshots. They are sent back in the response if not set.
+    backup_dir: Option<Arc<std::path::Path>>,
+    /// Applies configuration changes for `/_admin/reload`
+    reloader: Option<reload::Reloader>,
+    /// Reported by `/_admin/webhooks`
+    webhooks: webhook::Webhooks,
+    /// How many changes the changelog keeps. 0 if it's off.
+    changelog_entries: usize,
+    /// Reported by `/_admin/replication`
+    replication: replication::Replication,
+    /// Reported by `/_admin/backup/s3`
+    s3_backups: s3::Backups,
+    /// Run by `/_exec/{script}`
+    #[cfg(feature = "scripts")]
+    scripts: scripts::Scripts,
+    started: Instant,
+}
+
+impl DbState {
+    /// Opens a temporary database that's deleted on exit if `path` isn't given.
+    fn new(
+        path: Option<&str>,
+        table_name: &str,
+        create_if_missing: bool,
+        read_only: bool,
+        tables: TableAccess,
+        limits: Limits,
+    ) -> anyhow::Result<Self> {
+        let builder = Db::builder()
+            .create_if_missing(create_if_missing)
+            .read_only(read_only);
+        let mut db = match path {
+            Some(path) => builder.open(path, table_name)?,
+            None => builder.open_temporary(table_name)?,
+        };
+        let path = db
+            .path()
+            .expect("opened with a builder")
+            .display()
+            .to_string();
+
+        let metrics = Arc::new(Metrics::new());
+        db.set_commit_observer({
+            let metrics = metrics.clone();
+            move |took| metrics.observe_commit(took)
+        });
+
+        Ok(DbState {
+            db,
+            tables,
+            path: path.into(),
+            metrics,
+            limits: Arc::new(limits),
+            group: None,
+            backup_dir: None,
+            reloader: None,
+            webhooks: webhook::Webhooks::default(),
+            changelog_entries: 0,
+            replication: replication::Replication::default(),
+            s3_backups: s3::Backups::default(),
+            #[cfg(feature = "scripts")]
+            scripts: scripts::Scripts::default(),
+            started: Instant::now(),
+        })
+    }
+
+    /// A handle to the named table if requests may use it, or the default table if no name is
+    /// given.
+    fn open_table(&self, name: Option<&str>) -> Result<Db, (StatusCode, Json<Response>)> {
+        let name = match name {
+            Some(v) if v != self.table() => v,
+            _ => return Ok(self.db.clone()),
+        };
+
+        if !self.allows_table(name) {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json::from(
+                    Response::new(
+                        format!("table `{name}` is not available"),
+                        ywkv::Status::Read(ywkv::ReadStatus::Missing),
+                    )
+                    .with_code(ywkv::ErrorCode::TableMissing),
+                ),
+            ));
+        }
 
-        let table = TableDefinition::new(table_name);
+        self.with_table(name).map_err(Response::from_read_error)
+    }
 
-        return Ok(DbState(Arc::new(RwLock::new(Db { database, table }))));
+    /// Whether requests may use the named table.
+    fn allows_table(&self, name: &str) -> bool {
+        match &self.tables {
+            TableAccess::Only(tables) => name == self.table() || tables.contains(name),
+            TableAccess::Any => true,
+        }
     }
 }
 
-impl<'a> Deref for DbState<'a> {
-    type Target = Arc<RwLock<ywkv::Db<'a>>>;
+impl Deref for DbState {
+    type Target = ywkv::Db;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.db
     }
 }
 
-impl<'a> DerefMut for DbState<'a> {
+impl DerefMut for DbState {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.db
     }
 }
 
+impl FromRef<DbState> for Arc<Limits> {
+    fn from_ref(state: &DbState) -> Self {
+        state.limits.clone()
+    }
+}
+
+impl FromRef<DbState> for Option<GroupCommit> {
+    fn from_ref(state: &DbState) -> Self {
+        state.group.clone()
+    }
+}
+
+/// A key from the path that passes the configured [Limits], for routes that write to it.
+struct ValidKey(String);
+
+#[async_trait]
+impl FromRequestParts<DbState> for ValidKey {
+    type Rejection = (StatusCode, Json<Response>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &DbState,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(KeyPath { key }) = Path::<KeyPath>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json::from(Response::new(
+                        e.to_string(),
+                        ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                    )),
+                )
+            })?;
+
+        state.limits.check_key(&key)?;
+
+        Ok(ValidKey(key))
+    }
+}
+
+/// The table a request operates on. Routes nested under `/_table/:table` use the named table,
+/// everything else uses the default table.
+struct Table(Db);
+
+#[async_trait]
+impl FromRequestParts<DbState> for Table {
+    type Rejection = (StatusCode, Json<Response>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &DbState,
+    ) -> Result<Self, Self::Rejection> {
+        let params = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json::from(Response::new(
+                        e.to_string(),
+                        ywkv::Status::Read(ywkv::ReadStatus::Failure),
+                    )),
+                )
+            })?;
+
+        state
+            .open_table(params.get("table").map(String::as_str))
+            .map(|db| Table(db.with_actor(actor(parts))))
+    }
+}
+
+/// Who is making a request, for the audit log.
+struct Caller(Actor);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Caller(actor(parts)))
+    }
+}
+
+fn actor(parts: &Parts) -> Actor {
+    Actor {
+        token: parts.extensions.get::<Fingerprint>().map(|v| v.0.clone()),
+        ip: parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|v| v.0.ip()),
+        request_id: parts.extensions.get::<RequestId>().map(|v| v.0.clone()),
+    }
+}
+
+/// Let background writers finish what they started, then make every commit durable, so nothing
+/// acknowledged is lost when the process exits.
+async fn drain(
+    db: &Db,
+    group: Option<&GroupCommit>,
+    sweeper: Option<tokio::task::JoinHandle<()>>,
+    timeout: Duration,
+) {
+    let background = async {
+        if let Some(group) = group {
+            group.flush().await;
+        }
+        if let Some(sweeper) = sweeper {
+            let _ = sweeper.await;
+        }
+    };
+    if tokio::time::timeout(timeout, background).await.is_err() {
+        tracing::warn!(
+            "Background writes were still running after {}s",
+            timeout.as_secs()
+        );
+    }
+
+    let db = db.clone();
+    match blocking(move || db.checkpoint()).await {
+        Ok(()) => tracing::info!("Flushed the database to disk"),
+        Err(e) => tracing::error!("Failed to flush the database to disk: {e}"),
+    }
+}
+
+/// An address to listen on, either an IP address that listens on `--port` or a full socket address
+/// with its own port. IPv6 addresses may be wrapped in brackets without a port, like `[::]`.
+fn parse_bind(value: &str) -> Result<(IpAddr, Option<u16>), String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+
+    let ip = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Ok((ip, None)),
+        Err(_) => Err(
+            "expected an IP address like `127.0.0.1` or `[::]`, or one with a port like `[::1]:9958`"
+                .to_string(),
+        ),
+    }
+}
+
+fn listen(addr: SocketAddr, only_v6: bool) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Listen on `port` at every address in `binds`, for servers other than the HTTP one.
+fn listen_on_port(
+    binds: &[(IpAddr, Option<u16>)],
+    port: u16,
+    protocol: &str,
+) -> anyhow::Result<Vec<std::net::TcpListener>> {
+    let mut ips = binds.iter().map(|(ip, _)| *ip).collect::<Vec<_>>();
+    ips.sort();
+    ips.dedup();
+
+    let mut listeners = Vec::new();
+    for ip in &ips {
+        let addr = SocketAddr::new(*ip, port);
+        let only_v6 = ip.is_ipv6() && ips.iter().any(|v| v.is_ipv4());
+        let listener = listen(addr, only_v6)
+            .with_context(|| format!("failed to listen for {protocol} on `{addr}`"))?;
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+/// Every route that operates on a single table.
+fn table_routes() -> Router<DbState> {
+    // Layered onto each handler rather than the router, so it runs before compression
+    let negotiate = || middleware::from_fn(negotiate::respond);
+
+    let routes = Router::new();
+    #[cfg(feature = "scripts")]
+    let routes = routes.route("/_exec/:script", post(scripts::exec.layer(negotiate())));
+
+    routes
+        .route(
+            "/_keys",
+            get(list_keys.layer(negotiate()).layer(CompressionLayer::new())),
+        )
+        .route(
+            "/_scan",
+            get(scan.layer(negotiate()).layer(CompressionLayer::new())),
+        )
+        .route(
+            "/_changes",
+            get(list_changes
+                .layer(negotiate())
+                .layer(CompressionLayer::new())),
+        )
+        .route("/_stats", get(stats.layer(negotiate())))
+        .route("/_stats/prefixes", get(prefix_stats.layer(negotiate())))
+        .route("/_prefix/:prefix", delete(delete_prefix.layer(negotiate())))
+        .route("/_count", get(count_keys.layer(negotiate())))
+        .route("/_batch", post(write_batch.layer(negotiate())))
+        .route("/_txn", post(transaction.layer(negotiate())))
+        .route(
+            "/_lock/:name",
+            post(lock::acquire.layer(negotiate())).delete(lock::release.layer(negotiate())),
+        )
+        .route(
+            "/_mget",
+            post(read_batch.layer(negotiate()).layer(CompressionLayer::new())),
+        )
+        .route(
+            "/_snapshot-read",
+            post(
+                snapshot_read
+                    .layer(negotiate())
+                    .layer(CompressionLayer::new()),
+            ),
+        )
+        .route("/", post(insert_key.layer(negotiate())))
+        .route(
+            "/:key",
+            get(read_key.layer(negotiate()).layer(CompressionLayer::new()))
+                .head(head_key)
+                .post(write_key.layer(negotiate()))
+                .patch(patch_key.layer(negotiate()))
+                .delete(delete_key.layer(negotiate())),
+        )
+        .route("/:key/_meta", get(read_key_meta.layer(negotiate())))
+        .route("/:key/_history", get(read_key_history.layer(negotiate())))
+        .route("/:key/_rollback", post(rollback_key.layer(negotiate())))
+        .route("/:key/incr", post(increment_key.layer(negotiate())))
+        .route("/:key/decr", post(decrement_key.layer(negotiate())))
+        .route("/:key/append", post(append_key.layer(negotiate())))
+        .route("/:key/push", post(push_key.layer(negotiate())))
+        .route("/:key/pop", post(pop_key.layer(negotiate())))
+        .route("/:key/len", get(list_len.layer(negotiate())))
+        .route("/:key/sadd", post(set_add.layer(negotiate())))
+        .route("/:key/srem", post(set_remove.layer(negotiate())))
+        .route(
+            "/:key/smembers",
+            get(set_members
+                .layer(negotiate())
+                .layer(CompressionLayer::new())),
+        )
+        .route(
+            "/:key/sismember/:member",
+            get(set_contains.layer(negotiate())),
+        )
+        .route("/_watch", get(watch_prefix))
+        .route("/_watch/:key", get(watch_key))
+        .route("/_ws", get(ws::upgrade))
+        .route("/_export", get(export::export))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    const TABLE_NAME: &str = "table-name";
+    const TABLE_NAME: &str = TABLE_NAME_ARG;
+    const BIND: &str = "bind";
     const PORT: &str = "port";
+    const UNIX_SOCKET: &str = "unix-socket";
+    const UNIX_SOCKET_MODE: &str = "unix-socket-mode";
     const DB_FILE_NAME: &str = "db-file-name";
+    const CREATE_IF_MISSING: &str = "create-if-missing";
+    const READ_ONLY: &str = "read-only";
+    const IN_MEMORY: &str = "in-memory";
+    const TTL_SWEEP_INTERVAL: &str = "ttl-sweep-interval";
+    const SHUTDOWN_TIMEOUT: &str = "shutdown-timeout";
+    const TABLES: &str = "tables";
+    const CREATE_TABLES: &str = "create-tables";
+    const JSON_TABLES: &str = "json-tables";
+    const TLS_CERT: &str = "tls-cert";
+    const TLS_KEY: &str = "tls-key";
+    const READ_TOKENS: &str = "read-tokens";
+    const WRITE_TOKENS: &str = "write-tokens";
+    const TOKEN_FILE: &str = "token-file";
+    const MAX_VALUE_SIZE: &str = "max-value-size";
+    const MAX_KEY_LENGTH: &str = "max-key-length";
+    const KEY_CHARS: &str = "key-chars";
+    const MAX_KEYS: &str = "max-keys";
+    const MAX_DB_SIZE: &str = "max-db-size";
+    const RATE_LIMIT: &str = "rate-limit";
+    const RATE_LIMIT_BURST: &str = "rate-limit-burst";
+    const RATE_LIMIT_BY: &str = "rate-limit-by";
+    const CACHE_ENTRIES: &str = "cache-entries";
+    const CACHE_BYTES: &str = "cache-bytes";
+    const GROUP_COMMIT_WINDOW: &str = "group-commit-window";
+    const IDEMPOTENCY_WINDOW: &str = "idempotency-window";
+    const DURABILITY: &str = "durability";
+    const HISTORY_VERSIONS: &str = "history-versions";
+    const ENCRYPTION_KEY_FILE: &str = "encryption-key-file";
+    const COMPRESS_ABOVE: &str = "compress-above";
+    const AUDIT: &str = "audit";
+    const CORS_ORIGINS: &str = "cors-origins";
+    const CORS_METHODS: &str = "cors-methods";
+    const CORS_ALLOW_AUTHORIZATION: &str = "cors-allow-authorization";
+    const SWAGGER_UI: &str = "swagger-ui";
+    const BASE_PATH: &str = "base-path";
+    const WEBHOOKS: &str = "webhooks";
+    const WEBHOOK_SECRET: &str = "webhook-secret";
+    const CHANGELOG_ENTRIES: &str = "changelog-entries";
+    const REPLICATE_FROM: &str = "replicate-from";
+    const REPLICATION_TOKEN: &str = "replication-token";
+    #[cfg(feature = "grpc")]
+    const GRPC_PORT: &str = "grpc-port";
+    const RESP_PORT: &str = "resp-port";
+    const MEMCACHED_PORT: &str = "memcached-port";
+    #[cfg(feature = "scripts")]
+    const SCRIPTS_DIR: &str = "scripts-dir";
+    #[cfg(feature = "scripts")]
+    const SCRIPT_FUEL: &str = "script-fuel";
+    const LOG_LEVEL: &str = "log-level";
+    const LOG_FORMAT: &str = "log-format";
+    const BACKUP_DIR: &str = "backup-dir";
+    const RESTORE_FROM: &str = "restore-from";
+    const BACKUP_S3_URL: &str = "backup-s3-url";
+    const BACKUP_S3_REGION: &str = "backup-s3-region";
+    const BACKUP_S3_ACCESS_KEY_ID: &str = "backup-s3-access-key-id";
+    const BACKUP_S3_SECRET_ACCESS_KEY: &str = "backup-s3-secret-access-key";
+    const BACKUP_S3_INTERVAL: &str = "backup-s3-interval";
+    const BACKUP_S3_RETENTION: &str = "backup-s3-retention";
     const TOKEN: &str = "token";
+    const CONFIG: &str = "config";
 
-    let args = clap::Command::new("ywkv")
-        .arg(
-            Arg::new(TABLE_NAME)
-                .long(TABLE_NAME)
-                .required(false)
-                .default_value("main")
-                .action(ArgAction::Set),
-        )
-        .arg(
-            Arg::new(PORT)
-                .long(PORT)
-                .required(false)
-                .default_value("9958")
-                .action(ArgAction::Set),
-        )
-        .arg(
-            Arg::new(DB_FILE_NAME)
-                .long(DB_FILE_NAME)
+    fn command(config: &toml::Table) -> clap::Command {
+        let command = clap::Command::new("ywkv")
+            .subcommand(import::command())
+            .subcommand(compact::command())
+            .subcommand(migrate::command());
+        #[cfg(feature = "client")]
+        let command = command
+            .subcommands(cli::commands())
+            .subcommand(bench::command());
+        #[cfg(feature = "grpc")]
+        let command = command.arg(config::layer(
+            Arg::new(GRPC_PORT)
+                .long(GRPC_PORT)
                 .required(false)
-                .default_value("ywkv.redb")
+                .value_parser(clap::value_parser!(u16))
                 .action(ArgAction::Set),
-        )
-        .arg(Arg::new(TOKEN).required(true).action(ArgAction::Set))
-        .get_matches();
+            config,
+        ));
+        #[cfg(feature = "scripts")]
+        let command = command
+            .arg(config::layer(
+                Arg::new(SCRIPTS_DIR)
+                    .long(SCRIPTS_DIR)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(SCRIPT_FUEL)
+                    .long(SCRIPT_FUEL)
+                    .required(false)
+                    .default_value(scripts::DEFAULT_FUEL.to_string())
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ));
+
+        command
+            .arg(config::layer(
+                Arg::new(TABLE_NAME)
+                    .long(TABLE_NAME)
+                    .required(false)
+                    .global(true)
+                    .default_value("main")
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BIND)
+                    .long(BIND)
+                    .visible_alias("host")
+                    .required(false)
+                    .default_value("0.0.0.0")
+                    .value_delimiter(',')
+                    .value_parser(parse_bind)
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(PORT)
+                    .long(PORT)
+                    .required(false)
+                    .default_value("9958")
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(RESP_PORT)
+                    .long(RESP_PORT)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u16))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(MEMCACHED_PORT)
+                    .long(MEMCACHED_PORT)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u16))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(UNIX_SOCKET)
+                    .long(UNIX_SOCKET)
+                    .required(false)
+                    .conflicts_with(TLS_CERT)
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(UNIX_SOCKET_MODE)
+                    .long(UNIX_SOCKET_MODE)
+                    .required(false)
+                    .default_value("660")
+                    .value_parser(|v: &str| match u32::from_str_radix(v, 8) {
+                        Ok(v) if v <= 0o777 => Ok(v),
+                        _ => Err("expected octal permissions like `660`"),
+                    })
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(DB_FILE_NAME)
+                    .long(DB_FILE_NAME)
+                    .required(false)
+                    .global(true)
+                    .default_value("ywkv.redb")
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CREATE_IF_MISSING)
+                    .long(CREATE_IF_MISSING)
+                    .required(false)
+                    .global(true)
+                    .default_value("true")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(READ_ONLY)
+                    .long(READ_ONLY)
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(IN_MEMORY)
+                    .long(IN_MEMORY)
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(ENCRYPTION_KEY_FILE)
+                    .long(ENCRYPTION_KEY_FILE)
+                    .required(false)
+                    .global(true)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(COMPRESS_ABOVE)
+                    .long(COMPRESS_ABOVE)
+                    .required(false)
+                    .global(true)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CORS_ORIGINS)
+                    .long(CORS_ORIGINS)
+                    .required(false)
+                    .value_delimiter(',')
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CORS_METHODS)
+                    .long(CORS_METHODS)
+                    .required(false)
+                    .value_delimiter(',')
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CORS_ALLOW_AUTHORIZATION)
+                    .long(CORS_ALLOW_AUTHORIZATION)
+                    .required(false)
+                    .default_value("true")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(SWAGGER_UI)
+                    .long(SWAGGER_UI)
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BASE_PATH)
+                    .long(BASE_PATH)
+                    .required(false)
+                    .value_parser(base_path::parse)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(WEBHOOKS)
+                    .long(WEBHOOKS)
+                    .required(false)
+                    .value_delimiter(',')
+                    .value_parser(clap::value_parser!(webhook::Target))
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(WEBHOOK_SECRET)
+                    .long(WEBHOOK_SECRET)
+                    .hide_env_values(true)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CHANGELOG_ENTRIES)
+                    .long(CHANGELOG_ENTRIES)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(REPLICATE_FROM)
+                    .long(REPLICATE_FROM)
+                    .required(false)
+                    .value_parser(clap::value_parser!(Uri))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(REPLICATION_TOKEN)
+                    .long(REPLICATION_TOKEN)
+                    .required(false)
+                    .hide_env_values(true)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(LOG_LEVEL)
+                    .long(LOG_LEVEL)
+                    .required(false)
+                    .default_value("info")
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(LOG_FORMAT)
+                    .long(LOG_FORMAT)
+                    .required(false)
+                    .default_value("text")
+                    .value_parser(["text", "json"])
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(AUDIT)
+                    .long(AUDIT)
+                    .required(false)
+                    .global(true)
+                    .default_value("false")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(TTL_SWEEP_INTERVAL)
+                    .long(TTL_SWEEP_INTERVAL)
+                    .required(false)
+                    .default_value("60")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(SHUTDOWN_TIMEOUT)
+                    .long(SHUTDOWN_TIMEOUT)
+                    .required(false)
+                    .default_value("30")
+                    .value_parser(clap::value_parser!(u64))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(TABLES)
+                    .long(TABLES)
+                    .required(false)
+                    .value_delimiter(',')
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CREATE_TABLES)
+                    .long(CREATE_TABLES)
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(clap::value_parser!(bool))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(JSON_TABLES)
+                    .long(JSON_TABLES)
+                    .required(false)
+                    .value_delimiter(',')
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(TLS_CERT)
+                    .long(TLS_CERT)
+                    .required(false)
+                    .requires(TLS_KEY)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(TLS_KEY)
+                    .long(TLS_KEY)
+                    .required(false)
+                    .requires(TLS_CERT)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(READ_TOKENS)
+                    .long(READ_TOKENS)
+                    .required(false)
+                    .value_delimiter(',')
+                    .hide_env_values(true)
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(WRITE_TOKENS)
+                    .long(WRITE_TOKENS)
+                    .required(false)
+                    .value_delimiter(',')
+                    .hide_env_values(true)
+                    .action(ArgAction::Append),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(TOKEN_FILE)
+                    .long(TOKEN_FILE)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(MAX_VALUE_SIZE)
+                    .long(MAX_VALUE_SIZE)
+                    .required(false)
+                    // Matches the body limit axum applies by default
+                    .default_value("2097152")
+                    .value_parser(clap::value_parser!(usize))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(MAX_KEY_LENGTH)
+                    .long(MAX_KEY_LENGTH)
+                    .required(false)
+                    .value_parser(clap::value_parser!(usize))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(KEY_CHARS)
+                    .long(KEY_CHARS)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(MAX_KEYS)
+                    .long(MAX_KEYS)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(MAX_DB_SIZE)
+                    .long(MAX_DB_SIZE)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(RATE_LIMIT)
+                    .long(RATE_LIMIT)
+                    .required(false)
+                    .value_parser(clap::value_parser!(f64))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(RATE_LIMIT_BURST)
+                    .long(RATE_LIMIT_BURST)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u32).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(RATE_LIMIT_BY)
+                    .long(RATE_LIMIT_BY)
+                    .required(false)
+                    .default_value("token")
+                    .value_parser(["token", "ip"])
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CACHE_ENTRIES)
+                    .long(CACHE_ENTRIES)
+                    .required(false)
+                    .value_parser(clap::value_parser!(NonZeroUsize))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(CACHE_BYTES)
+                    .long(CACHE_BYTES)
+                    .required(false)
+                    .value_parser(clap::value_parser!(NonZeroUsize))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(GROUP_COMMIT_WINDOW)
+                    .long(GROUP_COMMIT_WINDOW)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(IDEMPOTENCY_WINDOW)
+                    .long(IDEMPOTENCY_WINDOW)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(DURABILITY)
+                    .long(DURABILITY)
+                    .global(true)
+                    .required(false)
+                    .default_value("immediate")
+                    .value_parser(["immediate", "eventual", "none"])
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(HISTORY_VERSIONS)
+                    .long(HISTORY_VERSIONS)
+                    .required(false)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_DIR)
+                    .long(BACKUP_DIR)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(RESTORE_FROM)
+                    .long(RESTORE_FROM)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_S3_URL)
+                    .long(BACKUP_S3_URL)
+                    .required(false)
+                    .value_parser(clap::value_parser!(s3::Location))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_S3_REGION)
+                    .long(BACKUP_S3_REGION)
+                    .required(false)
+                    .default_value("us-east-1")
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_S3_ACCESS_KEY_ID)
+                    .long(BACKUP_S3_ACCESS_KEY_ID)
+                    .required(false)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_S3_SECRET_ACCESS_KEY)
+                    .long(BACKUP_S3_SECRET_ACCESS_KEY)
+                    .required(false)
+                    .hide_env_values(true)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_S3_INTERVAL)
+                    .long(BACKUP_S3_INTERVAL)
+                    .required(false)
+                    .default_value("3600")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(BACKUP_S3_RETENTION)
+                    .long(BACKUP_S3_RETENTION)
+                    .required(false)
+                    .default_value("24")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(config::layer(
+                Arg::new(TOKEN)
+                    .required(false)
+                    .hide_env_values(true)
+                    .action(ArgAction::Set),
+                config,
+            ))
+            .arg(
+                Arg::new(CONFIG)
+                    .long(CONFIG)
+                    .env("YWKV_CONFIG")
+                    .required(false)
+                    .action(ArgAction::Set),
+            )
+    }
+
+    // Values from the config file become defaults, so it has to be loaded before the real parse
+    fn load_config() -> anyhow::Result<toml::Table> {
+        let config = match command(&toml::Table::new())
+            .ignore_errors(true)
+            .get_matches()
+            .get_one::<String>(CONFIG)
+        {
+            Some(path) => config::load(path)?,
+            None => toml::Table::new(),
+        };
+        config::validate(&config, &command(&toml::Table::new()))?;
+
+        Ok(config)
+    }
+
+    /// The settings [reload::Reloader] can change while running.
+    fn load_settings(args: &clap::ArgMatches) -> anyhow::Result<reload::Settings> {
+        let tls_cert = args.get_one::<String>(TLS_CERT);
+        let tls_key = args.get_one::<String>(TLS_KEY);
+        let read_tokens = args.get_many::<String>(READ_TOKENS).unwrap_or_default();
+        let write_tokens = args.get_many::<String>(WRITE_TOKENS).unwrap_or_default();
+        let token_file = args.get_one::<String>(TOKEN_FILE);
+        let rate_limit = args.get_one::<f64>(RATE_LIMIT).copied();
+        let rate_limit_burst = args.get_one::<u32>(RATE_LIMIT_BURST).copied();
+        let rate_limit_by = match args.get_one::<String>(RATE_LIMIT_BY).unwrap().as_str() {
+            "ip" => LimitBy::Ip,
+            _ => LimitBy::Token,
+        };
+        let log_level = args.get_one::<String>(LOG_LEVEL).unwrap();
+        let token = args.get_one::<String>(TOKEN);
+
+        // Config file values don't go through the command line's check that these are used
+        // together
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => anyhow::bail!("`{TLS_CERT}` and `{TLS_KEY}` must be set together"),
+        };
+        // Checked here rather than by clap so the token file can also come from the config file
+        if token.is_none() && token_file.is_none() {
+            anyhow::bail!("either `{TOKEN}` or `{TOKEN_FILE}` is required");
+        }
+
+        let rate_limit = match rate_limit {
+            Some(rate) if !(rate > 0.0 && rate.is_finite()) => {
+                anyhow::bail!("`{RATE_LIMIT}` must be a positive number of requests per second")
+            }
+            // Allow about a second's worth of requests at once unless told otherwise
+            Some(rate) => Some(Limit::new(
+                rate,
+                rate_limit_burst.unwrap_or(rate.ceil() as u32).max(1),
+                rate_limit_by,
+            )),
+            None => None,
+        };
+
+        let tokens = read_tokens
+            .map(|v| (v.clone(), Role::ReadOnly))
+            .chain(write_tokens.map(|v| (v.clone(), Role::ReadWrite)))
+            .chain(token.map(|v| (v.clone(), Role::Admin)))
+            .collect();
+
+        Ok(reload::Settings {
+            tokens,
+            token_file: token_file.map(PathBuf::from),
+            rate_limit,
+            log_level: log_level.clone(),
+            tls,
+        })
+    }
+
+    let args = command(&load_config()?).get_matches();
+
+    let log_level = args.get_one::<String>(LOG_LEVEL).unwrap();
+    let log_format = args.get_one::<String>(LOG_FORMAT).unwrap();
+    logging::init(log_level, log_format == "json")?;
 
     let table_name = args.get_one::<String>(TABLE_NAME).unwrap();
-    let port = args.get_one::<String>(PORT).unwrap();
     let db_file_name = args.get_one::<String>(DB_FILE_NAME).unwrap();
-    let token = args.get_one::<String>(TOKEN).unwrap();
+    let create_if_missing = *args.get_one::<bool>(CREATE_IF_MISSING).unwrap();
+    let read_only = *args.get_one::<bool>(READ_ONLY).unwrap();
+    let in_memory = *args.get_one::<bool>(IN_MEMORY).unwrap();
+    let encryption_keys = match args.get_one::<String>(ENCRYPTION_KEY_FILE) {
+        Some(path) => Some(encryption::load_keys(std::path::Path::new(path))?),
+        None => None,
+    };
+    let compress_above = args.get_one::<u64>(COMPRESS_ABOVE).map(|v| *v as usize);
+    let audit = *args.get_one::<bool>(AUDIT).unwrap();
+    let durability_arg = args.get_one::<String>(DURABILITY).unwrap();
+    let durability = match durability_arg.as_str() {
+        "eventual" => Durability::Eventual,
+        "none" => Durability::None,
+        _ => Durability::Immediate,
+    };
+    match args.subcommand() {
+        Some((import::NAME, args)) => {
+            return import::run(
+                args,
+                db_file_name,
+                table_name,
+                create_if_missing,
+                encryption_keys,
+                compress_above,
+                audit,
+                durability,
+            )
+        }
+        Some((compact::NAME, _)) => return compact::run(db_file_name, table_name),
+        Some((migrate::NAME, args)) => return migrate::run(args),
+        #[cfg(feature = "client")]
+        Some((bench::NAME, args)) => return bench::run(args, durability_arg).await,
+        #[cfg(feature = "client")]
+        Some((name, args)) if cli::NAMES.contains(&name) => {
+            return cli::run(name, args, table_name).await
+        }
+        _ => {}
+    }
+
+    let settings = load_settings(&args)?;
+
+    let binds = args
+        .get_many::<(IpAddr, Option<u16>)>(BIND)
+        .unwrap_or_default()
+        .copied()
+        .collect::<Vec<_>>();
+    let port = args.get_one::<String>(PORT).unwrap();
+    let unix_socket = args.get_one::<PathBuf>(UNIX_SOCKET);
+    let unix_socket_mode = *args.get_one::<u32>(UNIX_SOCKET_MODE).unwrap();
+    let ttl_sweep_interval = *args.get_one::<u64>(TTL_SWEEP_INTERVAL).unwrap();
+    let shutdown_timeout = Duration::from_secs(*args.get_one::<u64>(SHUTDOWN_TIMEOUT).unwrap());
+    let tables = args
+        .get_many::<String>(TABLES)
+        .unwrap_or_default()
+        .cloned()
+        .collect::<HashSet<_>>();
+    let create_tables = *args.get_one::<bool>(CREATE_TABLES).unwrap();
+    let json_tables = args
+        .get_many::<String>(JSON_TABLES)
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    if unix_socket.is_some() && settings.tls.is_some() {
+        anyhow::bail!("`{UNIX_SOCKET}` can't be used with `{TLS_CERT}`");
+    }
+    #[cfg(feature = "grpc")]
+    let grpc_port = args.get_one::<u16>(GRPC_PORT).copied();
+    #[cfg(feature = "grpc")]
+    if unix_socket.is_some() && grpc_port.is_some() {
+        anyhow::bail!("`{UNIX_SOCKET}` can't be used with `{GRPC_PORT}`");
+    }
+    let resp_port = args.get_one::<u16>(RESP_PORT).copied();
+    if unix_socket.is_some() && resp_port.is_some() {
+        anyhow::bail!("`{UNIX_SOCKET}` can't be used with `{RESP_PORT}`");
+    }
+    let memcached_port = args.get_one::<u16>(MEMCACHED_PORT).copied();
+    if unix_socket.is_some() && memcached_port.is_some() {
+        anyhow::bail!("`{UNIX_SOCKET}` can't be used with `{MEMCACHED_PORT}`");
+    }
+    let max_value_size = *args.get_one::<usize>(MAX_VALUE_SIZE).unwrap();
+    let max_key_length = args.get_one::<usize>(MAX_KEY_LENGTH).copied();
+    let key_chars = args.get_one::<String>(KEY_CHARS);
+    let quota = ywkv::Quota {
+        max_keys: args.get_one::<u64>(MAX_KEYS).copied(),
+        max_size: args.get_one::<u64>(MAX_DB_SIZE).copied(),
+    };
+    let cache_entries = args.get_one::<NonZeroUsize>(CACHE_ENTRIES).copied();
+    let cache_bytes = args.get_one::<NonZeroUsize>(CACHE_BYTES).copied();
+    let group_commit_window = args.get_one::<u64>(GROUP_COMMIT_WINDOW).copied();
+    let idempotency_window = args.get_one::<u64>(IDEMPOTENCY_WINDOW).copied();
+    let history_versions = args.get_one::<u64>(HISTORY_VERSIONS).copied();
+    let cors_origins = args
+        .get_many::<String>(CORS_ORIGINS)
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    let cors_methods = args
+        .get_many::<String>(CORS_METHODS)
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    let cors_allow_authorization = *args.get_one::<bool>(CORS_ALLOW_AUTHORIZATION).unwrap();
+    let swagger_ui = *args.get_one::<bool>(SWAGGER_UI).unwrap();
+    let base_path = args
+        .get_one::<String>(BASE_PATH)
+        .filter(|v| !v.is_empty())
+        .cloned();
+    let webhooks = args
+        .get_many::<webhook::Target>(WEBHOOKS)
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    let webhook_secret = args.get_one::<String>(WEBHOOK_SECRET);
+    if !webhooks.is_empty() && webhook_secret.is_none() {
+        anyhow::bail!("`{WEBHOOKS}` needs `{WEBHOOK_SECRET}` to sign deliveries with");
+    }
+    let backup_dir = args.get_one::<String>(BACKUP_DIR);
+    #[cfg(feature = "scripts")]
+    let scripts_dir = args.get_one::<String>(SCRIPTS_DIR);
+    #[cfg(feature = "scripts")]
+    let script_fuel = *args.get_one::<u64>(SCRIPT_FUEL).unwrap();
+    let restore_from = args.get_one::<String>(RESTORE_FROM);
+    let backup_s3 = match args.get_one::<s3::Location>(BACKUP_S3_URL) {
+        Some(location) => {
+            let (Some(access_key_id), Some(secret_access_key)) = (
+                args.get_one::<String>(BACKUP_S3_ACCESS_KEY_ID),
+                args.get_one::<String>(BACKUP_S3_SECRET_ACCESS_KEY),
+            ) else {
+                anyhow::bail!(
+                    "`{BACKUP_S3_URL}` needs `{BACKUP_S3_ACCESS_KEY_ID}` and `{BACKUP_S3_SECRET_ACCESS_KEY}`"
+                );
+            };
+
+            Some(s3::Schedule {
+                location: location.clone(),
+                region: args.get_one::<String>(BACKUP_S3_REGION).unwrap().clone(),
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+                interval: Duration::from_secs(*args.get_one::<u64>(BACKUP_S3_INTERVAL).unwrap()),
+                retention: *args.get_one::<u64>(BACKUP_S3_RETENTION).unwrap() as usize,
+            })
+        }
+        None => None,
+    };
+    let changelog_entries = args.get_one::<u64>(CHANGELOG_ENTRIES).copied();
+    let replicate_from = args.get_one::<Uri>(REPLICATE_FROM);
+    let replication_token = args.get_one::<String>(REPLICATION_TOKEN);
+    if let Some(primary) = replicate_from {
+        if !matches!(primary.scheme_str(), Some("http" | "https")) || primary.host().is_none() {
+            anyhow::bail!("`{REPLICATE_FROM}` must be an absolute http or https URL");
+        }
+        if replication_token.is_none() {
+            anyhow::bail!("`{REPLICATE_FROM}` needs `{REPLICATION_TOKEN}` to read changes with");
+        }
+        if restore_from.is_some() {
+            anyhow::bail!("`{RESTORE_FROM}` can't be used with `{REPLICATE_FROM}`");
+        }
+    }
+    if read_only && replicate_from.is_some() {
+        anyhow::bail!(
+            "`{READ_ONLY}` can't be used with `{REPLICATE_FROM}`, replicas are already read-only"
+        );
+    }
+    if read_only && in_memory {
+        anyhow::bail!("`{IN_MEMORY}` can't be used with `{READ_ONLY}`");
+    }
+    if read_only && restore_from.is_some() {
+        anyhow::bail!("`{RESTORE_FROM}` can't be used with `{READ_ONLY}`");
+    }
+
+    let tables = if create_tables {
+        TableAccess::Any
+    } else {
+        TableAccess::Only(Arc::new(tables))
+    };
 
-    // Intentionally leaking the String here in order to create a static TableDefinition at runtime
-    let state = DbState::new(db_file_name, Box::leak(table_name.clone().into_boxed_str()))?;
+    let tokens = Tokens::new(settings.load_tokens()?);
+    let limiter = RateLimiter::new(settings.rate_limit);
 
-    let app = Router::new().route(
-        "/:key",
-        get(read_key.layer(CompressionLayer::new()))
-            .post(write_key)
-            .layer(ValidateRequestHeaderLayer::bearer(token))
-            .with_state(state),
+    let tls = match &settings.tls {
+        Some((cert, key)) => {
+            let config = tls::load(cert, key).await?;
+            tokio::spawn(tls::watch(config.clone(), cert.clone(), key.clone()));
+
+            Some(config)
+        }
+        None => None,
+    };
+
+    let reloader = reload::Reloader::new(
+        settings,
+        tokens.clone(),
+        limiter.clone(),
+        tls.clone(),
+        || {
+            // Only the first line, since the rest is usage for the command line
+            let args = command(&load_config()?).try_get_matches().map_err(|e| {
+                anyhow::anyhow!("{}", e.to_string().lines().next().unwrap_or_default())
+            })?;
+            load_settings(&args)
+        },
     );
+    tokio::spawn(reload::watch(reloader.clone()));
+
+    let limits = Limits::new(
+        max_value_size,
+        max_key_length,
+        key_chars.map(String::as_str),
+    )
+    .with_context(|| format!("invalid `{KEY_CHARS}`"))?;
+
+    let mut state = DbState::new(
+        (!in_memory).then_some(db_file_name.as_str()),
+        table_name,
+        create_if_missing,
+        read_only,
+        tables,
+        limits,
+    )?;
+    state.reloader = Some(reloader);
+    state.set_durability(durability);
+    state.set_quota(quota);
+    state.require_json(json_tables);
+    if cache_entries.is_some() || cache_bytes.is_some() {
+        state.enable_cache(cache_entries, cache_bytes.map(NonZeroUsize::get));
+    }
+    if let Some(keys) = encryption_keys {
+        state.enable_encryption(keys);
+    }
+    state.enable_compression(compress_above);
+    if audit {
+        state.enable_audit();
+    }
+    if let Some(versions) = history_versions {
+        state.keep_history(versions as usize);
+    }
+    if let Some(entries) = changelog_entries {
+        state.keep_changelog(entries as usize);
+        state.changelog_entries = entries as usize;
+    }
+    if let Some(window) = group_commit_window {
+        state.group = Some(GroupCommit::spawn(Duration::from_millis(window)));
+    }
+    if let Some(dir) = backup_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create backup directory `{dir}`"))?;
+        state.backup_dir = Some(PathBuf::from(dir).into());
+    }
+    #[cfg(feature = "scripts")]
+    {
+        if let Some(dir) = scripts_dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create scripts directory `{dir}`"))?;
+        }
+        state.scripts = scripts::Scripts::new(scripts_dir.map(PathBuf::from), script_fuel)?;
+    }
+    if let Some(path) = restore_from {
+        state
+            .restore(path)
+            .with_context(|| format!("failed to restore from `{path}`"))?;
+        tracing::info!("Restored from `{path}`");
+    }
+    if let (Some(primary), Some(token)) = (replicate_from, replication_token) {
+        state.replication =
+            replication::Replication::follow(state.db.clone(), primary.clone(), token.clone());
+        // Everything else goes through a read-only handle, so only the primary's changes are
+        // written
+        state.set_read_only(true);
+        tracing::info!(%primary, "Replicating");
+    }
+    if let Some(secret) = webhook_secret.filter(|_| !webhooks.is_empty()) {
+        state.webhooks = webhook::Webhooks::start(&state.db, webhooks, secret);
+    }
+    if let Some(schedule) = backup_s3 {
+        state.s3_backups = s3::Backups::start(state.db.clone(), schedule);
+    }
 
     async fn shutdown() {
         let ctrlc = async {
@@ -183,18 +3256,325 @@ async fn main() -> anyhow::Result<()> {
             _ = terminate => {}
         }
 
-        println!("Starting graceful shutdown");
+        tracing::info!("Starting graceful shutdown");
     }
 
-    println!("Starting server!");
+    // Every listener and background task stops on the same signal
+    let (stop, stopping) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        shutdown().await;
+        let _ = stop.send(());
+    });
+    let stopped = move || {
+        let mut stopping = stopping.clone();
+        async move {
+            let _ = stopping.changed().await;
+        }
+    };
+    // Requests still running this long after the signal are cut off
+    let gave_up = {
+        let stopped = stopped.clone();
+        move || {
+            let stopped = stopped();
+            async move {
+                stopped.await;
+                tokio::time::sleep(shutdown_timeout).await;
+            }
+        }
+    };
 
-    axum::Server::bind(&SocketAddr::V4(SocketAddrV4::new(
-        "0.0.0.0".parse()?,
-        port.parse()?,
-    )))
-    .serve(app.into_make_service())
-    .with_graceful_shutdown(shutdown())
-    .await?;
+    // Expired keys are already hidden from reads, this just reclaims the space they use. Replicas
+    // get the purges from the primary's changelog instead, and read-only servers leave them be. A
+    // purge that has started is finished before shutting down.
+    let mut sweeper = None;
+    if replicate_from.is_none() && !read_only {
+        sweeper = Some(tokio::spawn({
+            let state = state.clone();
+            let stopped = stopped.clone();
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(ttl_sweep_interval));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stopped() => break,
+                    }
+                    let purged = blocking({
+                        let state = state.clone();
+                        move || state.purge_expired()
+                    })
+                    .await;
+                    match purged {
+                        Ok(0) => {}
+                        Ok(purged) => tracing::info!("Purged {purged} expired keys"),
+                        Err(e) => tracing::error!("Failed to purge expired keys: {e}"),
+                    }
+                }
+            }
+        }));
+    }
+
+    #[cfg(feature = "grpc")]
+    let grpc = grpc::Service::new(state.clone(), tokens.clone(), limiter.clone());
+    let resp = resp::Service::new(state.clone(), tokens.clone(), limiter.clone());
+    let memcached = memcached::Service::new(state.clone(), tokens.clone(), limiter.clone());
+
+    let mut routes = Router::new()
+        .route("/_metrics", get(metrics::render))
+        .route("/_admin/backup", post(admin::backup))
+        .route("/_admin/backup/s3", get(s3::backups))
+        .route("/_admin/compact", post(admin::compact))
+        .route("/_admin/audit", get(admin::audit))
+        .route("/_admin/tables", get(admin::tables))
+        .route(
+            "/_admin/tables/:table",
+            post(admin::create_table).delete(admin::drop_table),
+        )
+        .route(
+            "/_admin/tables/:table/truncate",
+            post(admin::truncate_table),
+        )
+        .route("/_admin/reload", post(admin::reload))
+        .route("/_admin/webhooks", get(webhook::deliveries))
+        .route("/_admin/replication", get(replication::status))
+        .route("/_admin/replication/changes", get(replication::changes))
+        .route("/_admin/replication/snapshot", get(replication::snapshot))
+        // Snapshots are usually much larger than a single value
+        .route(
+            "/_admin/restore",
+            post(admin::restore.layer(DefaultBodyLimit::disable())),
+        )
+        .merge(table_routes())
+        .nest("/_table/:table", table_routes());
+    #[cfg(feature = "scripts")]
+    {
+        routes = routes.route("/_admin/scripts", get(scripts::list)).route(
+            "/_admin/scripts/:script",
+            axum::routing::put(scripts::upload).delete(scripts::remove),
+        );
+    }
+    if replicate_from.is_some() {
+        routes = routes.layer(middleware::from_fn_with_state(
+            "this server is a read-only replica, write to the primary instead",
+            replication::reject_writes,
+        ));
+    } else if read_only {
+        routes = routes.layer(middleware::from_fn_with_state(
+            "this server is read-only",
+            replication::reject_writes,
+        ));
+    }
+    if let Some(window) = idempotency_window {
+        routes = routes.layer(middleware::from_fn_with_state(
+            idempotency::Idempotency::new(Duration::from_secs(window), max_value_size),
+            idempotency::replay,
+        ));
+    }
+    let mut app = routes
+        .layer(DefaultBodyLimit::max(max_value_size))
+        .layer(middleware::from_fn_with_state(limiter, ratelimit::limit))
+        .layer(middleware::from_fn_with_state(tokens, auth::authorize))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ));
+    // Added after the authentication layer, since the Swagger UI page can't send a token
+    app = app.route(
+        "/_openapi.json",
+        get({
+            let base_path = base_path.clone();
+            move || openapi::spec(base_path)
+        }),
+    );
+    if swagger_ui {
+        app = app.route("/_docs", get(openapi::docs));
+    }
+    // Outside of authentication, since preflight requests never include a token
+    if !cors_origins.is_empty() {
+        app = app.layer(
+            cors::layer(&cors_origins, &cors_methods, cors_allow_authorization)
+                .with_context(|| format!("invalid `{CORS_ORIGINS}` or `{CORS_METHODS}`"))?,
+        );
+    }
+    let (db, group) = (state.db.clone(), state.group.clone());
+    let mut app = app
+        .layer(middleware::from_fn(logging::trace))
+        .layer(middleware::from_fn(request_id::assign))
+        .with_state(state);
+    if let Some(base_path) = base_path {
+        app = base_path::mount(base_path, app);
+    }
+
+    if let Some(path) = unix_socket {
+        tracing::info!(path = %path.display(), "Starting server");
+
+        #[cfg(unix)]
+        tokio::select! {
+            res = unix_socket::serve(app, path, unix_socket_mode, stopped()) => res?,
+            _ = gave_up() => tracing::warn!(
+                "Requests were still running after {}s, stopping anyway",
+                shutdown_timeout.as_secs()
+            ),
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("`{UNIX_SOCKET}` is only supported on Unix");
+
+        drain(&db, group.as_ref(), sweeper, shutdown_timeout).await;
+        tracing::info!("Server stopped");
+        logging::shutdown();
+
+        return Ok(());
+    }
+
+    // Bind everything up front so a bad address fails startup before anything is served
+    let port = port.parse::<u16>()?;
+    let addrs = binds
+        .iter()
+        .map(|(ip, port_override)| SocketAddr::new(*ip, port_override.unwrap_or(port)))
+        .collect::<Vec<_>>();
+    let mut listeners = Vec::new();
+    for addr in &addrs {
+        // `[::]` also accepts IPv4 by default, which would clash with `0.0.0.0` on the same port
+        let only_v6 =
+            addr.is_ipv6() && addrs.iter().any(|v| v.is_ipv4() && v.port() == addr.port());
+        let listener =
+            listen(*addr, only_v6).with_context(|| format!("failed to listen on `{addr}`"))?;
+        listeners.push(listener);
+    }
+    #[cfg(feature = "grpc")]
+    let grpc_listeners = match grpc_port {
+        Some(port) => listen_on_port(&binds, port, "gRPC")?,
+        None => Vec::new(),
+    };
+    let resp_listeners = match resp_port {
+        Some(port) => listen_on_port(&binds, port, "RESP")?,
+        None => Vec::new(),
+    };
+    let memcached_listeners = match memcached_port {
+        Some(port) => listen_on_port(&binds, port, "memcached")?,
+        None => Vec::new(),
+    };
+
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        let addr = listener.local_addr()?;
+        tracing::info!(%addr, tls = tls.is_some(), "Starting server");
+
+        let app = app
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        match &tls {
+            Some(config) => {
+                let handle = axum_server::Handle::new();
+                tokio::spawn({
+                    let handle = handle.clone();
+                    let stopped = stopped();
+                    async move {
+                        stopped.await;
+                        handle.graceful_shutdown(None);
+                    }
+                });
+
+                let server = axum_server::from_tcp_rustls(listener, config.clone()).handle(handle);
+                servers.spawn(async move { anyhow::Ok(server.serve(app).await?) });
+            }
+            None => {
+                let server = axum::Server::from_tcp(listener)?
+                    .serve(app)
+                    .with_graceful_shutdown(stopped());
+                servers.spawn(async move { anyhow::Ok(server.await?) });
+            }
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    for listener in grpc_listeners {
+        tracing::info!(addr = %listener.local_addr()?, tls = tls.is_some(), "Starting gRPC server");
+
+        servers.spawn(grpc::serve(listener, grpc.clone(), tls.clone(), stopped()));
+    }
+
+    for listener in resp_listeners {
+        tracing::info!(addr = %listener.local_addr()?, tls = tls.is_some(), "Starting RESP server");
+
+        servers.spawn(resp::serve(
+            listener,
+            resp.clone(),
+            tls.clone(),
+            stopped.clone(),
+        ));
+    }
+
+    for listener in memcached_listeners {
+        tracing::info!(addr = %listener.local_addr()?, tls = tls.is_some(), "Starting memcached server");
+
+        servers.spawn(memcached::serve(
+            listener,
+            memcached.clone(),
+            tls.clone(),
+            stopped.clone(),
+        ));
+    }
+
+    let mut gave_up = std::pin::pin!(gave_up());
+    loop {
+        tokio::select! {
+            res = servers.join_next() => match res {
+                Some(res) => res??,
+                None => break,
+            },
+            _ = &mut gave_up => {
+                tracing::warn!(
+                    "Requests were still running after {}s, stopping anyway",
+                    shutdown_timeout.as_secs()
+                );
+                servers.abort_all();
+                break;
+            }
+        }
+    }
+
+    drain(&db, group.as_ref(), sweeper, shutdown_timeout).await;
+    tracing::info!("Server stopped");
+    logging::shutdown();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &[u8] = br#"{"a":{"b":[1,{"c":"d"}]},"e/f":true,"g~h":null}"#;
+
+    fn status(res: Result<Vec<u8>, (StatusCode, Json<Response>)>) -> StatusCode {
+        res.map_or_else(|(status, _)| status, |_| StatusCode::OK)
+    }
+
+    #[test]
+    fn json_pointer_reads_nested_values() {
+        assert_eq!(json_pointer("k", DOCUMENT, "").unwrap(), DOCUMENT);
+        assert_eq!(
+            json_pointer("k", DOCUMENT, "/a/b").unwrap(),
+            br#"[1,{"c":"d"}]"#
+        );
+        assert_eq!(json_pointer("k", DOCUMENT, "/a/b/1/c").unwrap(), br#""d""#);
+        assert_eq!(json_pointer("k", DOCUMENT, "/e~1f").unwrap(), b"true");
+        assert_eq!(json_pointer("k", DOCUMENT, "/g~0h").unwrap(), b"null");
+    }
+
+    #[test]
+    fn json_pointer_rejects_bad_pointers_and_documents() {
+        assert_eq!(
+            status(json_pointer("k", DOCUMENT, "a")),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(json_pointer("k", DOCUMENT, "/a/b/2")),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(json_pointer("k", b"not json", "/a")),
+            StatusCode::CONFLICT
+        );
+    }
+}
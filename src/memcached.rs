@@ -0,0 +1,537 @@
+//! A listener speaking the memcached text protocol for the default table: `get`, `set`,
+//! `delete`, `stats`, `version` and `quit`.
+//!
+//! memcached has no tokens, so connections authenticate the way memcached does with `-Y`: by
+//! first setting any key to `<username> <token>`, where the username is ignored.
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{http::StatusCode, Json};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use ywkv::{self, Actor, Response, Value, YwkvError};
+
+use crate::{
+    auth::{Fingerprint, Role, Tokens},
+    blocking,
+    ratelimit::RateLimiter,
+    tcp, DbState,
+};
+
+/// Expiration times up to this many seconds are relative, anything later is a Unix timestamp.
+const MAX_RELATIVE_EXPIRY: i64 = 60 * 60 * 24 * 30;
+
+/// Where the flags memcached clients store with each value are kept in its content type, like
+/// `application/octet-stream; flags=1`. Values without it have flags of 0.
+const FLAGS_PARAM: &str = "flags=";
+
+#[derive(Clone)]
+pub struct Service {
+    state: DbState,
+    tokens: Tokens,
+    limiter: RateLimiter,
+    stats: Arc<Stats>,
+}
+
+impl Service {
+    pub fn new(state: DbState, tokens: Tokens, limiter: RateLimiter) -> Self {
+        Self {
+            state,
+            tokens,
+            limiter,
+            stats: Arc::new(Stats::new()),
+        }
+    }
+}
+
+/// Counters for `stats`, shared by every connection.
+struct Stats {
+    started: Instant,
+    curr_connections: AtomicU64,
+    total_connections: AtomicU64,
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    delete_hits: AtomicU64,
+    delete_misses: AtomicU64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            curr_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+            delete_hits: AtomicU64::new(0),
+            delete_misses: AtomicU64::new(0),
+        }
+    }
+}
+
+fn add(counter: &AtomicU64, amount: u64) {
+    counter.fetch_add(amount, Ordering::Relaxed);
+}
+
+/// Serve memcached on `listener` until `stopped` resolves, over TLS if a certificate is
+/// configured. Connections are closed after the command they're running when the server stops.
+pub async fn serve<F>(
+    listener: std::net::TcpListener,
+    service: Service,
+    tls: Option<RustlsConfig>,
+    stopped: impl Fn() -> F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tcp::serve(
+        listener,
+        "memcached",
+        tls,
+        stopped,
+        move |stream, addr, stopped| {
+            let service = service.clone();
+            async move {
+                add(&service.stats.curr_connections, 1);
+                add(&service.stats.total_connections, 1);
+                let res = handle(stream, addr, &service, stopped).await;
+                service
+                    .stats
+                    .curr_connections
+                    .fetch_sub(1, Ordering::Relaxed);
+
+                res
+            }
+        },
+    )
+    .await
+}
+
+/// The token a connection authenticated with.
+struct Session {
+    token: String,
+    role: Role,
+    fingerprint: Fingerprint,
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    addr: SocketAddr,
+    service: &Service,
+    stopped: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let mut session = None;
+    let mut stopped = pin!(stopped);
+    loop {
+        let line = tokio::select! {
+            biased;
+            _ = &mut stopped => break,
+            line = tcp::read_line(&mut reader) => line,
+        };
+        let line = match line {
+            Ok(Some(v)) => v,
+            Ok(None) => break,
+            // Like memcached, give up on a connection sending lines that are too long
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                writer
+                    .write_all(format!("CLIENT_ERROR {e}\r\n").as_bytes())
+                    .await?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let args = line
+            .split(|v| v.is_ascii_whitespace())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_lowercase(), args),
+            None => {
+                writer.write_all(b"ERROR\r\n").await?;
+                continue;
+            }
+        };
+
+        // `set` is followed by its data, which has to be read even if it is rejected to stay in
+        // step with the client
+        let data = match (name.as_str(), args) {
+            ("set", [_, _, _, len, ..]) => {
+                let len = match std::str::from_utf8(len).ok().and_then(|v| v.parse().ok()) {
+                    Some(v) => v,
+                    None => {
+                        writer
+                            .write_all(b"CLIENT_ERROR bad command line format\r\n")
+                            .await?;
+                        continue;
+                    }
+                };
+
+                match read_data(&mut reader, len, service.state.limits.max_value_size).await? {
+                    Data::Read(v) => Some(v),
+                    Data::TooLarge => {
+                        writer
+                            .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                            .await?;
+                        continue;
+                    }
+                    Data::Invalid => {
+                        // Skip the rest of the line like memcached so the next command lines up
+                        tcp::read_line(&mut reader).await?;
+                        writer.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let quiet =
+            args.last() == Some(&&b"noreply"[..]) && matches!(name.as_str(), "set" | "delete");
+        let reply = match name.as_str() {
+            "quit" => break,
+            _ => execute(service, &mut session, addr.ip(), &name, args, data).await,
+        };
+
+        if !quiet {
+            writer.write_all(&reply).await?;
+        }
+        // Pipelined commands get their replies in one write
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await?;
+    writer.shutdown().await
+}
+
+enum Data {
+    Read(Vec<u8>),
+    /// The data was skipped since it is over the value size limit
+    TooLarge,
+    /// The data wasn't followed by CRLF
+    Invalid,
+}
+
+async fn read_data<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    len: u64,
+    max: usize,
+) -> io::Result<Data> {
+    if len > max as u64 {
+        let skip = len + 2;
+        let skipped =
+            tokio::io::copy(&mut (&mut *reader).take(skip), &mut tokio::io::sink()).await?;
+        if skipped < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        return Ok(Data::TooLarge);
+    }
+
+    let mut data = vec![0; len as usize + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(Data::Invalid);
+    }
+    data.truncate(len as usize);
+
+    Ok(Data::Read(data))
+}
+
+async fn execute(
+    service: &Service,
+    session: &mut Option<Session>,
+    ip: IpAddr,
+    name: &str,
+    args: &[&[u8]],
+    data: Option<Vec<u8>>,
+) -> Vec<u8> {
+    let authorization = session.as_ref().map(|v| format!("Bearer {}", v.token));
+    if service
+        .limiter
+        .check(authorization.as_deref(), Some(ip))
+        .is_err()
+    {
+        return b"SERVER_ERROR rate limit exceeded\r\n".to_vec();
+    }
+
+    let session = match (session.as_ref(), data) {
+        (Some(v), data) => (v, data),
+        // The first `set` is the credentials
+        (None, Some(data)) if name == "set" => {
+            let credentials = String::from_utf8_lossy(&data);
+            let token = match credentials.trim().split_once(' ') {
+                Some((_, token)) => token.trim(),
+                None => credentials.trim(),
+            };
+
+            return match service.tokens.check(&format!("Bearer {token}")) {
+                Some((role, fingerprint)) => {
+                    *session = Some(Session {
+                        token: token.to_string(),
+                        role,
+                        fingerprint,
+                    });
+                    b"STORED\r\n".to_vec()
+                }
+                None => b"CLIENT_ERROR authentication failure\r\n".to_vec(),
+            };
+        }
+        (None, _) => return b"CLIENT_ERROR unauthenticated\r\n".to_vec(),
+    };
+    let (session, data) = session;
+
+    let required = match name {
+        "set" | "delete" => Role::ReadWrite,
+        _ => Role::ReadOnly,
+    };
+    if session.role < required {
+        return format!(
+            "CLIENT_ERROR {:?} tokens cannot run '{name}'\r\n",
+            session.role
+        )
+        .into_bytes();
+    }
+
+    let db = service.state.db.clone().with_actor(Actor {
+        token: Some(session.fingerprint.0.clone()),
+        ip: Some(ip),
+        request_id: None,
+    });
+    let keys = match args
+        .iter()
+        .map(|v| String::from_utf8(v.to_vec()))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(v) => v,
+        Err(_) => return b"CLIENT_ERROR keys must be UTF-8\r\n".to_vec(),
+    };
+
+    match (name, keys.as_slice(), data) {
+        ("get", [_, ..], _) => get(service, db, keys).await,
+        ("set", [key, flags, exptime, _, ..], Some(data)) => {
+            set(service, db, key, flags, exptime, data).await
+        }
+        ("delete", [key, rest @ ..], _) if is_delete_options(rest) => {
+            delete(service, db, key.clone()).await
+        }
+        ("stats", [], _) => stats(service, db).await,
+        ("version", [], _) => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+        ("get" | "set" | "delete" | "stats" | "version", _, _) => {
+            b"CLIENT_ERROR bad command line format\r\n".to_vec()
+        }
+        _ => b"ERROR\r\n".to_vec(),
+    }
+}
+
+/// `delete <key> [0] [noreply]`, where old clients may send a time that has to be 0.
+fn is_delete_options(options: &[String]) -> bool {
+    matches!(
+        options
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .as_slice(),
+        [] | ["0"] | ["noreply"] | ["0", "noreply"]
+    )
+}
+
+async fn get(service: &Service, db: ywkv::Db, keys: Vec<String>) -> Vec<u8> {
+    add(&service.stats.cmd_get, keys.len() as u64);
+
+    let values = match blocking(move || db.read_many(keys)).await {
+        Ok(v) => v,
+        Err(e) => return server_error(e),
+    };
+
+    let mut out = Vec::new();
+    for (key, value) in values {
+        let value = match value {
+            Some(v) => v,
+            None => {
+                add(&service.stats.get_misses, 1);
+                continue;
+            }
+        };
+        add(&service.stats.get_hits, 1);
+
+        let flags = value
+            .content_type
+            .split(';')
+            .skip(1)
+            .find_map(|v| v.trim().strip_prefix(FLAGS_PARAM))
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+        out.extend_from_slice(format!("VALUE {key} {flags} {}\r\n", value.data.len()).as_bytes());
+        out.extend_from_slice(&value.data);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"END\r\n");
+
+    out
+}
+
+/// `set <key> <flags> <exptime> <bytes> [noreply]`
+async fn set(
+    service: &Service,
+    db: ywkv::Db,
+    key: &str,
+    flags: &str,
+    exptime: &str,
+    data: Vec<u8>,
+) -> Vec<u8> {
+    add(&service.stats.cmd_set, 1);
+
+    let (flags, exptime) = match (flags.parse::<u32>(), exptime.parse::<i64>()) {
+        (Ok(flags), Ok(exptime)) => (flags, exptime),
+        _ => return b"CLIENT_ERROR bad command line format\r\n".to_vec(),
+    };
+
+    let checked = service
+        .state
+        .limits
+        .check_key(key)
+        .and_then(|_| service.state.limits.check_value(&data));
+    if let Err(e) = checked {
+        return limit_error(e);
+    }
+
+    let (key, ttl) = match expiry(exptime) {
+        Some(ttl) => (key.to_string(), ttl),
+        // Already expired, so the value would never be read
+        None => {
+            let key = key.to_string();
+            return match blocking(move || db.delete(key)).await {
+                Ok(_) => b"STORED\r\n".to_vec(),
+                Err(e) => server_error(e),
+            };
+        }
+    };
+    let content_type = match flags {
+        0 => ywkv::DEFAULT_CONTENT_TYPE.to_string(),
+        v => format!("{}; {FLAGS_PARAM}{v}", ywkv::DEFAULT_CONTENT_TYPE),
+    };
+    let value = Value::new(data, content_type);
+
+    let res = match &service.state.group {
+        Some(group) => group.write(db, key, value, ttl).await,
+        None => blocking(move || db.write_with_ttl(key, value, ttl)).await,
+    };
+
+    match res {
+        Ok(_) => b"STORED\r\n".to_vec(),
+        Err(e) => server_error(e),
+    }
+}
+
+/// The TTL for a memcached expiration time, which is relative for up to 30 days and a Unix
+/// timestamp otherwise. `Some(None)` never expires and `None` has already expired.
+fn expiry(exptime: i64) -> Option<Option<Duration>> {
+    match exptime {
+        0 => Some(None),
+        v if v < 0 => None,
+        v if v <= MAX_RELATIVE_EXPIRY => Some(Some(Duration::from_secs(v as u64))),
+        v => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            match (v as u64).checked_sub(now) {
+                Some(v) if v > 0 => Some(Some(Duration::from_secs(v))),
+                _ => None,
+            }
+        }
+    }
+}
+
+async fn delete(service: &Service, db: ywkv::Db, key: String) -> Vec<u8> {
+    match blocking(move || db.delete(key)).await {
+        Ok(Some(_)) => {
+            add(&service.stats.delete_hits, 1);
+            b"DELETED\r\n".to_vec()
+        }
+        Ok(None) => {
+            add(&service.stats.delete_misses, 1);
+            b"NOT_FOUND\r\n".to_vec()
+        }
+        Err(e) => server_error(e),
+    }
+}
+
+async fn stats(service: &Service, db: ywkv::Db) -> Vec<u8> {
+    let items = match blocking(move || db.key_count()).await {
+        Ok(v) => v,
+        Err(e) => return server_error(e),
+    };
+    let stats = &service.stats;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+
+    let lines = [
+        ("pid", std::process::id().to_string()),
+        ("uptime", stats.started.elapsed().as_secs().to_string()),
+        ("time", time.to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        (
+            "curr_connections",
+            load(&stats.curr_connections).to_string(),
+        ),
+        (
+            "total_connections",
+            load(&stats.total_connections).to_string(),
+        ),
+        ("cmd_get", load(&stats.cmd_get).to_string()),
+        ("cmd_set", load(&stats.cmd_set).to_string()),
+        ("get_hits", load(&stats.get_hits).to_string()),
+        ("get_misses", load(&stats.get_misses).to_string()),
+        ("delete_hits", load(&stats.delete_hits).to_string()),
+        ("delete_misses", load(&stats.delete_misses).to_string()),
+        ("curr_items", items.to_string()),
+    ];
+
+    let mut out = String::new();
+    for (name, value) in lines {
+        out.push_str(&format!("STAT {name} {value}\r\n"));
+    }
+    out.push_str("END\r\n");
+
+    out.into_bytes()
+}
+
+fn server_error(e: YwkvError) -> Vec<u8> {
+    // Replies are a single line
+    format!(
+        "SERVER_ERROR {}\r\n",
+        e.to_string().replace(['\r', '\n'], " ")
+    )
+    .into_bytes()
+}
+
+fn limit_error((_, Json(response)): (StatusCode, Json<Response>)) -> Vec<u8> {
+    let message = response.into_value().replace(['\r', '\n'], " ");
+
+    format!("CLIENT_ERROR {message}\r\n").into_bytes()
+}
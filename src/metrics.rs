@@ -0,0 +1,257 @@
+//! A small metrics registry rendered in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+
+use crate::{blocking, DbState};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|v| secs <= *v) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let (bucket_labels, labels) = match labels {
+            "" => (String::new(), String::new()),
+            v => (format!("{v},"), format!("{{{v}}}")),
+        };
+
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{bucket_labels}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_bucket{{{bucket_labels}le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+pub struct Metrics {
+    /// Keyed by route, method and status code
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    read_latency: Histogram,
+    write_latency: Histogram,
+    commit_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            read_latency: Histogram::new(),
+            write_latency: Histogram::new(),
+            commit_duration: Histogram::new(),
+        }
+    }
+
+    pub fn observe_commit(&self, duration: Duration) {
+        self.commit_duration.observe(duration);
+    }
+
+    fn observe_request(&self, route: String, method: &Method, status: StatusCode, took: Duration) {
+        match *method {
+            Method::GET | Method::HEAD => self.read_latency.observe(took),
+            _ => self.write_latency.observe(took),
+        }
+
+        let mut requests = self.requests.lock().unwrap();
+        *requests
+            .entry((route, method.to_string(), status.as_u16()))
+            .or_default() += 1;
+    }
+}
+
+/// Escape a label value so it can be embedded in quotes.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Record the count and latency of every request.
+pub async fn track<B>(
+    State(state): State<DbState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(v) => v.as_str().to_string(),
+        None => "unmatched".to_string(),
+    };
+    let method = request.method().clone();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .observe_request(route, &method, response.status(), start.elapsed());
+
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/_metrics",
+    tag = "admin",
+    responses((status = 200, description = "Metrics in the Prometheus text format", content_type = "text/plain"))
+)]
+pub async fn render(State(state): State<DbState>) -> impl IntoResponse {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    out.push_str("# HELP ywkv_requests_total HTTP requests by route, method and status.\n");
+    out.push_str("# TYPE ywkv_requests_total counter\n");
+    for ((route, method, status), count) in metrics.requests.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "ywkv_requests_total{{route=\"{}\",method=\"{method}\",status=\"{status}\"}} {count}",
+            escape(route)
+        );
+    }
+
+    out.push_str("# HELP ywkv_request_duration_seconds Time taken to respond to requests.\n");
+    out.push_str("# TYPE ywkv_request_duration_seconds histogram\n");
+    metrics.read_latency.render(
+        &mut out,
+        "ywkv_request_duration_seconds",
+        "operation=\"read\"",
+    );
+    metrics.write_latency.render(
+        &mut out,
+        "ywkv_request_duration_seconds",
+        "operation=\"write\"",
+    );
+
+    out.push_str("# HELP ywkv_commit_duration_seconds Time taken to commit write transactions.\n");
+    out.push_str("# TYPE ywkv_commit_duration_seconds histogram\n");
+    metrics
+        .commit_duration
+        .render(&mut out, "ywkv_commit_duration_seconds", "");
+
+    if let Ok(file) = std::fs::metadata(state.path.as_ref()) {
+        out.push_str("# HELP ywkv_database_size_bytes Size of the database file.\n");
+        out.push_str("# TYPE ywkv_database_size_bytes gauge\n");
+        let _ = writeln!(out, "ywkv_database_size_bytes {}", file.len());
+    }
+
+    let quota = state.quota();
+    if let Some(max) = quota.max_size {
+        out.push_str("# HELP ywkv_database_max_size_bytes Size the database file can grow to before writes are rejected.\n");
+        out.push_str("# TYPE ywkv_database_max_size_bytes gauge\n");
+        let _ = writeln!(out, "ywkv_database_max_size_bytes {max}");
+    }
+    if let Some(max) = quota.max_keys {
+        out.push_str(
+            "# HELP ywkv_max_keys Keys each table can hold before new keys are rejected.\n",
+        );
+        out.push_str("# TYPE ywkv_max_keys gauge\n");
+        let _ = writeln!(out, "ywkv_max_keys {max}");
+    }
+
+    if let Some(cache) = state.cache_stats() {
+        out.push_str("# HELP ywkv_cache_hits_total Reads answered from the read cache.\n");
+        out.push_str("# TYPE ywkv_cache_hits_total counter\n");
+        let _ = writeln!(out, "ywkv_cache_hits_total {}", cache.hits);
+        out.push_str("# HELP ywkv_cache_misses_total Reads that had to go to the database.\n");
+        out.push_str("# TYPE ywkv_cache_misses_total counter\n");
+        let _ = writeln!(out, "ywkv_cache_misses_total {}", cache.misses);
+        out.push_str("# HELP ywkv_cache_entries Values held in the read cache.\n");
+        out.push_str("# TYPE ywkv_cache_entries gauge\n");
+        let _ = writeln!(out, "ywkv_cache_entries {}", cache.entries);
+        out.push_str("# HELP ywkv_cache_bytes Estimated size of the values in the read cache.\n");
+        out.push_str("# TYPE ywkv_cache_bytes gauge\n");
+        let _ = writeln!(out, "ywkv_cache_bytes {}", cache.bytes);
+    }
+
+    let key_counts = blocking({
+        let state = state.clone();
+        move || {
+            state.tables().map(|tables| {
+                tables
+                    .into_iter()
+                    .map(|table| {
+                        let count = state
+                            .with_table(table.as_str())
+                            .and_then(|db| db.key_count());
+
+                        (table, count)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        }
+    })
+    .await;
+    match key_counts {
+        Ok(key_counts) => {
+            out.push_str(
+                "# HELP ywkv_keys Keys in each table, including expired keys not purged yet.\n",
+            );
+            out.push_str("# TYPE ywkv_keys gauge\n");
+            for (table, count) in key_counts {
+                match count {
+                    Ok(count) => {
+                        let _ = writeln!(out, "ywkv_keys{{table=\"{}\"}} {count}", escape(&table));
+                    }
+                    Err(e) => tracing::error!("Failed to count keys in table `{table}`: {e}"),
+                }
+            }
+        }
+        Err(e) => tracing::error!("Failed to list tables: {e}"),
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+
+    (headers, out)
+}
@@ -0,0 +1,52 @@
+//! The `migrate` subcommand, which rewrites a database file from an older version of ywkv in the
+//! current format.
+
+use anyhow::Context;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use ywkv::Db;
+
+pub const NAME: &str = "migrate";
+
+const FROM: &str = "old-file";
+const TO: &str = "new-file";
+const CONTENT_TYPE: &str = "content-type";
+
+pub fn command() -> Command {
+    Command::new(NAME)
+        .about("Copy a database file into a new one in the current format, checking every entry")
+        .arg(Arg::new(FROM).required(true).action(ArgAction::Set))
+        .arg(Arg::new(TO).required(true).action(ArgAction::Set))
+        .arg(
+            Arg::new(CONTENT_TYPE)
+                .long(CONTENT_TYPE)
+                .required(false)
+                .default_value(ywkv::TEXT_CONTENT_TYPE)
+                .action(ArgAction::Set),
+        )
+}
+
+pub fn run(args: &ArgMatches) -> anyhow::Result<()> {
+    let from = args.get_one::<String>(FROM).unwrap();
+    let to = args.get_one::<String>(TO).unwrap();
+    let content_type = args.get_one::<String>(CONTENT_TYPE).unwrap();
+
+    let migration = Db::migrate(from, to, content_type)
+        .with_context(|| format!("failed to migrate `{from}` to `{to}`"))?;
+
+    for (name, keys) in &migration.tables {
+        match migration.converted.contains(name) {
+            true => println!("Converted {keys} text values in table `{name}` to `{content_type}`"),
+            false => println!("Copied {keys} keys in table `{name}`"),
+        }
+    }
+    for (name, entries) in &migration.internal {
+        println!("Copied {entries} entries in `{name}`");
+    }
+    println!(
+        "Migrated `{from}` ({} bytes) to `{to}` ({} bytes), and every entry matched",
+        std::fs::metadata(from)?.len(),
+        std::fs::metadata(to)?.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! Sending and receiving MessagePack or CBOR instead of JSON, picked by the `Accept` and
+//! `Content-Type` headers.
+
+use axum::{
+    async_trait,
+    body::{self, Bytes, Full, HttpBody},
+    extract::FromRequest,
+    http::{
+        header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use ywkv::CodecError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.trim().to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The format of a request body, if it's one of the supported ones.
+    fn of_body(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let media_type = content_type.split(';').next().unwrap_or_default();
+
+        Self::from_media_type(media_type)
+    }
+
+    /// The supported format the client prefers, going by the quality values in `Accept`. JSON if
+    /// it doesn't ask for any of them.
+    fn accepted(headers: &HeaderMap) -> Self {
+        let mut best = (Format::Json, 0.0);
+        for range in headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let mut params = range.split(';');
+            let format = match Self::from_media_type(params.next().unwrap_or_default()) {
+                Some(v) => v,
+                None => continue,
+            };
+            let quality = params
+                .filter_map(|v| v.trim().strip_prefix("q="))
+                .find_map(|v| v.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+
+        best.0
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            // Named fields, so the same keys come out as in JSON
+            Format::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+            Format::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(value, &mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(data)?),
+            Format::MessagePack => Ok(rmp_serde::from_slice(data)?),
+            Format::Cbor => Ok(ciborium::from_reader(data)?),
+        }
+    }
+}
+
+/// Marks a response whose body is a stored value rather than a JSON envelope, so [respond] sends
+/// it unchanged even if the value happens to be JSON.
+#[derive(Clone, Copy, Debug)]
+pub struct Verbatim;
+
+/// Re-encodes JSON responses in the format the client asked for in `Accept`.
+///
+/// Layered onto each handler inside any compression, since it needs the uncompressed body.
+pub async fn respond<B>(request: Request<B>, next: Next<B>) -> Response {
+    let format = Format::accepted(request.headers());
+    let mut response = next.run(request).await;
+
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| Format::from_media_type(v.split(';').next().unwrap_or_default()))
+        == Some(Format::Json);
+    if !is_json || response.extensions().get::<Verbatim>().is_some() {
+        return response;
+    }
+
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+    if format == Format::Json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let encoded = match hyper::body::to_bytes(body).await {
+        Ok(data) => serde_json::from_slice::<serde_json::Value>(&data)
+            .map_err(CodecError::from)
+            .and_then(|v| format.encode(&v)),
+        Err(e) => Err(e.into()),
+    };
+
+    match encoded {
+        Ok(data) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            );
+            Response::from_parts(parts, body::boxed(Full::from(data)))
+        }
+        Err(e) => {
+            tracing::error!(
+                "Failed to encode response as {}: {e}",
+                format.content_type()
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json::from(ywkv::Response::new(
+                    format!("failed to encode response: {e}"),
+                    ywkv::Status::Read(ywkv::ReadStatus::Failure),
+                )),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// A request body sent as JSON, MessagePack or CBOR, going by its `Content-Type`. Bodies without a
+/// MessagePack or CBOR content type are handled exactly like [Json].
+pub struct Body<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Body<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let format = match Format::of_body(request.headers()) {
+            Some(Format::Json) | None => {
+                return Json::<T>::from_request(request, state)
+                    .await
+                    .map(|Json(v)| Body(v))
+                    .map_err(IntoResponse::into_response)
+            }
+            Some(v) => v,
+        };
+
+        let data = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        format.decode(&data).map(Body).map_err(|e| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json::from(ywkv::Response::new(
+                    format!("invalid {} body: {e}", format.content_type()),
+                    ywkv::Status::Read(ywkv::ReadStatus::Failure),
+                )),
+            )
+                .into_response()
+        })
+    }
+}
@@ -0,0 +1,276 @@
+//! The OpenAPI description of the HTTP API at `/_openapi.json`, and Swagger UI for it at `/_docs`.
+
+use std::sync::OnceLock;
+
+use axum::{
+    http::{header::CONTENT_TYPE, HeaderValue},
+    response::{Html, IntoResponse},
+};
+use utoipa::{
+    openapi::{
+        content::Content,
+        path::{Parameter, ParameterBuilder, ParameterIn},
+        schema::{KnownFormat, ObjectBuilder, SchemaFormat, Type},
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        server::Server,
+        RefOr, Required, ResponseBuilder, Schema,
+    },
+    Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        description = "A simple key-value server that uses redb for persistence.",
+        license(name = "MPL-2.0"),
+    ),
+    paths(
+        crate::read_key,
+        crate::head_key,
+        crate::insert_key,
+        crate::write_key,
+        crate::patch_key,
+        crate::delete_key,
+        crate::delete_prefix,
+        crate::read_key_meta,
+        crate::read_key_history,
+        crate::rollback_key,
+        crate::increment_key,
+        crate::decrement_key,
+        crate::append_key,
+        crate::push_key,
+        crate::pop_key,
+        crate::list_len,
+        crate::set_add,
+        crate::set_remove,
+        crate::set_members,
+        crate::set_contains,
+        crate::list_keys,
+        crate::count_keys,
+        crate::scan,
+        crate::list_changes,
+        crate::stats,
+        crate::prefix_stats,
+        crate::read_batch,
+        crate::snapshot_read,
+        crate::write_batch,
+        crate::transaction,
+        crate::lock::acquire,
+        crate::lock::release,
+        crate::watch_key,
+        crate::watch_prefix,
+        crate::ws::upgrade,
+        crate::export::export,
+        crate::metrics::render,
+        crate::admin::backup,
+        crate::s3::backups,
+        crate::admin::restore,
+        crate::admin::compact,
+        crate::admin::audit,
+        crate::admin::tables,
+        crate::admin::create_table,
+        crate::admin::drop_table,
+        crate::admin::truncate_table,
+        crate::admin::reload,
+        crate::webhook::deliveries,
+        crate::replication::status,
+        crate::replication::changes,
+        crate::replication::snapshot,
+    ),
+    modifiers(&Scripts, &Negotiation, &TableRoutes, &BearerAuth),
+    security(("token" = [])),
+    tags(
+        (name = "values", description = "Reading and writing keys. Every route is also available under `/_table/{table}` for tables other than the default one."),
+        (name = "admin", description = "Server administration, which needs an admin token."),
+    )
+)]
+struct ApiDoc;
+
+/// A value exactly as it was written, in whatever format it was written in.
+pub struct RawValue;
+
+impl PartialSchema for RawValue {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)))
+            .into()
+    }
+}
+
+impl ToSchema for RawValue {}
+
+/// Adds the scripting routes when built with the `scripts` feature.
+struct Scripts;
+
+impl Modify for Scripts {
+    fn modify(&self, _openapi: &mut utoipa::openapi::OpenApi) {
+        #[cfg(feature = "scripts")]
+        _openapi.merge(<crate::scripts::ApiDoc as OpenApi>::openapi());
+    }
+}
+
+/// Lists MessagePack and CBOR next to JSON for the routes that [crate::negotiate] applies to.
+struct Negotiation;
+
+impl Modify for Negotiation {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if path == "/_metrics" || path == "/_export" || path.starts_with("/_admin/") {
+                continue;
+            }
+
+            for operation in operations(item) {
+                if let Some(body) = &mut operation.request_body {
+                    let formats = other_formats(body.content.get("application/json"));
+                    body.content.extend(formats);
+                }
+                for response in operation.responses.responses.values_mut() {
+                    if let RefOr::T(response) = response {
+                        let formats = other_formats(response.content.get("application/json"));
+                        response.content.extend(formats);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The same content as JSON in the other formats, if there's any JSON content.
+fn other_formats(json: Option<&Content>) -> Vec<(String, Content)> {
+    json.map(|v| {
+        vec![
+            ("application/msgpack".to_string(), v.clone()),
+            ("application/cbor".to_string(), v.clone()),
+        ]
+    })
+    .unwrap_or_default()
+}
+
+/// Repeats every route that operates on a table under `/_table/{table}`.
+struct TableRoutes;
+
+impl Modify for TableRoutes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let table = Parameter::from(
+            ParameterBuilder::new()
+                .name("table")
+                .parameter_in(ParameterIn::Path)
+                .required(Required::True)
+                .description(Some("A table other than the default one"))
+                .schema(Some(String::schema())),
+        );
+
+        let mut nested = vec![];
+        for (path, item) in &openapi.paths.paths {
+            if path == "/_metrics" || path.starts_with("/_admin/") {
+                continue;
+            }
+
+            let mut item = item.clone();
+            for operation in operations(&mut item) {
+                // Operation IDs have to be unique across the whole API
+                operation.operation_id = operation
+                    .operation_id
+                    .take()
+                    .map(|v| format!("{v}_in_table"));
+                operation
+                    .parameters
+                    .get_or_insert_with(Vec::new)
+                    .insert(0, table.clone());
+            }
+            nested.push((format!("/_table/{{table}}{path}"), item));
+        }
+
+        openapi.paths.paths.extend(nested);
+    }
+}
+
+/// Adds the bearer token scheme, and the responses every route can send when the token is wrong.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+
+        for item in openapi.paths.paths.values_mut() {
+            for operation in operations(item) {
+                let responses = &mut operation.responses.responses;
+                responses.insert(
+                    "401".to_string(),
+                    ResponseBuilder::new()
+                        .description("The token is missing or invalid")
+                        .into(),
+                );
+                responses.insert(
+                    "403".to_string(),
+                    ResponseBuilder::new()
+                        .description("The token's role isn't allowed to make this request")
+                        .into(),
+                );
+            }
+        }
+    }
+}
+
+fn operations(
+    item: &mut utoipa::openapi::PathItem,
+) -> impl Iterator<Item = &mut utoipa::openapi::path::Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.head,
+        &mut item.patch,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Paths are listed relative to `base_path` when the server is mounted under one.
+pub async fn spec(base_path: Option<String>) -> impl IntoResponse {
+    static SPEC: OnceLock<String> = OnceLock::new();
+
+    let spec = SPEC.get_or_init(|| {
+        let mut spec = ApiDoc::openapi();
+        if let Some(base_path) = base_path {
+            spec.servers = Some(vec![Server::new(base_path)]);
+        }
+        spec.to_pretty_json()
+            .expect("the spec is always valid JSON")
+    });
+
+    (
+        [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+        spec.as_str(),
+    )
+}
+
+/// Swagger UI is loaded from a CDN, so the page needs internet access to render.
+pub async fn docs() -> Html<&'static str> {
+    Html(
+        r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>ywkv API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    SwaggerUIBundle({ url: "_openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##,
+    )
+}
@@ -0,0 +1,165 @@
+//! Token bucket rate limiting, keyed by bearer token or client IP.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{
+        header::{AUTHORIZATION, RETRY_AFTER},
+        HeaderMap, HeaderValue, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+/// Once this many clients are tracked, clients whose buckets have refilled are forgotten.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitBy {
+    Token,
+    Ip,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    /// Requests allowed per second
+    rate: f64,
+    /// Requests allowed at once after being idle
+    burst: f64,
+    by: LimitBy,
+}
+
+impl Limit {
+    pub fn new(rate: f64, burst: u32, by: LimitBy) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst),
+            by,
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Clones share the same limit and buckets, so changing the limit affects every clone. Requests
+/// aren't limited while there is no limit.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: Arc<RwLock<Option<Limit>>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<Limit>) -> Self {
+        Self {
+            limit: Arc::new(RwLock::new(limit)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Every client starts over with a full bucket when the limit changes.
+    pub fn set(&self, limit: Option<Limit>) {
+        let mut current = self.limit.write().unwrap();
+        if *current != limit {
+            *current = limit;
+            self.buckets.lock().unwrap().clear();
+        }
+    }
+
+    fn limit(&self) -> Option<Limit> {
+        *self.limit.read().unwrap()
+    }
+
+    /// Take a token from the bucket of the client identified by its `Authorization` header or its IP,
+    /// depending on what the limit is by, or return how many seconds until one is available.
+    pub fn check(&self, authorization: Option<&str>, ip: Option<IpAddr>) -> Result<(), u64> {
+        let limit = match self.limit() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let client = match (limit.by, authorization, ip) {
+            (LimitBy::Ip, _, Some(ip)) => ip.to_string(),
+            (_, Some(token), _) => token.to_string(),
+            (_, None, Some(ip)) => ip.to_string(),
+            (_, None, None) => String::new(),
+        };
+
+        self.acquire(&limit, client)
+    }
+
+    /// Take a token from the client's bucket, or return how many seconds until one is available.
+    fn acquire(&self, limit: &Limit, client: String) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(&client) {
+            buckets.retain(|_, v| limit.refill(v, now) < limit.burst);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+        bucket.tokens = limit.refill(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(((1.0 - bucket.tokens) / limit.rate).ceil() as u64)
+    }
+}
+
+/// Reject requests from clients that are over their limit with 429 and a `Retry-After` header.
+///
+/// Runs after authentication so only valid tokens get their own bucket.
+pub async fn limit<B>(
+    State(limiter): State<RateLimiter>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|v| v.0.ip());
+
+    if let Err(retry_after) = limiter.check(token, ip) {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from(retry_after));
+
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            Json::from(ywkv::Response::new(
+                "rate limit exceeded".to_string(),
+                ywkv::Status::Read(ywkv::ReadStatus::Failure),
+            )),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
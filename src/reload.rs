@@ -0,0 +1,176 @@
+//! Applying configuration changes without a restart, on SIGHUP or `POST /_admin/reload`.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::{
+    auth::{self, Role, Tokens},
+    logging,
+    ratelimit::{Limit, RateLimiter},
+};
+
+/// How often to check the token file for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Everything that can change on reload.
+#[derive(Clone)]
+pub struct Settings {
+    /// Tokens from the command line and config file
+    pub tokens: Vec<(String, Role)>,
+    pub token_file: Option<PathBuf>,
+    pub rate_limit: Option<Limit>,
+    pub log_level: String,
+    /// The certificate and key. These files are reloaded, but switching to other files or turning
+    /// TLS on or off needs a restart.
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl Settings {
+    /// Every token, including the ones in the token file.
+    pub fn load_tokens(&self) -> anyhow::Result<Vec<(String, Role)>> {
+        let mut tokens = self.tokens.clone();
+        if let Some(path) = &self.token_file {
+            tokens.extend(auth::load_file(path)?);
+        }
+
+        Ok(tokens)
+    }
+
+    fn token_file_modified(&self) -> Option<SystemTime> {
+        let path = self.token_file.as_deref()?;
+
+        std::fs::metadata(path).and_then(|v| v.modified()).ok()
+    }
+}
+
+/// Clones share the same state, so a reload through any of them affects all.
+#[derive(Clone)]
+pub struct Reloader(Arc<Inner>);
+
+struct Inner {
+    /// Reads the configuration again
+    load: Box<dyn Fn() -> anyhow::Result<Settings> + Send + Sync>,
+    /// The settings last applied. Held for the whole reload so reloads don't interleave.
+    current: tokio::sync::Mutex<Settings>,
+    tokens: Tokens,
+    limiter: RateLimiter,
+    tls: Option<RustlsConfig>,
+}
+
+impl Reloader {
+    /// `settings` are the ones already in effect.
+    pub fn new(
+        settings: Settings,
+        tokens: Tokens,
+        limiter: RateLimiter,
+        tls: Option<RustlsConfig>,
+        load: impl Fn() -> anyhow::Result<Settings> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(Inner {
+            load: Box::new(load),
+            current: tokio::sync::Mutex::new(settings),
+            tokens,
+            limiter,
+            tls,
+        }))
+    }
+
+    /// Read the configuration and token file again and apply them. Nothing changes unless all
+    /// of it can be loaded, and requests in flight finish with the settings they started with.
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let mut current = self.0.current.lock().await;
+
+        let settings = (self.0.load)()?;
+        if settings.tls != current.tls {
+            anyhow::bail!("changing the TLS certificate or key files needs a restart");
+        }
+        let tokens = settings.load_tokens()?;
+        let filter = logging::filter(&settings.log_level)?;
+        if let (Some(config), Some((cert, key))) = (&self.0.tls, &settings.tls) {
+            config
+                .reload_from_pem_file(cert, key)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to load TLS certificate `{}` and key `{}`",
+                        cert.display(),
+                        key.display()
+                    )
+                })?;
+        }
+
+        let count = tokens.len();
+        self.0.tokens.replace(tokens);
+        self.0.limiter.set(settings.rate_limit);
+        logging::set_filter(filter)?;
+        *current = settings;
+
+        tracing::info!(tokens = count, "Reloaded configuration");
+
+        Ok(())
+    }
+
+    async fn reload_tokens(&self) -> anyhow::Result<()> {
+        let current = self.0.current.lock().await;
+        self.0.tokens.replace(current.load_tokens()?);
+
+        if let Some(path) = &current.token_file {
+            tracing::info!("Reloaded tokens from `{}`", path.display());
+        }
+
+        Ok(())
+    }
+
+    async fn token_file_modified(&self) -> Option<SystemTime> {
+        self.0.current.lock().await.token_file_modified()
+    }
+}
+
+/// Reload everything when the process receives SIGHUP, and just the tokens whenever the token
+/// file changes. If something can't be loaded the current settings stay in place.
+pub async fn watch(reloader: Reloader) {
+    let mut last_modified = reloader.token_file_modified().await;
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        #[cfg(unix)]
+        let hungup = tokio::select! {
+            _ = interval.tick() => false,
+            _ = hangup.recv() => true,
+        };
+        #[cfg(not(unix))]
+        let hungup = {
+            interval.tick().await;
+            false
+        };
+
+        if hungup {
+            // The token file may have moved, so its modification time is checked after
+            match reloader.reload().await {
+                Ok(()) => last_modified = reloader.token_file_modified().await,
+                Err(e) => tracing::error!("Failed to reload configuration: {e:#}"),
+            }
+            continue;
+        }
+
+        let current = reloader.token_file_modified().await;
+        if current == last_modified {
+            continue;
+        }
+
+        match reloader.reload_tokens().await {
+            Ok(()) => last_modified = current,
+            Err(e) => tracing::error!("Failed to reload tokens: {e:#}"),
+        }
+    }
+}
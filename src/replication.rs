@@ -0,0 +1,470 @@
+//! Streaming the changelog from a primary to read-only replicas. Replicas long-poll the primary
+//! for changes after the last one they applied, and copy a whole snapshot when they've fallen too
+//! far behind to catch up from the changelog.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Query, State},
+    http::{
+        header::{ALLOW, AUTHORIZATION},
+        HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use ywkv::{ChangeOp, ChangePage, Db};
+
+use crate::{admin, auth::Role, blocking, webhook, DbState};
+
+/// The longest a request for changes waits for one to be committed.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a request waiting for changes checks the changelog again, since restores aren't
+/// published to subscribers.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a replica waits for a response on top of the time the primary waits for changes.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the primary has to make a snapshot.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Waits after failing to reach the primary, doubling after every failure in a row.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What a replica reports about following its primary.
+#[derive(Clone, Default)]
+pub struct Replication {
+    follower: Option<Arc<Mutex<Follower>>>,
+}
+
+#[derive(Default)]
+struct Follower {
+    primary: String,
+    primary_position: Option<u64>,
+    last_synced_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Replication {
+    /// Keep `db` in sync with the primary at `primary`, which changes are requested from with
+    /// `token`.
+    pub fn follow(db: Db, primary: Uri, token: String) -> Self {
+        let follower = Arc::new(Mutex::new(Follower {
+            primary: primary.to_string(),
+            ..Default::default()
+        }));
+
+        tokio::spawn(follow(
+            webhook::client(),
+            db,
+            primary.to_string().trim_end_matches('/').to_string(),
+            token,
+            follower.clone(),
+        ));
+
+        Self {
+            follower: Some(follower),
+        }
+    }
+}
+
+async fn follow(
+    client: webhook::Client,
+    db: Db,
+    primary: String,
+    token: String,
+    follower: Arc<Mutex<Follower>>,
+) {
+    // Data written before the changelog was recorded only comes with a snapshot
+    let mut resync = match blocking({
+        let db = db.clone();
+        move || db.changelog_position()
+    })
+    .await
+    {
+        Ok(position) => position == 0,
+        Err(e) => {
+            tracing::error!("Failed to read the changelog, not replicating: {e}");
+            return;
+        }
+    };
+
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        let res = match resync {
+            true => fetch_snapshot(&client, &db, &primary, &token)
+                .await
+                .map(|()| false),
+            false => fetch_changes(&client, &db, &primary, &token, &follower).await,
+        };
+
+        match res {
+            Ok(v) => {
+                if resync {
+                    tracing::info!(%primary, "Resynced from a snapshot of the primary");
+                }
+                resync = v;
+                backoff = FIRST_BACKOFF;
+
+                let mut follower = follower.lock().unwrap();
+                follower.last_synced_at = Some(ywkv::now_millis());
+                follower.last_error = None;
+            }
+            Err(e) => {
+                tracing::warn!(%primary, "Failed to replicate, retrying in {}s: {e}", backoff.as_secs());
+                follower.lock().unwrap().last_error = Some(e);
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Replace the whole database with a snapshot of the primary.
+async fn fetch_snapshot(
+    client: &webhook::Client,
+    db: &Db,
+    primary: &str,
+    token: &str,
+) -> Result<(), String> {
+    let url = format!("{primary}/_admin/replication/snapshot");
+    let (status, body) = get(client, &url, token, SNAPSHOT_TIMEOUT).await?;
+    if !status.is_success() {
+        return Err(failure(status, &body));
+    }
+
+    let path = admin::temp_path("resync");
+    if let Err(e) = tokio::fs::write(&path, &body).await {
+        return Err(format!(
+            "failed to write snapshot `{}`: {e}",
+            path.display()
+        ));
+    }
+
+    let res = blocking({
+        let db = db.clone();
+        let path = path.clone();
+        move || db.resync(path)
+    })
+    .await;
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("Failed to remove snapshot `{}`: {e}", path.display());
+    }
+
+    res.map_err(|e| e.to_string())
+}
+
+/// Apply the next changes from the primary, waiting for some if there are none yet. Returns
+/// whether a snapshot is needed to continue.
+async fn fetch_changes(
+    client: &webhook::Client,
+    db: &Db,
+    primary: &str,
+    token: &str,
+    follower: &Mutex<Follower>,
+) -> Result<bool, String> {
+    let position = blocking({
+        let db = db.clone();
+        move || db.changelog_position()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let url = format!(
+        "{primary}/_admin/replication/changes?after={position}&wait={}",
+        MAX_WAIT.as_secs()
+    );
+    let (status, body) = get(client, &url, token, MAX_WAIT + TIMEOUT).await?;
+    if status == StatusCode::GONE {
+        tracing::info!(%primary, position, "Fell behind the primary's changelog");
+        return Ok(true);
+    }
+    if !status.is_success() {
+        return Err(failure(status, &body));
+    }
+
+    let page = match serde_json::from_slice::<ywkv::Response<ChangePage>>(&body) {
+        Ok(v) => v.into_value(),
+        Err(e) => return Err(format!("invalid changes from the primary: {e}")),
+    };
+    follower.lock().unwrap().primary_position = Some(page.last);
+
+    // Everything after a restore has to come from a new snapshot
+    let mut records = page.records;
+    let restored = match records.iter().position(|v| v.op == ChangeOp::Restore) {
+        Some(i) => {
+            records.truncate(i);
+            true
+        }
+        None => false,
+    };
+
+    if !records.is_empty() {
+        let db = db.clone();
+        blocking(move || db.apply_changes(&records))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(restored)
+}
+
+async fn get(
+    client: &webhook::Client,
+    url: &str,
+    token: &str,
+    timeout: Duration,
+) -> Result<(StatusCode, axum::body::Bytes), String> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(url)
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    let response = match tokio::time::timeout(timeout, client.request(request)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err(format!("no response within {}s", timeout.as_secs())),
+    };
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok((status, body))
+}
+
+/// Why the primary rejected a request, from the message in its response if there is one.
+fn failure(status: StatusCode, body: &[u8]) -> String {
+    match serde_json::from_slice::<ywkv::Response>(body) {
+        Ok(v) => format!("the primary responded with {status}: {}", v.into_value()),
+        Err(_) => format!("the primary responded with {status}"),
+    }
+}
+
+/// Reject requests that would write with 405 and `reason`, for replicas, since only the primary
+/// can be written to, and servers started with `--read-only`.
+pub async fn reject_writes<B>(
+    State(reason): State<&'static str>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|v| v.as_str());
+    // Creating, truncating and dropping tables need the admin role rather than read-write
+    let writes = Role::required(request.method(), route) == Role::ReadWrite
+        || matches!(route, Some("/_admin/restore" | "/_admin/compact"))
+        || (matches!(*request.method(), Method::POST | Method::DELETE)
+            && route.is_some_and(|v| v.starts_with("/_admin/tables/")));
+    if !writes {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        [(ALLOW, HeaderValue::from_static("GET, HEAD"))],
+        Json::from(
+            ywkv::Response::new(
+                reason.to_string(),
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            )
+            .with_code(ywkv::ErrorCode::ReadOnly),
+        ),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangesQuery {
+    /// Only list changes after this sequence number
+    #[serde(default)]
+    after: u64,
+    /// How many changes to list at most. Defaults to 1000.
+    limit: Option<usize>,
+    /// How many seconds to wait for a change when there are none yet, up to 30
+    #[serde(default)]
+    wait: u64,
+}
+
+/// Changes committed after a sequence number, oldest first. Responds with 410 when some of the
+/// changes after it were already forgotten, or it's ahead of the changelog, so the caller has to
+/// start over from a snapshot.
+#[utoipa::path(
+    get,
+    path = "/_admin/replication/changes",
+    tag = "admin",
+    params(ChangesQuery),
+    responses(
+        (status = 200, body = ywkv::Response<ChangePage>),
+        (status = 410, description = "The changes can't be listed from the changelog", body = ywkv::Response<String>),
+    )
+)]
+pub async fn changes(
+    State(state): State<DbState>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<(StatusCode, Json<ywkv::Response<ChangePage>>), (StatusCode, Json<ywkv::Response>)> {
+    const DEFAULT_LIMIT: usize = 1000;
+    const MAX_LIMIT: usize = 10000;
+
+    let error = |code, message: String| {
+        (
+            code,
+            Json::from(ywkv::Response::new(
+                message,
+                ywkv::Status::Read(ywkv::ReadStatus::Failure),
+            )),
+        )
+    };
+
+    if state.changelog_entries == 0 {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            "the changelog is not enabled".to_string(),
+        ));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let deadline = Instant::now() + Duration::from_secs(query.wait).min(MAX_WAIT);
+
+    // Subscribed before reading so a change committed in between isn't waited for
+    let mut changes = state.subscribe();
+    loop {
+        let page = blocking({
+            let state = state.clone();
+            move || state.changes_since(query.after, limit)
+        })
+        .await
+        .map_err(ywkv::Response::from_read_error)?;
+
+        if page.first.is_some_and(|v| v - 1 > query.after) || query.after > page.last {
+            return Err(error(
+                StatusCode::GONE,
+                format!(
+                    "changes after {} are not in the changelog, which is at {}",
+                    query.after, page.last
+                ),
+            ));
+        }
+
+        let now = Instant::now();
+        if page.records.is_empty() && now < deadline {
+            let recheck = deadline.min(now + RECHECK_INTERVAL);
+            // Read the changelog again after any change or once it's time to check anyway.
+            // Missed changes are still in the changelog, so lagging behind is fine.
+            let _ = tokio::time::timeout_at(recheck.into(), changes.recv()).await;
+            continue;
+        }
+
+        return Ok((
+            StatusCode::OK,
+            Json::from(ywkv::Response::new(
+                page,
+                ywkv::Status::Read(ywkv::ReadStatus::Found),
+            )),
+        ));
+    }
+}
+
+/// A consistent snapshot of the whole database including its changelog, for replicas to start
+/// following the changelog from.
+#[utoipa::path(
+    get,
+    path = "/_admin/replication/snapshot",
+    tag = "admin",
+    responses((status = 200, description = "The snapshot", body = crate::openapi::RawValue, content_type = "application/octet-stream"))
+)]
+pub async fn snapshot(State(state): State<DbState>) -> Response {
+    let path = admin::temp_path("snapshot");
+
+    let res = blocking({
+        let path = path.clone();
+        move || state.backup(path)
+    })
+    .await;
+    if let Err(e) = res {
+        return ywkv::Response::from_read_error(e).into_response();
+    }
+
+    admin::stream_file(path).await
+}
+
+/// Where this server is in the changelog, and how following the primary is going for replicas.
+#[derive(Serialize, ToSchema)]
+pub struct ReplicationStatus {
+    /// `primary`, or `replica` when following another server
+    role: &'static str,
+    /// The newest change in this server's changelog
+    position: u64,
+    /// The server being followed, for replicas
+    primary: Option<String>,
+    /// The newest change on the primary as of the last response from it
+    primary_position: Option<u64>,
+    /// Milliseconds since the unix epoch
+    last_synced_at: Option<u64>,
+    /// Why the last attempt to reach the primary failed, if the next one hasn't succeeded yet
+    last_error: Option<String>,
+}
+
+/// How replication is going.
+#[utoipa::path(
+    get,
+    path = "/_admin/replication",
+    tag = "admin",
+    responses((status = 200, body = ywkv::Response<ReplicationStatus>))
+)]
+pub async fn status(
+    State(state): State<DbState>,
+) -> Result<(StatusCode, Json<ywkv::Response<ReplicationStatus>>), (StatusCode, Json<ywkv::Response>)>
+{
+    let position = blocking({
+        let state = state.clone();
+        move || state.changelog_position()
+    })
+    .await
+    .map_err(ywkv::Response::from_read_error)?;
+
+    let status = match &state.replication.follower {
+        Some(follower) => {
+            let follower = follower.lock().unwrap();
+            ReplicationStatus {
+                role: "replica",
+                position,
+                primary: Some(follower.primary.clone()),
+                primary_position: follower.primary_position,
+                last_synced_at: follower.last_synced_at,
+                last_error: follower.last_error.clone(),
+            }
+        }
+        None => ReplicationStatus {
+            role: "primary",
+            position,
+            primary: None,
+            primary_position: None,
+            last_synced_at: None,
+            last_error: None,
+        },
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json::from(ywkv::Response::new(
+            status,
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        )),
+    ))
+}
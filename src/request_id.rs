@@ -0,0 +1,113 @@
+//! An ID for every HTTP request, taken from the caller's `X-Request-Id` header or made up, so a
+//! failed call can be matched up with the server's logs and audit log.
+//!
+//! Error responses also get the [ywkv::ErrorCode] for their status here, unless the handler
+//! gave them a more specific one. Plain text errors, like the ones axum's extractors reject
+//! requests with, are wrapped in the same JSON envelope.
+
+use axum::{
+    body::{self, Full},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderName, HeaderValue, Request, StatusCode,
+    },
+    middleware::Next,
+    response::Response,
+};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+use crate::negotiate::Verbatim;
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longer IDs from callers are replaced, so they can't bloat every log line.
+const MAX_LEN: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The caller's ID if it's short printable ASCII, or a new random one.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let given = headers
+            .get(&HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= MAX_LEN)
+            .filter(|v| v.bytes().all(|b| b.is_ascii_graphic()));
+        if let Some(id) = given {
+            return Self(id.to_string());
+        }
+
+        let mut bytes = [0_u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes.iter().map(|v| format!("{v:02x}")).collect())
+    }
+}
+
+/// Give the request an ID for the handlers and logs to use, and send it back in `X-Request-Id`.
+/// Error responses also get it as `request_id`, and a `code` if they don't have one.
+pub async fn assign<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let id = RequestId::from_headers(request.headers());
+    request.extensions_mut().insert(id.clone());
+
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        response = with_id_in_body(response, &id, status).await;
+    }
+
+    // Only printable ASCII makes it this far
+    if let Ok(value) = HeaderValue::from_str(&id.0) {
+        response.headers_mut().insert(HEADER, value);
+    }
+
+    response
+}
+
+/// Add `request_id` and `code` to a JSON envelope, or wrap a plain text error in one, leaving
+/// anything else alone.
+async fn with_id_in_body(response: Response, id: &RequestId, status: StatusCode) -> Response {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let is_json = content_type.starts_with("application/json");
+    if !(is_json || content_type.starts_with("text/plain"))
+        || response.extensions().get::<Verbatim>().is_some()
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let data = match hyper::body::to_bytes(body).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Failed to read error response: {e}");
+            return Response::from_parts(parts, body::boxed(Full::default()));
+        }
+    };
+
+    let envelope = match is_json {
+        true => serde_json::from_slice::<ywkv::Response<serde_json::Value>>(&data).ok(),
+        false => Some(ywkv::Response::new(
+            String::from_utf8_lossy(&data).into_owned().into(),
+            ywkv::Status::Write(ywkv::WriteStatus::Failure),
+        )),
+    };
+    let data = match envelope {
+        Some(envelope) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            let envelope = envelope
+                .with_request_id(id.0.clone())
+                .with_default_code(status);
+            serde_json::to_vec(&envelope).unwrap_or_default().into()
+        }
+        None => data,
+    };
+
+    Response::from_parts(parts, body::boxed(Full::from(data)))
+}
@@ -0,0 +1,567 @@
+//! A listener speaking enough of the Redis protocol (RESP2) for Redis clients and `redis-cli` to
+//! read and write the default table: `AUTH`, `GET`, `SET`, `DEL`, `EXISTS`, `SCAN`, `PING` and
+//! `QUIT`.
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{http::StatusCode, Json};
+use axum_server::tls_rustls::RustlsConfig;
+use lru::LruCache;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use ywkv::{self, Actor, Response, Value, YwkvError};
+
+use crate::{
+    auth::{Fingerprint, Role, Tokens},
+    blocking,
+    ratelimit::RateLimiter,
+    tcp, DbState,
+};
+
+/// The most arguments a single command may have.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// How many `SCAN` cursors are remembered before the oldest ones stop working.
+const MAX_CURSORS: usize = 10_000;
+
+/// At most this many keys are looked at per `SCAN` call, whatever `COUNT` asks for.
+const MAX_SCAN_COUNT: usize = 1000;
+
+#[derive(Clone)]
+pub struct Service {
+    state: DbState,
+    tokens: Tokens,
+    limiter: RateLimiter,
+    cursors: Arc<Cursors>,
+}
+
+impl Service {
+    pub fn new(state: DbState, tokens: Tokens, limiter: RateLimiter) -> Self {
+        Self {
+            state,
+            tokens,
+            limiter,
+            cursors: Arc::new(Cursors::new()),
+        }
+    }
+}
+
+/// Redis clients expect `SCAN` cursors to be integers, so each page's last key is remembered under
+/// a number. Cursors are shared by every connection since clients with a connection pool may
+/// continue a scan on another connection.
+struct Cursors {
+    next: AtomicU64,
+    keys: Mutex<LruCache<u64, String>>,
+}
+
+impl Cursors {
+    fn new() -> Self {
+        Self {
+            // 0 starts and ends a scan
+            next: AtomicU64::new(1),
+            keys: Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_CURSORS).expect("the limit isn't zero"),
+            )),
+        }
+    }
+
+    fn save(&self, key: String) -> u64 {
+        let cursor = self.next.fetch_add(1, Ordering::Relaxed);
+        self.keys.lock().unwrap().put(cursor, key);
+
+        cursor
+    }
+
+    fn get(&self, cursor: u64) -> Option<String> {
+        self.keys.lock().unwrap().get(&cursor).cloned()
+    }
+}
+
+enum Reply {
+    Ok,
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: impl Into<String>) -> Self {
+        Reply::Error(format!("ERR {}", message.into()))
+    }
+
+    fn arity(command: &str) -> Self {
+        Reply::error(format!(
+            "wrong number of arguments for '{}' command",
+            command.to_ascii_lowercase()
+        ))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Ok => out.extend_from_slice(b"+OK\r\n"),
+            Reply::Simple(v) => out.extend_from_slice(format!("+{v}\r\n").as_bytes()),
+            // Errors are a single line
+            Reply::Error(v) => {
+                out.extend_from_slice(format!("-{}\r\n", v.replace(['\r', '\n'], " ")).as_bytes())
+            }
+            Reply::Integer(v) => out.extend_from_slice(format!(":{v}\r\n").as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(v)) => {
+                out.extend_from_slice(format!("${}\r\n", v.len()).as_bytes());
+                out.extend_from_slice(v);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(v) => {
+                out.extend_from_slice(format!("*{}\r\n", v.len()).as_bytes());
+                for reply in v {
+                    reply.write(out);
+                }
+            }
+        }
+    }
+}
+
+/// Serve RESP on `listener` until `stopped` resolves, over TLS if a certificate is configured.
+/// Connections are closed after the command they're running when the server stops.
+pub async fn serve<F>(
+    listener: std::net::TcpListener,
+    service: Service,
+    tls: Option<RustlsConfig>,
+    stopped: impl Fn() -> F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tcp::serve(
+        listener,
+        "RESP",
+        tls,
+        stopped,
+        move |stream, addr, stopped| handle(stream, addr, service.clone(), stopped),
+    )
+    .await
+}
+
+/// The token a connection authenticated with.
+struct Session {
+    token: String,
+    role: Role,
+    fingerprint: Fingerprint,
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    addr: SocketAddr,
+    service: Service,
+    stopped: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let max_bulk = service.state.limits.max_value_size;
+
+    let mut session = None;
+    let mut stopped = pin!(stopped);
+    let mut out = Vec::new();
+    loop {
+        let command = tokio::select! {
+            biased;
+            _ = &mut stopped => break,
+            command = read_command(&mut reader, max_bulk) => command,
+        };
+        let args = match command {
+            Ok(Some(v)) if v.is_empty() => continue,
+            Ok(Some(v)) => v,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // Like Redis, give up on the connection since the rest of the stream can't be
+                // trusted to line up with command boundaries
+                Reply::error(format!("Protocol error: {e}")).write(&mut out);
+                writer.write_all(&out).await?;
+                writer.flush().await?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let quit = name == "QUIT";
+        let reply = execute(&service, &mut session, addr.ip(), &name, &args[1..]).await;
+
+        reply.write(&mut out);
+        writer.write_all(&out).await?;
+        out.clear();
+        // Pipelined commands get their replies in one write
+        if reader.buffer().is_empty() || quit {
+            writer.flush().await?;
+        }
+        if quit {
+            break;
+        }
+    }
+
+    writer.flush().await?;
+    writer.shutdown().await
+}
+
+/// Read a command as an array of bulk strings, or an inline command split on whitespace. `None`
+/// once the client has disconnected.
+async fn read_command<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    max_bulk: usize,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match tcp::read_line(reader).await? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let count = match line.strip_prefix(b"*") {
+        Some(v) => parse_length(v, MAX_ARGS)?,
+        None => {
+            let args = line
+                .split(|v| v.is_ascii_whitespace())
+                .filter(|v| !v.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+
+            return Ok(Some(args));
+        }
+    };
+
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = tcp::read_line(reader)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = match line.strip_prefix(b"$") {
+            Some(v) => parse_length(v, max_bulk)?,
+            None => return Err(tcp::invalid("expected '$'")),
+        };
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(tcp::invalid("expected CRLF after bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+fn parse_length(value: &[u8], max: usize) -> io::Result<usize> {
+    let len = std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .ok_or_else(|| tcp::invalid("invalid length"))?;
+
+    match usize::try_from(len) {
+        Ok(v) if v <= max => Ok(v),
+        _ => Err(tcp::invalid("invalid length")),
+    }
+}
+
+async fn execute(
+    service: &Service,
+    session: &mut Option<Session>,
+    ip: IpAddr,
+    name: &str,
+    args: &[Vec<u8>],
+) -> Reply {
+    let authorization = session.as_ref().map(|v| format!("Bearer {}", v.token));
+    if service
+        .limiter
+        .check(authorization.as_deref(), Some(ip))
+        .is_err()
+    {
+        return Reply::error("rate limit exceeded");
+    }
+
+    match name {
+        "AUTH" => {
+            // The username of `AUTH username password` is ignored, only the token matters
+            let token = match args {
+                [token] | [_, token] => String::from_utf8_lossy(token).into_owned(),
+                _ => return Reply::arity(name),
+            };
+
+            match service.tokens.check(&format!("Bearer {token}")) {
+                Some((role, fingerprint)) => {
+                    *session = Some(Session {
+                        token,
+                        role,
+                        fingerprint,
+                    });
+                    Reply::Ok
+                }
+                None => Reply::Error(
+                    "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                ),
+            }
+        }
+        "QUIT" => Reply::Ok,
+        _ => {
+            let session = match session {
+                Some(v) => v,
+                None => return Reply::Error("NOAUTH Authentication required.".to_string()),
+            };
+
+            let required = match name {
+                "SET" | "DEL" => Role::ReadWrite,
+                _ => Role::ReadOnly,
+            };
+            if session.role < required {
+                return Reply::Error(format!(
+                    "NOPERM this user has no permissions to run the '{}' command",
+                    name.to_ascii_lowercase()
+                ));
+            }
+
+            let db = service.state.db.clone().with_actor(Actor {
+                token: Some(session.fingerprint.0.clone()),
+                ip: Some(ip),
+                request_id: None,
+            });
+            let keys = match args
+                .iter()
+                .map(|v| String::from_utf8(v.clone()))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(v) => v,
+                // Values are the only arguments that can be binary
+                Err(_) if name == "SET" => Vec::new(),
+                Err(_) => return Reply::error("keys must be UTF-8"),
+            };
+
+            match name {
+                "PING" => match args {
+                    [] => Reply::Simple("PONG"),
+                    [message] => Reply::Bulk(Some(message.clone())),
+                    _ => Reply::arity(name),
+                },
+                "GET" => match keys.as_slice() {
+                    [key] => get(db, key.clone()).await,
+                    _ => Reply::arity(name),
+                },
+                "SET" => match args {
+                    [key, value, options @ ..] => set(service, db, key, value, options).await,
+                    _ => Reply::arity(name),
+                },
+                "DEL" if !keys.is_empty() => {
+                    count(db, keys, |db, key| Ok(db.delete(key)?.is_some())).await
+                }
+                "EXISTS" if !keys.is_empty() => {
+                    count(db, keys, |db, key| match db.read(key) {
+                        Ok(_) => Ok(true),
+                        Err(YwkvError::KeyMissing(_) | YwkvError::EmptyTable(_)) => Ok(false),
+                        Err(e) => Err(e),
+                    })
+                    .await
+                }
+                "DEL" | "EXISTS" => Reply::arity(name),
+                "SCAN" => scan(service, db, &keys).await,
+                _ => Reply::error(format!("unknown command '{}'", name.to_ascii_lowercase())),
+            }
+        }
+    }
+}
+
+async fn get(db: ywkv::Db, key: String) -> Reply {
+    match blocking(move || db.read(key)).await {
+        Ok(v) => Reply::Bulk(Some(v.data)),
+        Err(YwkvError::KeyMissing(_) | YwkvError::EmptyTable(_)) => Reply::Bulk(None),
+        Err(e) => Reply::error(e.to_string()),
+    }
+}
+
+/// `SET key value [EX seconds | PX milliseconds]`
+async fn set(
+    service: &Service,
+    db: ywkv::Db,
+    key: &[u8],
+    value: &[u8],
+    options: &[Vec<u8>],
+) -> Reply {
+    let key = match String::from_utf8(key.to_vec()) {
+        Ok(v) => v,
+        Err(_) => return Reply::error("keys must be UTF-8"),
+    };
+
+    let ttl = match options {
+        [] => None,
+        [unit, amount] => {
+            let amount = std::str::from_utf8(amount)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|v| *v > 0);
+            match (unit.to_ascii_uppercase().as_slice(), amount) {
+                (b"EX", Some(v)) => Some(Duration::from_secs(v)),
+                (b"PX", Some(v)) => Some(Duration::from_millis(v)),
+                (b"EX" | b"PX", None) => {
+                    return Reply::error("invalid expire time in 'set' command")
+                }
+                _ => return Reply::error("syntax error"),
+            }
+        }
+        _ => return Reply::error("syntax error"),
+    };
+
+    let checked = service
+        .state
+        .limits
+        .check_key(&key)
+        .and_then(|_| service.state.limits.check_value(value));
+    if let Err(e) = checked {
+        return limit_error(e);
+    }
+
+    let value = Value::new(value.to_vec(), ywkv::DEFAULT_CONTENT_TYPE);
+    let res = match &service.state.group {
+        Some(group) => group.write(db, key, value, ttl).await,
+        None => blocking(move || db.write_with_ttl(key, value, ttl)).await,
+    };
+
+    match res {
+        Ok(_) => Reply::Ok,
+        Err(e) => Reply::error(e.to_string()),
+    }
+}
+
+/// How many of `keys` `f` returns true for, like for `DEL` and `EXISTS`.
+async fn count(
+    db: ywkv::Db,
+    keys: Vec<String>,
+    f: impl Fn(&ywkv::Db, &str) -> Result<bool, YwkvError> + Send + 'static,
+) -> Reply {
+    let counted = blocking(move || {
+        keys.iter().try_fold(0, |count, key| {
+            Ok::<_, YwkvError>(count + i64::from(f(&db, key)?))
+        })
+    })
+    .await;
+
+    match counted {
+        Ok(v) => Reply::Integer(v),
+        Err(e) => Reply::error(e.to_string()),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`. Like Redis, `COUNT` is how many keys are looked at
+/// and `MATCH` filters those afterwards, so a page may come back empty before the scan is done.
+async fn scan(service: &Service, db: ywkv::Db, args: &[String]) -> Reply {
+    let (cursor, options) = match args {
+        [cursor, options @ ..] => (cursor, options),
+        _ => return Reply::arity("SCAN"),
+    };
+    let after = match cursor.parse::<u64>() {
+        Ok(0) => None,
+        Ok(v) => match service.cursors.get(v) {
+            Some(key) => Some(key),
+            None => return Reply::error("invalid cursor"),
+        },
+        Err(_) => return Reply::error("invalid cursor"),
+    };
+
+    let mut pattern = None;
+    let mut count = 10;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.to_ascii_uppercase().as_str(), options.next()) {
+            ("MATCH", Some(v)) => pattern = Some(v.clone()),
+            ("COUNT", Some(v)) => match v.parse::<usize>() {
+                Ok(v) if v > 0 => count = v.min(MAX_SCAN_COUNT),
+                _ => return Reply::error("syntax error"),
+            },
+            _ => return Reply::error("syntax error"),
+        }
+    }
+
+    let page = match blocking(move || db.list_keys(after.as_deref(), count)).await {
+        Ok(v) => v,
+        Err(e) => return Reply::error(e.to_string()),
+    };
+    let next = match page.cursor {
+        Some(key) => service.cursors.save(key),
+        None => 0,
+    };
+    let keys = page
+        .keys
+        .into_iter()
+        .filter(|key| {
+            pattern
+                .as_deref()
+                .is_none_or(|v| glob(v.as_bytes(), key.as_bytes()))
+        })
+        .map(|key| Reply::Bulk(Some(key.into_bytes())))
+        .collect();
+
+    Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string().into_bytes())),
+        Reply::Array(keys),
+    ])
+}
+
+/// Redis style glob matching with `*`, `?`, `[abc]`, `[a-z]`, `[^abc]` and `\` escapes.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let (c, text_rest) = match text.split_first() {
+                Some(v) => v,
+                None => return false,
+            };
+            let (negated, mut set) = match rest.split_first() {
+                Some((b'^', v)) => (true, v),
+                _ => (false, rest),
+            };
+
+            let mut matched = false;
+            loop {
+                match set {
+                    // An unclosed set matches like the rest of the pattern was the set
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        set = tail;
+                        break;
+                    }
+                    [b'\\', v, tail @ ..] | [v, tail @ ..] if tail.first() != Some(&b'-') => {
+                        matched |= v == c;
+                        set = tail;
+                    }
+                    [start, b'-', end, tail @ ..] => {
+                        let (start, end) = (start.min(end), start.max(end));
+                        matched |= (start..=end).contains(&c);
+                        set = tail;
+                    }
+                    [v, tail @ ..] => {
+                        matched |= v == c;
+                        set = tail;
+                    }
+                }
+            }
+
+            matched != negated && glob(set, text_rest)
+        }
+        Some((b'\\', [v, rest @ ..])) => text.first() == Some(v) && glob(rest, &text[1..]),
+        Some((v, rest)) => text.first() == Some(v) && glob(rest, &text[1..]),
+    }
+}
+
+fn limit_error((_, Json(response)): (StatusCode, Json<Response>)) -> Reply {
+    Reply::error(response.into_value())
+}
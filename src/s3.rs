@@ -0,0 +1,476 @@
+//! Scheduled backups uploaded to S3 compatible object storage, keeping only the newest ones.
+
+use std::{
+    fmt::Write,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header::HOST, Method, Request, StatusCode, Uri},
+    Json,
+};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use ywkv::Db;
+
+use crate::{admin, blocking, webhook, DbState};
+
+/// The headers included in request signatures.
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// How long the object storage has to respond to each request, including uploads.
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Where backups are uploaded to, as a path style URL like `https://s3.example.com/bucket/prefix/`.
+/// Objects are named by appending `ywkv-<milliseconds>.redb` to the prefix.
+#[derive(Clone, Debug)]
+pub struct Location {
+    endpoint: Uri,
+    bucket: String,
+    prefix: String,
+}
+
+impl FromStr for Location {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = s
+            .parse::<Uri>()
+            .map_err(|e| anyhow::anyhow!("invalid S3 URL `{s}`: {e}"))?;
+        if !matches!(url.scheme_str(), Some("http" | "https")) || url.host().is_none() {
+            anyhow::bail!("S3 URL `{s}` must be an absolute http or https URL");
+        }
+        if url.query().is_some() {
+            anyhow::bail!("S3 URL `{s}` can't have a query");
+        }
+
+        let path = decode(url.path().trim_start_matches('/'))
+            .ok_or_else(|| anyhow::anyhow!("S3 URL `{s}` has an invalid path"))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((&path, ""));
+        if bucket.is_empty() {
+            anyhow::bail!("S3 URL `{s}` must start its path with the bucket name");
+        }
+
+        Ok(Self {
+            endpoint: Uri::builder()
+                .scheme(url.scheme_str().unwrap_or_default())
+                .authority(url.authority().map(|v| v.as_str()).unwrap_or_default())
+                .path_and_query("/")
+                .build()?,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+/// How and when backups are made.
+pub struct Schedule {
+    pub location: Location,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub interval: Duration,
+    /// How many backups to keep in the bucket
+    pub retention: usize,
+}
+
+/// How scheduled backups have gone since the server started.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct BackupStatus {
+    url: String,
+    /// Seconds between backups
+    interval: u64,
+    /// How many backups are kept in the bucket
+    retention: usize,
+    /// Backups uploaded since the server started
+    uploaded: u64,
+    /// Backups that failed since the server started
+    failed: u64,
+    /// Milliseconds since the unix epoch
+    last_attempt_at: Option<u64>,
+    /// Milliseconds since the unix epoch
+    last_success_at: Option<u64>,
+    /// The name of the last object uploaded
+    last_object: Option<String>,
+    /// Why the last backup failed, if the next one hasn't succeeded yet
+    last_error: Option<String>,
+}
+
+/// Clones share the same status.
+#[derive(Clone, Default)]
+pub struct Backups {
+    status: Option<Arc<Mutex<BackupStatus>>>,
+}
+
+impl Backups {
+    /// Upload a backup of `db` now and then again after every interval.
+    pub fn start(db: Db, schedule: Schedule) -> Self {
+        let location = &schedule.location;
+        let status = Arc::new(Mutex::new(BackupStatus {
+            url: format!(
+                "{}{}/{}",
+                location.endpoint, location.bucket, location.prefix
+            ),
+            interval: schedule.interval.as_secs(),
+            retention: schedule.retention,
+            uploaded: 0,
+            failed: 0,
+            last_attempt_at: None,
+            last_success_at: None,
+            last_object: None,
+            last_error: None,
+        }));
+
+        tokio::spawn({
+            let status = status.clone();
+            let bucket = Bucket {
+                client: webhook::client(),
+                schedule,
+            };
+            async move {
+                let mut interval = tokio::time::interval(bucket.schedule.interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    let res = bucket.back_up(&db).await;
+
+                    let mut status = status.lock().unwrap();
+                    status.last_attempt_at = Some(ywkv::now_millis());
+                    match res {
+                        Ok(name) => {
+                            tracing::info!(name, "Uploaded backup");
+                            status.uploaded += 1;
+                            status.last_success_at = status.last_attempt_at;
+                            status.last_object = Some(name);
+                            status.last_error = None;
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to upload backup: {e}");
+                            status.failed += 1;
+                            status.last_error = Some(e);
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            status: Some(status),
+        }
+    }
+}
+
+struct Bucket {
+    client: webhook::Client,
+    schedule: Schedule,
+}
+
+impl Bucket {
+    /// Upload a new backup and remove the ones past the retention count, returning the name of
+    /// the new one.
+    async fn back_up(&self, db: &Db) -> Result<String, String> {
+        let path = admin::temp_path("s3");
+        let res = blocking({
+            let db = db.clone();
+            let path = path.clone();
+            move || db.backup(path)
+        })
+        .await;
+        let data = match res {
+            Ok(()) => tokio::fs::read(&path).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            tracing::warn!("Failed to remove backup `{}`: {e}", path.display());
+        }
+
+        let name = format!(
+            "{}ywkv-{}.redb",
+            self.schedule.location.prefix,
+            ywkv::now_millis()
+        );
+        self.request(Method::PUT, &name, &[], data?.into()).await?;
+
+        // Listed after uploading, so a failed upload never removes an older backup
+        let mut backups = self.list_backups().await?;
+        backups.sort_by_key(|(millis, _)| *millis);
+        let expired = backups.len().saturating_sub(self.schedule.retention);
+        for (_, old) in backups.into_iter().take(expired) {
+            self.request(Method::DELETE, &old, &[], Bytes::new())
+                .await?;
+        }
+
+        Ok(name)
+    }
+
+    /// Every backup under the prefix as `(milliseconds, name)`.
+    async fn list_backups(&self) -> Result<Vec<(u64, String)>, String> {
+        let prefix = format!("{}ywkv-", self.schedule.location.prefix);
+
+        let mut backups = vec![];
+        let mut token = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.clone())];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let body = self.request(Method::GET, "", &query, Bytes::new()).await?;
+            let body = String::from_utf8_lossy(&body);
+
+            for key in elements(&body, "Key") {
+                let millis = key
+                    .strip_prefix(&prefix)
+                    .and_then(|v| v.strip_suffix(".redb"))
+                    .and_then(|v| v.parse::<u64>().ok());
+                if let Some(millis) = millis {
+                    backups.push((millis, key));
+                }
+            }
+
+            let truncated = elements(&body, "IsTruncated")
+                .first()
+                .is_some_and(|v| v == "true");
+            match elements(&body, "NextContinuationToken").pop() {
+                Some(next) if truncated => token = Some(next),
+                _ => break,
+            }
+        }
+
+        Ok(backups)
+    }
+
+    /// Send a request for the object `name` in the bucket, or the bucket itself if `name` is
+    /// empty, signed with AWS Signature Version 4.
+    async fn request(
+        &self,
+        method: Method,
+        name: &str,
+        query: &[(&str, String)],
+        body: Bytes,
+    ) -> Result<Bytes, String> {
+        let schedule = &self.schedule;
+        let location = &schedule.location;
+
+        let path = match name {
+            "" => format!("/{}", encode(&location.bucket, false)),
+            name => format!(
+                "/{}/{}",
+                encode(&location.bucket, false),
+                encode(name, true)
+            ),
+        };
+        let mut query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", encode(k, false), encode(v, false)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let host = location
+            .endpoint
+            .authority()
+            .map(|v| v.as_str())
+            .unwrap_or_default();
+
+        let now = ywkv::now_millis() / 1000;
+        let (date, time) = utc(now);
+        let amz_date = format!("{date}T{time}Z");
+        let payload_hash = hex(&Sha256::digest(&body));
+
+        let canonical_request = [
+            method.as_str(),
+            &path,
+            &query,
+            &format!("host:{host}"),
+            &format!("x-amz-content-sha256:{payload_hash}"),
+            &format!("x-amz-date:{amz_date}"),
+            "",
+            SIGNED_HEADERS,
+            &payload_hash,
+        ]
+        .join("\n");
+        let scope = format!("{date}/{}/s3/aws4_request", schedule.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request))
+        );
+        let key = [date.as_str(), &schedule.region, "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", schedule.secret_access_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        let mut uri = format!(
+            "{}{path}",
+            location.endpoint.to_string().trim_end_matches('/')
+        );
+        if !query.is_empty() {
+            uri = format!("{uri}?{query}");
+        }
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(HOST, host)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={SIGNED_HEADERS}, Signature={signature}",
+                    schedule.access_key_id
+                ),
+            )
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+
+        let response = match tokio::time::timeout(TIMEOUT, self.client.request(request)).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("no response within {}s", TIMEOUT.as_secs())),
+        };
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            let message = elements(&String::from_utf8_lossy(&body), "Message").pop();
+            return Err(match message {
+                Some(message) => format!("object storage responded with {status}: {message}"),
+                None => format!("object storage responded with {status}"),
+            });
+        }
+
+        Ok(body)
+    }
+}
+
+/// Percent-encode everything but unreserved characters, and `/` if `path` is set, the way
+/// signatures expect.
+fn encode(s: &str, path: bool) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if path => encoded.push('/'),
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Undo percent-encoding, or `None` if it's invalid or the result isn't UTF-8.
+fn decode(s: &str) -> Option<String> {
+    let mut decoded = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[2..];
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// The text of every `<name>` element. S3 responses are simple enough not to need an XML parser.
+fn elements(xml: &str, name: &str) -> Vec<String> {
+    let (open, close) = (format!("<{name}>"), format!("</{name}>"));
+
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some((_, after)) = rest.split_once(&open) {
+        let Some((text, after)) = after.split_once(&close) else {
+            break;
+        };
+        found.push(
+            text.replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = after;
+    }
+
+    found
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length works");
+    mac.update(data);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{v:02x}")).collect()
+}
+
+/// `secs` since the unix epoch as a `YYYYMMDD` date and `HHMMSS` time in UTC.
+fn utc(secs: u64) -> (String, String) {
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // From Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        format!("{year:04}{month:02}{day:02}"),
+        format!("{:02}{:02}{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+    )
+}
+
+/// How scheduled backups to object storage have gone since the server started.
+#[utoipa::path(
+    get,
+    path = "/_admin/backup/s3",
+    tag = "admin",
+    responses(
+        (status = 200, body = ywkv::Response<BackupStatus>),
+        (status = 404, description = "Scheduled backups are not enabled", body = ywkv::Response<String>),
+    )
+)]
+pub async fn backups(
+    State(state): State<DbState>,
+) -> Result<(StatusCode, Json<ywkv::Response<BackupStatus>>), (StatusCode, Json<ywkv::Response>)> {
+    match &state.s3_backups.status {
+        Some(status) => Ok((
+            StatusCode::OK,
+            Json::from(ywkv::Response::new(
+                status.lock().unwrap().clone(),
+                ywkv::Status::Read(ywkv::ReadStatus::Found),
+            )),
+        )),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json::from(ywkv::Response::new(
+                "scheduled backups are not enabled".to_string(),
+                ywkv::Status::Read(ywkv::ReadStatus::Missing),
+            )),
+        )),
+    }
+}
@@ -0,0 +1,566 @@
+//! Small WebAssembly modules that run on the server inside a single transaction, for
+//! read-modify-write logic like merging values that would otherwise take several round trips.
+//!
+//! A script exports its `memory` and a `run` function taking nothing and returning an `i32`, and
+//! can import these functions from the `ywkv` module:
+//!
+//! - `input_len() -> i32` and `input(ptr: i32)`, the request body
+//! - `get(key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32) -> i32`, copying as much of
+//!   the value as fits into the buffer and returning its full length, or -1 if the key is missing
+//! - `set(key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32)`
+//! - `delete(key_ptr: i32, key_len: i32) -> i32`, returning 1 if the key existed and 0 otherwise
+//! - `output(ptr: i32, len: i32)`, the value to respond with
+//!
+//! `run` returning anything but 0 rolls the transaction back.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use axum::{
+    body::Bytes,
+    extract::{self, FromRef, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use utoipa::OpenApi;
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+use ywkv::{Response, Transaction, Value, YwkvError};
+
+use crate::{blocking, limits::Limits, openapi::RawValue, DbState, Table};
+
+/// Scripts can't grow their memory past this.
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Longer names are refused, since they're also file names in `--scripts-dir`.
+const MAX_NAME_LEN: usize = 64;
+
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+#[derive(OpenApi)]
+#[openapi(paths(exec, list, upload, remove))]
+pub struct ApiDoc;
+
+/// The scripts that can be run, by name.
+#[derive(Clone)]
+pub struct Scripts {
+    engine: Engine,
+    modules: Arc<RwLock<BTreeMap<String, Module>>>,
+    /// Uploads are saved here so they are loaded again on restart. They only last until the
+    /// server stops if not set.
+    dir: Option<Arc<Path>>,
+    /// How many instructions a run may take, roughly
+    fuel: u64,
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+
+        Self {
+            engine: Engine::new(&config),
+            modules: Arc::default(),
+            dir: None,
+            fuel: DEFAULT_FUEL,
+        }
+    }
+}
+
+impl FromRef<DbState> for Scripts {
+    fn from_ref(state: &DbState) -> Self {
+        state.scripts.clone()
+    }
+}
+
+impl Scripts {
+    /// Load every `.wasm` and `.wat` file in `dir`, named after the file without its extension.
+    pub fn new(dir: Option<PathBuf>, fuel: u64) -> anyhow::Result<Self> {
+        let mut scripts = Self {
+            fuel,
+            ..Self::default()
+        };
+        let dir = match dir {
+            Some(v) => v,
+            None => return Ok(scripts),
+        };
+
+        let mut modules = BTreeMap::new();
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("failed to read scripts directory `{}`", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let is_script = path.extension().is_some_and(|v| v == "wasm" || v == "wat");
+            let name = match path.file_stem().and_then(|v| v.to_str()) {
+                Some(v) if is_script && valid_name(v) => v.to_string(),
+                _ => continue,
+            };
+
+            let module = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|v| scripts.compile(&v))
+                .with_context(|| format!("failed to load script `{}`", path.display()))?;
+            if modules.insert(name, module).is_some() {
+                anyhow::bail!("script `{}` exists as both .wasm and .wat", path.display());
+            }
+        }
+        tracing::info!("Loaded {} scripts from {}", modules.len(), dir.display());
+
+        scripts.modules = Arc::new(RwLock::new(modules));
+        scripts.dir = Some(dir.into());
+        Ok(scripts)
+    }
+
+    /// Compile either the binary or text format, checking the module exports what scripts need.
+    fn compile(&self, source: &[u8]) -> anyhow::Result<Module> {
+        let module = Module::new(&self.engine, source)?;
+
+        let exports = module.exports().map(|v| v.name()).collect::<Vec<_>>();
+        for name in ["memory", "run"] {
+            if !exports.contains(&name) {
+                anyhow::bail!("module doesn't export `{name}`");
+            }
+        }
+
+        Ok(module)
+    }
+
+    /// Run `module` in `txn`, returning the code `run` returned and the output it set.
+    fn run(
+        &self,
+        module: &Module,
+        txn: &mut Transaction,
+        limits: &Limits,
+        input: &[u8],
+    ) -> Result<(i32, Vec<u8>), Stopped> {
+        let host = Host {
+            txn,
+            limits,
+            input,
+            output: vec![],
+            store_limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            failure: None,
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.store_limits);
+        store
+            .set_fuel(self.fuel)
+            .expect("fuel is enabled for every script");
+
+        let res = link(&self.engine)
+            .and_then(|linker| linker.instantiate_and_start(&mut store, module))
+            .and_then(|instance| instance.get_typed_func::<(), i32>(&store, "run"))
+            .and_then(|run| run.call(&mut store, ()));
+
+        let host = store.into_data();
+        if let Some(failure) = host.failure {
+            return Err(failure);
+        }
+        match res {
+            Ok(code) => Ok((code, host.output)),
+            Err(e) => Err(Stopped::Trapped(e.to_string())),
+        }
+    }
+}
+
+/// What a script can reach while it runs.
+struct Host<'a, 'db, 'txn> {
+    txn: &'a mut Transaction<'db, 'txn>,
+    limits: &'a Limits,
+    input: &'a [u8],
+    output: Vec<u8>,
+    store_limits: StoreLimits,
+    /// Why a host function stopped the script, when it wasn't the script's fault
+    failure: Option<Stopped>,
+}
+
+/// Why a script didn't finish.
+enum Stopped {
+    Trapped(String),
+    /// A write didn't pass the configured [Limits]
+    Rejected((StatusCode, Json<Response>)),
+    Db(YwkvError),
+}
+
+fn link<'a, 'db, 'txn>(engine: &Engine) -> Result<Linker<Host<'a, 'db, 'txn>>, wasmi::Error> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap("ywkv", "input_len", |caller: Caller<Host>| {
+        caller.data().input.len() as i32
+    })?;
+    linker.func_wrap("ywkv", "input", |mut caller: Caller<Host>, ptr: i32| {
+        let input = caller.data().input;
+        write_memory(&mut caller, ptr, input)
+    })?;
+    linker.func_wrap(
+        "ywkv",
+        "get",
+        |mut caller: Caller<Host>, key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32| {
+            let key = read_key(&caller, key_ptr, key_len)?;
+            let value = match caller.data().txn.read(&key) {
+                Ok(v) => v.data,
+                Err(YwkvError::KeyMissing(_)) => return Ok(-1),
+                Err(e) => return Err(fail(&mut caller, Stopped::Db(e))),
+            };
+
+            let copied = value.len().min(buf_len.max(0) as usize);
+            write_memory(&mut caller, buf_ptr, &value[..copied])?;
+            Ok(value.len() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "ywkv",
+        "set",
+        |mut caller: Caller<Host>, key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32| {
+            let key = read_key(&caller, key_ptr, key_len)?;
+            let value = read_memory(&caller, value_ptr, value_len)?;
+            let limits = caller.data().limits;
+            if let Err(e) = limits.check_key(&key).and(limits.check_value(&value)) {
+                return Err(fail(&mut caller, Stopped::Rejected(e)));
+            }
+
+            let value = match String::from_utf8(value) {
+                Ok(v) => Value::from(v),
+                Err(e) => Value::from(e.into_bytes()),
+            };
+            match caller.data_mut().txn.write(key, value) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(fail(&mut caller, Stopped::Db(e))),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "ywkv",
+        "delete",
+        |mut caller: Caller<Host>, key_ptr: i32, key_len: i32| {
+            let key = read_key(&caller, key_ptr, key_len)?;
+            match caller.data_mut().txn.delete(key) {
+                Ok(v) => Ok(v.is_some() as i32),
+                Err(e) => Err(fail(&mut caller, Stopped::Db(e))),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "ywkv",
+        "output",
+        |mut caller: Caller<Host>, ptr: i32, len: i32| {
+            caller.data_mut().output = read_memory(&caller, ptr, len)?;
+            Ok(())
+        },
+    )?;
+
+    Ok(linker)
+}
+
+/// Stop the script with `failure` as the reason.
+fn fail(caller: &mut Caller<Host>, failure: Stopped) -> wasmi::Error {
+    caller.data_mut().failure = Some(failure);
+    wasmi::Error::new("stopped by the host")
+}
+
+fn memory(caller: &Caller<Host>) -> Result<wasmi::Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("module doesn't export `memory`"))
+}
+
+/// Copy bytes out of the script's memory. The range is checked before anything is allocated, so
+/// a script can't make the host allocate more than [MAX_MEMORY] by asking for a huge length.
+fn read_memory(caller: &Caller<Host>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = memory(caller)?;
+    let start = ptr as u32 as usize;
+    match start.checked_add(len.max(0) as usize) {
+        Some(end) if end <= memory.data_size(caller) => {
+            Ok(memory.data(caller)[start..end].to_vec())
+        }
+        _ => Err(wasmi::Error::new("out of bounds memory access")),
+    }
+}
+
+fn read_key(caller: &Caller<Host>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    String::from_utf8(read_memory(caller, ptr, len)?)
+        .map_err(|_| wasmi::Error::new("key isn't valid UTF-8"))
+}
+
+fn write_memory(caller: &mut Caller<Host>, ptr: i32, data: &[u8]) -> Result<(), wasmi::Error> {
+    memory(caller)?.write(caller, ptr as u32 as usize, data)?;
+    Ok(())
+}
+
+/// Letters, digits, `-` and `_`, so every name is also a safe file name.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[derive(Deserialize)]
+pub struct ScriptPath {
+    script: String,
+}
+
+/// Run a script inside a single transaction with the request body as its input, responding with
+/// its output. Everything it wrote is rolled back if it fails or returns anything but 0.
+#[utoipa::path(
+    post,
+    path = "/_exec/{script}",
+    tag = "values",
+    params(("script" = String, Path, description = "The name the script was uploaded or loaded with")),
+    request_body(content = RawValue, description = "The script's input", content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "The script's output", body = Response<String>),
+        (status = 400, description = "A key the script wrote isn't allowed", body = Response<String>),
+        (status = 404, description = "There's no script with this name", body = Response<String>),
+        (status = 412, description = "The script returned something other than 0, so nothing was written. The value is its output.", body = Response<String>),
+        (status = 413, description = "A value the script wrote is too large", body = Response<String>),
+        (status = 500, description = "The script trapped or ran out of fuel, so nothing was written", body = Response<String>),
+        (status = 507, description = "A quota is full", body = Response<String>),
+    )
+)]
+pub async fn exec(
+    Table(db): Table,
+    State(scripts): State<Scripts>,
+    State(limits): State<Arc<Limits>>,
+    extract::Path(ScriptPath { script }): extract::Path<ScriptPath>,
+    input: Bytes,
+) -> Result<Json<Response>, (StatusCode, Json<Response>)> {
+    let module = scripts.modules.read().unwrap().get(&script).cloned();
+    let module = match module {
+        Some(v) => v,
+        None => return Err(missing(&script)),
+    };
+
+    let output = blocking({
+        let script = script.clone();
+        move || {
+            // Set when the response isn't the usual one for the error the transaction returns
+            let mut stopped = None;
+            let res = db.transaction(|txn| match scripts.run(&module, txn, &limits, &input) {
+                Ok((0, output)) => Ok(output),
+                Ok((code, output)) => {
+                    stopped = Some(returned(&script, code, &output));
+                    Err(YwkvError::Aborted(format!("script returned {code}")))
+                }
+                Err(Stopped::Rejected(e)) => {
+                    stopped = Some(e);
+                    Err(YwkvError::Aborted("a write was rejected".to_string()))
+                }
+                Err(Stopped::Trapped(e)) => {
+                    Err(YwkvError::Aborted(format!("script `{script}` failed: {e}")))
+                }
+                Err(Stopped::Db(e)) => Err(e),
+            });
+
+            match (res, stopped) {
+                (Ok(output), _) => Ok(output),
+                (Err(_), Some(e)) => Err(e),
+                (Err(e), None) => Err(Response::from_write_error(e)),
+            }
+        }
+    })
+    .await?;
+
+    Ok(Json::from(Response::new(
+        String::from_utf8_lossy(&output).into_owned(),
+        ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+    )))
+}
+
+/// The response when a script returns something other than 0, with its output as the message.
+fn returned(script: &str, code: i32, output: &[u8]) -> (StatusCode, Json<Response>) {
+    let message = match output.is_empty() {
+        true => format!("script `{script}` returned {code}"),
+        false => String::from_utf8_lossy(output).into_owned(),
+    };
+
+    (
+        StatusCode::PRECONDITION_FAILED,
+        Json::from(Response::new(
+            message,
+            ywkv::Status::Write(ywkv::WriteStatus::PreconditionFailed),
+        )),
+    )
+}
+
+fn missing(script: &str) -> (StatusCode, Json<Response>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json::from(Response::new(
+            format!("script `{script}` not found"),
+            ywkv::Status::Read(ywkv::ReadStatus::Missing),
+        )),
+    )
+}
+
+/// The names of every script that can be run.
+#[utoipa::path(
+    get,
+    path = "/_admin/scripts",
+    tag = "admin",
+    responses((status = 200, body = Response<Vec<String>>))
+)]
+pub async fn list(State(scripts): State<Scripts>) -> Json<Response<Vec<String>>> {
+    let names = scripts.modules.read().unwrap().keys().cloned().collect();
+    Json::from(Response::new(
+        names,
+        ywkv::Status::Read(ywkv::ReadStatus::Found),
+    ))
+}
+
+/// Add or replace a script, in either the WebAssembly binary or text format. It's saved to
+/// `--scripts-dir` if one is set.
+#[utoipa::path(
+    put,
+    path = "/_admin/scripts/{script}",
+    tag = "admin",
+    params(("script" = String, Path, description = "Letters, digits, `-` and `_`, up to 64 characters")),
+    request_body(content = RawValue, description = "The module", content_type = "application/wasm"),
+    responses(
+        (status = 200, description = "The script was replaced", body = Response<String>),
+        (status = 201, description = "The script was added", body = Response<String>),
+        (status = 400, description = "The name isn't allowed or the module is invalid", body = Response<String>),
+    )
+)]
+pub async fn upload(
+    State(scripts): State<Scripts>,
+    extract::Path(ScriptPath { script }): extract::Path<ScriptPath>,
+    source: Bytes,
+) -> (StatusCode, Json<Response>) {
+    let error = |status, message| {
+        (
+            status,
+            Json::from(Response::new(
+                message,
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            )),
+        )
+    };
+    if !valid_name(&script) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "script names may only use letters, digits, `-` and `_`, up to 64 characters"
+                .to_string(),
+        );
+    }
+
+    let res = blocking({
+        let scripts = scripts.clone();
+        let script = script.clone();
+        move || {
+            let module = scripts
+                .compile(&source)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+            if let Some(dir) = &scripts.dir {
+                save(dir, &script, &source)
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))?;
+            }
+            Ok(module)
+        }
+    })
+    .await;
+    let module = match res {
+        Ok(v) => v,
+        Err((status, message)) => return error(status, message),
+    };
+
+    match scripts
+        .modules
+        .write()
+        .unwrap()
+        .insert(script.clone(), module)
+    {
+        Some(_) => (
+            StatusCode::OK,
+            Json::from(Response::new(
+                script,
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+            )),
+        ),
+        None => (
+            StatusCode::CREATED,
+            Json::from(Response::new(
+                script,
+                ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+            )),
+        ),
+    }
+}
+
+/// Write `source` to `dir` under the extension for its format, replacing the script's old file.
+fn save(dir: &Path, script: &str, source: &[u8]) -> anyhow::Result<()> {
+    let (extension, other) = match source.starts_with(b"\0asm") {
+        true => ("wasm", "wat"),
+        false => ("wat", "wasm"),
+    };
+
+    let path = dir.join(format!("{script}.{extension}"));
+    std::fs::write(&path, source)
+        .with_context(|| format!("failed to save script to `{}`", path.display()))?;
+    remove_file(&dir.join(format!("{script}.{other}")))
+}
+
+fn remove_file(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("failed to remove `{}`", path.display())),
+    }
+}
+
+/// Remove a script, along with its file in `--scripts-dir`.
+#[utoipa::path(
+    delete,
+    path = "/_admin/scripts/{script}",
+    tag = "admin",
+    params(("script" = String, Path)),
+    responses(
+        (status = 200, description = "The script was removed", body = Response<String>),
+        (status = 404, description = "There's no script with this name", body = Response<String>),
+    )
+)]
+pub async fn remove(
+    State(scripts): State<Scripts>,
+    extract::Path(ScriptPath { script }): extract::Path<ScriptPath>,
+) -> (StatusCode, Json<Response>) {
+    if scripts.modules.write().unwrap().remove(&script).is_none() {
+        return missing(&script);
+    }
+
+    if let Some(dir) = scripts.dir.clone() {
+        let res = blocking({
+            let script = script.clone();
+            move || {
+                remove_file(&dir.join(format!("{script}.wasm")))?;
+                remove_file(&dir.join(format!("{script}.wat")))
+            }
+        })
+        .await;
+        if let Err(e) = res {
+            tracing::error!("Failed to remove script `{script}`: {e:#}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json::from(Response::new(
+                    format!("{e:#}"),
+                    ywkv::Status::Write(ywkv::WriteStatus::Failure),
+                )),
+            );
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json::from(Response::new(
+            script,
+            ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+        )),
+    )
+}
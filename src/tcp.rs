@@ -0,0 +1,107 @@
+//! Accepting connections for the servers that speak their own protocol over TCP, like RESP and
+//! memcached.
+
+use std::{future::Future, io, net::SocketAddr, pin::pin};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// The longest line a client may send.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// A plain or TLS connection.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Accept connections on `listener` until `stopped` resolves, over TLS if a certificate is
+/// configured, and run `handle` for each of them. `handle` gets its own `stopped` signal to close
+/// the connection with, and this returns once every connection has closed.
+pub async fn serve<F, H, R>(
+    listener: std::net::TcpListener,
+    protocol: &'static str,
+    tls: Option<RustlsConfig>,
+    stopped: impl Fn() -> F,
+    handle: H,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+    H: Fn(Box<dyn Stream>, SocketAddr, F) -> R + Clone + Send + 'static,
+    R: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let listener = TcpListener::from_std(listener)?;
+    let mut connections = tokio::task::JoinSet::new();
+
+    let mut stop = pin!(stopped());
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::warn!("Failed to accept {protocol} connection: {e}");
+                    continue;
+                }
+            },
+            // Forget connections that have closed
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = &mut stop => break,
+        };
+        let _ = stream.set_nodelay(true);
+
+        let handle = handle.clone();
+        let stopped = stopped();
+        let tls = tls.clone();
+        connections.spawn(async move {
+            let stream: Box<dyn Stream> = match tls {
+                Some(config) => match TlsAcceptor::from(config.get_inner()).accept(stream).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(e) => {
+                        tracing::debug!("{protocol} TLS handshake failed: {e}");
+                        return;
+                    }
+                },
+                None => Box::new(stream),
+            };
+            if let Err(e) = handle(stream, addr, stopped).await {
+                tracing::debug!(%addr, "{protocol} connection closed: {e}");
+            }
+        });
+    }
+
+    while connections.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+/// A line without its CRLF, or `None` at the end of the stream.
+pub async fn read_line<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(invalid("line too long"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+/// An error for the client sending something that doesn't follow the protocol.
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
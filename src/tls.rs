@@ -0,0 +1,57 @@
+//! Serving over TLS with a certificate that is reloaded whenever it changes on disk.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+
+/// How often to check the certificate and key for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+pub async fn load(cert: &Path, key: &Path) -> anyhow::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(cert, key)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load TLS certificate `{}` and key `{}`",
+                cert.display(),
+                key.display()
+            )
+        })
+}
+
+/// Reload the certificate and key whenever either file is modified so they can be rotated
+/// without a restart. Existing connections keep using the certificate they were opened with.
+pub async fn watch(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut last_modified = modified(&cert, &key);
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let current = modified(&cert, &key);
+        if current == last_modified {
+            continue;
+        }
+
+        // Only remember the new modification times once the reload works, so a half-written file
+        // is retried on the next tick instead of being skipped
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(_) => {
+                tracing::info!("Reloaded TLS certificate");
+                last_modified = current;
+            }
+            Err(e) => tracing::error!("Failed to reload TLS certificate: {e}"),
+        }
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(cert).and_then(|v| v.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|v| v.modified()).ok()?;
+
+    Some((cert, key))
+}
@@ -0,0 +1,88 @@
+//! Serving over a Unix domain socket instead of TCP.
+
+use std::{
+    future::Future,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use axum::Router;
+use hyper::server::accept::Accept;
+use tokio::net::{UnixListener, UnixStream};
+
+struct Listener(UnixListener);
+
+impl Accept for Listener {
+    type Conn = UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|v| Some(v.map(|(stream, _)| stream)))
+    }
+}
+
+/// Bind `path` with permissions `mode`, replacing a socket left behind by a server that didn't
+/// shut down cleanly. The socket file is removed again once the server stops.
+///
+/// There is no client address, so rate limiting by IP puts every client in one bucket.
+pub async fn serve(
+    app: Router,
+    path: &Path,
+    mode: u32,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    remove_stale(path)?;
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind Unix socket `{}`", path.display()))?;
+    let _guard = Cleanup(path.to_path_buf());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).with_context(|| {
+        format!(
+            "failed to set permissions of Unix socket `{}`",
+            path.display()
+        )
+    })?;
+
+    axum::Server::builder(Listener(listener))
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+
+fn remove_stale(path: &Path) -> anyhow::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("`{}` exists and is not a socket", path.display());
+    }
+    // Something is still accepting connections on it, so it isn't ours to remove
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        anyhow::bail!("Unix socket `{}` is already in use", path.display());
+    }
+
+    std::fs::remove_file(path)
+        .with_context(|| format!("failed to remove stale Unix socket `{}`", path.display()))
+}
+
+/// Removes the socket file when dropped, whether the server stopped cleanly or not.
+struct Cleanup(PathBuf);
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Failed to remove Unix socket `{}`: {e}", self.0.display());
+        }
+    }
+}
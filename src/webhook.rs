@@ -0,0 +1,334 @@
+//! Signed JSON POSTs to webhook targets whenever a key is created, overwritten or deleted.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header::CONTENT_TYPE, Method, Request, StatusCode, Uri},
+    Json,
+};
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use utoipa::ToSchema;
+use ywkv::{Change, ChangeKind, Db};
+
+use crate::DbState;
+
+/// How many deliveries can wait for a slow target before new ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Attempts per delivery, waiting twice as long after each failure.
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long a target has to respond to a delivery.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Client = hyper::Client<HttpsConnector<HttpConnector>>;
+
+/// A client for http and https URLs that trusts the usual web certificate roots.
+pub fn client() -> Client {
+    hyper::Client::builder().build(
+        hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build(),
+    )
+}
+
+/// A URL to POST changes to. A fragment like `https://example.com/hook#config/` only sends changes
+/// to keys starting with `config/`, since fragments are never sent to the server anyway.
+#[derive(Clone, Debug)]
+pub struct Target {
+    url: Uri,
+    prefix: String,
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, prefix) = s.split_once('#').unwrap_or((s, ""));
+        let url = url
+            .parse::<Uri>()
+            .map_err(|e| anyhow::anyhow!("invalid webhook URL `{url}`: {e}"))?;
+        if !matches!(url.scheme_str(), Some("http" | "https")) || url.host().is_none() {
+            anyhow::bail!("webhook URL `{url}` must be an absolute http or https URL");
+        }
+
+        Ok(Self {
+            url,
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+/// How deliveries to a target have gone since the server started.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct DeliveryStatus {
+    url: String,
+    prefix: String,
+    /// Deliveries the target accepted with a 2xx response
+    delivered: u64,
+    /// Deliveries given up on after every attempt failed
+    failed: u64,
+    /// Changes that were never sent because too many deliveries were waiting
+    dropped: u64,
+    /// Deliveries waiting to be sent, including the one being attempted
+    pending: u64,
+    /// Milliseconds since the unix epoch
+    last_attempt_at: Option<u64>,
+    /// Milliseconds since the unix epoch
+    last_success_at: Option<u64>,
+    /// Why the last failed attempt failed
+    last_error: Option<String>,
+}
+
+struct Delivery {
+    id: String,
+    event: &'static str,
+    body: Bytes,
+    signature: String,
+}
+
+/// Clones share the same targets and their delivery statuses.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    statuses: Arc<Vec<Mutex<DeliveryStatus>>>,
+}
+
+impl Webhooks {
+    /// Deliver every change committed to `db` from now on to `targets`, signed with `secret`.
+    pub fn start(db: &Db, targets: Vec<Target>, secret: &str) -> Self {
+        let client = client();
+        let statuses = Arc::new(
+            targets
+                .iter()
+                .map(|target| {
+                    Mutex::new(DeliveryStatus {
+                        url: target.url.to_string(),
+                        prefix: target.prefix.clone(),
+                        delivered: 0,
+                        failed: 0,
+                        dropped: 0,
+                        pending: 0,
+                        last_attempt_at: None,
+                        last_success_at: None,
+                        last_error: None,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        let mut queues = Vec::new();
+        for (i, target) in targets.into_iter().enumerate() {
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(deliver(
+                client.clone(),
+                target.url.clone(),
+                receiver,
+                statuses.clone(),
+                i,
+            ));
+            queues.push((target, sender));
+        }
+
+        let mut changes = db.subscribe();
+        let mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length works");
+        tokio::spawn({
+            let statuses = statuses.clone();
+            async move {
+                let started = ywkv::now_millis();
+                let mut count = 0_u64;
+                loop {
+                    let change = match changes.recv().await {
+                        Ok(v) => v,
+                        // Count the changes missed as dropped for every target they could have
+                        // been for
+                        Err(RecvError::Lagged(skipped)) => {
+                            for status in statuses.iter() {
+                                status.lock().unwrap().dropped += skipped;
+                            }
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    let targets = queues
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (target, _))| change.key.starts_with(&target.prefix))
+                        .collect::<Vec<_>>();
+                    if targets.is_empty() {
+                        continue;
+                    }
+
+                    let id = format!("{started}-{count}");
+                    count += 1;
+                    let delivery = Arc::new(payload(id, &change, mac.clone()));
+                    for (i, (_, queue)) in targets {
+                        let mut status = statuses[i].lock().unwrap();
+                        match queue.try_send(delivery.clone()) {
+                            Ok(()) => status.pending += 1,
+                            Err(_) => status.dropped += 1,
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { statuses }
+    }
+
+    pub fn statuses(&self) -> Vec<DeliveryStatus> {
+        self.statuses
+            .iter()
+            .map(|v| v.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+/// The body and signature sent for a change.
+fn payload(id: String, change: &Change, mut mac: Hmac<Sha256>) -> Delivery {
+    #[derive(Serialize)]
+    struct Payload<'a> {
+        id: &'a str,
+        event: &'static str,
+        table: &'a str,
+        key: &'a str,
+        /// Sent as text, like the `/_watch` events
+        value: Option<&'a ywkv::Value>,
+        content_type: Option<&'a str>,
+        /// Milliseconds since the unix epoch
+        at: u64,
+    }
+
+    let event = match (change.kind, change.created) {
+        (ChangeKind::Set, true) => "created",
+        (ChangeKind::Set, false) => "overwritten",
+        (ChangeKind::Delete, _) => "deleted",
+    };
+    let body = serde_json::to_vec(&Payload {
+        id: &id,
+        event,
+        table: &change.table,
+        key: &change.key,
+        value: change.value.as_ref(),
+        content_type: change.value.as_ref().map(|v| v.content_type.as_str()),
+        at: ywkv::now_millis(),
+    })
+    .expect("payloads always serialize");
+
+    mac.update(&body);
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|v| format!("{v:02x}"))
+        .collect::<String>();
+
+    Delivery {
+        id,
+        event,
+        body: body.into(),
+        signature: format!("sha256={signature}"),
+    }
+}
+
+/// Send deliveries to a target one at a time, in the order the changes were committed, retrying
+/// each with backoff until it succeeds or runs out of attempts.
+async fn deliver(
+    client: Client,
+    url: Uri,
+    mut receiver: mpsc::Receiver<Arc<Delivery>>,
+    statuses: Arc<Vec<Mutex<DeliveryStatus>>>,
+    i: usize,
+) {
+    while let Some(delivery) = receiver.recv().await {
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let res = send(&client, &url, &delivery).await;
+
+            let done = {
+                let mut status = statuses[i].lock().unwrap();
+                status.last_attempt_at = Some(ywkv::now_millis());
+                match res {
+                    Ok(()) => {
+                        status.delivered += 1;
+                        status.pending -= 1;
+                        status.last_success_at = status.last_attempt_at;
+                        true
+                    }
+                    Err(e) if attempt >= MAX_ATTEMPTS => {
+                        tracing::warn!(%url, id = delivery.id, "Giving up on webhook delivery: {e}");
+                        status.failed += 1;
+                        status.pending -= 1;
+                        status.last_error = Some(e);
+                        true
+                    }
+                    Err(e) => {
+                        tracing::debug!(%url, id = delivery.id, attempt, "Webhook delivery failed: {e}");
+                        status.last_error = Some(e);
+                        false
+                    }
+                }
+            };
+            if done {
+                break;
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+async fn send(client: &Client, url: &Uri, delivery: &Delivery) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .header("x-ywkv-delivery", &delivery.id)
+        .header("x-ywkv-event", delivery.event)
+        .header("x-ywkv-signature", &delivery.signature)
+        .body(Body::from(delivery.body.clone()))
+        .map_err(|e| e.to_string())?;
+
+    match tokio::time::timeout(TIMEOUT, client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => Ok(()),
+        Ok(Ok(response)) => Err(format!("the target responded with {}", response.status())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no response within {}s", TIMEOUT.as_secs())),
+    }
+}
+
+/// How deliveries to each webhook target have gone since the server started.
+#[utoipa::path(
+    get,
+    path = "/_admin/webhooks",
+    tag = "admin",
+    responses((status = 200, body = ywkv::Response<Vec<DeliveryStatus>>))
+)]
+pub async fn deliveries(
+    State(state): State<DbState>,
+) -> (StatusCode, Json<ywkv::Response<Vec<DeliveryStatus>>>) {
+    (
+        StatusCode::OK,
+        Json::from(ywkv::Response::new(
+            state.webhooks.statuses(),
+            ywkv::Status::Read(ywkv::ReadStatus::Found),
+        )),
+    )
+}
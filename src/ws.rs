@@ -0,0 +1,210 @@
+//! A JSON command protocol over a single WebSocket connection so clients can pipeline operations
+//! without paying for a full HTTP request each time.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use ywkv::{self, Db, Response};
+
+use crate::{auth::Role, blocking, limits::Limits, Table};
+
+#[derive(Deserialize)]
+struct Request {
+    /// Echoed back in the reply so clients can match up replies with commands
+    id: Option<u64>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Command {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        /// Seconds until the key expires
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    Batch {
+        values: BTreeMap<String, String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Reply<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(flatten)]
+    response: Response<T>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/_ws",
+    tag = "values",
+    responses((
+        status = 101,
+        description = "Upgrades to a WebSocket that takes `get`, `set`, `delete` and `batch` commands as JSON and replies to each in order",
+    ))
+)]
+pub async fn upgrade(
+    ws: WebSocketUpgrade,
+    Table(db): Table,
+    State(limits): State<Arc<Limits>>,
+    Extension(role): Extension<Role>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle(socket, db, limits, role))
+}
+
+/// Run commands in the order they arrive, sending one reply per command.
+async fn handle(mut socket: WebSocket, db: Db, limits: Arc<Limits>, role: Role) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let message = match message {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) => data,
+            Message::Close(_) => break,
+            // Pings are answered automatically
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+
+        let reply = blocking({
+            let (db, limits) = (db.clone(), limits.clone());
+            move || execute(&db, &limits, role, &message)
+        })
+        .await;
+
+        if socket.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn execute(db: &Db, limits: &Limits, role: Role, message: &[u8]) -> String {
+    let request = match serde_json::from_slice::<Request>(message) {
+        Ok(v) => v,
+        Err(e) => {
+            return reply(
+                None,
+                Response::new(
+                    format!("invalid command: {e}"),
+                    ywkv::Status::Read(ywkv::ReadStatus::Failure),
+                ),
+            )
+        }
+    };
+    let id = request.id;
+
+    let writes = !matches!(request.command, Command::Get { .. });
+    if writes && role < Role::ReadWrite {
+        return reply(
+            id,
+            Response::new(
+                format!("{role:?} tokens cannot write"),
+                ywkv::Status::Write(ywkv::WriteStatus::Failure),
+            ),
+        );
+    }
+
+    let checked = match &request.command {
+        Command::Get { .. } | Command::Delete { .. } => Ok(()),
+        Command::Set { key, value, .. } => limits
+            .check_key(key)
+            .and_then(|_| limits.check_value(value.as_bytes())),
+        Command::Batch { values } => values.iter().try_for_each(|(key, value)| {
+            limits.check_key(key)?;
+            limits.check_value(value.as_bytes())
+        }),
+    };
+    if let Err(e) = checked {
+        return reply(id, error_body(e));
+    }
+
+    match request.command {
+        Command::Get { key } => reply(
+            id,
+            match db.read(key) {
+                Ok(v) => Response::new(
+                    v.into_string_lossy(),
+                    ywkv::Status::Read(ywkv::ReadStatus::Found),
+                ),
+                Err(e) => error_body(Response::from_read_error(e)),
+            },
+        ),
+        Command::Set { key, value, ttl } => reply(
+            id,
+            match db.write_with_ttl(key, value, ttl.map(Duration::from_secs)) {
+                Ok(Some(old_value)) => Response::new(
+                    old_value.into_string_lossy(),
+                    ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+                ),
+                Ok(None) => Response::new(
+                    String::new(),
+                    ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+                ),
+                Err(e) => error_body(Response::from_write_error(e)),
+            },
+        ),
+        Command::Delete { key } => reply(
+            id,
+            match db.delete(key) {
+                Ok(Some(old_value)) => Response::new(
+                    old_value.into_string_lossy(),
+                    ywkv::Status::Write(ywkv::WriteStatus::Deleted),
+                ),
+                Ok(None) => Response::new(
+                    String::new(),
+                    ywkv::Status::Write(ywkv::WriteStatus::Missing),
+                ),
+                Err(e) => error_body(Response::from_write_error(e)),
+            },
+        ),
+        Command::Batch { values } => match db.write_many(values) {
+            Ok(old_values) => reply(
+                id,
+                Response::new(
+                    old_values
+                        .into_iter()
+                        .map(|(key, old_value)| {
+                            let response = match old_value {
+                                Some(v) => Response::new(
+                                    v.into_string_lossy(),
+                                    ywkv::Status::Write(ywkv::WriteStatus::SuccessOverwrite),
+                                ),
+                                None => Response::new(
+                                    String::new(),
+                                    ywkv::Status::Write(ywkv::WriteStatus::SuccessNew),
+                                ),
+                            };
+
+                            (key, response)
+                        })
+                        .collect::<BTreeMap<_, _>>(),
+                    ywkv::Status::Write(ywkv::WriteStatus::SuccessUpdate),
+                ),
+            ),
+            Err(e) => reply(id, error_body(Response::from_write_error(e))),
+        },
+    }
+}
+
+/// The body of the HTTP error response, which is what the reply holds.
+fn error_body((status, Json(response)): (StatusCode, Json<Response>)) -> Response {
+    response.with_default_code(status)
+}
+
+fn reply<T: Serialize>(id: Option<u64>, response: Response<T>) -> String {
+    serde_json::to_string(&Reply { id, response }).expect("replies always serialize")
+}